@@ -18,6 +18,7 @@ use shared::{
 
 use crate::{
     game_state::NetworkGameState,
+    interpolation::RemoteInterpolation,
     network::{ManHands, ServerTick},
     ui::skills_menu::binds::BeginSkillUse,
 };
@@ -53,8 +54,10 @@ fn our_client_begin_skill_use(
     time: Res<Time>,
     tick: Res<CurrentTick>,
     mse: Res<MainServerEndpoint>,
+    interpolation: Res<RemoteInterpolation>,
     mut commands: Commands,
 ) {
+    let seen_tick = interpolation.render_tick.map(|t| Tick(t as u64));
     for BeginSkillUse { skill, unit } in ev_sa.read() {
         for (ent_id, entity, maybe_existing_skill) in our_unit.iter_mut() {
             if unit != ent_id {
@@ -79,13 +82,14 @@ fn our_client_begin_skill_use(
                         net_ent_id: *ent_id,
                         begin_casting: false,
                         skill: existing_cast.skill.clone(),
+                        seen_tick,
                     },
                 );
                 info!(
                     "Sending stop skill use {:?} for unit {:?} to server",
                     existing_cast.skill, ent_id
                 );
-                sr.send_outgoing_event_next_tick(mse.0, &event);
+                sr.send_event(mse.0, &event);
 
                 // Finally, we can change this unit to using this skill instead
                 *existing_cast = new_using_skill;
@@ -98,13 +102,14 @@ fn our_client_begin_skill_use(
                     net_ent_id: *ent_id,
                     begin_casting: true,
                     skill: skill.clone(),
+                    seen_tick,
                 },
             );
             trace!(
                 "Sending begin skill use {:?} for unit {:?} to server",
                 skill, ent_id
             );
-            sr.send_outgoing_event_next_tick(mse.0, &event);
+            sr.send_event(mse.0, &event);
         }
     }
 }
@@ -0,0 +1,352 @@
+//! Recording what we receive to a [`shared::demo`] file, and playing one back as a spectator.
+//!
+//! Playback feeds the recorded events through the same messages the network would, so a demo is
+//! drawn by the usual handlers. There is no server, so whatever we send goes nowhere.
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Write},
+};
+
+use bevy::prelude::*;
+use shared::{
+    BASE_TICKS_PER_SECOND, Config, GameAction,
+    demo::{Demo, DemoRecord, DemoWorld, KEYFRAME_INTERVAL},
+    event::{NetEntId, PlayerId, ResumeToken, client::WorldData2},
+    framed::{read_all, write_record},
+    netlib::{
+        ClientNetworkingResources, EndpointGeneral, EventToClient, MainServerEndpoint, Tick,
+        transport::MemoryNetwork,
+    },
+    snapshot::{SnapshotHistory, WorldSnapshot},
+};
+
+use crate::{
+    game_state::MenuState, interpolation::RemoteInterpolation, network::ServerTick,
+    notification::Notification, snapshots::ReceivedSnapshots,
+};
+
+/// How far the seek keys jump
+const SEEK_TICKS: u64 = KEYFRAME_INTERVAL;
+const MIN_SPEED: f32 = 0.125;
+const MAX_SPEED: f32 = 8.0;
+
+pub struct DemoPlugin;
+
+impl Plugin for DemoPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(Startup, insert_demo_resources)
+            .add_systems(
+                OnEnter(MenuState::Home),
+                start_playback.run_if(resource_exists::<DemoPlayback>),
+            )
+            .add_systems(
+                Update,
+                demo_controls.run_if(resource_exists::<DemoPlayback>),
+            );
+    }
+}
+
+fn insert_demo_resources(mut commands: Commands, args: Res<crate::ClapArgs>) {
+    if let Some(path) = &args.record_demo {
+        match File::create(path) {
+            Ok(file) => {
+                info!(?path, "Recording demo");
+                commands.insert_resource(DemoRecorder::new(BufWriter::new(file)));
+            }
+            Err(e) => error!(?e, ?path, "Failed to create demo file"),
+        }
+    }
+
+    if let Some(path) = &args.play_demo {
+        match File::open(path) {
+            Ok(file) => {
+                let demo = Demo {
+                    records: read_all(BufReader::new(file)),
+                };
+                info!(?path, count = demo.records.len(), "Loaded demo");
+                commands.insert_resource(DemoPlayback::new(demo, args.demo_seek.map(Tick)));
+            }
+            Err(e) => error!(?e, ?path, "Failed to open demo file"),
+        }
+    }
+}
+
+#[derive(Resource)]
+pub struct DemoRecorder {
+    file: BufWriter<File>,
+    world: DemoWorld,
+    /// Baselines for the snapshots we receive, so we can store them whole
+    snapshots: SnapshotHistory,
+    /// The newest tick the server told us about
+    server_tick: Option<Tick>,
+    /// Events that arrived before the first tick, so we don't know what to tag them with yet
+    pending: Vec<EventToClient>,
+    last_keyframe: Option<Tick>,
+}
+
+impl DemoRecorder {
+    fn new(file: BufWriter<File>) -> Self {
+        Self {
+            file,
+            world: DemoWorld::default(),
+            snapshots: SnapshotHistory::default(),
+            server_tick: None,
+            pending: vec![],
+            last_keyframe: None,
+        }
+    }
+
+    fn record(&mut self, events: &[(EndpointGeneral, EventToClient)]) {
+        if events.is_empty() {
+            return;
+        }
+
+        let mut written = Ok(());
+        for (_, event) in events {
+            if let EventToClient::TickHappened(tick_happened) = event {
+                let tick = self
+                    .server_tick
+                    .map_or(tick_happened.tick, |t| t.max(tick_happened.tick));
+                self.server_tick = Some(tick);
+            }
+
+            let event = self.standalone(event);
+            let Some(tick) = self.server_tick else {
+                self.pending.push(event);
+                continue;
+            };
+
+            let pending = std::mem::take(&mut self.pending);
+            for event in pending.into_iter().chain([event]) {
+                written = written.and_then(|()| self.write_event(tick, event));
+            }
+        }
+
+        // Flushed every time, so a crash doesn't lose the end of the match
+        if let Err(e) = written.and_then(|()| self.file.flush()) {
+            error!(?e, "Failed to write demo");
+        }
+    }
+
+    /// Snapshots are stored without their baseline, so playback can start from any keyframe
+    fn standalone(&mut self, event: &EventToClient) -> EventToClient {
+        let snapshot = match event {
+            EventToClient::Snapshot(snapshot) => snapshot,
+            // Whoever we share the demo with shouldn't be able to take over our player
+            EventToClient::WorldData2(world) => {
+                return EventToClient::WorldData2(WorldData2 {
+                    resume_token: ResumeToken(0),
+                    ..world.clone()
+                });
+            }
+            _ => return event.clone(),
+        };
+
+        let baseline = snapshot.baseline.and_then(|tick| self.snapshots.get(tick));
+        if snapshot.baseline.is_some() && baseline.is_none() {
+            warn!(?snapshot.baseline, "Missing snapshot baseline, recording it as is");
+            return event.clone();
+        }
+        let decoded = WorldSnapshot::decode_delta(snapshot, baseline);
+        let full = decoded.encode_delta(None);
+        self.snapshots.push(decoded);
+        EventToClient::Snapshot(full)
+    }
+
+    fn write_event(&mut self, tick: Tick, event: EventToClient) -> std::io::Result<()> {
+        self.world.apply(&event);
+        let new_world = matches!(event, EventToClient::WorldData2(_));
+        write_record(&mut self.file, &DemoRecord::Event { tick, event })?;
+
+        let keyframe_due = self
+            .last_keyframe
+            .is_none_or(|last| tick.0.saturating_sub(last.0) >= KEYFRAME_INTERVAL);
+        if (new_world || keyframe_due)
+            && let Some(world) = self.world.keyframe()
+        {
+            write_record(&mut self.file, &DemoRecord::Keyframe { tick, world })?;
+            self.last_keyframe = Some(tick);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Resource)]
+pub struct DemoPlayback {
+    demo: Demo,
+    /// Where we are, in fractional ticks
+    position: f64,
+    /// The first record we haven't played yet
+    next: usize,
+    seek: Option<Tick>,
+    finished: bool,
+}
+
+impl DemoPlayback {
+    fn new(demo: Demo, seek: Option<Tick>) -> Self {
+        let start = demo.start();
+        Self {
+            position: start.0 as f64,
+            next: 0,
+            // We always start with a seek, it gives us the first `WorldData2`
+            seek: Some(seek.unwrap_or(start)),
+            finished: false,
+            demo,
+        }
+    }
+
+    fn seek_to(&mut self, tick: Tick) -> Option<WorldData2> {
+        let tick = tick.clamp(self.demo.start(), self.demo.end());
+        let Some((world, next)) = self.demo.world_at(tick) else {
+            warn!(?tick, "No keyframe to seek to");
+            return None;
+        };
+        self.position = tick.0 as f64;
+        self.next = next;
+        self.finished = false;
+        Some(spectate(world))
+    }
+
+    /// Everything recorded up to where `delta_ticks` more of playback gets us
+    fn advance(&mut self, delta_ticks: f64) -> Vec<EventToClient> {
+        self.position += delta_ticks;
+        let until = Tick(self.position as u64);
+
+        let mut due = vec![];
+        while let Some(record) = self.demo.records.get(self.next)
+            && record.tick() <= until
+        {
+            // Keyframes are only for seeking, the events already cover them
+            match record {
+                DemoRecord::Event {
+                    event: EventToClient::WorldData2(world),
+                    ..
+                } => due.push(EventToClient::WorldData2(spectate(world.clone()))),
+                DemoRecord::Event { event, .. } => due.push(event.clone()),
+                DemoRecord::Keyframe { .. } => {}
+            }
+            self.next += 1;
+        }
+
+        if self.next >= self.demo.records.len() && !self.finished {
+            info!(?until, "Demo finished");
+            self.finished = true;
+        }
+        due
+    }
+}
+
+/// Watch as a spectator rather than as whoever recorded it
+fn spectate(world: WorldData2) -> WorldData2 {
+    WorldData2 {
+        your_player_id: PlayerId(0),
+        your_camera_unit_id: NetEntId::none(),
+        ..world
+    }
+}
+
+fn start_playback(
+    mut commands: Commands,
+    mut next_menu_state: ResMut<NextState<MenuState>>,
+    mut started: Local<bool>,
+) {
+    if *started {
+        return;
+    }
+    *started = true;
+
+    // Nothing is bound on the other end, so what we send is dropped
+    commands.insert_resource(MemoryNetwork::default());
+    next_menu_state.set(MenuState::Connecting);
+}
+
+/// Hand what arrived this frame to the event handlers. While playing a demo that is the demo
+/// instead of the network, and while recording it is written down first.
+pub fn drain_incoming_events(world: &mut World) {
+    let events = if world.contains_resource::<DemoPlayback>() {
+        playback_events(world)
+    } else {
+        let sr = world.resource::<ClientNetworkingResources>();
+        std::mem::take(&mut *sr.event_list_incoming.write().unwrap())
+    };
+
+    if let Some(mut recorder) = world.get_resource_mut::<DemoRecorder>() {
+        recorder.record(&events);
+    }
+
+    shared::event::client::dispatch_incoming_events(world, events);
+}
+
+fn playback_events(world: &mut World) -> Vec<(EndpointGeneral, EventToClient)> {
+    let endpoint = world.resource::<MainServerEndpoint>().0;
+    let delta_ticks =
+        world.resource::<Time<Virtual>>().delta_secs_f64() * BASE_TICKS_PER_SECOND as f64;
+
+    let mut playback = world.resource_mut::<DemoPlayback>();
+    let (events, seeked_to) = match playback.seek.take() {
+        Some(tick) => match playback.seek_to(tick) {
+            Some(world_data) => (
+                vec![EventToClient::WorldData2(world_data)],
+                Some(Tick(playback.position as u64)),
+            ),
+            None => (vec![], None),
+        },
+        None => (playback.advance(delta_ticks), None),
+    };
+
+    if let Some(tick) = seeked_to {
+        // Ticks and snapshots from before the seek have to be accepted again
+        let now = world.resource::<Time>().elapsed_secs_f64();
+        let mut server_tick = world.resource_mut::<ServerTick>();
+        server_tick.tick = tick;
+        server_tick.realtime = now;
+        *world.resource_mut::<ReceivedSnapshots>() = ReceivedSnapshots::default();
+        *world.resource_mut::<RemoteInterpolation>() = RemoteInterpolation::default();
+    }
+
+    events.into_iter().map(|event| (endpoint, event)).collect()
+}
+
+fn demo_controls(
+    keyboard: Res<ButtonInput<KeyCode>>,
+    mouse: Res<ButtonInput<MouseButton>>,
+    config: Res<Config>,
+    mut time: ResMut<Time<Virtual>>,
+    mut playback: ResMut<DemoPlayback>,
+    mut notif: MessageWriter<Notification>,
+) {
+    if config.just_pressed(&keyboard, &mouse, GameAction::DemoPause) {
+        if time.is_paused() {
+            time.unpause();
+            notif.write(Notification("Demo resumed".to_string()));
+        } else {
+            time.pause();
+            notif.write(Notification("Demo paused".to_string()));
+        }
+    }
+
+    let slower = config.just_pressed(&keyboard, &mouse, GameAction::DemoSlower);
+    let faster = config.just_pressed(&keyboard, &mouse, GameAction::DemoFaster);
+    if slower != faster {
+        let factor = if faster { 2.0 } else { 0.5 };
+        let speed = (time.relative_speed() * factor).clamp(MIN_SPEED, MAX_SPEED);
+        time.set_relative_speed(speed);
+        notif.write(Notification(format!("Demo speed: {speed}x")));
+    }
+
+    let back = config.just_pressed(&keyboard, &mouse, GameAction::DemoSeekBack);
+    let forward = config.just_pressed(&keyboard, &mouse, GameAction::DemoSeekForward);
+    if back != forward {
+        let position = playback.position as u64;
+        let target = if forward {
+            position + SEEK_TICKS
+        } else {
+            position.saturating_sub(SEEK_TICKS)
+        };
+        playback.seek = Some(Tick(target));
+
+        let start = playback.demo.start().0;
+        let seconds = target.saturating_sub(start) / BASE_TICKS_PER_SECOND as u64;
+        notif.write(Notification(format!("Demo: {seconds}s")));
+    }
+}
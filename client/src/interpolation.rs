@@ -0,0 +1,147 @@
+//! Drawing remote units between the states snapshots gave us, see [`shared::interpolation`].
+use std::collections::HashMap;
+
+use avian3d::prelude::{LinearVelocity, Rotation};
+use bevy::prelude::*;
+use shared::{
+    event::NetEntId,
+    interpolation::{InterpolationBuffer, InterpolationClock, MAX_EXTRAPOLATION_TICKS},
+    net_components::ents::SendNetworkTranformUpdates,
+    snapshot::WorldSnapshot,
+};
+
+use crate::{
+    game_state::NetworkGameState,
+    network::{CurrentThirdPersonControlledUnit, ServerTick},
+};
+
+pub struct InterpolationPlugin;
+
+impl Plugin for InterpolationPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<RemoteInterpolation>()
+            .add_systems(
+                Update,
+                (observe_server_tick, interpolate_remote_units)
+                    .chain()
+                    .run_if(in_state(NetworkGameState::ClientConnected)),
+            )
+            .add_systems(
+                OnExit(NetworkGameState::ClientConnected),
+                reset_interpolation,
+            );
+    }
+}
+
+#[derive(Resource, Default)]
+pub struct RemoteInterpolation {
+    pub clock: InterpolationClock,
+    buffers: HashMap<NetEntId, InterpolationBuffer>,
+    /// The server tick we last drew remote units at
+    pub render_tick: Option<f64>,
+    /// How the buffers looked the last time we drew from them
+    pub health: BufferHealth,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BufferHealth {
+    pub units: usize,
+    /// Average ticks of states we have past the render tick
+    pub buffered_ahead: f64,
+    pub extrapolating: usize,
+    /// Further past their newest state than we extrapolate, so held in place
+    pub starved: usize,
+}
+
+impl RemoteInterpolation {
+    /// Buffer every unit in the newest snapshot, which arrived at `now`
+    pub fn push_snapshot(&mut self, snapshot: &WorldSnapshot, now: f64) {
+        self.clock.observe(snapshot.tick, now);
+
+        self.buffers
+            .retain(|net_ent_id, _| snapshot.entities.contains_key(net_ent_id));
+        for (net_ent_id, state) in &snapshot.entities {
+            self.buffers
+                .entry(*net_ent_id)
+                .or_default()
+                .push(snapshot.tick, *state);
+        }
+    }
+}
+
+fn reset_interpolation(mut interpolation: ResMut<RemoteInterpolation>) {
+    *interpolation = RemoteInterpolation::default();
+}
+
+fn observe_server_tick(
+    server_tick: Res<ServerTick>,
+    mut interpolation: ResMut<RemoteInterpolation>,
+) {
+    if server_tick.is_changed() && server_tick.tick.0 > 0 {
+        interpolation
+            .clock
+            .observe(server_tick.tick, server_tick.realtime);
+    }
+}
+
+#[allow(clippy::type_complexity)]
+fn interpolate_remote_units(
+    time: Res<Time>,
+    mut interpolation: ResMut<RemoteInterpolation>,
+    mut units: Query<
+        (
+            &NetEntId,
+            &mut Transform,
+            Option<&mut LinearVelocity>,
+            Option<&mut Rotation>,
+        ),
+        (
+            With<SendNetworkTranformUpdates>,
+            Without<CurrentThirdPersonControlledUnit>,
+        ),
+    >,
+) {
+    let RemoteInterpolation {
+        clock,
+        buffers,
+        render_tick: last_render_tick,
+        health,
+    } = &mut *interpolation;
+    let Some(render_tick) = clock.render_tick(time.elapsed_secs_f64(), time.delta_secs_f64())
+    else {
+        return;
+    };
+
+    *last_render_tick = Some(render_tick);
+
+    let mut new_health = BufferHealth::default();
+    for (net_ent_id, mut transform, velocity, rotation) in &mut units {
+        let Some(buffer) = buffers.get_mut(net_ent_id) else {
+            continue;
+        };
+        new_health.units += 1;
+        new_health.buffered_ahead += buffer.buffered_ahead(render_tick);
+
+        let Some(sampled) = buffer.sample(render_tick) else {
+            continue;
+        };
+        if sampled.late_by > MAX_EXTRAPOLATION_TICKS {
+            new_health.starved += 1;
+        } else if sampled.late_by > 0.0 {
+            new_health.extrapolating += 1;
+        }
+
+        *transform = sampled.state.transform;
+        if let (Some(mut velocity), Some(sampled)) = (velocity, sampled.state.velocity) {
+            *velocity = sampled;
+        }
+        if let (Some(mut rotation), Some(sampled)) = (rotation, sampled.state.rotation) {
+            *rotation = sampled;
+        }
+    }
+
+    if new_health.units > 0 {
+        new_health.buffered_ahead /= new_health.units as f64;
+    }
+    *health = new_health;
+}
@@ -4,15 +4,20 @@ mod assets;
 mod camera;
 mod character_controller_client;
 mod debug;
+mod demo;
 pub mod game_state;
 mod grass;
+mod interpolation;
 mod login;
 mod network;
 pub mod notification;
 mod physics;
 mod picking;
+mod prediction;
 mod projectile;
 mod remote_players;
+mod rpc;
+mod snapshots;
 mod terrain;
 mod ui;
 mod water;
@@ -64,6 +69,34 @@ struct ClapArgs {
     /// If set, will add jitter equal to `0..fake_ping` to the fake ping with the given ms max jitter
     #[clap(long)]
     fake_ping_jitter: Option<u64>,
+    /// Percent of UDP datagrams to drop, in both directions. Overrides `network_conditions` in
+    /// the config. See also --net-burst-loss, --net-duplicate, --net-reorder, --net-bandwidth
+    #[clap(long)]
+    net_loss: Option<f32>,
+    /// Percent chance of starting a burst of --net-burst-length lost datagrams
+    #[clap(long)]
+    net_burst_loss: Option<f32>,
+    #[clap(long, default_value = "5")]
+    net_burst_length: u32,
+    #[clap(long)]
+    net_duplicate: Option<f32>,
+    #[clap(long)]
+    net_reorder: Option<f32>,
+    /// Bytes per second allowed each way, anything over is dropped
+    #[clap(long)]
+    net_bandwidth: Option<usize>,
+    /// Same seed and same traffic drops the same datagrams
+    #[clap(long)]
+    net_seed: Option<u64>,
+    /// Record everything the server sends us to this file, to watch later with --play-demo
+    #[clap(long)]
+    record_demo: Option<std::path::PathBuf>,
+    /// Watch a recorded demo instead of playing. See also --demo-seek
+    #[clap(long, conflicts_with = "record_demo")]
+    play_demo: Option<std::path::PathBuf>,
+    /// Server tick to start the demo from
+    #[clap(long)]
+    demo_seek: Option<u64>,
 }
 
 #[cfg(not(feature = "web"))]
@@ -153,6 +186,11 @@ fn main_client(runtime: Option<std::sync::Arc<Runtime>>) {
         character_controller_client::ClientCharacterControllerPlugin,
         animations::CharacterAnimationPlugin,
         projectile::ProjectilePlugin,
+        snapshots::SnapshotPlugin,
+        prediction::PredictionPlugin,
+        interpolation::InterpolationPlugin,
+        demo::DemoPlugin,
+        rpc::RpcPlugin,
     ))
     .insert_resource(ClearColor(Color::srgb(0.4, 0.7, 1.0))) // Sky blue
     .insert_resource(args)
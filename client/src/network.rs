@@ -1,15 +1,15 @@
 use std::time::Duration;
 
-use avian3d::prelude::{ColliderConstructor, LinearVelocity, RigidBody, Rotation};
+use avian3d::prelude::{ColliderConstructor, RigidBody};
 use bevy::{prelude::*, time::common_conditions::on_timer};
 use dashmap::DashMap;
 use shared::{
     BASE_TICKS_PER_SECOND, Config,
     event::{
-        MyNetEntParentId, NetEntId, PlayerId, UDPacketEvent,
+        MyNetEntParentId, NetEntId, PlayerId, ResumeToken, UDPacketEvent,
         client::{
-            BeginThirdpersonControllingUnit, HeartbeatChallenge, HeartbeatResponse, SpawnUnit2,
-            WorldData2,
+            BeginThirdpersonControllingUnit, ConnectionRejected, HeartbeatChallenge,
+            HeartbeatResponse, MovementCorrection, SpawnUnit2, WorldData2,
         },
         server::{
             ChangeMovement, ConnectRequest, Heartbeat, HeartbeatChallengeResponse,
@@ -22,8 +22,8 @@ use shared::{
         ours::{PlayerColor, PlayerName},
     },
     netlib::{
-        ClientNetworkingResources, EventToClient, EventToServer, FakePingSettings,
-        MainServerEndpoint, Tick, setup_incoming_client,
+        ClientNetworkingResources, EventToClient, EventToServer, MainServerEndpoint, Tick,
+        conditioner::NetworkConditionOverrides, envelope, setup_incoming_client,
     },
     physics::terrain::TerrainParams,
 };
@@ -58,6 +58,14 @@ struct PendingCameraId(NetEntId);
 #[derive(Resource)]
 struct LocalPlayerId(pub PlayerId);
 
+/// From our last `WorldData2`, so we get our player back if we reconnect to the same server
+#[derive(Resource)]
+struct ResumeSession {
+    ip: String,
+    port: u16,
+    token: ResumeToken,
+}
+
 pub struct NetworkingPlugin;
 impl Plugin for NetworkingPlugin {
     fn build(&self, app: &mut App) {
@@ -88,14 +96,10 @@ impl Plugin for NetworkingPlugin {
             // alive
             .add_systems(
                 Update,
-                (
-                    receive_world_data,
-                    shared::event::client::drain_incoming_events,
-                )
-                    .run_if(
-                        in_state(NetworkGameState::ClientSendRequestPacket)
-                            .or(in_state(NetworkGameState::ClientConnected)),
-                    ),
+                (receive_world_data, crate::demo::drain_incoming_events).run_if(
+                    in_state(NetworkGameState::ClientSendRequestPacket)
+                        .or(in_state(NetworkGameState::ClientConnected)),
+                ),
             )
             .add_systems(
                 Update,
@@ -103,6 +107,11 @@ impl Plugin for NetworkingPlugin {
                     .run_if(on_timer(Duration::from_millis(1000)))
                     .run_if(in_state(NetworkGameState::ClientSendRequestPacket)),
             )
+            .add_systems(
+                Update,
+                receive_connection_rejected
+                    .run_if(in_state(NetworkGameState::ClientSendRequestPacket)),
+            )
             // Once we are connected, advance normally
             .add_systems(
                 Update,
@@ -125,10 +134,14 @@ impl Plugin for NetworkingPlugin {
             )
             .add_systems(
                 FixedPostUpdate,
-                (shared::netlib::flush_outgoing_events_udp::<EventToClient, EventToServer>).run_if(
-                    in_state(NetworkGameState::ClientSendRequestPacket)
-                        .or(in_state(NetworkGameState::ClientConnected)),
-                ),
+                (
+                    shared::netlib::flush_outgoing_events_udp::<EventToClient, EventToServer>,
+                    shared::netlib::flush_outgoing_events_stream::<EventToClient, EventToServer>,
+                )
+                    .run_if(
+                        in_state(NetworkGameState::ClientSendRequestPacket)
+                            .or(in_state(NetworkGameState::ClientConnected)),
+                    ),
             )
             //.add_systems(
             //Update,
@@ -139,7 +152,7 @@ impl Plugin for NetworkingPlugin {
             //)
             .add_systems(
                 FixedUpdate,
-                (send_movement_camera, send_movement_unit)
+                send_movement_camera
                     .run_if(in_state(NetworkGameState::ClientConnected))
                     //.run_if(in_state(InputControlState::Freecam))
                     .run_if(in_state(GameState::Playing)),
@@ -158,6 +171,7 @@ impl Plugin for NetworkingPlugin {
                     receive_heartbeat,
                     receive_tick_just_happened,
                     receive_challenge,
+                    receive_movement_correction,
                 )
                     .run_if(in_state(NetworkGameState::ClientConnected)),
             )
@@ -169,13 +183,22 @@ impl Plugin for NetworkingPlugin {
                     .run_if(on_timer(Duration::from_millis(200)))
                     .run_if(in_state(NetworkGameState::ClientConnected)),
             )
+            .add_systems(
+                Update,
+                check_if_connection_was_lost.run_if(in_state(NetworkGameState::ClientConnected)),
+            )
             .add_systems(
                 Update,
                 check_if_we_are_timed_out
                     .run_if(in_state(NetworkGameState::ClientConnected))
+                    // Nothing answers our heartbeats while watching a demo
+                    .run_if(not(resource_exists::<crate::demo::DemoPlayback>))
                     .run_if(on_timer(Duration::from_secs(5))),
             )
-            .add_systems(Startup, insert_fake_ping_settings)
+            .add_systems(
+                Startup,
+                (insert_fake_ping_settings, insert_network_conditions),
+            )
             .add_message::<SpawnUnit2>()
             .add_message::<SpawnMan>()
             .insert_resource(ServerInterpMap {
@@ -212,6 +235,29 @@ fn insert_fake_ping_settings(mut commands: Commands, args: Res<crate::ClapArgs>)
     });
 }
 
+fn insert_network_conditions(
+    mut commands: Commands,
+    args: Res<crate::ClapArgs>,
+    config: Res<Config>,
+) {
+    let overrides = NetworkConditionOverrides {
+        loss_percent: args.net_loss,
+        burst_loss_percent: args.net_burst_loss,
+        burst_length: args.net_burst_length,
+        duplicate_percent: args.net_duplicate,
+        reorder_percent: args.net_reorder,
+        bytes_per_second: args.net_bandwidth,
+        seed: args.net_seed,
+    };
+    let conditions = overrides.apply(config.network_conditions.unwrap_or_default());
+    if conditions.outgoing.is_perfect() && conditions.incoming.is_perfect() {
+        return;
+    }
+
+    info!(?conditions, "Simulating network conditions");
+    commands.insert_resource(conditions);
+}
+
 fn spawn_networked_unit_forward_local(
     mut unit_spawns: UDPacketEvent<SpawnUnit2>,
     mut unit_spawn_writer: MessageWriter<SpawnUnit2>,
@@ -499,6 +545,7 @@ fn send_connect_packet(
     config: Res<Config>,
     mut notif: MessageWriter<Notification>,
     local_player: Query<&Transform, With<LocalCamera>>,
+    resume: Option<Res<ResumeSession>>,
 ) {
     // Use LocalCamera (FreeCam) transform if available, otherwise use default spawn location
     let my_location = local_player
@@ -509,21 +556,46 @@ fn send_connect_packet(
     //let name = args.name_override.clone().or(config.name.clone());
     let name = config.name.clone();
     let event = EventToServer::ConnectRequest(ConnectRequest {
+        protocol_version: shared::event::PROTOCOL_VERSION,
+        event_schemas: envelope::our_event_schemas(),
         name: name.clone(),
         my_location,
         color_hue: config.player_color_hue,
+        compression: config.compression(),
+        resume: resume
+            .filter(|resume| resume.ip == config.ip && resume.port == config.port)
+            .map(|resume| resume.token),
     });
     notif.write(Notification(format!(
         "Connecting server={:?} name={name:?}",
         mse.0,
     )));
-    sr.send_outgoing_event_now(mse.0, &event, None);
+    sr.send_event(mse.0, &event);
     info!("Sent connection packet to {:?}", mse.0);
 }
 
+/// The server refuses to let us in, e.g. because it was built from a different version
+fn receive_connection_rejected(
+    mut rejections: UDPacketEvent<ConnectionRejected>,
+    mut notif: MessageWriter<Notification>,
+    mut state: ResMut<NextState<NetworkGameState>>,
+) {
+    for rejection in rejections.read() {
+        error!(
+            reason = rejection.event.reason,
+            "Server rejected our connection"
+        );
+        notif.write(Notification(format!(
+            "Disconnected: {}",
+            rejection.event.reason
+        )));
+        state.set(NetworkGameState::Quit);
+    }
+}
+
 fn send_disconnect_packet(sr: Res<ClientNetworkingResources>, mse: Res<MainServerEndpoint>) {
     let event = EventToServer::IWantToDisconnect(IWantToDisconnect {});
-    sr.send_outgoing_event_now(mse.0, &event, None);
+    sr.send_event(mse.0, &event);
     info!("Sent disconnect packet to {:?}", mse.0);
 }
 
@@ -649,10 +721,18 @@ fn receive_world_data(
     ents_to_despawn: Query<Entity, Or<(With<WorldEntity>, With<TerrainEntity>)>>,
     mut msg_terrain_events: MessageWriter<SetupTerrain>,
     mut next_control_state: ResMut<NextState<crate::game_state::InputControlState>>,
+    sr: Res<ClientNetworkingResources>,
+    config: Res<Config>,
 ) {
     for event in world_data.read() {
         game_state.set(NetworkGameState::ClientConnected);
         commands.insert_resource(LocalPlayerId(event.event.your_player_id));
+        commands.insert_resource(ResumeSession {
+            ip: config.ip.clone(),
+            port: config.port,
+            token: event.event.resume_token,
+        });
+        sr.compression.insert(event.endpoint, event.event.compression);
         // We spawn in freecam
         next_control_state.set(crate::game_state::InputControlState::Freecam);
         info!("Connected to server");
@@ -704,12 +784,11 @@ fn send_heartbeat(
     sr: Res<ClientNetworkingResources>,
     mse: Res<MainServerEndpoint>,
     time: Res<Time>,
-    fake_ping: Option<Res<FakePingSettings>>,
 ) {
     let event = EventToServer::Heartbeat(Heartbeat {
         client_started_time: time.elapsed_secs_f64(),
     });
-    sr.send_outgoing_event_now(mse.0, &event, fake_ping.as_deref().cloned());
+    sr.send_event(mse.0, &event);
 }
 
 #[derive(Resource)]
@@ -727,15 +806,30 @@ fn receive_heartbeat(
     time: Res<Time>,
     mut latency_res: ResMut<LocalLatencyMeasurement>,
     mut last_heartbeat: ResMut<LastHeartbeatReceived>,
+    sr: Res<ClientNetworkingResources>,
 ) {
     for event in heartbeat_events.read() {
         let cur_client_time = time.elapsed_secs_f64();
-        let latency = 0.5 * (cur_client_time - event.event.client_started_time);
+        let rtt = cur_client_time - event.event.client_started_time;
+        sr.networking_stats
+            .record_rtt(event.endpoint, Duration::from_secs_f64(rtt.max(0.0)));
+        let latency = 0.5 * rtt;
         latency_res.latency = latency;
         last_heartbeat.time = cur_client_time;
     }
 }
 
+fn check_if_connection_was_lost(
+    sr: Res<ClientNetworkingResources>,
+    mut notif: MessageWriter<Notification>,
+    mut state: ResMut<NextState<NetworkGameState>>,
+) {
+    if !sr.take_lost_endpoints().is_empty() {
+        notif.write(Notification("Disconnected: Connection lost".to_string()));
+        state.set(NetworkGameState::Quit);
+    }
+}
+
 fn check_if_we_are_timed_out(
     time: Res<Time>,
     last_heartbeat: Res<LastHeartbeatReceived>,
@@ -817,14 +911,13 @@ fn receive_challenge(
     local: Res<LocalLatencyMeasurement>,
     sr: Res<ClientNetworkingResources>,
     mse: Res<MainServerEndpoint>,
-    fake_ping: Option<Res<FakePingSettings>>,
 ) {
     for event in heartbeat_challenges.read() {
         let event = EventToServer::HeartbeatChallengeResponse(HeartbeatChallengeResponse {
             server_time: event.event.server_time,
             local_latency_microsecs: local.latency * 1_000_000.0,
         });
-        sr.send_outgoing_event_now(mse.0, &event, fake_ping.as_deref().cloned());
+        sr.send_event(mse.0, &event);
     }
 }
 
@@ -858,47 +951,37 @@ fn apply_pending_camera_id(
     }
 }
 
-fn send_movement_camera(
-    sr: Res<ClientNetworkingResources>,
-    mse: Res<MainServerEndpoint>,
-    our_transform: Query<
-        (&Transform, &NetEntId),
-        (With<LocalCamera>, With<PlayerCamera>, Changed<Transform>),
-    >,
-    fake_ping: Option<Res<FakePingSettings>>,
+/// The server didn't let our camera move, go back to where it has it
+fn receive_movement_correction(
+    mut corrections: UDPacketEvent<MovementCorrection>,
+    mut our_camera: Query<(&mut Transform, &NetEntId), (With<LocalCamera>, With<PlayerCamera>)>,
 ) {
-    if let Ok((transform, ent_id)) = our_transform.single() {
-        let mut events = vec![];
-        events.push(EventToServer::ChangeMovement(ChangeMovement {
-            net_ent_id: *ent_id,
-            velocity: None,
-            rotation: None,
-            transform: *transform,
-        }));
-
-        sr.send_outgoing_event_now_batch(mse.0, &events, fake_ping.as_deref().cloned());
+    for correction in corrections.read() {
+        if let Ok((mut transform, ent_id)) = our_camera.single_mut()
+            && *ent_id == correction.event.net_ent_id
+        {
+            warn!(?correction.event.transform, "Server moved our camera back");
+            transform.translation = correction.event.transform.translation;
+        }
     }
 }
 
-fn send_movement_unit(
+fn send_movement_camera(
     sr: Res<ClientNetworkingResources>,
     mse: Res<MainServerEndpoint>,
     our_transform: Query<
-        (&Transform, &LinearVelocity, &Rotation, &NetEntId),
-        (With<CurrentThirdPersonControlledUnit>, Changed<Transform>),
+        (&Transform, &NetEntId),
+        (With<LocalCamera>, With<PlayerCamera>, Changed<Transform>),
     >,
-    fake_ping: Option<Res<FakePingSettings>>,
 ) {
-    if let Ok((transform, velo, rotation, ent_id)) = our_transform.single() {
+    if let Ok((transform, ent_id)) = our_transform.single() {
         let mut events = vec![];
         events.push(EventToServer::ChangeMovement(ChangeMovement {
             net_ent_id: *ent_id,
-            velocity: Some(*velo),
-            rotation: Some(*rotation),
             transform: *transform,
         }));
 
-        sr.send_outgoing_event_now_batch(mse.0, &events, fake_ping.as_deref().cloned());
+        sr.send_event_batch(mse.0, &events);
     }
 }
 
@@ -976,7 +1059,7 @@ fn our_client_wants_to_spawn_man(
     for thing in ev_sa.read() {
         let event = EventToServer::SpawnMan(thing.clone());
         info!("Sending spawn man event to server");
-        sr.send_outgoing_event_next_tick(mse.0, &event);
+        sr.send_event(mse.0, &event);
     }
 }
 
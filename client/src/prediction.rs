@@ -0,0 +1,157 @@
+//! Predicting our controlled unit from our own inputs, and reconciling with the server's acks. See
+//! [`shared::prediction`].
+use avian3d::prelude::{LinearVelocity, Position};
+use bevy::prelude::*;
+use shared::{
+    character_controller::{
+        CharacterControllerSystems, ControllerGravity, GroundNormal, Groundedness, JumpImpulse,
+        MovementAcceleration, MovementAction, MovementDampingFactor,
+    },
+    event::{NetEntId, UDPacketEvent, client::MovementAck, server::MovementInput},
+    netlib::{ClientNetworkingResources, EventToServer, MainServerEndpoint},
+    prediction::{ControllerParams, ControllerState, PredictionHistory},
+};
+
+use crate::{
+    game_state::{GameState, NetworkGameState},
+    network::CurrentThirdPersonControlledUnit,
+};
+
+pub struct PredictionPlugin;
+
+impl Plugin for PredictionPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<Prediction>()
+            .add_systems(
+                FixedUpdate,
+                (
+                    record_prediction.before(CharacterControllerSystems),
+                    send_movement_input.after(CharacterControllerSystems),
+                )
+                    .run_if(in_state(NetworkGameState::ClientConnected))
+                    .run_if(in_state(GameState::Playing)),
+            )
+            .add_systems(
+                Update,
+                reconcile.run_if(in_state(NetworkGameState::ClientConnected)),
+            )
+            .add_systems(OnExit(NetworkGameState::ClientConnected), reset_prediction);
+    }
+}
+
+#[derive(Resource, Default)]
+struct Prediction {
+    /// The unit `history` belongs to
+    unit: Option<NetEntId>,
+    history: PredictionHistory,
+}
+
+fn reset_prediction(mut prediction: ResMut<Prediction>) {
+    *prediction = Prediction::default();
+}
+
+/// Runs before the next input is applied, so the state is where physics left the last one
+fn record_prediction(
+    mut prediction: ResMut<Prediction>,
+    unit: Query<(&NetEntId, &Position, &LinearVelocity), With<CurrentThirdPersonControlledUnit>>,
+) {
+    let Ok((net_ent_id, position, velocity)) = unit.single() else {
+        return;
+    };
+    if prediction.unit == Some(*net_ent_id) {
+        prediction.history.record(ControllerState {
+            position: position.0,
+            velocity: velocity.0,
+        });
+    }
+}
+
+/// The character controller has just applied this tick's action, send it along
+fn send_movement_input(
+    mut prediction: ResMut<Prediction>,
+    unit: Query<(&NetEntId, &MovementAction), With<CurrentThirdPersonControlledUnit>>,
+    sr: Res<ClientNetworkingResources>,
+    mse: Res<MainServerEndpoint>,
+) {
+    let Ok((net_ent_id, action)) = unit.single() else {
+        return;
+    };
+    if prediction.unit != Some(*net_ent_id) {
+        *prediction = Prediction {
+            unit: Some(*net_ent_id),
+            history: PredictionHistory::default(),
+        };
+    }
+
+    prediction.history.push(action.clone());
+    let event = EventToServer::MovementInput(MovementInput {
+        net_ent_id: *net_ent_id,
+        inputs: prediction.history.to_send(),
+    });
+    sr.send_event(mse.0, &event);
+}
+
+#[allow(clippy::type_complexity)]
+fn reconcile(
+    mut acks: UDPacketEvent<MovementAck>,
+    mut prediction: ResMut<Prediction>,
+    mut unit: Query<
+        (
+            &NetEntId,
+            &mut Position,
+            &mut LinearVelocity,
+            &MovementAcceleration,
+            &MovementDampingFactor,
+            &JumpImpulse,
+            &ControllerGravity,
+            &Groundedness,
+            &GroundNormal,
+        ),
+        With<CurrentThirdPersonControlledUnit>,
+    >,
+    time: Res<Time<Fixed>>,
+) {
+    for ack in acks.read() {
+        let Ok((
+            net_ent_id,
+            mut position,
+            mut velocity,
+            acceleration,
+            damping,
+            jump_impulse,
+            gravity,
+            groundedness,
+            ground_normal,
+        )) = unit.single_mut()
+        else {
+            continue;
+        };
+        if ack.event.net_ent_id != *net_ent_id || prediction.unit != Some(*net_ent_id) {
+            continue;
+        }
+
+        let params = ControllerParams {
+            acceleration: acceleration.0,
+            damping: damping.0,
+            jump_impulse: jump_impulse.0,
+            gravity: gravity.0,
+            is_grounded: groundedness.0,
+            ground_normal: ground_normal.0,
+        };
+        let corrected = prediction.history.acknowledge(
+            ack.event.sequence,
+            ack.event.state,
+            &params,
+            time.timestep().as_secs_f32(),
+        );
+        if let Some(state) = corrected {
+            debug!(
+                sequence = ack.event.sequence,
+                error = ?(state.position - position.0),
+                "Server corrected our prediction"
+            );
+            position.0 = state.position;
+            velocity.0 = state.velocity;
+        }
+    }
+}
@@ -0,0 +1,54 @@
+//! Sending the calls made through [`RpcClient`] and handing replies back, see [`shared::rpc`].
+use bevy::prelude::*;
+use shared::{
+    event::{UDPacketEvent, client::RpcResponse},
+    netlib::{ClientNetworkingResources, EventToServer, MainServerEndpoint},
+    rpc::{RpcClient, RpcError},
+};
+
+use crate::game_state::NetworkGameState;
+
+pub struct RpcPlugin;
+
+impl Plugin for RpcPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<RpcClient>()
+            .add_systems(
+                Update,
+                (receive_rpc_responses, expire_rpc_calls)
+                    .chain()
+                    .run_if(in_state(NetworkGameState::ClientConnected)),
+            )
+            // After everything that might have called this frame
+            .add_systems(
+                PostUpdate,
+                send_rpc_requests.run_if(in_state(NetworkGameState::ClientConnected)),
+            )
+            .add_systems(OnExit(NetworkGameState::ClientConnected), fail_rpc_calls);
+    }
+}
+
+fn receive_rpc_responses(mut responses: UDPacketEvent<RpcResponse>, mut rpc: ResMut<RpcClient>) {
+    for response in responses.read() {
+        rpc.resolve(response.event.clone());
+    }
+}
+
+fn expire_rpc_calls(time: Res<Time>, mut rpc: ResMut<RpcClient>) {
+    rpc.expire(time.elapsed_secs_f64());
+}
+
+fn send_rpc_requests(
+    time: Res<Time>,
+    mut rpc: ResMut<RpcClient>,
+    sr: Res<ClientNetworkingResources>,
+    mse: Res<MainServerEndpoint>,
+) {
+    for request in rpc.take_outgoing(time.elapsed_secs_f64()) {
+        sr.send_event(mse.0, &EventToServer::RpcRequest(request));
+    }
+}
+
+fn fail_rpc_calls(mut rpc: ResMut<RpcClient>) {
+    rpc.fail_all(RpcError::Disconnected);
+}
@@ -0,0 +1,74 @@
+use bevy::prelude::*;
+use shared::{
+    event::{UDPacketEvent, client::Snapshot, server::SnapshotAck},
+    netlib::{ClientNetworkingResources, EventToServer, MainServerEndpoint},
+    snapshot::{SnapshotHistory, WorldSnapshot},
+};
+
+use crate::{game_state::NetworkGameState, interpolation::RemoteInterpolation};
+
+pub struct SnapshotPlugin;
+
+impl Plugin for SnapshotPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ReceivedSnapshots>()
+            .add_systems(
+                Update,
+                receive_snapshots.run_if(in_state(NetworkGameState::ClientConnected)),
+            )
+            .add_systems(OnExit(NetworkGameState::ClientConnected), reset_snapshots);
+    }
+}
+
+#[derive(Resource, Default)]
+pub struct ReceivedSnapshots {
+    /// Decoded snapshots, used as baselines for the deltas the server sends us
+    pub history: SnapshotHistory,
+    /// The newest snapshot we have handed to interpolation
+    pub applied: Option<WorldSnapshot>,
+}
+
+fn reset_snapshots(mut received: ResMut<ReceivedSnapshots>) {
+    *received = ReceivedSnapshots::default();
+}
+
+/// Decode snapshots, buffer the newest ones for interpolation, and ack them so the server can
+/// delta against them.
+fn receive_snapshots(
+    time: Res<Time>,
+    mut snapshots: UDPacketEvent<Snapshot>,
+    mut received: ResMut<ReceivedSnapshots>,
+    mut interpolation: ResMut<RemoteInterpolation>,
+    sr: Res<ClientNetworkingResources>,
+    mse: Res<MainServerEndpoint>,
+) {
+    for snapshot in snapshots.read() {
+        let baseline = match snapshot.event.baseline {
+            Some(tick) => match received.history.get(tick) {
+                Some(baseline) => Some(baseline),
+                None => {
+                    warn!(?tick, "Missing snapshot baseline, skipping");
+                    continue;
+                }
+            },
+            None => None,
+        };
+        let decoded = WorldSnapshot::decode_delta(&snapshot.event, baseline);
+
+        sr.send_event(
+            mse.0,
+            &EventToServer::SnapshotAck(SnapshotAck { tick: decoded.tick }),
+        );
+
+        let is_newer = received
+            .applied
+            .as_ref()
+            .is_none_or(|applied| decoded.tick > applied.tick);
+        if is_newer {
+            interpolation.push_snapshot(&decoded, time.elapsed_secs_f64());
+            received.applied = Some(decoded.clone());
+        }
+
+        received.history.push(decoded);
+    }
+}
@@ -126,6 +126,7 @@ pub fn handle_home_buttons(
     //next_game_state: ResMut<NextState<GameState>>,
     mut next_menu_state: ResMut<NextState<MenuState>>,
     mut _config: ResMut<crate::Config>,
+    mut _commands: Commands,
 ) {
     // Play button - skip networking, go directly to single-player
     for interaction in play_query.iter_mut() {
@@ -133,17 +134,23 @@ pub fn handle_home_buttons(
             info!("Play button pressed - starting single-player");
             #[cfg(feature = "singleplayer")]
             {
-                use shared::netlib::NetworkConnectionTarget;
+                use shared::netlib::{NetworkConnectionTarget, transport::MemoryNetwork};
                 let port = rand::random_range(20000..60000);
                 _config.port = port;
                 _config.ip = "127.0.0.1".to_string();
                 _config.host_ip = None;
+                // Client and server talk over this instead of sockets
+                let memory = MemoryNetwork::default();
+                _commands.insert_resource(memory.clone());
                 std::thread::spawn(move || {
                     // TODO exit
-                    server::call_from_client_for_singleplayer(NetworkConnectionTarget {
-                        ip: "127.0.0.1".to_string(),
-                        port,
-                    });
+                    server::call_from_client_for_singleplayer(
+                        NetworkConnectionTarget {
+                            ip: "127.0.0.1".to_string(),
+                            port,
+                        },
+                        memory,
+                    );
                 });
                 next_menu_state.set(MenuState::Connecting);
             }
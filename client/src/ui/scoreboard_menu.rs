@@ -1,12 +1,16 @@
 use super::styles::*;
-use crate::game_state::OverlayMenuState;
+use crate::{game_state::OverlayMenuState, interpolation::RemoteInterpolation};
 
 use std::time::Duration;
 
-use bevy::{prelude::*, time::common_conditions::on_timer};
+use bevy::{platform::time::Instant, prelude::*, time::common_conditions::on_timer};
 use shared::{
-    event::{PlayerId, UDPacketEvent, client::RequestScoreboardResponse},
-    netlib::{ClientNetworkingResources, FakePingSettings, MainServerEndpoint, NetworkingStats},
+    event::PlayerId,
+    netlib::{ClientNetworkingResources, NetworkingStats},
+    rpc::{
+        RpcClient, RpcHandle,
+        methods::{RequestScoreboard, RequestScoreboardResponse},
+    },
 };
 
 /// Marker for the paused menu root entity
@@ -25,30 +29,34 @@ pub struct ScoreboardMenuLoading;
 #[derive(Component)]
 pub struct DebugNetworkMenu;
 
+/// The scoreboard we asked for and are still waiting on
+#[derive(Resource, Default)]
+pub struct ScoreboardCall(Option<RpcHandle<RequestScoreboard>>);
+
 pub struct ScoreboardMenuPlugin;
 
 impl Plugin for ScoreboardMenuPlugin {
     fn build(&self, app: &mut App) {
         app.add_systems(
             OnEnter(OverlayMenuState::Scoreboard),
-            (spawn_scoreboard_menu, send_scoreboard_request_packet),
+            (spawn_scoreboard_menu, request_scoreboard),
         )
         .add_systems(
             OnExit(OverlayMenuState::Scoreboard),
-            despawn_scoreboard_menu,
+            (despawn_scoreboard_menu, forget_scoreboard_call),
         )
         .add_systems(
             Update,
             (
                 handle_scoreboard_menu_buttons,
-                handle_scoreboard_data_packet,
+                handle_scoreboard_response,
                 update_scoreboard_menu,
             )
                 .run_if(in_state(OverlayMenuState::Scoreboard)),
         )
         .add_systems(
             Update,
-            send_scoreboard_request_packet.run_if(
+            request_scoreboard.run_if(
                 in_state(OverlayMenuState::Scoreboard).and(on_timer(Duration::from_millis(100))),
             ),
         )
@@ -58,7 +66,8 @@ impl Plugin for ScoreboardMenuPlugin {
                 in_state(OverlayMenuState::Scoreboard).and(on_timer(Duration::from_millis(500))),
             ),
         )
-        .add_message::<RequestScoreboardResponse>();
+        .add_message::<RequestScoreboardResponse>()
+        .init_resource::<ScoreboardCall>();
     }
 }
 
@@ -121,22 +130,20 @@ pub fn spawn_scoreboard_menu(mut commands: Commands) {
         });
 }
 
-pub fn send_scoreboard_request_packet(
-    sr: Res<ClientNetworkingResources>,
-    mse: Res<MainServerEndpoint>,
-    fake_ping: Option<Res<FakePingSettings>>,
-) {
-    // send packet
-    let event = shared::event::server::RequestScoreboard {};
-    sr.send_outgoing_event_now(
-        mse.0,
-        &shared::netlib::EventToServer::RequestScoreboard(event),
-        fake_ping.as_deref().cloned(),
-    );
+/// Ask for the scoreboard, unless we are still waiting for the last one
+pub fn request_scoreboard(mut rpc: ResMut<RpcClient>, mut call: ResMut<ScoreboardCall>) {
+    if call.0.is_none() {
+        call.0 = Some(rpc.call(&RequestScoreboard {}));
+    }
+}
+
+fn forget_scoreboard_call(mut call: ResMut<ScoreboardCall>) {
+    call.0 = None;
 }
 
 pub fn update_debug_network_menu(
     res: Res<ClientNetworkingResources>,
+    interpolation: Res<RemoteInterpolation>,
     mut text_part: Query<&mut Text, With<DebugNetworkMenu>>,
 ) {
     let stats: &NetworkingStats = &res.networking_stats;
@@ -159,8 +166,83 @@ pub fn update_debug_network_menu(
             },
         );
 
+    let budget = stats
+        .budget_bytes_per_second
+        .load(std::sync::atomic::Ordering::Relaxed);
+    let sent_to_server: usize = stats
+        .recent_bytes_sent_per_endpoint
+        .read()
+        .unwrap()
+        .values()
+        .sum();
+    let budget_line = format!(
+        "Budget: {:4}KB/sec, using {:4}KB/sec",
+        budget / 1024,
+        sent_to_server / 1024
+    );
+
+    let compressed_from = stats
+        .recent_bytes_before_compression
+        .read()
+        .unwrap()
+        .back()
+        .copied()
+        .unwrap_or_default();
+    let compressed_to = stats
+        .recent_bytes_after_compression
+        .read()
+        .unwrap()
+        .back()
+        .copied()
+        .unwrap_or_default();
+    let compression_line = format!(
+        "Compressed: {:4}KB/sec down to {:4}KB/sec",
+        compressed_from / 1024,
+        compressed_to / 1024
+    );
+
+    let connection_lines: Vec<_> = stats
+        .per_endpoint
+        .iter()
+        .map(|counters| {
+            let connection = counters.snapshot(Instant::now());
+            format!(
+                "{:?}: rtt {}, loss {:4.1}%, in {:4}KB/sec, out {:4}KB/sec, {} resent, seen {}",
+                counters.key(),
+                connection
+                    .rtt_ms
+                    .map_or("?".to_string(), |rtt| format!("{rtt:.0}ms")),
+                connection.estimated_loss * 100.0,
+                connection.bytes_received_last_second / 1024,
+                connection.bytes_sent_last_second / 1024,
+                connection.packets_resent,
+                connection
+                    .last_seen_secs_ago
+                    .map_or("never".to_string(), |ago| format!("{ago:.1}s ago")),
+            )
+        })
+        .collect();
+
+    let health = &interpolation.health;
+    let interpolation_line = format!(
+        "Interp: {:4.1} ticks behind (target {:4.1}, jitter {:4.1})",
+        interpolation.clock.delay(),
+        interpolation.clock.target_delay(),
+        interpolation.clock.jitter(),
+    );
+    let buffer_line = format!(
+        "Buffered: {:4.1} ticks ahead for {} units, {} extrapolating, {} starved",
+        health.buffered_ahead, health.units, health.extrapolating, health.starved
+    );
+
     for mut text in text_part.iter_mut() {
-        let mut parts = Vec::new();
+        let mut parts = vec![
+            budget_line.clone(),
+            compression_line.clone(),
+            interpolation_line.clone(),
+            buffer_line.clone(),
+        ];
+        parts.extend(connection_lines.iter().cloned());
         for (sent_pkts, recv_pkts, sent_bytes, recv_bytes, ignored_bytes) in
             zipped.clone().rev().take(10)
         {
@@ -279,23 +361,34 @@ pub fn update_scoreboard_menu(
     }
 }
 
-pub fn handle_scoreboard_data_packet(
-    mut packets: UDPacketEvent<RequestScoreboardResponse>,
+pub fn handle_scoreboard_response(
+    mut call: ResMut<ScoreboardCall>,
     mut commands: Commands,
     mut mwriter: MessageWriter<RequestScoreboardResponse>,
     menu_loading_query: Query<Entity, With<ScoreboardMenuLoading>>,
 ) {
-    for packet in packets.read() {
-        trace!("Received scoreboard data packet: {:?}", packet.event);
-        // despawn loading screen
-        if let Ok(loading_entity) = menu_loading_query.single() {
-            commands.entity(loading_entity).despawn();
-            // this means we also need to spawn the new scoreboard element
-            spawn_scoreboard_menu_base(&mut commands);
+    let Some(result) = call.0.as_mut().and_then(|handle| handle.try_take()) else {
+        return;
+    };
+    call.0 = None;
+
+    let scoreboard = match result {
+        Ok(scoreboard) => scoreboard,
+        Err(e) => {
+            warn!(%e, "Couldn't get the scoreboard");
+            return;
         }
+    };
+    trace!("Received scoreboard: {:?}", scoreboard);
 
-        mwriter.write(packet.event.clone());
+    // despawn loading screen
+    if let Ok(loading_entity) = menu_loading_query.single() {
+        commands.entity(loading_entity).despawn();
+        // this means we also need to spawn the new scoreboard element
+        spawn_scoreboard_menu_base(&mut commands);
     }
+
+    mwriter.write(scoreboard);
 }
 
 /// The scoreboard is a large box in the center of the screen with a list of players and their
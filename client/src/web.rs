@@ -5,7 +5,8 @@ use web_sys::HtmlParagraphElement;
 use web_sys::js_sys::Reflect;
 
 use shared::netlib::ClientNetworkingResources;
-use shared::netlib::MainServerEndpoint;
+use shared::netlib::EndpointGeneral;
+use shared::netlib::transport::{Transport, TransportKind};
 
 //use raw_window_handle::HasRawWindowHandle;
 
@@ -33,10 +34,6 @@ impl Plugin for WebPlugin {
             .parse()
             .expect("server_port element text content is not a valid u16");
 
-        let ip_addr: std::net::IpAddr = ip_str
-            .parse()
-            .expect("server_ip element text content is not a valid IpAddr");
-
         let loading_element = document
             .get_element_by_id("loading")
             .expect("No loading_status element in html")
@@ -112,11 +109,6 @@ impl Plugin for WebPlugin {
             config.ip = ip_str.clone();
             config.port = port.clone();
         });
-
-        app.add_systems(
-            Update,
-            send_messages_over_websocket.run_if(resource_exists::<WebSocketResource>),
-        );
     }
 }
 
@@ -156,49 +148,29 @@ impl std::ops::Deref for MagicWebSocketPointer {
 unsafe impl Send for MagicWebSocketPointer {}
 unsafe impl Sync for MagicWebSocketPointer {}
 
-#[derive(Resource)]
-pub struct WebSocketResource {
+/// Sends what `flush_outgoing_events_stream` queued for the server
+struct WebSocketTransport {
     websocket: MagicWebSocketPointer,
 }
 
-fn send_messages_over_websocket(
-    net_res: Res<ClientNetworkingResources>,
-    ws_res: Res<WebSocketResource>,
-    our_endpoint: Res<MainServerEndpoint>,
-) {
-    let taken_events;
-    if let Some(mut m) = net_res
-        .event_list_outgoing_websocket
-        .get_mut(&our_endpoint.as_websocket().unwrap())
-    {
-        taken_events = std::mem::take(&mut *m);
-    } else {
-        taken_events = Vec::new();
-    }
-
-    trace!(
-        "Sending {} queued events over websocket",
-        taken_events.len()
-    );
-    if taken_events.is_empty() {
-        return;
+impl Transport for WebSocketTransport {
+    fn send(&self, _endpoint: EndpointGeneral, data: &[u8]) -> bool {
+        self.websocket.send_with_u8_array(data).is_ok()
     }
-
-    use shared::netlib::EventGroupingRef;
-    let data = postcard::to_stdvec(&EventGroupingRef::Batch(&taken_events)).unwrap();
-
-    // don't check result
-    let _ = ws_res.websocket.send_with_u8_array(&data);
 }
 
-fn setup(
-    mut commands: Commands,
-    net_res: Res<ClientNetworkingResources>,
-    main_server_endpoint: Res<MainServerEndpoint>,
-) {
-    let ws_endpoint = main_server_endpoint.as_websocket().unwrap();
-    let addr = ws_endpoint.socket_addr;
-    let url = format!("ws://{}:{}", addr.ip(), addr.port());
+fn setup(net_res: Res<ClientNetworkingResources>, config: Res<crate::Config>) {
+    // Browsers won't let an https page open a plain ws:// socket
+    let page_is_https = web_sys::window()
+        .and_then(|window| window.location().protocol().ok())
+        .is_some_and(|protocol| protocol == "https:");
+    let scheme = if page_is_https { "wss" } else { "ws" };
+    // By the name we were given, a certificate is almost never for a bare IP
+    let host = match config.ip.parse::<std::net::Ipv6Addr>() {
+        Ok(ip) => format!("[{ip}]"),
+        Err(_) => config.ip.clone(),
+    };
+    let url = format!("{scheme}://{host}:{}", config.port);
     let ws = web_sys::WebSocket::new(&url).unwrap();
     let magic_ws = MagicWebSocketPointer::new(ws);
 
@@ -257,11 +229,9 @@ fn setup(
             let data_len = chunk_from_blob.len();
             trace!("Received blob of size {}", data_len);
 
-            let data_ptr = our_resources.event_list_incoming_websocket.clone();
             shared::netlib::on_data_incoming(
                 &our_resources,
-                ws_endpoint,
-                data_ptr,
+                EndpointGeneral::WebSocket(ws_endpoint),
                 &chunk_from_blob,
             );
         });
@@ -275,7 +245,10 @@ fn setup(
     magic_ws.set_onopen(Some(onopen_callback.as_ref().unchecked_ref()));
     onopen_callback.forget();
 
-    commands.insert_resource(WebSocketResource {
-        websocket: magic_ws,
-    });
+    net_res.transports.insert(
+        TransportKind::WebSocket,
+        std::sync::Arc::new(WebSocketTransport {
+            websocket: magic_ws,
+        }),
+    );
 }
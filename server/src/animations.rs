@@ -1,32 +1,39 @@
 use bevy::prelude::*;
+use rand::RngExt;
 use shared::{
     CurrentTick,
-    event::{NetEntId, PlayerId, UDPacketEvent, client::SpawnProjectile, server::CastSkillUpdate},
+    event::{NetEntId, UDPacketEvent, client::SpawnProjectile, server::CastSkillUpdate},
     net_components::{ents::SendNetworkTranformUpdates, make_npc, ours::ControlledBy},
     netlib::ServerNetworkingResources,
     physics::terrain::TerrainParams,
     projectile::{ProjectileAI, ProjectileSource},
+    rng::GameRng,
     skills::{
         Skill,
         animations::{CastComplete, SharedAnimationPlugin, UnitFinishedSkillCast, UsingSkillSince},
     },
 };
 
-use crate::{ConnectedPlayer, EndpointToPlayerId, PlayerEndpoint};
+use crate::{EndpointToPlayerId, interest::Interest};
 
 pub struct AnimationPluginServer;
 
 impl Plugin for AnimationPluginServer {
     fn build(&self, app: &mut App) {
-        app.add_plugins(SharedAnimationPlugin)
-            .add_systems(Update, (on_unit_begin_skill_use, on_unit_finish_cast));
+        app.add_plugins(SharedAnimationPlugin).add_systems(
+            Update,
+            (
+                on_unit_begin_skill_use,
+                // See `shared::rng` for why everything drawing from it runs in order
+                on_unit_finish_cast.after(shared::projectile::update_projectiles),
+            ),
+        );
     }
 }
 
 // Lets of TODO here
 fn on_unit_begin_skill_use(
     mut skill_change: UDPacketEvent<CastSkillUpdate>,
-    current_tick: Res<shared::CurrentTick>,
     mut our_unit: Query<
         (
             &NetEntId,
@@ -38,9 +45,8 @@ fn on_unit_begin_skill_use(
     >,
     sr: Res<ServerNetworkingResources>,
     endpont_to_player: Res<EndpointToPlayerId>,
-    time: Res<Time>,
-    tick: Res<CurrentTick>,
-    clients: Query<(&PlayerEndpoint, &PlayerId), With<ConnectedPlayer>>,
+    (time, tick): (Res<Time>, Res<CurrentTick>),
+    interest: Res<Interest>,
     mut commands: Commands,
 ) {
     for packet in skill_change.read() {
@@ -121,29 +127,31 @@ fn on_unit_begin_skill_use(
                 net_ent_id: packet.event.net_ent_id,
                 begin_casting: packet.event.begin_casting && !cancelled,
                 skill: packet.event.skill.clone(),
-                begin_casting_tick: current_tick.0,
+                begin_casting_tick: tick.0,
             },
         );
-        for (client_endpoint, _client_player_id) in clients {
-            if client_endpoint.0 == packet.endpoint {
-                // Don't send back to the original sender
-                continue;
-            }
-            sr.send_outgoing_event_next_tick(client_endpoint.0, &event_to_send);
-        }
+        // The sender already knows what it is casting
+        interest.send_to_known(
+            &sr,
+            &packet.event.net_ent_id,
+            &event_to_send,
+            Some(packet.endpoint),
+        );
     }
 }
 
-fn on_unit_finish_cast(
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn on_unit_finish_cast(
     mut cast_event_reader: MessageReader<UnitFinishedSkillCast>,
     query: Query<(&Transform, &NetEntId), With<UsingSkillSince>>,
     _time: Res<Time>,
     server_tick: Res<CurrentTick>,
     mut commands: Commands,
-    connected_clients: Query<&PlayerEndpoint, With<ConnectedPlayer>>,
+    interest: Res<Interest>,
     sr: Res<ServerNetworkingResources>,
     mut spawn_projectile_writer: MessageWriter<SpawnProjectile>,
     terrain: Res<TerrainParams>,
+    mut rng: ResMut<GameRng>,
 ) {
     for UnitFinishedSkillCast {
         tick,
@@ -182,9 +190,9 @@ fn on_unit_finish_cast(
                                 || next_target.length_squared() > 40.0
                             {
                                 next_target = Vec3::new(
-                                    rand::random_range(-10.0..10.0),
+                                    rng.random_range(-10.0..10.0),
                                     0.0,
-                                    rand::random_range(-10.0..10.0),
+                                    rng.random_range(-10.0..10.0),
                                 );
                             }
                             cur_pos += next_target;
@@ -221,9 +229,9 @@ fn on_unit_finish_cast(
                 }
                 Skill::SummonTestNPC => {
                     let random_xy = Vec3::new(
-                        rand::random_range(-5.0..5.0),
+                        rng.random_range(-5.0..5.0),
                         0.0,
-                        rand::random_range(-5.0..5.0),
+                        rng.random_range(-5.0..5.0),
                     );
                     let transform = Transform::from_translation(
                         transform.translation + Vec3::Y * 2.5 + random_xy,
@@ -232,17 +240,14 @@ fn on_unit_finish_cast(
                         ?net_ent_id,
                         "Spawning test NPC at {:?}", transform.translation
                     );
-                    let npc = make_npc(transform);
+                    let npc = make_npc(&mut rng, transform);
                     npc.clone().spawn_entity(&mut commands);
-                    let event = shared::netlib::EventToClient::SpawnUnit2(npc);
-                    for client_endpoint in &connected_clients {
-                        sr.send_outgoing_event_next_tick(client_endpoint.0, &event);
-                    }
+                    interest.send_spawn(&sr, &npc);
                 }
 
                 Skill::Blink => {
                     let random_xy =
-                        Vec2::new(rand::random_range(-5.0..5.0), rand::random_range(-5.0..5.0))
+                        Vec2::new(rng.random_range(-5.0..5.0), rng.random_range(-5.0..5.0))
                             .normalize()
                             * 2.0;
 
@@ -256,19 +261,19 @@ fn on_unit_finish_cast(
                     );
                     use crate::ToNetComponent;
                     use shared::net_components::ents::Tower;
-                    let npc = shared::event::client::SpawnUnit2::new_with_vec(vec![
-                        transform.to_net_component(),
-                        Tower.to_net_component(),
-                        //avian3d::prelude::RigidBody::Dynamic.to_net_component(),
-                        //avian3d::prelude::Collider::sphere(3.0).to_net_component(),
-                        //avian3d::prelude::Mass(70.0).to_net_component(),
-                    ]);
+                    let npc = shared::event::client::SpawnUnit2::new_with_vec(
+                        &mut rng,
+                        vec![
+                            transform.to_net_component(),
+                            Tower.to_net_component(),
+                            //avian3d::prelude::RigidBody::Dynamic.to_net_component(),
+                            //avian3d::prelude::Collider::sphere(3.0).to_net_component(),
+                            //avian3d::prelude::Mass(70.0).to_net_component(),
+                        ],
+                    );
 
                     npc.clone().spawn_entity(&mut commands);
-                    let event = shared::netlib::EventToClient::SpawnUnit2(npc);
-                    for client_endpoint in &connected_clients {
-                        sr.send_outgoing_event_next_tick(client_endpoint.0, &event);
-                    }
+                    interest.send_spawn(&sr, &npc);
 
                     info!(
                         ?net_ent_id,
@@ -1,11 +1,17 @@
-use axum::Router;
+use axum::{
+    Router,
+    http::{StatusCode, header},
+};
 use bevy::{prelude::*, time::common_conditions::on_real_timer};
 use shared::{
-    event::PlayerId, net_components::ours::PlayerName, netlib::ServerNetworkingResources,
+    PlayerPing, PlayerPingInteger,
+    event::PlayerId,
+    net_components::ours::PlayerName,
+    netlib::{ServerNetworkingResources, connection_stats::ConnectionStats},
     tokio_udp::TokioRuntimeResource,
 };
 
-use crate::{ConnectedPlayer, ServerState};
+use crate::{ConnectedPlayer, HeartbeatList, PlayerEndpoint, ServerState, metrics};
 
 pub struct AxumServerPlugin;
 
@@ -14,7 +20,7 @@ impl Plugin for AxumServerPlugin {
         app.add_systems(OnEnter(ServerState::Running), setup_shared_axum_server);
         app.add_systems(
             Update,
-            (respond_to_get_players_request,)
+            (respond_to_get_players_request, respond_to_metrics_request)
                 .run_if(in_state(ServerState::Running))
                 .run_if(on_real_timer(std::time::Duration::from_millis(100))),
         );
@@ -29,12 +35,18 @@ pub struct AxumRequestId(pub u64);
 #[derive(Debug, Clone)]
 pub struct BevyAxumRequestGetPlayers;
 
+#[derive(Debug, Clone)]
+pub struct BevyAxumRequestMetrics;
+
 use serde::{Deserialize, Serialize};
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PlayerInfo {
     // serailze as number doesnt work here because u64
     pub id: String,
     pub name: String,
+    pub ping: Option<PlayerPing<PlayerPingInteger>>,
+    /// What we have seen of their connection, see [`ConnectionStats`]
+    pub connection: Option<ConnectionStats>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -50,6 +62,9 @@ use std::sync::Arc;
 pub struct AxumServerResource {
     pub get_players_requests: Arc<DashMap<AxumRequestId, BevyAxumRequestGetPlayers>>,
     pub get_players_replys: Arc<DashMap<AxumRequestId, BevyAxumReplyGetPlayers>>,
+    pub metrics_requests: Arc<DashMap<AxumRequestId, BevyAxumRequestMetrics>>,
+    /// Rendered by [`crate::metrics::render_metrics`]
+    pub metrics_replys: Arc<DashMap<AxumRequestId, String>>,
 }
 
 #[derive(Clone)]
@@ -76,6 +91,7 @@ fn setup_shared_axum_server(
         .route("/healthz", axum::routing::get(|| async { "OK" }))
         .route("/", axum::routing::get(|| async { "Hello, World!" }))
         .route("/players", axum::routing::get(get_players_endpoint))
+        .route("/metrics", axum::routing::get(get_metrics_endpoint))
         .with_state(state);
 
     let _x = tokio_runtime.spawn(async move {
@@ -117,9 +133,61 @@ async fn get_players_endpoint(
     })
 }
 
+async fn get_metrics_endpoint(
+    state: axum::extract::State<AxumState>,
+) -> impl axum::response::IntoResponse {
+    let req = AxumRequestId(rand::random());
+    state
+        .axum_bevy_queues
+        .metrics_requests
+        .insert(req, BevyAxumRequestMetrics);
+
+    for _ in 0..100 {
+        if let Some(reply) = state.axum_bevy_queues.metrics_replys.remove(&req) {
+            return (
+                StatusCode::OK,
+                [(header::CONTENT_TYPE, metrics::CONTENT_TYPE)],
+                reply.1,
+            );
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+
+    warn!("Metrics reply timed out for request: {:?}", req);
+    state.axum_bevy_queues.metrics_requests.remove(&req);
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        [(header::CONTENT_TYPE, metrics::CONTENT_TYPE)],
+        String::new(),
+    )
+}
+
+fn respond_to_metrics_request(world: &mut World) {
+    let axum_server_res = world.resource::<AxumServerResource>().clone();
+    if axum_server_res.metrics_requests.is_empty() {
+        return;
+    }
+
+    let text = match world.run_system_cached(metrics::render_metrics) {
+        Ok(text) => text,
+        Err(e) => {
+            error!(?e, "Couldn't render metrics");
+            return;
+        }
+    };
+    axum_server_res.metrics_requests.retain(|request, _| {
+        axum_server_res
+            .metrics_replys
+            .insert(*request, text.clone());
+        false
+    });
+}
+
 fn respond_to_get_players_request(
-    connected_player_query: Query<(&PlayerId, &PlayerName), With<ConnectedPlayer>>,
+    connected_player_query: Query<(&PlayerId, &PlayerName, &PlayerEndpoint), With<ConnectedPlayer>>,
     axum_server_res: Res<AxumServerResource>,
+    heartbeat_mapping: Res<HeartbeatList>,
+    sr: Res<ServerNetworkingResources>,
 ) {
     let mut key_to_remove = vec![];
     for request in axum_server_res.get_players_requests.iter() {
@@ -127,10 +195,15 @@ fn respond_to_get_players_request(
             error: false,
             players: vec![],
         };
-        for (player_id, player_name) in connected_player_query.iter() {
+        for (player_id, player_name, endpoint) in connected_player_query.iter() {
             reply.players.push(PlayerInfo {
                 id: player_id.0.to_string(),
                 name: player_name.name.clone(),
+                ping: heartbeat_mapping
+                    .pings
+                    .get(player_id)
+                    .map(|ping| ping.to_integer()),
+                connection: sr.networking_stats.connection_stats(endpoint.0),
             });
         }
 
@@ -0,0 +1,238 @@
+//! Records every event the server receives, and plays a recording back without any network.
+//!
+//! A capture is a file of [`shared::framed`] [`CapturedEvent`]s. Replaying one seeds the server's
+//! [`GameRng`] with the recorded seed and hands each event to the server on the tick it first
+//! arrived, so the server ends up in the same state. That takes the replay running exactly one
+//! fixed tick per update, like the harness does, rather than following the clock. UDP and
+//! websocket endpoints can't be recreated, so replayed events come from in-memory endpoints, one
+//! per captured endpoint.
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::File,
+    io::{BufReader, BufWriter, Write},
+    net::SocketAddr,
+    path::PathBuf,
+};
+
+use bevy::{prelude::*, time::TimeUpdateStrategy};
+use serde::{Deserialize, Serialize};
+use shared::{
+    CurrentTick,
+    framed::{read_all, write_record},
+    netlib::{
+        EndpointGeneral, EventToServer, ServerNetworkingResources, Tick, transport::MemoryEndpoint,
+    },
+    rng::GameRng,
+};
+
+/// Where an event came from, in a form that can be written to disk
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CapturedEndpoint {
+    WebSocket(SocketAddr),
+    UDP(SocketAddr),
+    Memory(u64),
+}
+
+impl From<EndpointGeneral> for CapturedEndpoint {
+    fn from(endpoint: EndpointGeneral) -> Self {
+        match endpoint {
+            EndpointGeneral::WebSocket(ws) => Self::WebSocket(ws.socket_addr),
+            EndpointGeneral::UDP(udp) => Self::UDP(udp.addr()),
+            EndpointGeneral::Memory(memory) => Self::Memory(memory.0),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CapturedEvent {
+    /// The tick it was handed to the server's systems on
+    pub tick: Tick,
+    /// What the server's [`GameRng`] was seeded with when the capture started
+    pub seed: u64,
+    pub endpoint: CapturedEndpoint,
+    pub event: EventToServer,
+}
+
+/// Write every incoming event to `path`
+pub struct CapturePlugin {
+    pub path: PathBuf,
+}
+
+#[derive(Resource)]
+struct Capture {
+    file: BufWriter<File>,
+    seed: u64,
+}
+
+impl Plugin for CapturePlugin {
+    fn build(&self, app: &mut App) {
+        let file = File::create(&self.path).expect("Failed to create capture file");
+        // Start the stream over, so a replay seeded the same way draws the same values
+        let seed = app.world().resource::<GameRng>().seed();
+        app.insert_resource(GameRng::new(seed));
+        info!(?self.path, seed, "Capturing incoming events");
+
+        app.insert_resource(Capture {
+            file: BufWriter::new(file),
+            seed,
+        });
+    }
+}
+
+/// Play the capture at `path` back instead of listening for players
+pub struct ReplayPlugin {
+    pub path: PathBuf,
+}
+
+#[derive(Resource)]
+struct Replay {
+    events: VecDeque<CapturedEvent>,
+    endpoints: HashMap<CapturedEndpoint, EndpointGeneral>,
+    finished: bool,
+}
+
+impl Replay {
+    /// Everything that arrived up to and including `tick`
+    fn events_until(&mut self, tick: Tick) -> Vec<(EndpointGeneral, EventToServer)> {
+        let mut due = vec![];
+        while let Some(next) = self.events.front()
+            && next.tick <= tick
+        {
+            let captured = self.events.pop_front().unwrap();
+            let next_id = self.endpoints.len() as u64 + 1;
+            let endpoint = *self
+                .endpoints
+                .entry(captured.endpoint)
+                .or_insert(EndpointGeneral::Memory(MemoryEndpoint(next_id)));
+            due.push((endpoint, captured.event));
+        }
+
+        if self.events.is_empty() && !self.finished {
+            info!(?tick, "Replay finished");
+            self.finished = true;
+        }
+        due
+    }
+}
+
+impl Plugin for ReplayPlugin {
+    fn build(&self, app: &mut App) {
+        let file = File::open(&self.path).expect("Failed to open capture file");
+        let events: VecDeque<CapturedEvent> = read_all(BufReader::new(file)).into();
+
+        match events.front() {
+            Some(first) => {
+                app.insert_resource(GameRng::new(first.seed));
+            }
+            None => warn!(?self.path, "Capture is empty"),
+        }
+        info!(?self.path, count = events.len(), "Replaying captured events");
+
+        // The clock decides how many ticks run each frame, which a capture doesn't record
+        let tick = app.world().resource::<Time<Fixed>>().timestep();
+        app.insert_resource(TimeUpdateStrategy::ManualDuration(tick));
+
+        app.insert_resource(Replay {
+            events,
+            endpoints: HashMap::new(),
+            finished: false,
+        });
+    }
+}
+
+/// Hand what arrived this frame to the server's systems. While replaying that is the capture
+/// instead of the network, and while capturing it is written down first. Runs in `PreUpdate`, so
+/// every handler sees the events in the frame they were drained.
+pub fn drain_incoming_events(world: &mut World) {
+    let tick = world.resource::<CurrentTick>().0;
+    let events = match world.get_resource_mut::<Replay>() {
+        Some(mut replay) => replay.events_until(tick),
+        None => {
+            let sr = world.resource::<ServerNetworkingResources>();
+            std::mem::take(&mut *sr.event_list_incoming.write().unwrap())
+        }
+    };
+
+    if !events.is_empty()
+        && let Some(mut capture) = world.get_resource_mut::<Capture>()
+    {
+        let seed = capture.seed;
+        let written = events.iter().try_for_each(|(endpoint, event)| {
+            let captured = CapturedEvent {
+                tick,
+                seed,
+                endpoint: (*endpoint).into(),
+                event: event.clone(),
+            };
+            write_record(&mut capture.file, &captured)
+        });
+        // Flushed every time, so a crash doesn't lose the events that led up to it
+        if let Err(e) = written.and_then(|()| capture.file.flush()) {
+            error!(?e, "Failed to write capture");
+        }
+    }
+
+    shared::event::server::dispatch_incoming_events(world, events);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use shared::event::server::Heartbeat;
+
+    #[test]
+    fn test_capture_round_trip() {
+        let events = [
+            CapturedEvent {
+                tick: Tick(3),
+                seed: 42,
+                endpoint: CapturedEndpoint::UDP("127.0.0.1:5000".parse().unwrap()),
+                event: EventToServer::Heartbeat(Heartbeat {
+                    client_started_time: 0.0,
+                }),
+            },
+            CapturedEvent {
+                tick: Tick(9),
+                seed: 42,
+                endpoint: CapturedEndpoint::Memory(7),
+                event: EventToServer::Heartbeat(Heartbeat {
+                    client_started_time: 0.0,
+                }),
+            },
+        ];
+        let mut file = vec![];
+        for event in &events {
+            write_record(&mut file, event).unwrap();
+        }
+        // Half of a third one, like a crash mid-write
+        file.extend_from_slice(&[200, 0, 0, 0, 1, 2]);
+
+        let read: Vec<CapturedEvent> = read_all(file.as_slice());
+        assert_eq!(read.len(), 2);
+        assert_eq!(read[1].tick, Tick(9));
+        assert_eq!(read[1].endpoint, CapturedEndpoint::Memory(7));
+
+        let mut replay = Replay {
+            events: read.into(),
+            endpoints: HashMap::new(),
+            finished: false,
+        };
+        assert!(replay.events_until(Tick(2)).is_empty());
+        let due = replay.events_until(Tick(9));
+        assert_eq!(due.len(), 2);
+        assert_ne!(due[0].0, due[1].0);
+        assert!(replay.finished);
+    }
+
+    #[test]
+    fn test_stops_at_an_impossible_length() {
+        let mut file = vec![];
+        write_record(&mut file, &Tick(1)).unwrap();
+        // A corrupt length of almost 4GB, followed by a real record
+        file.extend_from_slice(&[0xff, 0xff, 0xff, 0xf0]);
+        write_record(&mut file, &Tick(2)).unwrap();
+
+        let read: Vec<Tick> = read_all(file.as_slice());
+        assert_eq!(read, [Tick(1)]);
+    }
+}
@@ -0,0 +1,273 @@
+//! Runs a server and any number of headless clients in one process, connected over a
+//! [`MemoryNetwork`] and stepped one fixed tick at a time.
+//!
+//! The clients are bare networking Apps, they don't render or simulate anything. They just
+//! remember every event the server sent them, so tests can check what a real client would see.
+use std::time::Duration;
+
+use bevy::{log::LogPlugin, prelude::*, time::TimeUpdateStrategy};
+use shared::{
+    Config,
+    event::{
+        NetEntId, PlayerId, ResumeToken,
+        client::{RpcResponse, SpawnUnit2, WorldData2},
+        server::{ConnectRequest, RpcRequest},
+    },
+    net_components::{NetComponent, ours::NetComponentOurs},
+    netlib::{
+        ClientNetworkingResources, EventToClient, EventToServer, MainServerEndpoint,
+        NetworkConnectionTarget, compression::Compression, envelope, flush_outgoing_events_stream,
+        setup_incoming_client, transport::MemoryNetwork,
+    },
+    rpc::{self, Rpc, RpcId},
+};
+
+use crate::ServerState;
+
+/// Everything a harness client was sent, in the order it arrived
+#[derive(Resource, Default)]
+pub struct ReceivedEvents(pub Vec<EventToClient>);
+
+pub struct Harness {
+    pub server: App,
+    pub clients: Vec<App>,
+    network: MemoryNetwork,
+}
+
+impl Harness {
+    /// A running server with `client_count` clients that haven't sent anything yet
+    pub fn new(client_count: usize) -> Self {
+        Self::with_server(client_count, |_| {})
+    }
+
+    /// Like [`Harness::new`], with `setup` adding to the server before it starts
+    pub fn with_server(client_count: usize, setup: impl FnOnce(&mut App)) -> Self {
+        let network = MemoryNetwork::default();
+        let server = server_app(&network, setup);
+        let tick = server.world().resource::<Time<Fixed>>().timestep();
+
+        let clients = (0..client_count)
+            .map(|_| client_app(&network, tick))
+            .collect();
+
+        let mut harness = Self {
+            server,
+            clients,
+            network,
+        };
+        harness
+            .tick_until(10, |h| {
+                h.server.world().resource::<State<ServerState>>().get() == &ServerState::Running
+            })
+            .expect("The server never started running");
+        harness
+    }
+
+    /// Advance the server and then every client by one tick, delivering everything each of them
+    /// sent before the next one runs
+    pub fn tick(&mut self) {
+        self.server.update();
+        self.network.wait_idle();
+        for client in &mut self.clients {
+            client.update();
+            self.network.wait_idle();
+        }
+    }
+
+    /// Tick until `done` returns true. Returns how many ticks that took, or `None` if it took more
+    /// than `max_ticks`.
+    #[must_use]
+    pub fn tick_until(
+        &mut self,
+        max_ticks: usize,
+        mut done: impl FnMut(&Self) -> bool,
+    ) -> Option<usize> {
+        for ticks in 0..=max_ticks {
+            if done(self) {
+                return Some(ticks);
+            }
+            self.tick();
+        }
+        None
+    }
+
+    pub fn send(&self, client: usize, event: EventToServer) {
+        let world = self.clients[client].world();
+        let endpoint = world.resource::<MainServerEndpoint>().0;
+        world
+            .resource::<ClientNetworkingResources>()
+            .send_event(endpoint, &event);
+    }
+
+    /// Call `R` as the real client's [`shared::rpc::RpcClient`] would, with our own id
+    pub fn call<R: Rpc>(&self, client: usize, id: u64, request: &R) {
+        self.send(
+            client,
+            EventToServer::RpcRequest(RpcRequest {
+                id: RpcId(id),
+                method: R::METHOD.to_string(),
+                payload: rpc::encode(request).unwrap(),
+            }),
+        );
+    }
+
+    /// Replies to this client's calls, in the order they arrived
+    pub fn rpc_responses(&self, client: usize) -> Vec<&RpcResponse> {
+        self.received(client)
+            .iter()
+            .filter_map(|event| match event {
+                EventToClient::RpcResponse(response) => Some(response),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Send the `ConnectRequest` the real client sends, standing at the origin
+    pub fn connect(&self, client: usize, name: &str) {
+        self.connect_resuming(client, name, None);
+    }
+
+    /// Connect and wait for our `WorldData2`, failing the test if it doesn't come
+    pub fn join(&mut self, client: usize, name: &str) -> WorldData2 {
+        self.connect(client, name);
+        let joined = self.tick_until(10, |h| h.world_data(client).is_some());
+        assert!(joined.is_some(), "{name} never got its world data");
+        self.world_data(client).unwrap().clone()
+    }
+
+    /// Like [`Harness::connect`], with the token from an earlier `WorldData2`
+    pub fn connect_resuming(&self, client: usize, name: &str, resume: Option<ResumeToken>) {
+        self.send(
+            client,
+            EventToServer::ConnectRequest(ConnectRequest {
+                protocol_version: shared::event::PROTOCOL_VERSION,
+                event_schemas: envelope::our_event_schemas(),
+                name: Some(name.to_string()),
+                my_location: Transform::default(),
+                color_hue: 0.0,
+                compression: Compression::Lz4,
+                resume,
+            }),
+        );
+    }
+
+    /// The last `WorldData2` this client was sent
+    pub fn world_data(&self, client: usize) -> Option<&WorldData2> {
+        self.received(client)
+            .iter()
+            .rev()
+            .find_map(|event| match event {
+                EventToClient::WorldData2(world_data) => Some(world_data),
+                _ => None,
+            })
+    }
+
+    pub fn received(&self, client: usize) -> &[EventToClient] {
+        &self.clients[client].world().resource::<ReceivedEvents>().0
+    }
+
+    /// Players waiting out their reconnect grace period
+    pub fn players_disconnected(&self) -> usize {
+        let world = self.server.world();
+        world
+            .try_query::<&crate::DisconnectedPlayer>()
+            .map_or(0, |mut players| players.iter(world).count())
+    }
+
+    /// Whether the server has a unit with this id
+    pub fn has_unit(&self, net_ent_id: NetEntId) -> bool {
+        let world = self.server.world();
+        world
+            .try_query::<&NetEntId>()
+            .is_some_and(|mut units| units.iter(world).any(|id| *id == net_ent_id))
+    }
+
+    /// Movement this player sent that the server refused
+    pub fn movement_violations(&self, player_id: PlayerId) -> u32 {
+        let world = self.server.world();
+        world
+            .try_query::<(&PlayerId, &crate::movement::MovementViolations)>()
+            .and_then(|mut players| {
+                players
+                    .iter(world)
+                    .find(|(id, _)| **id == player_id)
+                    .map(|(_, violations)| violations.total())
+            })
+            .unwrap_or_default()
+    }
+
+    /// Units this client was told to spawn that carry this player name
+    pub fn spawns_named(&self, client: usize, name: &str) -> Vec<&SpawnUnit2> {
+        self.received(client)
+            .iter()
+            .filter_map(|event| match event {
+                EventToClient::SpawnUnit2(spawn) => Some(spawn),
+                _ => None,
+            })
+            .filter(|spawn| {
+                spawn.components.iter().any(|c| match c {
+                    NetComponent::Ours(NetComponentOurs::PlayerName(n)) => n.name == name,
+                    _ => false,
+                })
+            })
+            .collect()
+    }
+}
+
+fn client_app(network: &MemoryNetwork, tick: Duration) -> App {
+    let mut app = App::new();
+    app.add_plugins((MinimalPlugins, shared::TickPlugin))
+        .insert_resource(TimeUpdateStrategy::ManualDuration(tick))
+        .insert_resource(network.clone())
+        .insert_resource(NetworkConnectionTarget {
+            ip: "localhost".to_string(),
+            port: 0,
+        })
+        .init_resource::<ReceivedEvents>()
+        .add_systems(
+            Startup,
+            setup_incoming_client::<EventToClient, EventToServer>,
+        )
+        .add_systems(
+            PreUpdate,
+            record_received_events.run_if(resource_exists::<ClientNetworkingResources>),
+        )
+        .add_systems(
+            FixedPostUpdate,
+            flush_outgoing_events_stream::<EventToClient, EventToServer>,
+        );
+    app.finish();
+    app.cleanup();
+    app
+}
+
+/// The server the harness runs, before its first update. Each update runs exactly one fixed tick.
+pub fn server_app(network: &MemoryNetwork, setup: impl FnOnce(&mut App)) -> App {
+    let mut server = crate::build_app(DefaultPlugins.build().disable::<LogPlugin>());
+    server
+        .insert_resource(Config::default())
+        .insert_resource(network.clone())
+        .insert_resource(NetworkConnectionTarget {
+            ip: "localhost".to_string(),
+            port: 0,
+        });
+    let tick = server.world().resource::<Time<Fixed>>().timestep();
+    server.insert_resource(TimeUpdateStrategy::ManualDuration(tick));
+    setup(&mut server);
+    server.finish();
+    server.cleanup();
+    server
+}
+
+fn record_received_events(
+    sr: Res<ClientNetworkingResources>,
+    mut received: ResMut<ReceivedEvents>,
+) {
+    let events = std::mem::take(&mut *sr.event_list_incoming.write().unwrap());
+    received
+        .0
+        .extend(events.into_iter().map(|(_endpoint, event)| event));
+}
+
+#[cfg(test)]
+mod test;
@@ -0,0 +1,32 @@
+//! The server's end-to-end tests, grouped by what they exercise
+use super::*;
+use shared::event::server::SpawnMan;
+
+mod connection;
+mod movement;
+mod rate_limit;
+mod replay;
+mod rpc;
+mod stats;
+
+/// Connect and spawn a man to control
+fn spawn_man(harness: &mut Harness, client: usize, name: &str) -> NetEntId {
+    harness.join(client, name);
+
+    harness.send(
+        client,
+        EventToServer::SpawnMan(SpawnMan {
+            position: Vec3::new(0.0, 50.0, 0.0),
+            controller_type: "TypeQ".to_string(),
+        }),
+    );
+    let control = |h: &Harness| {
+        h.received(client).iter().find_map(|e| match e {
+            EventToClient::BeginThirdpersonControllingUnit(begin) => begin.unit,
+            _ => None,
+        })
+    };
+    let controlled = harness.tick_until(10, |h| control(h).is_some());
+    assert!(controlled.is_some(), "We never got control of our man");
+    control(&*harness).unwrap()
+}
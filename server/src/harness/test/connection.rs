@@ -0,0 +1,124 @@
+use super::*;
+use shared::event::server::IWantToDisconnect;
+
+#[test]
+fn test_clients_see_each_other_join() {
+    let mut harness = Harness::new(2);
+
+    harness.join(0, "A");
+    harness.connect(1, "B");
+    // B's camera starts next to A's, so each is sent the other's
+    let seen = harness.tick_until(10, |h| {
+        !h.spawns_named(1, "A").is_empty() && !h.spawns_named(0, "B").is_empty()
+    });
+    assert!(seen.is_some(), "A and B never saw each other");
+}
+
+#[test]
+fn test_rejects_other_protocol_versions() {
+    let mut harness = Harness::new(1);
+
+    harness.send(
+        0,
+        EventToServer::ConnectRequest(ConnectRequest {
+            protocol_version: shared::event::PROTOCOL_VERSION ^ 1,
+            event_schemas: envelope::our_event_schemas(),
+            name: Some("Old".to_string()),
+            my_location: Transform::default(),
+            color_hue: 0.0,
+            compression: Compression::None,
+            resume: None,
+        }),
+    );
+    let rejected = harness.tick_until(10, |h| {
+        h.received(0)
+            .iter()
+            .any(|e| matches!(e, EventToClient::ConnectionRejected(_)))
+    });
+    assert!(rejected.is_some());
+    assert!(harness.world_data(0).is_none());
+}
+
+#[test]
+fn test_only_rejects_clients_with_conflicting_events() {
+    let mut harness = Harness::new(2);
+    let connect = |event_schemas| {
+        EventToServer::ConnectRequest(ConnectRequest {
+            protocol_version: shared::event::PROTOCOL_VERSION,
+            event_schemas,
+            name: None,
+            my_location: Transform::default(),
+            color_hue: 0.0,
+            compression: Compression::None,
+            resume: None,
+        })
+    };
+
+    // A is newer, with an event we don't have and without one we do
+    let mut newer = envelope::our_event_schemas();
+    newer.remove(0);
+    newer.push(envelope::EventSchema {
+        id: u32::MAX,
+        hash: 0,
+    });
+    harness.send(0, connect(newer));
+
+    // B changed the fields of one we both have
+    let mut changed = envelope::our_event_schemas();
+    changed[0].hash ^= 1;
+    harness.send(1, connect(changed));
+
+    let done = harness.tick_until(10, |h| {
+        h.world_data(0).is_some()
+            && h.received(1)
+                .iter()
+                .any(|e| matches!(e, EventToClient::ConnectionRejected(_)))
+    });
+    assert!(done.is_some());
+    assert!(harness.world_data(1).is_none());
+}
+
+#[test]
+fn test_resume_after_disconnect() {
+    let mut harness = Harness::new(3);
+    let first = harness.join(0, "A");
+
+    // A's connection goes away, and comes back as client 1
+    harness.send(0, EventToServer::IWantToDisconnect(IWantToDisconnect {}));
+    let left = harness.tick_until(10, |h| h.players_disconnected() == 1);
+    assert!(left.is_some(), "A never left");
+    harness.connect_resuming(1, "A again", Some(first.resume_token));
+    let resumed = harness.tick_until(10, |h| h.world_data(1).is_some());
+    assert!(resumed.is_some(), "A never got back in");
+
+    let second = harness.world_data(1).unwrap();
+    assert_eq!(second.your_player_id, first.your_player_id);
+    assert_eq!(second.your_camera_unit_id, first.your_camera_unit_id);
+    assert_eq!(harness.players_disconnected(), 0);
+
+    // A token nobody has just joins as someone new
+    harness.connect_resuming(2, "C", Some(ResumeToken(first.resume_token.0 ^ 1)));
+    let joined = harness.tick_until(10, |h| h.world_data(2).is_some());
+    assert!(joined.is_some(), "C never got in");
+    assert_ne!(
+        harness.world_data(2).unwrap().your_player_id,
+        first.your_player_id
+    );
+}
+
+#[test]
+fn test_units_despawn_after_grace() {
+    let mut harness = Harness::new(1);
+    harness
+        .server
+        .world_mut()
+        .resource_mut::<Config>()
+        .reconnect_grace_seconds = Some(0.0);
+
+    let camera = harness.join(0, "A").your_camera_unit_id;
+    assert!(harness.has_unit(camera));
+
+    harness.send(0, EventToServer::IWantToDisconnect(IWantToDisconnect {}));
+    let despawned = harness.tick_until(10, |h| !h.has_unit(camera));
+    assert!(despawned.is_some(), "A's camera outlived the grace period");
+}
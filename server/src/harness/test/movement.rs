@@ -0,0 +1,123 @@
+use super::*;
+use crate::movement::{MovementStats, UnitInventory};
+use shared::{
+    character_controller::MovementAction,
+    event::server::{ChangeMovement, MovementInput},
+    prediction::SequencedInput,
+};
+
+#[test]
+fn test_server_simulates_movement_inputs() {
+    let mut harness = Harness::new(1);
+    let unit = spawn_man(&mut harness, 0, "A");
+
+    let forward = MovementAction {
+        move_input_dir: Vec2::Y,
+        ..Default::default()
+    };
+    let acks = |h: &Harness| {
+        h.received(0)
+            .iter()
+            .filter_map(|e| match e {
+                EventToClient::MovementAck(ack) if ack.net_ent_id == unit => Some(ack.clone()),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+    };
+    for sequence in 0..10 {
+        // Resending the previous one, like the client does
+        let inputs = (sequence.max(1) - 1..=sequence)
+            .map(|sequence| SequencedInput {
+                sequence,
+                action: forward.clone(),
+            })
+            .collect();
+        harness.send(
+            0,
+            EventToServer::MovementInput(MovementInput {
+                net_ent_id: unit,
+                inputs,
+            }),
+        );
+        harness.tick();
+    }
+    let acked = harness.tick_until(10, |h| acks(h).last().is_some_and(|a| a.sequence == 9));
+    assert!(acked.is_some(), "The last input was never acked");
+
+    let acks = acks(&harness);
+    let sequences: Vec<_> = acks.iter().map(|a| a.sequence).collect();
+    assert_eq!(sequences, (0..10).collect::<Vec<_>>());
+    let first = acks.first().unwrap().state;
+    let last = acks.last().unwrap().state;
+    assert!(last.position.z > first.position.z, "Inputs didn't move us");
+}
+
+#[test]
+fn test_movement_stats_follow_the_inventory() {
+    let mut harness = Harness::new(1);
+    let unit = spawn_man(&mut harness, 0, "A");
+    let speed = |h: &mut Harness| {
+        let world = h.server.world_mut();
+        let mut units = world.query::<(&NetEntId, &MovementStats)>();
+        let (_, stats) = units.iter(world).find(|(id, _)| **id == unit).unwrap();
+        stats.speed_multiplier
+    };
+    assert!(
+        speed(&mut harness) > 1.0,
+        "Goblin drops didn't make us faster"
+    );
+
+    let world = harness.server.world_mut();
+    let mut inventories = world.query::<(&NetEntId, &mut UnitInventory)>();
+    for (net_ent_id, mut inventory) in inventories.iter_mut(world) {
+        if *net_ent_id == unit {
+            inventory.0.items.clear();
+        }
+    }
+    harness.tick();
+    assert_eq!(speed(&mut harness), 1.0);
+}
+
+#[test]
+fn test_refuses_movement_players_may_not_make() {
+    let mut harness = Harness::new(2);
+    let unit = spawn_man(&mut harness, 0, "A");
+    let b = harness.join(1, "B");
+
+    // B tries to walk A's man
+    harness.send(
+        1,
+        EventToServer::MovementInput(MovementInput {
+            net_ent_id: unit,
+            inputs: vec![SequencedInput {
+                sequence: 0,
+                action: MovementAction::default(),
+            }],
+        }),
+    );
+    // and to teleport their own camera across the map
+    harness.send(
+        1,
+        EventToServer::ChangeMovement(ChangeMovement {
+            net_ent_id: b.your_camera_unit_id,
+            transform: Transform::from_xyz(10_000.0, 0.0, 0.0),
+        }),
+    );
+    let corrected = |h: &Harness| {
+        h.received(1).iter().any(|e| {
+            matches!(e, EventToClient::MovementCorrection(c)
+                if c.net_ent_id == b.your_camera_unit_id)
+        })
+    };
+    let refused = harness.tick_until(10, |h| {
+        h.movement_violations(b.your_player_id) == 2 && corrected(h)
+    });
+    assert!(refused.is_some(), "B got away with it");
+    assert!(
+        !harness
+            .received(0)
+            .iter()
+            .chain(harness.received(1))
+            .any(|e| matches!(e, EventToClient::MovementAck(_)))
+    );
+}
@@ -0,0 +1,52 @@
+use super::*;
+use shared::rpc::{RpcError, methods::RequestScoreboard};
+
+#[test]
+fn test_kicks_clients_that_flood_events() {
+    let mut harness = Harness::new(1);
+    let first = harness.join(0, "A");
+
+    let mut id = 0;
+    let kicked = harness.tick_until(20, |h| {
+        for _ in 0..100 {
+            id += 1;
+            h.call(0, id, &RequestScoreboard {});
+        }
+        h.received(0)
+            .iter()
+            .any(|e| matches!(e, EventToClient::PlayerDisconnected(_)))
+    });
+    assert!(kicked.is_some(), "A flooded us and stayed");
+    // Only about a burst of them got through
+    let answered = harness
+        .rpc_responses(0)
+        .iter()
+        .filter(|r| r.result.is_ok())
+        .count();
+    assert!(answered < 100);
+    assert!(
+        harness
+            .rpc_responses(0)
+            .iter()
+            .any(|r| r.result == Err(RpcError::RateLimited))
+    );
+    let metrics = harness
+        .server
+        .world_mut()
+        .run_system_cached(crate::metrics::render_metrics)
+        .unwrap();
+    assert!(metrics.contains("\nserver_rate_limited_events_total{event=\"RequestScoreboard\"} "));
+
+    // No grace period, and no coming back as the same player
+    let despawned = harness.tick_until(2, |h| !h.has_unit(first.your_camera_unit_id));
+    assert!(despawned.is_some(), "Their camera waited for them");
+    harness.connect_resuming(0, "A", Some(first.resume_token));
+    let rejoined = harness.tick_until(10, |h| {
+        h.world_data(0).unwrap().resume_token != first.resume_token
+    });
+    assert!(rejoined.is_some());
+    assert_ne!(
+        harness.world_data(0).unwrap().your_player_id,
+        first.your_player_id
+    );
+}
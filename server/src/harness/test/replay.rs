@@ -0,0 +1,44 @@
+use super::*;
+use crate::capture::{CapturePlugin, ReplayPlugin};
+use shared::CurrentTick;
+
+/// Every unit's id and where it is, in id order
+fn units(server: &mut App) -> Vec<(u64, Vec3)> {
+    let world = server.world_mut();
+    let mut units: Vec<_> = world
+        .query::<(&NetEntId, &Transform)>()
+        .iter(world)
+        .map(|(id, transform)| (id.0, transform.translation))
+        .collect();
+    units.sort_by_key(|(id, _)| *id);
+    units
+}
+
+#[test]
+fn test_replay_reproduces_the_world() {
+    let path = std::env::temp_dir().join(format!("replay-test-{}.capture", std::process::id()));
+    let mut harness = Harness::with_server(2, |server| {
+        server.add_plugins(CapturePlugin { path: path.clone() });
+    });
+    spawn_man(&mut harness, 0, "A");
+    harness.join(1, "B");
+    // Let the man fall for a while
+    for _ in 0..30 {
+        harness.tick();
+    }
+    let captured_tick = harness.server.world().resource::<CurrentTick>().0;
+
+    let mut replay = crate::harness::server_app(&MemoryNetwork::default(), |server| {
+        server.add_plugins(ReplayPlugin { path: path.clone() });
+    });
+    let replayed = (0..captured_tick.0 * 2).find(|_| {
+        replay.update();
+        replay.world().resource::<CurrentTick>().0 == captured_tick
+    });
+    std::fs::remove_file(&path).unwrap();
+    assert!(replayed.is_some(), "The replay never got as far");
+
+    let expected = units(&mut harness.server);
+    assert!(expected.len() > 2, "Nothing was spawned");
+    assert_eq!(units(&mut replay), expected);
+}
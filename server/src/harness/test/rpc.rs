@@ -0,0 +1,42 @@
+use super::*;
+use shared::rpc::{
+    RpcError, decode,
+    methods::{RequestScoreboard, RequestScoreboardResponse},
+};
+
+#[test]
+fn test_answers_rpc_calls() {
+    let mut harness = Harness::new(2);
+    let a = harness.join(0, "A").your_player_id;
+
+    harness.call(0, 7, &RequestScoreboard {});
+    harness.send(
+        0,
+        EventToServer::RpcRequest(RpcRequest {
+            id: RpcId(8),
+            method: "NoSuchMethod".to_string(),
+            payload: vec![],
+        }),
+    );
+    // B never joined
+    harness.call(1, 7, &RequestScoreboard {});
+
+    let answered = harness.tick_until(10, |h| {
+        h.rpc_responses(0).len() == 2 && h.rpc_responses(1).len() == 1
+    });
+    assert!(answered.is_some(), "Calls went unanswered");
+
+    let reply = |id| {
+        harness
+            .rpc_responses(0)
+            .into_iter()
+            .find(|r| r.id == RpcId(id))
+            .unwrap()
+            .result
+            .clone()
+    };
+    let scoreboard: RequestScoreboardResponse = decode(&reply(7).unwrap()).unwrap();
+    assert_eq!(scoreboard.player_names[&a], "A");
+    assert_eq!(reply(8), Err(RpcError::UnknownMethod));
+    assert_eq!(harness.rpc_responses(1)[0].result, Err(RpcError::NotJoined));
+}
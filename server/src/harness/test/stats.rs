@@ -0,0 +1,40 @@
+use super::*;
+use shared::netlib::ServerNetworkingResources;
+
+#[test]
+fn test_renders_metrics() {
+    let mut harness = Harness::new(1);
+    harness.join(0, "A");
+    harness.tick();
+
+    let text = harness
+        .server
+        .world_mut()
+        .run_system_cached(crate::metrics::render_metrics)
+        .unwrap();
+    assert!(text.contains("\nserver_connected_players 1\n"));
+    assert!(text.contains("\nserver_entities{kind=\"camera\"} 1\n"));
+    assert!(text.contains("\nserver_received_events_total{event=\"ConnectRequest\"} 1\n"));
+    assert!(text.contains("\nserver_tick_time_seconds{quantile=\"0.99\"} "));
+    assert!(text.contains("\nserver_websocket_handshake_failures_total 0\n"));
+}
+
+#[test]
+fn test_counts_traffic_per_connection() {
+    let mut harness = Harness::new(2);
+    harness.join(0, "A");
+
+    let stats = &harness
+        .server
+        .world()
+        .resource::<ServerNetworkingResources>()
+        .networking_stats;
+    // B hasn't sent us anything
+    let endpoints: Vec<_> = stats.per_endpoint.iter().map(|e| *e.key()).collect();
+    assert_eq!(endpoints.len(), 1);
+
+    let connection = stats.connection_stats(endpoints[0]).unwrap();
+    assert!(connection.packets_received > 0);
+    assert!(connection.bytes_sent > 0);
+    assert!(connection.last_seen_secs_ago.is_some());
+}
@@ -0,0 +1,391 @@
+//! Area of interest: each client only gets told about entities near their camera or the units they
+//! control. Entities are bucketed into a grid over the XZ plane every fixed tick, clients get a
+//! `SpawnUnit2` when something enters their radius and a `DespawnUnit2` once it is well outside it.
+use std::collections::{HashMap, HashSet};
+
+use bevy::prelude::*;
+use dashmap::DashMap;
+use shared::{
+    Config,
+    event::{
+        NetEntId, PlayerId,
+        client::{DespawnUnit2, SpawnUnit2},
+    },
+    net_components::{
+        NetComponent,
+        ents::{Man, PlayerCamera},
+        foreign::NetComponentForeign,
+        ours::{ControlledBy, Dead, NetComponentOurs},
+    },
+    netlib::{EndpointGeneral, EventToClient, ServerNetworkingResources},
+};
+
+use crate::{ConnectedPlayer, ServerState};
+
+/// Used when `interest_radius` is not set in the config
+pub const DEFAULT_INTEREST_RADIUS: f32 = 100.0;
+/// Side length of one grid cell
+pub const INTEREST_CELL_SIZE: f32 = 25.0;
+/// Entities are only despawned once they are this much further out than the radius, so something
+/// sitting on the edge doesn't get spawned and despawned every tick
+pub const INTEREST_HYSTERESIS: f32 = 1.2;
+
+pub struct InterestPlugin;
+
+impl Plugin for InterestPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<Interest>()
+            .add_systems(Startup, load_interest_radius)
+            .add_systems(
+                FixedUpdate,
+                update_interest.run_if(in_state(ServerState::Running)),
+            );
+    }
+}
+
+pub struct ClientInterest {
+    pub player_id: PlayerId,
+    /// Positions of this player's camera and controlled units, as of the last fixed tick
+    pub centres: Vec<Vec3>,
+    /// Entities this client has been sent a `SpawnUnit2` for
+    pub known: HashSet<NetEntId>,
+}
+
+impl ClientInterest {
+    /// `camera` is spawned locally by the client, so it counts as known from the start
+    pub fn new(player_id: PlayerId, camera: NetEntId) -> Self {
+        Self {
+            player_id,
+            centres: vec![],
+            known: HashSet::from([camera]),
+        }
+    }
+
+    fn is_near(&self, position: Vec3, radius: f32) -> bool {
+        self.centres
+            .iter()
+            .any(|centre| horizontal_distance_squared(*centre, position) <= radius * radius)
+    }
+}
+
+#[derive(Resource)]
+pub struct Interest {
+    pub radius: f32,
+    pub clients: DashMap<EndpointGeneral, ClientInterest>,
+}
+
+impl Default for Interest {
+    fn default() -> Self {
+        Self {
+            radius: DEFAULT_INTEREST_RADIUS,
+            clients: DashMap::new(),
+        }
+    }
+}
+
+impl Interest {
+    /// Clients that should see something happening at `position`
+    pub fn clients_near(&self, position: Vec3) -> Vec<EndpointGeneral> {
+        self.clients
+            .iter()
+            .filter(|client| client.is_near(position, self.radius))
+            .map(|client| *client.key())
+            .collect()
+    }
+
+    /// Send a newly spawned unit to everyone who can see it. Its owners always get it, as does
+    /// everyone if it has no transform.
+    pub fn send_spawn(&self, sr: &ServerNetworkingResources, unit: &SpawnUnit2) {
+        let mut position = None;
+        let mut owners: &[PlayerId] = &[];
+        for component in &unit.components {
+            match component {
+                NetComponent::Foreign(NetComponentForeign::Transform(t)) => {
+                    position = Some(t.translation)
+                }
+                NetComponent::Ours(NetComponentOurs::ControlledBy(c)) => owners = &c.players,
+                _ => {}
+            }
+        }
+
+        let event = EventToClient::SpawnUnit2(unit.clone());
+        for mut client in self.clients.iter_mut() {
+            let relevant = owners.contains(&client.player_id)
+                || position.is_none_or(|p| client.is_near(p, self.radius));
+            if relevant && client.known.insert(unit.net_ent_id) {
+                sr.send_event(*client.key(), &event);
+            }
+        }
+    }
+
+    /// Send an event about `net_ent_id` to every client that knows about it, except `except`
+    pub fn send_to_known(
+        &self,
+        sr: &ServerNetworkingResources,
+        net_ent_id: &NetEntId,
+        event: &EventToClient,
+        except: Option<EndpointGeneral>,
+    ) {
+        for client in self.clients.iter() {
+            if client.known.contains(net_ent_id) && except != Some(*client.key()) {
+                sr.send_event(*client.key(), event);
+            }
+        }
+    }
+
+    /// The unit is gone for good, tell everyone who knew about it
+    pub fn send_despawn(&self, sr: &ServerNetworkingResources, net_ent_id: NetEntId) {
+        let event = EventToClient::DespawnUnit2(DespawnUnit2 { net_ent_id });
+        for mut client in self.clients.iter_mut() {
+            if client.known.remove(&net_ent_id) {
+                sr.send_event(*client.key(), &event);
+            }
+        }
+    }
+}
+
+fn horizontal_distance_squared(a: Vec3, b: Vec3) -> f32 {
+    Vec2::new(a.x - b.x, a.z - b.z).length_squared()
+}
+
+/// Entities bucketed by which cell of the XZ plane they are in
+#[derive(Default)]
+pub struct SpatialGrid {
+    cells: HashMap<IVec2, Vec<(NetEntId, Vec3)>>,
+}
+
+impl SpatialGrid {
+    fn cell(position: Vec3) -> IVec2 {
+        IVec2::new(
+            (position.x / INTEREST_CELL_SIZE).floor() as i32,
+            (position.z / INTEREST_CELL_SIZE).floor() as i32,
+        )
+    }
+
+    pub fn insert(&mut self, net_ent_id: NetEntId, position: Vec3) {
+        self.cells
+            .entry(Self::cell(position))
+            .or_default()
+            .push((net_ent_id, position));
+    }
+
+    /// Add everything within `radius` of `centre` to `out`
+    pub fn query(&self, centre: Vec3, radius: f32, out: &mut HashSet<NetEntId>) {
+        let min = Self::cell(centre - Vec3::splat(radius));
+        let max = Self::cell(centre + Vec3::splat(radius));
+        for x in min.x..=max.x {
+            for z in min.y..=max.y {
+                let Some(cell) = self.cells.get(&IVec2::new(x, z)) else {
+                    continue;
+                };
+                for (net_ent_id, position) in cell {
+                    if horizontal_distance_squared(centre, *position) <= radius * radius {
+                        out.insert(*net_ent_id);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Add everything within `radius` of any of `centres` to `relevant`. Returns those and
+    /// everything else within the hysteresis radius, which a client that knows them keeps.
+    pub fn query_with_hysteresis(
+        &self,
+        centres: &[Vec3],
+        radius: f32,
+        relevant: &mut HashSet<NetEntId>,
+    ) -> HashSet<NetEntId> {
+        let mut keep = relevant.clone();
+        for centre in centres {
+            self.query(*centre, radius, relevant);
+            self.query(*centre, radius * INTEREST_HYSTERESIS, &mut keep);
+        }
+        keep
+    }
+}
+
+fn load_interest_radius(config: Res<Config>, mut interest: ResMut<Interest>) {
+    if let Some(radius) = config.interest_radius {
+        interest.radius = radius;
+    }
+}
+
+/// Serialize every networked component on `entity` so a client can spawn it
+pub fn serialize_unit(world: &World, entity: Entity, net_ent_id: NetEntId) -> Option<SpawnUnit2> {
+    let component_ids = world
+        .inspect_entity(entity)
+        .ok()?
+        .map(|ci| ci.id())
+        .collect::<bevy::platform::collections::HashSet<_>>();
+    let Ok(components) = world.entity(entity).get_by_id(&component_ids) else {
+        error!("Failed to get components for entity {:?}", entity);
+        return None;
+    };
+
+    let mut spawn_unit = SpawnUnit2 {
+        net_ent_id,
+        components: vec![],
+    };
+
+    for (component_id, component_ptr) in components.iter() {
+        let type_id = world
+            .components()
+            .get_info(*component_id)
+            .unwrap()
+            .type_id()
+            .unwrap();
+
+        // SAFETY: Trust that bevy gives us a valid type id and pointer from `get_by_id`
+        if let Some(net_comp) = unsafe { NetComponent::from_type_id_ptr(type_id, *component_ptr) } {
+            spawn_unit.components.push(net_comp);
+        }
+    }
+
+    Some(spawn_unit)
+}
+
+/// Rebuild the grid, then spawn and despawn units for each client as they move around
+pub fn update_interest(world: &World) {
+    let interest = world.resource::<Interest>();
+    let sr = world.resource::<ServerNetworkingResources>();
+
+    let mut grid = SpatialGrid::default();
+    let mut entities = HashMap::new();
+    // Units without a position, everyone gets these
+    let mut everywhere = HashSet::new();
+    let mut owned: HashMap<PlayerId, Vec<NetEntId>> = HashMap::new();
+
+    let units = world.try_query_filtered::<(
+        Entity,
+        &NetEntId,
+        Option<&Transform>,
+        Option<&ControlledBy>,
+    ), Without<ConnectedPlayer>>();
+    if let Some(mut units) = units {
+        for (entity, net_ent_id, transform, controlled_by) in units.iter(world) {
+            if net_ent_id.is_none() {
+                continue;
+            }
+            entities.insert(*net_ent_id, entity);
+
+            match transform {
+                Some(transform) => grid.insert(*net_ent_id, transform.translation),
+                None => {
+                    everywhere.insert(*net_ent_id);
+                }
+            }
+
+            for player_id in controlled_by.map(|c| c.players.as_slice()).unwrap_or(&[]) {
+                owned.entry(*player_id).or_default().push(*net_ent_id);
+            }
+        }
+    }
+
+    let mut centres: HashMap<PlayerId, Vec<Vec3>> = HashMap::new();
+    let viewers = world.try_query_filtered::<(&Transform, &ControlledBy), (
+        Or<(With<PlayerCamera>, With<Man>)>,
+        Without<Dead>,
+    )>();
+    if let Some(mut viewers) = viewers {
+        for (transform, controlled_by) in viewers.iter(world) {
+            for player_id in &controlled_by.players {
+                centres
+                    .entry(*player_id)
+                    .or_default()
+                    .push(transform.translation);
+            }
+        }
+    }
+
+    for mut client in interest.clients.iter_mut() {
+        let endpoint = *client.key();
+        client.centres = centres.remove(&client.player_id).unwrap_or_default();
+
+        let mut relevant = everywhere.clone();
+        relevant.extend(owned.get(&client.player_id).into_iter().flatten());
+        let keep = grid.query_with_hysteresis(&client.centres, interest.radius, &mut relevant);
+
+        let mut events = vec![];
+        let mut entered = vec![];
+        for net_ent_id in &relevant {
+            if client.known.contains(net_ent_id) {
+                continue;
+            }
+            if let Some(unit) = serialize_unit(world, entities[net_ent_id], *net_ent_id) {
+                events.push(EventToClient::SpawnUnit2(unit));
+                entered.push(*net_ent_id);
+            }
+        }
+
+        // Known units that don't exist yet were spawned this tick and sent by `send_spawn`
+        let left = client
+            .known
+            .iter()
+            .filter(|id| entities.contains_key(id) && !keep.contains(id))
+            .copied()
+            .collect::<Vec<_>>();
+        for net_ent_id in &left {
+            client.known.remove(net_ent_id);
+            events.push(EventToClient::DespawnUnit2(DespawnUnit2 {
+                net_ent_id: *net_ent_id,
+            }));
+        }
+
+        if events.is_empty() {
+            continue;
+        }
+
+        client.known.extend(entered);
+        trace!(?endpoint, events = events.len(), "Interest changed");
+        sr.send_event_batch(endpoint, &events);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_query_spans_cells() {
+        let mut grid = SpatialGrid::default();
+        let corners = [(-1.0, -1.0), (1.0, -1.0), (-1.0, 1.0), (1.0, 1.0)];
+        for (i, (x, z)) in corners.into_iter().enumerate() {
+            grid.insert(NetEntId(i as u64), Vec3::new(x, 0.0, z));
+        }
+        // Height doesn't count
+        grid.insert(NetEntId(4), Vec3::new(0.0, 500.0, 0.0));
+        grid.insert(NetEntId(5), Vec3::new(6.0, 0.0, 0.0));
+
+        let mut found = HashSet::new();
+        grid.query(Vec3::ZERO, 5.0, &mut found);
+        assert_eq!(found, (0..5).map(NetEntId).collect());
+    }
+
+    #[test]
+    fn test_enter_and_leave_with_hysteresis() {
+        let radius = 100.0;
+        let unit = NetEntId(10);
+        let mut known = HashSet::new();
+
+        // Walking out along X through several cells, then back in. It is kept until it is
+        // more than 120 out, and only comes back once it is within 100 again.
+        let steps = [
+            (90.0, true),
+            (110.0, true),
+            (119.0, true),
+            (121.0, false),
+            (110.0, false),
+            (99.0, true),
+        ];
+        for (x, expected) in steps {
+            let mut grid = SpatialGrid::default();
+            grid.insert(unit, Vec3::new(x, 0.0, 0.0));
+
+            let mut relevant = HashSet::new();
+            let keep = grid.query_with_hysteresis(&[Vec3::ZERO], radius, &mut relevant);
+            known.retain(|id| keep.contains(id));
+            known.extend(relevant);
+            assert_eq!(known.contains(&unit), expected, "at x = {x}");
+        }
+    }
+}
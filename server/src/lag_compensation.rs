@@ -0,0 +1,236 @@
+//! Resolving projectile hits against where units were when the caster saw them.
+//!
+//! Clients draw other units a little in the past (see [`shared::interpolation`]) and their casts
+//! take a while to reach us, so by the time a projectile flies its targets have moved on from
+//! where the caster aimed. We remember the bounds of every unit's collider for the last few ticks,
+//! and projectiles cast by players test their hits against the bounds from as far back as the
+//! caster was behind. That is capped, so a laggy player can't shoot where units used to be.
+use std::collections::VecDeque;
+
+use avian3d::prelude::ColliderAabb;
+use bevy::prelude::*;
+use shared::{
+    BASE_TICKS_PER_SECOND, CurrentTick,
+    event::{NetEntId, UDPacketEvent, server::CastSkillUpdate},
+    interpolation::MAX_DELAY_TICKS,
+    net_components::ours::ControlledBy,
+    netlib::Tick,
+    projectile::ProjectileSource,
+};
+
+use crate::{
+    EndpointToPlayerId, HeartbeatList, ServerState, projectile::ProjectileCollisionLocalServer,
+};
+
+/// 300ms, the furthest back we rewind whatever the caster's ping
+pub const MAX_REWIND_TICKS: u64 = BASE_TICKS_PER_SECOND as u64 * 3 / 10;
+/// How far behind us a client may draw units on top of its ping
+const MAX_VIEW_DELAY_TICKS: u64 = MAX_DELAY_TICKS as u64 + 1;
+
+pub struct LagCompensationPlugin;
+
+impl Plugin for LagCompensationPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(
+            Update,
+            remember_caster_rewind.run_if(in_state(ServerState::Running)),
+        )
+        .add_systems(
+            FixedUpdate,
+            (record_rewind_history, rewound_projectile_hits)
+                .chain()
+                .run_if(in_state(ServerState::Running)),
+        );
+    }
+}
+
+/// The bounds of a unit's collider over the last few ticks, oldest first
+#[derive(Component, Debug, Default)]
+pub struct RewindHistory {
+    bounds: VecDeque<(Tick, ColliderAabb)>,
+}
+
+impl RewindHistory {
+    pub fn record(&mut self, tick: Tick, bounds: ColliderAabb) {
+        self.bounds.push_back((tick, bounds));
+        while self.bounds.len() > MAX_REWIND_TICKS as usize + 1 {
+            self.bounds.pop_front();
+        }
+    }
+
+    /// The bounds at `tick`, or the oldest we have if that is further back
+    pub fn at(&self, tick: Tick) -> Option<ColliderAabb> {
+        self.bounds
+            .iter()
+            .rev()
+            .find(|(t, _)| *t <= tick)
+            .or(self.bounds.front())
+            .map(|(_, bounds)| *bounds)
+    }
+}
+
+/// How many ticks behind us this unit's player was when they last began a cast
+#[derive(Component, Debug, Clone, Copy)]
+pub struct CasterRewind {
+    pub ticks: u64,
+}
+
+/// A projectile whose hits on units are tested against their [`RewindHistory`]
+#[derive(Component, Debug)]
+pub struct LagCompensated {
+    pub rewind_ticks: u64,
+    /// Units it already hit, so each is only hit once
+    pub hit: Vec<Entity>,
+}
+
+/// How far to rewind for a cast that arrived at `arrival` from someone who says they were drawing
+/// `seen_tick`. They can't claim to be further behind than their ping and interpolation allow.
+pub fn rewind_ticks(arrival: Tick, seen_tick: Option<Tick>, ping_microsec: i32) -> u64 {
+    let Some(seen_tick) = seen_tick else {
+        return 0;
+    };
+    let claimed = arrival.0.saturating_sub(seen_tick.0);
+    let ping_ticks =
+        (ping_microsec.max(0) as u64 * BASE_TICKS_PER_SECOND as u64).div_ceil(1_000_000);
+
+    claimed
+        .min(ping_ticks + MAX_VIEW_DELAY_TICKS)
+        .min(MAX_REWIND_TICKS)
+}
+
+fn remember_caster_rewind(
+    mut casts: UDPacketEvent<CastSkillUpdate>,
+    tick: Res<CurrentTick>,
+    endpoint_to_player: Res<EndpointToPlayerId>,
+    heartbeats: Res<HeartbeatList>,
+    units: Query<(Entity, &NetEntId, &ControlledBy)>,
+    mut commands: Commands,
+) {
+    for cast in casts.read() {
+        if !cast.event.begin_casting {
+            continue;
+        }
+        let Some(player_id) = endpoint_to_player.map.get(&cast.endpoint).map(|p| *p) else {
+            continue;
+        };
+        let ping = heartbeats
+            .pings
+            .get(&player_id)
+            .map(|p| p.to_integer().server_challenged_ping_microsec)
+            .unwrap_or(-1);
+
+        for (entity, net_ent_id, controlled_by) in &units {
+            if *net_ent_id != cast.event.net_ent_id || !controlled_by.players.contains(&player_id) {
+                continue;
+            }
+            let ticks = rewind_ticks(tick.0, cast.event.seen_tick, ping);
+            commands.entity(entity).insert(CasterRewind { ticks });
+        }
+    }
+}
+
+/// Runs alongside the snapshots, so the bounds at a tick match where that tick's snapshot put
+/// the unit
+#[allow(clippy::type_complexity)]
+fn record_rewind_history(
+    tick: Res<CurrentTick>,
+    mut units: Query<
+        (Entity, &ColliderAabb, Option<&mut RewindHistory>),
+        (With<NetEntId>, Without<ProjectileSource>),
+    >,
+    mut commands: Commands,
+) {
+    for (entity, bounds, history) in &mut units {
+        match history {
+            Some(mut history) => history.record(tick.0, *bounds),
+            None => {
+                let mut history = RewindHistory::default();
+                history.record(tick.0, *bounds);
+                commands.entity(entity).insert(history);
+            }
+        }
+    }
+}
+
+fn rewound_projectile_hits(
+    tick: Res<CurrentTick>,
+    mut projectiles: Query<(
+        Entity,
+        &ColliderAabb,
+        &ProjectileSource,
+        &mut LagCompensated,
+    )>,
+    units: Query<(Entity, &NetEntId, &RewindHistory)>,
+    mut proj_hit_writer: MessageWriter<ProjectileCollisionLocalServer>,
+) {
+    for (projectile, proj_bounds, source, mut lag) in &mut projectiles {
+        let then = Tick(tick.0.0.saturating_sub(lag.rewind_ticks));
+
+        for (unit, net_ent_id, history) in &units {
+            if source.source_entity == *net_ent_id || lag.hit.contains(&unit) {
+                continue;
+            }
+            let Some(bounds) = history.at(then) else {
+                continue;
+            };
+            if !overlaps(&bounds, proj_bounds) {
+                continue;
+            }
+
+            lag.hit.push(unit);
+            proj_hit_writer.write(ProjectileCollisionLocalServer {
+                projectile_entity: projectile,
+                hit_entity: unit,
+                net_ent_id: *net_ent_id,
+            });
+        }
+    }
+}
+
+fn overlaps(a: &ColliderAabb, b: &ColliderAabb) -> bool {
+    a.min.cmple(b.max).all() && b.min.cmple(a.max).all()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn bounds_at(x: f32) -> ColliderAabb {
+        ColliderAabb {
+            min: Vec3::new(x - 1.0, 0.0, -1.0),
+            max: Vec3::new(x + 1.0, 2.0, 1.0),
+        }
+    }
+
+    #[test]
+    fn test_rewind_is_capped() {
+        // 100ms of ping is 6 ticks, plus what the client may spend interpolating
+        assert_eq!(rewind_ticks(Tick(100), Some(Tick(92)), 100_000), 8);
+        assert_eq!(rewind_ticks(Tick(100), None, 100_000), 0);
+        // Claiming to be further behind than the ping allows
+        assert_eq!(
+            rewind_ticks(Tick(100), Some(Tick(50)), 0),
+            MAX_VIEW_DELAY_TICKS
+        );
+        // Or actually being that far behind
+        assert_eq!(
+            rewind_ticks(Tick(1000), Some(Tick(0)), 2_000_000),
+            MAX_REWIND_TICKS
+        );
+
+        let mut history = RewindHistory::default();
+        for tick in 0..100 {
+            history.record(Tick(tick), bounds_at(tick as f32));
+        }
+        assert_eq!(history.at(Tick(95)), Some(bounds_at(95.0)));
+        // Older than we keep
+        assert_eq!(
+            history.at(Tick(0)),
+            Some(bounds_at((99 - MAX_REWIND_TICKS) as f32))
+        );
+
+        let projectile = bounds_at(95.5);
+        assert!(overlaps(&history.at(Tick(95)).unwrap(), &projectile));
+        assert!(!overlaps(&history.at(Tick(99)).unwrap(), &projectile));
+    }
+}
@@ -1,31 +1,40 @@
 use std::{collections::HashMap, sync::Arc, time::Duration};
 
-use avian3d::prelude::{Gravity, LinearVelocity, Rotation};
-use bevy::{platform::collections::HashSet, prelude::*};
-use rand::RngExt;
+use avian3d::prelude::Gravity;
+use bevy::{app::PluginGroupBuilder, prelude::*};
+use rand::{RngExt, rngs::StdRng};
 use shared::{
-    Config, ConfigPlugin, CurrentTick, PlayerPing, PlayerPingAtomic, PlayerPingInteger,
+    BASE_TICKS_PER_SECOND, Config, ConfigPlugin, CurrentTick, PlayerPing, PlayerPingAtomic,
+    PlayerPingInteger,
     event::{
-        NetEntId, PlayerId, UDPacketEvent,
+        NetEntId, PlayerId, ResumeToken, UDPacketEvent,
         client::{
-            DespawnUnit2, HeartbeatChallenge, HeartbeatResponse, PlayerDisconnected, SpawnUnit2,
-            UpdateUnit2, WorldData2,
+            BeginThirdpersonControllingUnit, ConnectionRejected, DespawnUnit2, HeartbeatChallenge,
+            HeartbeatResponse, PlayerDisconnected, SpawnUnit2, WorldData2,
         },
-        server::{ChangeMovement, Heartbeat, HeartbeatChallengeResponse, IWantToDisconnect},
+        server::{Heartbeat, HeartbeatChallengeResponse, IWantToDisconnect},
     },
     net_components::{
         ToNetComponent,
-        ents::{PlayerCamera, SendNetworkTranformUpdates},
+        ents::{Man, PlayerCamera, SendNetworkTranformUpdates},
         make_ball,
-        ours::{ControlledBy, DespawnOnPlayerDisconnect, PlayerColor, PlayerName},
+        ours::{ControlledBy, Dead, DespawnOnPlayerDisconnect, PlayerColor, PlayerName},
     },
     netlib::{
         EndpointGeneral, EventToClient, EventToServer, NetworkConnectionTarget,
-        ServerNetworkingResources, Tick,
+        ServerNetworkingResources, Tick, compression::Compression,
+        conditioner::NetworkConditionOverrides, envelope, transport::MemoryNetwork,
     },
     physics::terrain::TerrainParams,
+    rng::GameRng,
+    rpc::{
+        RpcError,
+        methods::{RequestScoreboard, RequestScoreboardResponse},
+    },
 };
 
+use crate::rpc::{AddRpcHandler, RpcCall};
+
 /// How often to run the system
 const HEARTBEAT_MILLIS: u64 = 200;
 /// How long until disconnect
@@ -33,6 +42,8 @@ const HEARTBEAT_TIMEOUT: u64 = 2000;
 /// How long do you have to connect, as a multipler of the heartbeart timeout.
 /// If the timeout is 1000 ms, then `5` would mean you have `5000ms` to connect.
 const HEARTBEAT_CONNECTION_GRACE_PERIOD: u64 = 15;
+/// How long a disconnected player's units wait for them, unless the config says otherwise
+const DEFAULT_RECONNECT_GRACE_SECONDS: f32 = 30.0;
 
 #[derive(States, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
 enum ServerState {
@@ -68,42 +79,98 @@ struct PlayerEndpoint(EndpointGeneral);
 //pub mod game_manager;
 pub mod animations;
 pub mod axum;
+pub mod capture;
+#[cfg(any(test, feature = "harness"))]
+pub mod harness;
+pub mod interest;
+pub mod lag_compensation;
+pub mod metrics;
+pub mod movement;
 pub mod projectile;
+pub mod rate_limit;
+pub mod replication;
+pub mod rpc;
 pub mod spawns;
 pub mod terrain;
 pub mod websocket;
 
-pub fn main_multiplayer_server(tokio_runtime: Arc<tokio::runtime::Runtime>) {
+pub fn main_multiplayer_server(
+    tokio_runtime: Arc<tokio::runtime::Runtime>,
+    capture: Option<capture::CapturePlugin>,
+    seed: Option<u64>,
+    net_overrides: NetworkConditionOverrides,
+) {
     do_app(|app| {
-        app.add_systems(Startup, add_network_connection_info_from_config);
+        if let Some(seed) = seed {
+            app.insert_resource(GameRng::new(seed));
+        }
+        app.insert_resource(net_overrides);
+        app.add_systems(
+            Startup,
+            (
+                add_network_connection_info_from_config,
+                insert_network_conditions,
+            ),
+        );
         app.insert_resource(shared::tokio_udp::TokioRuntimeResource(tokio_runtime));
         app.add_plugins(axum::AxumServerPlugin);
+        if let Some(capture) = capture {
+            app.add_plugins(capture);
+        }
     });
 }
 
-pub fn call_from_client_for_singleplayer(network_target: NetworkConnectionTarget) {
+/// Runs a server that plays back a capture, with no ports open
+pub fn replay_server(replay: capture::ReplayPlugin) {
+    do_app(|app| {
+        app.insert_resource(NetworkConnectionTarget {
+            ip: "localhost".to_string(),
+            port: 0,
+        });
+        app.insert_resource(MemoryNetwork::default());
+        app.add_plugins(replay);
+    });
+}
+
+/// Runs a server that only the client holding `memory` can reach
+pub fn call_from_client_for_singleplayer(
+    network_target: NetworkConnectionTarget,
+    memory: MemoryNetwork,
+) {
     info!(
         "Starting singleplayer server connecting to {:?}",
         network_target
     );
     do_app(|app| {
         app.insert_resource(network_target);
+        app.insert_resource(memory);
     });
 }
 
 fn do_app(f: impl FnOnce(&mut App)) {
     info!("Main Start");
+    let mut app = build_app(DefaultPlugins.build());
+    app.add_plugins(ConfigPlugin);
+
+    f(&mut app);
+
+    app.run();
+}
+
+/// The whole server except for its config and where it listens. `harness` builds one without
+/// logging, since it runs several Apps in the same process.
+fn build_app(default_plugins: PluginGroupBuilder) -> App {
     let mut app = App::new();
 
     app.insert_resource(EndpointToPlayerId::default())
         .insert_resource(HeartbeatList::default())
+        .insert_resource(GameRng::default())
         .add_message::<PlayerDisconnected>()
         .add_message::<DespawnUnit2>()
-        .add_plugins(DefaultPlugins)
+        .add_plugins(default_plugins)
         .add_plugins(avian3d::PhysicsPlugins::default())
         .insert_resource(Gravity(Vec3::new(0.0, -9.81, 0.0)))
         .add_plugins((
-            ConfigPlugin,
             //chat::ChatPlugin,
             //game_manager::GamePlugin,
             spawns::SpawnPlugin,
@@ -115,6 +182,12 @@ fn do_app(f: impl FnOnce(&mut App)) {
             shared::character_controller::CharacterControllerPlugin,
             websocket::WebsocketPlugin,
             projectile::ProjectilePlugin,
+            replication::ReplicationPlugin,
+            interest::InterestPlugin,
+            movement::MovementPlugin,
+            lag_compensation::LagCompensationPlugin,
+            rate_limit::RateLimitPlugin,
+            rpc::RpcPlugin,
             //StatusPlugin,
         ))
         .init_state::<ServerState>()
@@ -142,27 +215,26 @@ fn do_app(f: impl FnOnce(&mut App)) {
             Update,
             (
                 on_player_disconnect,
+                expire_disconnected_players,
                 on_player_connect,
                 on_player_heartbeat,
                 on_receive_ping_challenge,
-                on_player_scoreboard_request,
                 on_unit_despawn,
                 on_disconnect_packet,
-                shared::event::server::drain_incoming_events,
-                on_movement,
+                on_connection_lost,
             )
                 .run_if(in_state(ServerState::Running)),
         )
-        // Stuff to be calculated and sent out at the end of each tick
         .add_systems(
-            FixedUpdate,
-            (broadcast_movement_updates).run_if(in_state(ServerState::Running)),
+            PreUpdate,
+            capture::drain_incoming_events.run_if(in_state(ServerState::Running)),
         )
         .add_systems(
             FixedPostUpdate,
             (
                 shared::increment_ticks,
                 shared::netlib::flush_outgoing_events_udp::<EventToServer, EventToClient>,
+                shared::netlib::flush_outgoing_events_stream::<EventToServer, EventToClient>,
                 add_tick_just_happened_packet,
             )
                 .chain()
@@ -179,11 +251,10 @@ fn do_app(f: impl FnOnce(&mut App)) {
             check_heartbeats.run_if(bevy::time::common_conditions::on_timer(
                 Duration::from_millis(200),
             )),
-        );
-
-    f(&mut app);
+        )
+        .add_rpc_handler(answer_scoreboard);
 
-    app.run();
+    app
 }
 
 fn add_tick_just_happened_packet(
@@ -195,7 +266,7 @@ fn add_tick_just_happened_packet(
         let event = EventToClient::TickHappened(shared::event::client::TickHappened {
             tick: current_tick.0,
         });
-        sr.send_outgoing_event_next_tick(net_client.0, &event);
+        sr.send_event(net_client.0, &event);
     }
 }
 
@@ -206,6 +277,20 @@ fn add_network_connection_info_from_config(config: Res<Config>, mut commands: Co
     });
 }
 
+fn insert_network_conditions(
+    mut commands: Commands,
+    overrides: Res<NetworkConditionOverrides>,
+    config: Res<Config>,
+) {
+    let conditions = overrides.apply(config.network_conditions.unwrap_or_default());
+    if conditions.outgoing.is_perfect() && conditions.incoming.is_perfect() {
+        return;
+    }
+
+    info!(?conditions, "Simulating network conditions");
+    commands.insert_resource(conditions);
+}
+
 /// This component is added to each of the meta entities representing a connected player
 #[derive(Component)]
 pub struct ConnectedPlayer;
@@ -215,50 +300,118 @@ pub struct DisconnectedPlayer {
     pub disconnect_tick: Tick,
 }
 
+/// Kicked players lose their `ResumeToken`, and their units don't wait for them
+#[derive(Component)]
+pub struct Kicked;
+
 #[allow(clippy::too_many_arguments)]
 fn on_player_connect(
     mut new_players: UDPacketEvent<shared::event::server::ConnectRequest>,
     // We need the world here so we can do dynamic queries for all existing units with NetEntId
     world: &World,
     mut commands: Commands,
+    mut rng: Local<Option<StdRng>>,
 ) {
+    let rng = rng.get_or_insert_with(|| world.resource::<GameRng>().fork(1));
     let sr = world.resource::<ServerNetworkingResources>().clone();
     let terrain = world.resource::<TerrainParams>().clone();
-    let _config = world.resource::<Config>().clone();
+    let config = world.resource::<Config>().clone();
     for player in new_players.read() {
         info!("Got packet");
+        let conflicting = envelope::conflicting_events(&player.event.event_schemas);
+        if player.event.protocol_version != shared::event::PROTOCOL_VERSION
+            || !conflicting.is_empty()
+        {
+            let reason = if conflicting.is_empty() {
+                format!(
+                    "client version {:016x}, server version {:016x}",
+                    player.event.protocol_version,
+                    shared::event::PROTOCOL_VERSION
+                )
+            } else {
+                format!("client and server differ on {}", conflicting.join(", "))
+            };
+            warn!(endpoint = ?player.endpoint, reason, "Rejecting client");
+            sr.send_event(
+                player.endpoint,
+                &EventToClient::ConnectionRejected(ConnectionRejected { reason }),
+            );
+            continue;
+        }
+
+        // Compress what we send them if we both want to. The client can already read it, so this
+        // applies to the world data as well.
+        let compression = if player.event.compression == config.compression() {
+            player.event.compression
+        } else {
+            Compression::None
+        };
+
+        if let Some(token) = player.event.resume {
+            match find_session(world, token) {
+                Some(session) if session.disconnected => {
+                    info!(player_id = ?session.player_id, who = ?player.endpoint, "Player resumed");
+                    resume_session(world, &mut commands, player.endpoint, &session);
+                    welcome_player(
+                        world,
+                        &sr,
+                        player.endpoint,
+                        &session.name,
+                        &session.color,
+                        WorldData2 {
+                            your_player_id: session.player_id,
+                            your_camera_unit_id: session.camera,
+                            terrain_params: terrain.clone(),
+                            units: vec![],
+                            compression,
+                            resume_token: token,
+                        },
+                    );
+                    give_back_control(world, &sr, player.endpoint, session.player_id);
+                    continue;
+                }
+                // They sent it again before our `WorldData2` reached them
+                Some(session) if session.endpoint == player.endpoint => continue,
+                _ => warn!(who = ?player.endpoint, "Unknown resume token, joining as a new player"),
+            }
+        }
+
         // Generate their name
-        let name = player
-            .event
-            .name
-            .clone()
-            .unwrap_or_else(|| format!("Player #{}", rand::rng().random_range(1..10000)));
+        let name = player.event.name.clone().unwrap_or_else(|| {
+            let number = rng.random_range(1..10000);
+            format!("Player #{number}")
+        });
+        let name = PlayerName { name };
 
         let spawn_location = player.event.my_location;
         let player_color = PlayerColor {
             hue: player.event.color_hue,
         };
 
-        let new_player_id = PlayerId::random();
+        let new_player_id = PlayerId::random(rng);
+        let resume_token = ResumeToken::random();
 
         // Spawn player entity as ConnectedPlayer
         // SPAWN A
         commands.spawn((
-            PlayerName { name: name.clone() },
+            name.clone(),
             player_color.clone(),
             new_player_id,
+            resume_token,
             PlayerEndpoint(player.endpoint),
             ConnectedPlayer,
+            replication::ClientSnapshots::default(),
+            movement::MovementViolations::default(),
         ));
 
         // This is the unit to represent the player themselves
         // SPAWN B
         let spawn_camera_unit = SpawnUnit2 {
-            net_ent_id: NetEntId::random(),
+            net_ent_id: NetEntId::random(rng),
             components: vec![
                 PlayerCamera.to_net_component(),
                 spawn_location.to_net_component(),
-                PlayerName { name: name.clone() }.to_net_component(),
+                name.clone().to_net_component(),
                 player_color.clone().to_net_component(),
                 ControlledBy::single(new_player_id).to_net_component(),
                 SendNetworkTranformUpdates.to_net_component(),
@@ -271,143 +424,201 @@ fn on_player_connect(
             player_id: new_player_id,
         });
 
-        let mut unit_list_to_new_client = vec![];
+        welcome_player(
+            world,
+            &sr,
+            player.endpoint,
+            &name,
+            &player_color,
+            WorldData2 {
+                your_player_id: new_player_id,
+                your_camera_unit_id: spawn_camera_unit.net_ent_id,
+                terrain_params: terrain.clone(),
+                units: vec![],
+                compression,
+                resume_token,
+            },
+        );
+    }
+}
 
-        let mut client_query = world.try_query_filtered::<(&PlayerEndpoint, &PlayerId, &PlayerName, &PlayerColor), With<ConnectedPlayer>>();
-        if let Some(client_query_thing) = &mut client_query {
-            for (c_net_client, c_player_id, c_name, c_color) in client_query_thing.iter(world) {
-                // Send each existing player's info to the new client
-                // SPAWN A
-                unit_list_to_new_client.push(SpawnUnit2 {
+/// Tell a player who just (re)joined about everyone else and everyone else about them, start
+/// tracking their heartbeats and interest, and send them `world_data` with the other players in it
+fn welcome_player(
+    world: &World,
+    sr: &ServerNetworkingResources,
+    endpoint: EndpointGeneral,
+    name: &PlayerName,
+    player_color: &PlayerColor,
+    mut world_data: WorldData2,
+) {
+    let new_player_id = world_data.your_player_id;
+
+    let mut client_query = world.try_query_filtered::<(&PlayerEndpoint, &PlayerId, &PlayerName, &PlayerColor), With<ConnectedPlayer>>();
+    if let Some(client_query_thing) = &mut client_query {
+        for (c_net_client, c_player_id, c_name, c_color) in client_query_thing.iter(world) {
+            // Send each existing player's info to the new client
+            // SPAWN A
+            world_data.units.push(SpawnUnit2 {
+                net_ent_id: NetEntId::none(),
+                components: vec![
+                    c_name.clone().to_net_component(),
+                    c_color.clone().to_net_component(),
+                    c_player_id.to_net_component(),
+                    //ConnectedPlayer.to_net_component(),
+                ],
+            });
+
+            // Tell all connected clients about your new player. Their camera is sent by
+            // `update_interest` to whoever is close enough to see it.
+            // SPAWN A
+            sr.send_event(
+                c_net_client.0,
+                &EventToClient::SpawnUnit2(SpawnUnit2 {
                     net_ent_id: NetEntId::none(),
                     components: vec![
-                        c_name.clone().to_net_component(),
-                        c_color.clone().to_net_component(),
-                        c_player_id.to_net_component(),
+                        name.clone().to_net_component(),
+                        player_color.clone().to_net_component(),
+                        new_player_id.to_net_component(),
                         //ConnectedPlayer.to_net_component(),
                     ],
-                });
-
-                // Tell all connected clients about your new player and camera
-                sr.send_outgoing_event_next_tick_batch(
-                    c_net_client.0,
-                    &[
-                        // their camera
-                        // SPAWN B
-                        // TODO make the player actually move to their spawn location
-                        EventToClient::SpawnUnit2(spawn_camera_unit.clone()),
-                        // their player unit
-                        // SPAWN A
-                        EventToClient::SpawnUnit2(SpawnUnit2 {
-                            net_ent_id: NetEntId::none(),
-                            components: vec![
-                                PlayerName { name: name.clone() }.to_net_component(),
-                                player_color.clone().to_net_component(),
-                                new_player_id.to_net_component(),
-                                //ConnectedPlayer.to_net_component(),
-                            ],
-                        }),
-                    ],
-                );
-            }
+                }),
+            );
         }
+    }
 
-        let mut large_unit_list_to_send: Vec<SpawnUnit2> = vec![];
+    // Each time we miss a heartbeat, we increment the Atomic counter.
+    // So, we initially set this to negative number to give extra time for the initial
+    // connection.
+    let hb_grace_period =
+        (HEARTBEAT_CONNECTION_GRACE_PERIOD - 1) * (HEARTBEAT_TIMEOUT / HEARTBEAT_MILLIS);
+
+    let heartbeat_mapping = world.resource::<HeartbeatList>();
+    heartbeat_mapping.heartbeats.insert(
+        new_player_id,
+        Arc::new(PlayerPingAtomic::new(
+            -(hb_grace_period as PlayerPingInteger),
+        )),
+    );
+    heartbeat_mapping.pings.insert(
+        new_player_id,
+        PlayerPing {
+            server_challenged_ping_microsec: PlayerPingAtomic::new(-1),
+            client_reported_ping_microsec: PlayerPingAtomic::new(-1),
+        },
+    );
 
-        let units_to_spawn =
-            world.try_query_filtered::<(Entity, &NetEntId), Without<ConnectedPlayer>>();
+    world
+        .resource::<EndpointToPlayerId>()
+        .map
+        .insert(endpoint, new_player_id);
 
-        if let Some(mut units_spawns_thing) = units_to_spawn {
-            for (unit_ent, unit_net_ent_id) in units_spawns_thing.iter(world) {
-                let component_info = world.inspect_entity(unit_ent).unwrap();
-                info!(
-                    "Preparing to send existing unit {:?} to new player",
-                    unit_net_ent_id
-                );
-                let ciids = component_info.map(|ci| ci.id()).collect::<HashSet<_>>();
-                let Ok(ents_res) = world.entity(unit_ent).get_by_id(&ciids) else {
-                    error!("Failed to get components for entity {:?}", unit_ent);
-                    continue;
-                };
-
-                let mut spawn_unit = SpawnUnit2 {
-                    net_ent_id: *unit_net_ent_id,
-                    components: vec![],
-                };
-
-                for (component_id, component_ptr) in ents_res.iter() {
-                    let type_id = world
-                        .components()
-                        .get_info(*component_id)
-                        .unwrap()
-                        .type_id()
-                        .unwrap();
-
-                    // SAFETY: Trust that bevy gives us a valid type id and pointer from `get_by_id`
-                    if let Some(net_comp) = unsafe {
-                        shared::net_components::NetComponent::from_type_id_ptr(
-                            type_id,
-                            *component_ptr,
-                        )
-                    } {
-                        info!("Component to send: {:?}", net_comp);
-                        spawn_unit.components.push(net_comp);
-                    }
-                }
-                large_unit_list_to_send.push(spawn_unit);
-            }
-        }
+    // Units around them are sent on the next fixed tick
+    world.resource::<interest::Interest>().clients.insert(
+        endpoint,
+        interest::ClientInterest::new(new_player_id, world_data.your_camera_unit_id),
+    );
 
-        // Each time we miss a heartbeat, we increment the Atomic counter.
-        // So, we initially set this to negative number to give extra time for the initial
-        // connection.
-        let hb_grace_period =
-            (HEARTBEAT_CONNECTION_GRACE_PERIOD - 1) * (HEARTBEAT_TIMEOUT / HEARTBEAT_MILLIS);
+    sr.compression.insert(endpoint, world_data.compression);
 
-        let heartbeat_mapping = world.resource::<HeartbeatList>();
-        heartbeat_mapping.heartbeats.insert(
-            new_player_id,
-            Arc::new(PlayerPingAtomic::new(
-                -(hb_grace_period as PlayerPingInteger),
-            )),
-        );
-        heartbeat_mapping.pings.insert(
-            new_player_id,
-            PlayerPing {
-                server_challenged_ping_microsec: PlayerPingAtomic::new(-1),
-                client_reported_ping_microsec: PlayerPingAtomic::new(-1),
-            },
-        );
+    // Finally, tell the client all this info.
+    // send initial world data
+    info!(
+        who = ?endpoint,
+        "Player connected - sending {} existing units",
+        world_data.units.len()
+    );
+    let event = EventToClient::WorldData2(world_data);
+    sr.send_event(endpoint, &event);
+}
 
-        world
-            .resource::<EndpointToPlayerId>()
-            .map
-            .insert(player.endpoint, new_player_id);
-
-        // Finally, tell the client all this info.
-        let world_data = WorldData2 {
-            your_player_id: new_player_id,
-            your_camera_unit_id: spawn_camera_unit.net_ent_id,
-            terrain_params: terrain.clone(),
-            units: unit_list_to_new_client,
-        };
+/// A player who was handed a [`ResumeToken`], connected or not
+struct Session {
+    entity: Entity,
+    player_id: PlayerId,
+    endpoint: EndpointGeneral,
+    disconnected: bool,
+    name: PlayerName,
+    color: PlayerColor,
+    camera: NetEntId,
+}
 
-        // send initial world data
-        info!(
-            who = ?player.endpoint,
-            "Player connected - sending {} existing units",
-            world_data.units.len()
-        );
-        let event = EventToClient::WorldData2(world_data);
-        sr.send_outgoing_event_next_tick(player.endpoint, &event);
+fn find_session(world: &World, token: ResumeToken) -> Option<Session> {
+    let mut players = world.try_query::<(
+        Entity,
+        &ResumeToken,
+        &PlayerId,
+        &PlayerEndpoint,
+        &PlayerName,
+        &PlayerColor,
+        Has<DisconnectedPlayer>,
+    )>()?;
+    let (entity, _, player_id, endpoint, name, color, disconnected) = players
+        .iter(world)
+        .find(|(_, player_token, ..)| **player_token == token)?;
+
+    let mut cameras = world
+        .try_query_filtered::<(&NetEntId, &DespawnOnPlayerDisconnect), With<PlayerCamera>>()?;
+    let camera = cameras
+        .iter(world)
+        .find(|(_, owner)| owner.player_id == *player_id)
+        .map_or(NetEntId::none(), |(net_ent_id, _)| *net_ent_id);
+
+    Some(Session {
+        entity,
+        player_id: *player_id,
+        endpoint: endpoint.0,
+        disconnected,
+        name: name.clone(),
+        color: color.clone(),
+        camera,
+    })
+}
 
-        // send remaining world data in batches
-        let events = large_unit_list_to_send
-            .iter()
-            .map(|u| EventToClient::SpawnUnit2(u.clone()))
-            .collect::<Vec<_>>();
+/// Move a disconnected player over to `endpoint`
+fn resume_session(
+    world: &World,
+    commands: &mut Commands,
+    endpoint: EndpointGeneral,
+    session: &Session,
+) {
+    world
+        .resource::<EndpointToPlayerId>()
+        .map
+        .remove(&session.endpoint);
+    commands
+        .entity(session.entity)
+        .remove::<DisconnectedPlayer>()
+        .insert((
+            PlayerEndpoint(endpoint),
+            ConnectedPlayer,
+            replication::ClientSnapshots::default(),
+        ));
+}
 
-        sr.send_outgoing_event_next_tick_batch(player.endpoint, &events);
+/// Put a player who came back in control of the man they left behind. Sent after their
+/// `WorldData2`, the man itself follows with the units around them.
+fn give_back_control(
+    world: &World,
+    sr: &ServerNetworkingResources,
+    endpoint: EndpointGeneral,
+    player_id: PlayerId,
+) {
+    let mut men =
+        world.try_query_filtered::<(&NetEntId, &ControlledBy), (With<Man>, Without<Dead>)>();
+    let man = men.as_mut().and_then(|men| {
+        men.iter(world)
+            .find(|(_, controlled_by)| controlled_by.players.contains(&player_id))
+            .map(|(net_ent_id, _)| *net_ent_id)
+    });
+    if let Some(unit) = man {
+        sr.send_event(
+            endpoint,
+            &EventToClient::BeginThirdpersonControllingUnit(BeginThirdpersonControllingUnit {
+                player_id,
+                unit: Some(unit),
+            }),
+        );
     }
 }
 
@@ -453,6 +664,25 @@ fn on_disconnect_packet(
     }
 }
 
+/// Disconnect whoever our reliable datagrams stopped getting through to
+fn on_connection_lost(
+    mut on_disconnect: MessageWriter<PlayerDisconnected>,
+    endpoint_mapping: Res<EndpointToPlayerId>,
+    sr: Res<ServerNetworkingResources>,
+) {
+    for endpoint in sr.take_lost_endpoints() {
+        match endpoint_mapping.map.get(&endpoint) {
+            Some(player_id) => {
+                on_disconnect.write(PlayerDisconnected {
+                    id: *player_id,
+                    reason: "Connection lost".to_string(),
+                });
+            }
+            None => sr.forget_endpoint(endpoint),
+        }
+    }
+}
+
 fn send_ping_challenge(
     clients: Query<&PlayerEndpoint, With<ConnectedPlayer>>,
     time: Res<Time>,
@@ -462,7 +692,7 @@ fn send_ping_challenge(
         server_time: time.elapsed_secs_f64(),
     });
     for net_client in &clients {
-        sr.send_outgoing_event_now(net_client.0, &event, None);
+        sr.send_event(net_client.0, &event);
     }
 }
 
@@ -472,10 +702,13 @@ fn on_receive_ping_challenge(
     //tick: Res<CurrentTick>,
     heartbeat_mapping: Res<HeartbeatList>,
     endpoint_mapping: Res<EndpointToPlayerId>,
+    sr: Res<ServerNetworkingResources>,
 ) {
     for hb in pd.read() {
         if let Some(player_id) = endpoint_mapping.map.get(&hb.endpoint) {
             let ping = time.elapsed_secs_f64() - hb.event.server_time;
+            sr.networking_stats
+                .record_rtt(hb.endpoint, Duration::from_secs_f64(ping.max(0.0)));
             let ping = ping / 2.0;
             let ping = (ping * 1_000_000.0) as PlayerPingInteger; // in us
             if let Some(player_ping) = heartbeat_mapping.pings.get(&*player_id) {
@@ -500,13 +733,12 @@ fn on_player_disconnect(
     mut pd: MessageReader<PlayerDisconnected>,
 
     clients: Query<(Entity, &PlayerEndpoint, &PlayerId), With<ConnectedPlayer>>,
-    clients_owned_items: Query<(&NetEntId, &DespawnOnPlayerDisconnect)>,
 
-    mut despawn_unit: MessageWriter<DespawnUnit2>,
     mut commands: Commands,
     heartbeat_mapping: Res<HeartbeatList>,
     tick: Res<CurrentTick>,
     sr: Res<ServerNetworkingResources>,
+    interest: Res<interest::Interest>,
 ) {
     for player in pd.read() {
         heartbeat_mapping.heartbeats.remove(&player.id);
@@ -514,19 +746,15 @@ fn on_player_disconnect(
 
         let events = vec![EventToClient::PlayerDisconnected(player.clone())];
 
-        for (owned_ent_id, despawn_tag) in &clients_owned_items {
-            if despawn_tag.player_id == player.id {
-                despawn_unit.write(DespawnUnit2 {
-                    net_ent_id: *owned_ent_id,
-                });
-            }
-        }
-
+        // Their units wait for them in case they come back, see `expire_disconnected_players`
         info!("Player {:?} disconnected: {}", player.id, player.reason);
 
         for (c_ent, net_client, player_id) in &clients {
-            sr.send_outgoing_event_next_tick_batch(net_client.0, &events);
+            sr.send_event_batch(net_client.0, &events);
             if player_id == &player.id {
+                // After the flush, so they still get told why
+                sr.forget_endpoint_after_flush(net_client.0);
+                interest.clients.remove(&net_client.0);
                 commands
                     .entity(c_ent)
                     .remove::<ConnectedPlayer>()
@@ -538,14 +766,55 @@ fn on_player_disconnect(
     }
 }
 
+/// Despawn the units of players who didn't come back in time
+fn expire_disconnected_players(
+    players: Query<(
+        Entity,
+        &PlayerId,
+        &PlayerEndpoint,
+        &DisconnectedPlayer,
+        Has<Kicked>,
+    )>,
+    owned_units: Query<(&NetEntId, &DespawnOnPlayerDisconnect)>,
+    mut despawn_unit: MessageWriter<DespawnUnit2>,
+    mut commands: Commands,
+    tick: Res<CurrentTick>,
+    config: Res<Config>,
+    endpoint_mapping: Res<EndpointToPlayerId>,
+) {
+    let grace_seconds = config
+        .reconnect_grace_seconds
+        .unwrap_or(DEFAULT_RECONNECT_GRACE_SECONDS);
+    let grace_ticks = (grace_seconds * BASE_TICKS_PER_SECOND as f32) as u64;
+
+    for (ent, player_id, endpoint, disconnected, kicked) in &players {
+        if !kicked && tick.0.0.saturating_sub(disconnected.disconnect_tick.0) < grace_ticks {
+            continue;
+        }
+
+        info!(
+            ?player_id,
+            "Player didn't reconnect in time, despawning their units"
+        );
+        for (owned_ent_id, despawn_tag) in &owned_units {
+            if despawn_tag.player_id == *player_id {
+                despawn_unit.write(DespawnUnit2 {
+                    net_ent_id: *owned_ent_id,
+                });
+            }
+        }
+        endpoint_mapping.map.remove(&endpoint.0);
+        commands.entity(ent).despawn();
+    }
+}
+
 fn on_unit_despawn(
     mut pd: MessageReader<DespawnUnit2>,
-    clients: Query<&PlayerEndpoint, With<ConnectedPlayer>>,
     units: Query<(Entity, &NetEntId)>,
     mut commands: Commands,
     sr: Res<ServerNetworkingResources>,
+    interest: Res<interest::Interest>,
 ) {
-    let mut events = vec![];
     for despawn in pd.read() {
         'unit: for (unit_ent, unit_net_ent_id) in &units {
             if unit_net_ent_id == &despawn.net_ent_id {
@@ -556,19 +825,8 @@ fn on_unit_despawn(
 
         trace!("Despawning unit {:?}", despawn.net_ent_id);
 
-        // Now tell all clients to also despawn
-        let event = EventToClient::DespawnUnit2(DespawnUnit2 {
-            net_ent_id: despawn.net_ent_id,
-        });
-        events.push(event);
-    }
-
-    if events.is_empty() {
-        return;
-    }
-
-    for net_client in &clients {
-        sr.send_outgoing_event_next_tick_batch(net_client.0, &events);
+        // Now tell the clients that could see it to also despawn
+        interest.send_despawn(&sr, despawn.net_ent_id);
     }
 }
 
@@ -590,19 +848,18 @@ fn on_player_heartbeat(
                     server_time: time.elapsed_secs_f64(),
                     server_tick: tick.0,
                 });
-                sr.send_outgoing_event_now(hb.endpoint, &event, None);
+                sr.send_event(hb.endpoint, &event);
             }
         }
     }
 }
 
-fn on_player_scoreboard_request(
-    mut pd: UDPacketEvent<shared::event::server::RequestScoreboard>,
+fn answer_scoreboard(
+    In(_call): In<RpcCall<RequestScoreboard>>,
     plys: Query<(&PlayerId, &PlayerName), With<ConnectedPlayer>>,
     heartbeat_mapping: Res<HeartbeatList>,
-    sr: Res<ServerNetworkingResources>,
-) {
-    let mut scoreboard_data = shared::event::client::RequestScoreboardResponse {
+) -> Result<RequestScoreboardResponse, RpcError> {
+    let mut scoreboard_data = RequestScoreboardResponse {
         player_names: HashMap::new(),
         player_pings: HashMap::new(),
     };
@@ -615,101 +872,5 @@ fn on_player_scoreboard_request(
             scoreboard_data.player_pings.insert(*ply_id, ping);
         }
     }
-    for req in pd.read() {
-        let event = EventToClient::RequestScoreboardResponse(scoreboard_data.clone());
-        sr.send_outgoing_event_now(req.endpoint, &event, None);
-    }
-}
-
-fn on_movement(
-    mut pd: UDPacketEvent<ChangeMovement>,
-    mut ent_to_move: Query<
-        (
-            &NetEntId,
-            &mut Transform,
-            Option<&mut LinearVelocity>,
-            Option<&mut Rotation>,
-        ),
-        With<SendNetworkTranformUpdates>,
-    >,
-) {
-    'event: for movement in pd.read() {
-        // The camera NetEntId is directly in the movement event
-        let camera_net_id = movement.event.net_ent_id;
-
-        // Find and update the camera entity
-        for (cam_net_id, mut cam_transform, maybe_lv, maybe_rot) in &mut ent_to_move {
-            if cam_net_id == &camera_net_id {
-                // Update the camera's transform on the server
-                *cam_transform = movement.event.transform;
-
-                // Update linear velocity if provided
-                if let Some(mut lv) = maybe_lv {
-                    if let Some(new_lv) = movement.event.velocity {
-                        *lv = new_lv;
-                    }
-                }
-
-                // Update rotation if provided
-                if let Some(mut rot) = maybe_rot {
-                    if let Some(new_rot) = movement.event.rotation {
-                        *rot = new_rot;
-                    }
-                }
-                continue 'event;
-            }
-        }
-
-        warn!(
-            "Received movement update for unknown entity {:?}",
-            camera_net_id
-        );
-    }
-}
-
-// TODO make this more efficient by batching updates per client and only sending changed components
-// for physics if we think the client needs them
-fn broadcast_movement_updates(
-    clients: Query<&PlayerEndpoint, With<ConnectedPlayer>>,
-    sr: Res<ServerNetworkingResources>,
-    changed_transforms: Query<
-        (
-            &NetEntId,
-            &Transform,
-            Option<&LinearVelocity>,
-            Option<&Rotation>,
-        ),
-        (With<SendNetworkTranformUpdates>, Changed<Transform>),
-    >,
-    //current_tick: Res<CurrentTick>,
-) {
-    let mut events_to_send = vec![];
-    for (cam_net_id, cam_transform, cam_lv, cam_rot) in &changed_transforms {
-        let mut components = vec![cam_transform.to_net_component()];
-        if let Some(lv) = cam_lv {
-            components.push(lv.to_net_component());
-        }
-        if let Some(rot) = cam_rot {
-            components.push(rot.to_net_component());
-        }
-
-        let event = EventToClient::UpdateUnit2(UpdateUnit2 {
-            net_ent_id: *cam_net_id,
-            changed_components: components,
-            new_component: vec![],
-            removed_components: vec![],
-        });
-
-        events_to_send.push(event);
-    }
-
-    if !events_to_send.is_empty() {
-        trace!(
-            "Broadcasting {} movement updates to clients",
-            events_to_send.len()
-        );
-        for c_net_client in &clients {
-            sr.send_outgoing_event_next_tick_batch(c_net_client.0, &events_to_send);
-        }
-    }
+    Ok(scoreboard_data)
 }
@@ -1,8 +1,63 @@
-use server::main_multiplayer_server;
+use std::path::PathBuf;
+
+use clap::Parser;
+use server::{
+    capture::{CapturePlugin, ReplayPlugin},
+    main_multiplayer_server, replay_server,
+};
+use shared::netlib::conditioner::NetworkConditionOverrides;
 use tokio::runtime;
 
+#[derive(Parser, Debug)]
+struct ServerArgs {
+    /// Record every event we receive to this file, to replay it later with --replay
+    #[clap(long)]
+    capture: Option<PathBuf>,
+    /// Play back a file written by --capture instead of listening for players
+    #[clap(long, conflicts_with = "capture")]
+    replay: Option<PathBuf>,
+    /// Seed for entity ids and gameplay rolls. Replays use the one they were captured with.
+    #[clap(long)]
+    seed: Option<u64>,
+    /// Percent of UDP datagrams to drop, in both directions. Overrides `network_conditions` in
+    /// the config. See also --net-burst-loss, --net-duplicate, --net-reorder, --net-bandwidth
+    #[clap(long)]
+    net_loss: Option<f32>,
+    /// Percent chance of starting a burst of --net-burst-length lost datagrams
+    #[clap(long)]
+    net_burst_loss: Option<f32>,
+    #[clap(long, default_value = "5")]
+    net_burst_length: u32,
+    #[clap(long)]
+    net_duplicate: Option<f32>,
+    #[clap(long)]
+    net_reorder: Option<f32>,
+    /// Bytes per second allowed each way to each client, anything over is dropped
+    #[clap(long)]
+    net_bandwidth: Option<usize>,
+    /// Same seed and same traffic drops the same datagrams
+    #[clap(long)]
+    net_seed: Option<u64>,
+}
+
 //single thread
 fn main() {
+    let args = ServerArgs::parse();
+    if let Some(path) = args.replay {
+        replay_server(ReplayPlugin { path });
+        return;
+    }
+
+    let net_overrides = NetworkConditionOverrides {
+        loss_percent: args.net_loss,
+        burst_loss_percent: args.net_burst_loss,
+        burst_length: args.net_burst_length,
+        duplicate_percent: args.net_duplicate,
+        reorder_percent: args.net_reorder,
+        bytes_per_second: args.net_bandwidth,
+        seed: args.net_seed,
+    };
+
     let runtime = runtime::Builder::new_multi_thread()
         .enable_all()
         .build()
@@ -12,6 +67,11 @@ fn main() {
 
     let runtime2 = runtime.clone();
     runtime.block_on(async {
-        main_multiplayer_server(runtime2);
+        main_multiplayer_server(
+            runtime2,
+            args.capture.map(|path| CapturePlugin { path }),
+            args.seed,
+            net_overrides,
+        );
     });
 }
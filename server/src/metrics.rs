@@ -0,0 +1,247 @@
+//! The server's state in the Prometheus text format, served on `/metrics` by [`crate::axum`].
+use std::{collections::BTreeMap, fmt::Write, sync::atomic::Ordering};
+
+use bevy::prelude::*;
+use shared::{
+    CurrentTick, ServerTPS,
+    event::NetEntId,
+    net_components::ents::{Ball, ItemDrop, Man, NPC, PlayerCamera, Tower},
+    netlib::{ServerNetworkingResources, rate_limit::RateLimiter},
+    projectile::ProjectileSource,
+};
+
+use crate::{ConnectedPlayer, websocket::WebsocketResource};
+
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+const TICK_TIME_QUANTILES: [f64; 4] = [0.5, 0.9, 0.99, 1.0];
+
+/// Writes metrics in the text format, see
+/// <https://prometheus.io/docs/instrumenting/exposition_formats/>
+#[derive(Default)]
+pub struct MetricsText {
+    text: String,
+}
+
+impl MetricsText {
+    /// Describe a metric, its samples come after
+    pub fn metric(&mut self, name: &str, kind: &str, help: &str) -> &mut Self {
+        writeln!(self.text, "# HELP {name} {help}").unwrap();
+        writeln!(self.text, "# TYPE {name} {kind}").unwrap();
+        self
+    }
+
+    pub fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: f64) -> &mut Self {
+        self.text.push_str(name);
+        if !labels.is_empty() {
+            let labels = labels
+                .iter()
+                .map(|(label, value)| format!("{label}=\"{}\"", escape_label(value)))
+                .collect::<Vec<_>>();
+            write!(self.text, "{{{}}}", labels.join(",")).unwrap();
+        }
+        writeln!(self.text, " {value}").unwrap();
+        self
+    }
+
+    pub fn finish(self) -> String {
+        self.text
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Everything we export. A system so it can be run whenever someone asks.
+#[allow(clippy::type_complexity)]
+pub fn render_metrics(
+    tick: Res<CurrentTick>,
+    tps: Res<ServerTPS>,
+    sr: Res<ServerNetworkingResources>,
+    (websockets, limiter): (Res<WebsocketResource>, Res<RateLimiter>),
+    players: Query<(), With<ConnectedPlayer>>,
+    units: Query<
+        (
+            Has<Man>,
+            Has<NPC>,
+            Has<Ball>,
+            Has<PlayerCamera>,
+            Has<ItemDrop>,
+            Has<Tower>,
+        ),
+        With<NetEntId>,
+    >,
+    projectiles: Query<(), With<ProjectileSource>>,
+) -> String {
+    let mut metrics = MetricsText::default();
+
+    metrics
+        .metric(
+            "server_ticks_total",
+            "counter",
+            "Ticks simulated since we started",
+        )
+        .sample("server_ticks_total", &[], tick.0.0 as f64);
+
+    metrics.metric(
+        "server_tick_time_seconds",
+        "summary",
+        "Time between the last 1000 ticks",
+    );
+    if let Some(times) = tps.tick_time_quantiles(&TICK_TIME_QUANTILES) {
+        for (quantile, time) in TICK_TIME_QUANTILES.iter().zip(times) {
+            metrics.sample(
+                "server_tick_time_seconds",
+                &[("quantile", &quantile.to_string())],
+                time,
+            );
+        }
+    }
+
+    let stats = &sr.networking_stats;
+    for (name, help, counter) in [
+        (
+            "server_sent_bytes_total",
+            "Bytes sent, updated every second",
+            &stats.bytes_sent_since_start,
+        ),
+        (
+            "server_received_bytes_total",
+            "Bytes received, updated every second",
+            &stats.bytes_received_since_start,
+        ),
+        (
+            "server_sent_packets_total",
+            "Packets sent, updated every second",
+            &stats.packets_sent_since_start,
+        ),
+        (
+            "server_received_packets_total",
+            "Packets received, updated every second",
+            &stats.packets_received_since_start,
+        ),
+        (
+            "server_resent_packets_total",
+            "Reliable packets sent again, updated every second",
+            &stats.packets_resent_since_start,
+        ),
+    ] {
+        metrics.metric(name, "counter", help).sample(
+            name,
+            &[],
+            counter.load(Ordering::Relaxed) as f64,
+        );
+    }
+
+    for (name, help, count) in [
+        (
+            "server_websocket_handshake_failures_total",
+            "Websocket connections that failed or timed out in their handshake",
+            websockets.handshake_failures(),
+        ),
+        (
+            "server_websocket_rejected_connections_total",
+            "Websocket connections refused for their IP having too many open",
+            websockets.rejected_connections(),
+        ),
+    ] {
+        metrics
+            .metric(name, "counter", help)
+            .sample(name, &[], count as f64);
+    }
+
+    metrics.metric(
+        "server_received_events_total",
+        "counter",
+        "Events received from clients by type, before rate limiting",
+    );
+    let events: BTreeMap<_, _> = stats
+        .events_received_since_start
+        .iter()
+        .map(|entry| (*entry.key(), *entry.value()))
+        .collect();
+    for (event, count) in events {
+        metrics.sample(
+            "server_received_events_total",
+            &[("event", event)],
+            count as f64,
+        );
+    }
+
+    metrics.metric(
+        "server_rate_limited_events_total",
+        "counter",
+        "Events from clients dropped for going over their rate limits, by type",
+    );
+    for (event, count) in limiter.dropped() {
+        metrics.sample(
+            "server_rate_limited_events_total",
+            &[("event", event)],
+            count as f64,
+        );
+    }
+
+    metrics
+        .metric("server_connected_players", "gauge", "Players connected now")
+        .sample(
+            "server_connected_players",
+            &[],
+            players.iter().count() as f64,
+        );
+
+    let mut kinds = BTreeMap::<_, usize>::new();
+    for (is_man, is_npc, is_ball, is_camera, is_item, is_tower) in &units {
+        let kind = if is_npc {
+            "npc"
+        } else if is_man {
+            "man"
+        } else if is_ball {
+            "ball"
+        } else if is_camera {
+            "camera"
+        } else if is_item {
+            "item_drop"
+        } else if is_tower {
+            "tower"
+        } else {
+            "other"
+        };
+        *kinds.entry(kind).or_default() += 1;
+    }
+    metrics.metric("server_entities", "gauge", "Networked entities by kind");
+    for (kind, count) in kinds {
+        metrics.sample("server_entities", &[("kind", kind)], count as f64);
+    }
+
+    metrics
+        .metric("server_projectiles", "gauge", "Projectiles in flight")
+        .sample("server_projectiles", &[], projectiles.iter().count() as f64);
+
+    metrics.finish()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_text_format() {
+        let mut metrics = MetricsText::default();
+        metrics
+            .metric("requests_total", "counter", "Requests served")
+            .sample("requests_total", &[], 3.0)
+            .sample("requests_total", &[("path", "/a\"b\\")], 0.5);
+
+        assert_eq!(
+            metrics.finish(),
+            "# HELP requests_total Requests served\n\
+             # TYPE requests_total counter\n\
+             requests_total 3\n\
+             requests_total{path=\"/a\\\"b\\\\\"} 0.5\n"
+        );
+    }
+}
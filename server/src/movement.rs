@@ -0,0 +1,464 @@
+//! Authoritative movement for the units players control. Clients send their inputs, we simulate
+//! one per tick with the shared character controller and ack where it left the unit, see
+//! [`shared::prediction`].
+//!
+//! Everything a client sends about movement is validated first. Only the players in a unit's
+//! `ControlledBy` may move it, inputs can't ask for more speed than the unit's stats give it, and
+//! units and cameras have to stay on the map. Refused camera moves are answered with a
+//! `MovementCorrection`, and units put back on the map reach the client through the next ack.
+use avian3d::prelude::{LinearVelocity, Position};
+use bevy::prelude::*;
+use shared::{
+    character_controller::{
+        CharacterController, CharacterControllerSystems, MovementAcceleration, MovementAction,
+        MovementDampingFactor,
+    },
+    event::{
+        NetEntId, PlayerId, UDPacketEvent,
+        client::{MovementAck, MovementCorrection},
+        server::{ChangeMovement, MovementInput},
+    },
+    items::{Inventory, Item},
+    net_components::{
+        ents::SendNetworkTranformUpdates,
+        ours::{ControlledBy, Dead},
+    },
+    netlib::{EndpointGeneral, EventToClient, ServerNetworkingResources},
+    physics::terrain::TerrainParams,
+    prediction::{ControllerState, InputQueue},
+    stats::PlayerFinalStats,
+};
+
+use crate::{EndpointToPlayerId, ServerState};
+
+/// How far past the boundary walls a unit may get before we put it back
+const BOUNDARY_MARGIN: f32 = 5.0;
+/// How far below the terrain a unit may sink before we put it back
+const BELOW_TERRAIN_TOLERANCE: f32 = 1.0;
+/// Room above a unit's top speed, for slopes and knockback
+const SPEED_TOLERANCE: f32 = 1.5;
+/// The freecam moves at 20 along every axis at once, with room for frame time spikes
+const MAX_CAMERA_SPEED: f32 = 40.0;
+const CAMERA_MOVE_SLACK: f32 = 2.0;
+/// The third person camera can sit this far from the unit it follows, so it may jump anywhere
+/// this close to one of the player's units
+const CAMERA_FOLLOW_DISTANCE: f32 = 140.0;
+
+pub struct MovementPlugin;
+
+impl Plugin for MovementPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(
+            Update,
+            (on_movement_input, on_camera_movement).run_if(in_state(ServerState::Running)),
+        )
+        .add_systems(
+            FixedUpdate,
+            (
+                update_final_stats,
+                update_movement_stats,
+                check_unit_movement,
+                send_movement_acks,
+                apply_movement_inputs,
+            )
+                .chain()
+                .before(CharacterControllerSystems)
+                .run_if(in_state(ServerState::Running)),
+        );
+    }
+}
+
+/// Inputs for a unit from whoever is controlling it
+#[derive(Component)]
+pub struct MovementInputs {
+    endpoint: EndpointGeneral,
+    queue: InputQueue,
+    /// Simulated last tick and not acked yet
+    simulated: Option<u64>,
+    /// Where the unit last was on the map, to put it back to
+    last_valid: Option<Vec3>,
+}
+
+/// What a unit's stats allow its inputs
+#[derive(Component, Debug, Clone, Copy)]
+pub struct MovementStats {
+    pub speed_multiplier: f32,
+}
+
+impl From<&PlayerFinalStats> for MovementStats {
+    fn from(stats: &PlayerFinalStats) -> Self {
+        Self {
+            speed_multiplier: stats.movement_speed_multiplier(),
+        }
+    }
+}
+
+/// The items a unit carries, its [`PlayerFinalStats`] are worked out from them
+#[derive(Component, Debug, Clone)]
+pub struct UnitInventory(pub Inventory<Item>);
+
+fn update_final_stats(
+    mut units: Query<(&UnitInventory, &mut PlayerFinalStats), Changed<UnitInventory>>,
+) {
+    for (inventory, mut stats) in &mut units {
+        *stats = inventory.0.get_player_stats();
+    }
+}
+
+fn update_movement_stats(
+    mut units: Query<(&PlayerFinalStats, &mut MovementStats), Changed<PlayerFinalStats>>,
+) {
+    for (stats, mut movement_stats) in &mut units {
+        *movement_stats = MovementStats::from(stats);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Violation {
+    /// Moving something they don't control
+    NotOwner,
+    /// Numbers that aren't numbers
+    InvalidInput,
+    TooFast,
+    OutOfBounds,
+}
+
+/// Movement a player sent that we refused or had to fix
+#[derive(Component, Debug, Default, Clone)]
+pub struct MovementViolations {
+    pub not_owner: u32,
+    pub invalid_input: u32,
+    pub too_fast: u32,
+    pub out_of_bounds: u32,
+}
+
+impl MovementViolations {
+    pub fn total(&self) -> u32 {
+        self.not_owner + self.invalid_input + self.too_fast + self.out_of_bounds
+    }
+
+    fn record(&mut self, violation: Violation) {
+        let count = match violation {
+            Violation::NotOwner => &mut self.not_owner,
+            Violation::InvalidInput => &mut self.invalid_input,
+            Violation::TooFast => &mut self.too_fast,
+            Violation::OutOfBounds => &mut self.out_of_bounds,
+        };
+        *count += 1;
+    }
+}
+
+fn record_violation(
+    players: &mut Query<(&PlayerId, &mut MovementViolations)>,
+    player_id: PlayerId,
+    violation: Violation,
+) {
+    let Some((_, mut violations)) = players.iter_mut().find(|(id, _)| **id == player_id) else {
+        return;
+    };
+    violations.record(violation);
+    warn!(
+        ?player_id,
+        ?violation,
+        total = violations.total(),
+        "Refused movement"
+    );
+}
+
+/// Make an input safe to simulate, and say what was wrong with it
+fn sanitize_input(action: &mut MovementAction, max_speed_modifier: f32) -> Option<Violation> {
+    let finite = action.move_input_dir.is_finite()
+        && action.camera_yaw.is_finite()
+        && action.move_speed_modifier.is_finite();
+    if !finite {
+        *action = MovementAction::default();
+        return Some(Violation::InvalidInput);
+    }
+
+    action.move_input_dir = action.move_input_dir.clamp_length_max(1.0);
+    if !(0.0..=max_speed_modifier).contains(&action.move_speed_modifier) {
+        action.move_speed_modifier = action.move_speed_modifier.clamp(0.0, max_speed_modifier);
+        return Some(Violation::TooFast);
+    }
+    None
+}
+
+fn outside_boundary(position: Vec3, terrain: &TerrainParams, margin: f32) -> bool {
+    let limit = terrain.plane_size * 0.5 + margin;
+    !position.is_finite() || position.x.abs() > limit || position.z.abs() > limit
+}
+
+#[allow(clippy::type_complexity)]
+fn on_movement_input(
+    mut inputs: UDPacketEvent<MovementInput>,
+    mut commands: Commands,
+    endpoint_to_player_id: Res<EndpointToPlayerId>,
+    mut players: Query<(&PlayerId, &mut MovementViolations)>,
+    mut units: Query<
+        (
+            Entity,
+            &NetEntId,
+            &ControlledBy,
+            Option<&MovementStats>,
+            Option<&mut MovementInputs>,
+        ),
+        With<CharacterController>,
+    >,
+) {
+    for input in inputs.read() {
+        let Some(player_id) = endpoint_to_player_id.map.get(&input.endpoint).map(|id| *id) else {
+            warn!(?input.endpoint, "Movement input from an endpoint without a player");
+            continue;
+        };
+        let Some((unit, _, controlled_by, stats, existing)) = units
+            .iter_mut()
+            .find(|(_, net_ent_id, ..)| **net_ent_id == input.event.net_ent_id)
+        else {
+            warn!(
+                "Received movement input for unknown unit {:?}",
+                input.event.net_ent_id
+            );
+            continue;
+        };
+        if !controlled_by.players.contains(&player_id) {
+            record_violation(&mut players, player_id, Violation::NotOwner);
+            continue;
+        }
+
+        let max_speed_modifier = stats.map_or(1.0, |stats| stats.speed_multiplier);
+        let mut sanitized = input.event.inputs.clone();
+        for sequenced in &mut sanitized {
+            if let Some(violation) = sanitize_input(&mut sequenced.action, max_speed_modifier) {
+                record_violation(&mut players, player_id, violation);
+            }
+        }
+
+        match existing {
+            Some(mut existing) if existing.endpoint == input.endpoint => {
+                existing.queue.push(sanitized);
+            }
+            // A new controller numbers its inputs from scratch
+            _ => {
+                let mut queue = InputQueue::default();
+                queue.push(sanitized);
+                commands.entity(unit).insert(MovementInputs {
+                    endpoint: input.endpoint,
+                    queue,
+                    simulated: None,
+                    last_valid: None,
+                });
+            }
+        }
+    }
+}
+
+/// Cap how fast units go and put them back on the map if they left it
+#[allow(clippy::type_complexity)]
+fn check_unit_movement(
+    terrain: Res<TerrainParams>,
+    endpoint_to_player_id: Res<EndpointToPlayerId>,
+    mut players: Query<(&PlayerId, &mut MovementViolations)>,
+    mut units: Query<(
+        &mut Position,
+        &mut LinearVelocity,
+        &MovementAcceleration,
+        &MovementDampingFactor,
+        Option<&MovementStats>,
+        &mut MovementInputs,
+    )>,
+) {
+    let perlin = terrain.perlin();
+    for (mut position, mut velocity, acceleration, damping, stats, mut inputs) in &mut units {
+        // Where movement alone would top out, see `apply_movement_damping`
+        let speed_multiplier = stats.map_or(1.0, |stats| stats.speed_multiplier);
+        let max_speed = acceleration.0 / damping.0 * speed_multiplier * SPEED_TOLERANCE;
+        let horizontal = velocity.0.with_y(0.0).clamp_length_max(max_speed);
+        velocity.x = horizontal.x;
+        velocity.z = horizontal.z;
+
+        let ground = perlin.sample_height(position.x, position.z) * terrain.max_height_delta;
+        let on_map = !outside_boundary(position.0, &terrain, BOUNDARY_MARGIN)
+            && position.y >= ground - BELOW_TERRAIN_TOLERANCE;
+        if on_map {
+            inputs.last_valid = Some(position.0);
+            continue;
+        }
+
+        let fallback = position.0.clamp(
+            Vec3::splat(-terrain.plane_size * 0.5),
+            Vec3::splat(terrain.plane_size * 0.5),
+        );
+        let back = inputs.last_valid.unwrap_or(fallback.with_y(ground + 1.0));
+        debug!(from = ?position.0, to = ?back, "Putting a unit back on the map");
+        position.0 = back;
+        velocity.0 = Vec3::ZERO;
+
+        if let Some(player_id) = endpoint_to_player_id.map.get(&inputs.endpoint) {
+            record_violation(&mut players, *player_id, Violation::OutOfBounds);
+        }
+    }
+}
+
+/// Runs before the next input is applied, so the state is where physics left the last one
+fn send_movement_acks(
+    sr: Res<ServerNetworkingResources>,
+    mut units: Query<(&NetEntId, &Position, &LinearVelocity, &mut MovementInputs)>,
+) {
+    for (net_ent_id, position, velocity, mut inputs) in &mut units {
+        let Some(sequence) = inputs.simulated.take() else {
+            continue;
+        };
+        let event = EventToClient::MovementAck(MovementAck {
+            net_ent_id: *net_ent_id,
+            sequence,
+            state: ControllerState {
+                position: position.0,
+                velocity: velocity.0,
+            },
+        });
+        sr.send_event(inputs.endpoint, &event);
+    }
+}
+
+fn apply_movement_inputs(
+    mut units: Query<(&mut MovementAction, &mut MovementInputs), Without<Dead>>,
+) {
+    for (mut action, mut inputs) in &mut units {
+        // Without a new input the unit keeps doing what it was doing
+        if let Some(input) = inputs.queue.pop() {
+            *action = input.action;
+            inputs.simulated = Some(input.sequence);
+        }
+    }
+}
+
+/// When we last let this camera move
+#[derive(Component)]
+pub struct LastCameraMove {
+    time: f64,
+}
+
+/// Cameras go where the client puts them, as long as they could have got there
+#[allow(clippy::too_many_arguments, clippy::type_complexity)]
+fn on_camera_movement(
+    mut pd: UDPacketEvent<ChangeMovement>,
+    mut commands: Commands,
+    time: Res<Time>,
+    terrain: Res<TerrainParams>,
+    endpoint_to_player_id: Res<EndpointToPlayerId>,
+    sr: Res<ServerNetworkingResources>,
+    mut players: Query<(&PlayerId, &mut MovementViolations)>,
+    mut cameras: Query<
+        (
+            Entity,
+            &NetEntId,
+            &mut Transform,
+            &ControlledBy,
+            Option<&mut LastCameraMove>,
+        ),
+        (
+            With<SendNetworkTranformUpdates>,
+            Without<CharacterController>,
+        ),
+    >,
+    units: Query<(&Transform, &ControlledBy), (With<CharacterController>, Without<Dead>)>,
+) {
+    let now = time.elapsed_secs_f64();
+    for movement in pd.read() {
+        let Some(player_id) = endpoint_to_player_id
+            .map
+            .get(&movement.endpoint)
+            .map(|id| *id)
+        else {
+            warn!(?movement.endpoint, "Camera movement from an endpoint without a player");
+            continue;
+        };
+        let Some((camera, net_ent_id, mut transform, controlled_by, last_move)) = cameras
+            .iter_mut()
+            .find(|(_, net_ent_id, ..)| **net_ent_id == movement.event.net_ent_id)
+        else {
+            warn!(
+                "Received movement update for unknown entity {:?}",
+                movement.event.net_ent_id
+            );
+            continue;
+        };
+        if !controlled_by.players.contains(&player_id) {
+            record_violation(&mut players, player_id, Violation::NotOwner);
+            continue;
+        }
+
+        let target = movement.event.transform;
+        let elapsed = last_move
+            .as_ref()
+            .map_or(f64::INFINITY, |last| now - last.time);
+        let allowed = MAX_CAMERA_SPEED * elapsed as f32 + CAMERA_MOVE_SLACK;
+        let following = units.iter().any(|(unit, controlled_by)| {
+            controlled_by.players.contains(&player_id)
+                && unit.translation.distance(target.translation) <= CAMERA_FOLLOW_DISTANCE
+        });
+
+        let violation = if !target.is_finite() {
+            Some(Violation::InvalidInput)
+        } else if outside_boundary(target.translation, &terrain, CAMERA_FOLLOW_DISTANCE)
+            || target.translation.y.abs() > terrain.plane_size + CAMERA_FOLLOW_DISTANCE
+        {
+            Some(Violation::OutOfBounds)
+        } else if transform.translation.distance(target.translation) > allowed && !following {
+            Some(Violation::TooFast)
+        } else {
+            None
+        };
+
+        if let Some(violation) = violation {
+            record_violation(&mut players, player_id, violation);
+            let event = EventToClient::MovementCorrection(MovementCorrection {
+                net_ent_id: *net_ent_id,
+                transform: *transform,
+            });
+            sr.send_event(movement.endpoint, &event);
+            continue;
+        }
+
+        *transform = target;
+        match last_move {
+            Some(mut last_move) => last_move.time = now,
+            None => {
+                commands.entity(camera).insert(LastCameraMove { time: now });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_sanitize_input() {
+        let mut fine = MovementAction {
+            move_input_dir: Vec2::Y,
+            ..Default::default()
+        };
+        assert_eq!(sanitize_input(&mut fine, 1.0), None);
+
+        let mut fast = MovementAction {
+            move_input_dir: Vec2::new(3.0, 4.0),
+            move_speed_modifier: 5.0,
+            ..Default::default()
+        };
+        assert_eq!(sanitize_input(&mut fast, 1.2), Some(Violation::TooFast));
+        assert_eq!(fast.move_speed_modifier, 1.2);
+        assert!((fast.move_input_dir.length() - 1.0).abs() < 1e-5);
+
+        let mut broken = MovementAction {
+            camera_yaw: f32::NAN,
+            ..Default::default()
+        };
+        assert_eq!(
+            sanitize_input(&mut broken, 1.0),
+            Some(Violation::InvalidInput)
+        );
+        assert!(broken.camera_yaw.is_finite());
+    }
+}
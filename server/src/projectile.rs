@@ -1,3 +1,5 @@
+use std::collections::HashMap;
+
 use avian3d::prelude::CollisionStart;
 use bevy::prelude::*;
 use shared::{
@@ -6,7 +8,12 @@ use shared::{
     projectile::{ProjectileAI, ProjectileRealtime, ProjectileSource},
 };
 
-use crate::{ConnectedPlayer, PlayerEndpoint, ServerState, spawns::UnitDie};
+use crate::{
+    ServerState,
+    interest::Interest,
+    lag_compensation::{CasterRewind, LagCompensated, RewindHistory},
+    spawns::UnitDie,
+};
 
 pub struct ProjectilePlugin;
 
@@ -24,16 +31,23 @@ impl Plugin for ProjectilePlugin {
 
 fn network_projectiles(
     mut messager_reader: MessageReader<SpawnProjectile>,
-    connected_clients: Query<&PlayerEndpoint, With<ConnectedPlayer>>,
+    interest: Res<Interest>,
     mut commands: Commands,
     time: Res<Time>,
     tick: Res<shared::CurrentTick>,
     sr: Res<ServerNetworkingResources>,
+    casters: Query<(&NetEntId, &CasterRewind)>,
 ) {
-    let mut events_collected = vec![];
+    let mut events_collected: HashMap<_, Vec<_>> = HashMap::new();
 
     for event in messager_reader.read() {
-        events_collected.push(crate::EventToClient::SpawnProjectile(event.clone()));
+        // Only clients that can see where it starts get told about it
+        for endpoint in interest.clients_near(event.projectile_origin) {
+            events_collected
+                .entry(endpoint)
+                .or_default()
+                .push(crate::EventToClient::SpawnProjectile(event.clone()));
+        }
         // spawn it in the world as well
         let mut ec = commands.spawn((
             event.base_bundle(&tick.0),
@@ -44,18 +58,26 @@ fn network_projectiles(
 
         if let Some(collider) = event.collider_bundle() {
             ec.insert(collider);
+
+            let rewind = casters
+                .iter()
+                .find(|(id, _)| **id == event.projectile_source.source_entity);
+            if let Some((_, rewind)) = rewind
+                && rewind.ticks > 0
+            {
+                ec.insert(LagCompensated {
+                    rewind_ticks: rewind.ticks,
+                    hit: vec![],
+                });
+            }
         }
 
         // on the server, setup observers for collisions
         ec.observe(on_projectile_collision);
     }
 
-    if events_collected.is_empty() {
-        return;
-    }
-
-    for client in connected_clients {
-        sr.send_outgoing_event_next_tick_batch(client.0, &events_collected);
+    for (endpoint, events) in events_collected {
+        sr.send_event_batch(endpoint, &events);
     }
 }
 
@@ -70,6 +92,8 @@ fn on_projectile_collision(
     coll_event: On<CollisionStart>,
     units: Query<(Entity, &NetEntId)>,
     proj_data: Query<(&ProjectileSource, &ProjectileAI)>,
+    compensated: Query<(), With<LagCompensated>>,
+    rewound: Query<(), With<RewindHistory>>,
     mut proj_hit_writer: MessageWriter<ProjectileCollisionLocalServer>,
 ) {
     let proj_collider = coll_event.collider1;
@@ -89,6 +113,11 @@ fn on_projectile_collision(
         return;
     }
 
+    if compensated.contains(proj_collider) && rewound.contains(unit_collider) {
+        // Hit where the caster saw it instead, see `lag_compensation`
+        return;
+    }
+
     proj_hit_writer.write(ProjectileCollisionLocalServer {
         projectile_entity: proj_collider,
         hit_entity: ent,
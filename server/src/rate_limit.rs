@@ -0,0 +1,79 @@
+//! Sets up the [`RateLimiter`] the generated event dispatch checks, and kicks whoever keeps going
+//! over it.
+use bevy::prelude::*;
+use shared::{
+    Config,
+    event::{PlayerId, ResumeToken, client::PlayerDisconnected},
+    netlib::{ServerNetworkingResources, rate_limit::RateLimiter},
+};
+
+use crate::{ConnectedPlayer, EndpointToPlayerId, Kicked, PlayerEndpoint, ServerState};
+
+pub struct RateLimitPlugin;
+
+impl Plugin for RateLimitPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(OnEnter(ServerState::Starting), insert_rate_limiter)
+            .add_systems(
+                Update,
+                (kick_abusive_endpoints, forget_disconnected_endpoints)
+                    .run_if(in_state(ServerState::Running)),
+            );
+    }
+}
+
+fn insert_rate_limiter(mut commands: Commands, config: Res<Config>) {
+    let limits = config.rate_limits.clone().unwrap_or_default();
+    commands.insert_resource(RateLimiter::new(limits));
+}
+
+fn kick_abusive_endpoints(
+    mut limiter: ResMut<RateLimiter>,
+    endpoint_to_player: Res<EndpointToPlayerId>,
+    sr: Res<ServerNetworkingResources>,
+    players: Query<(Entity, &PlayerId), With<ConnectedPlayer>>,
+    mut on_disconnect: MessageWriter<PlayerDisconnected>,
+    mut commands: Commands,
+) {
+    for endpoint in limiter.take_abusive() {
+        match endpoint_to_player.map.get(&endpoint) {
+            Some(player_id) => {
+                warn!(?endpoint, ?player_id, "Kicking player for flooding us");
+                // So they can't resume, `on_player_disconnect` forgets their endpoint once it has
+                // told them
+                if let Some((player, _)) = players.iter().find(|(_, id)| *id == &*player_id) {
+                    commands
+                        .entity(player)
+                        .remove::<ResumeToken>()
+                        .insert(Kicked);
+                }
+                limiter.forget(endpoint);
+                on_disconnect.write(PlayerDisconnected {
+                    id: *player_id,
+                    reason: "Kicked for sending too many events".to_string(),
+                });
+            }
+            None => {
+                warn!(
+                    ?endpoint,
+                    "Forgetting endpoint that flooded us before joining"
+                );
+                sr.forget_endpoint(endpoint);
+                limiter.forget(endpoint);
+            }
+        }
+    }
+}
+
+/// Whoever comes back starts with a fresh budget
+fn forget_disconnected_endpoints(
+    mut pd: MessageReader<PlayerDisconnected>,
+    players: Query<(&PlayerId, &PlayerEndpoint)>,
+    mut limiter: ResMut<RateLimiter>,
+) {
+    for player in pd.read() {
+        for (_, endpoint) in players.iter().filter(|(id, _)| **id == player.id) {
+            limiter.forget(endpoint.0);
+        }
+    }
+}
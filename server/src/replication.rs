@@ -0,0 +1,173 @@
+use std::collections::HashMap;
+
+use avian3d::prelude::{LinearVelocity, Rotation};
+use bevy::prelude::*;
+use shared::{
+    CurrentTick,
+    event::{NetEntId, UDPacketEvent, server::SnapshotAck},
+    net_components::ents::{ItemDrop, Man, PlayerCamera, SendNetworkTranformUpdates},
+    netlib::{EventToClient, ServerNetworkingResources, bandwidth::PriorityAccumulator},
+    snapshot::{EntityState, SnapshotHistory, WorldSnapshot},
+};
+
+use crate::{
+    ConnectedPlayer, PlayerEndpoint, ServerState,
+    interest::{Interest, update_interest},
+};
+
+pub struct ReplicationPlugin;
+
+impl Plugin for ReplicationPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(
+            Update,
+            on_snapshot_ack.run_if(in_state(ServerState::Running)),
+        )
+        .add_systems(
+            FixedUpdate,
+            send_snapshots
+                .after(update_interest)
+                .run_if(in_state(ServerState::Running)),
+        );
+    }
+}
+
+/// Weight of a player's `Man` when deciding what to send under a tight budget, a plain unit is 1
+const MAN_PRIORITY: f32 = 4.0;
+const CAMERA_PRIORITY: f32 = 2.0;
+const ITEM_DROP_PRIORITY: f32 = 0.25;
+/// Priority halves at this distance from the nearest of the client's cameras and units
+const PRIORITY_FALLOFF_DISTANCE: f32 = 10.0;
+
+/// The snapshots we sent to this player, which of them they acked, and how long each entity's
+/// update has been waiting for room in their budget
+#[derive(Component, Default)]
+pub struct ClientSnapshots {
+    pub history: SnapshotHistory,
+    pub priorities: PriorityAccumulator<NetEntId>,
+}
+
+#[allow(clippy::type_complexity)]
+fn send_snapshots(
+    tick: Res<CurrentTick>,
+    sr: Res<ServerNetworkingResources>,
+    interest: Res<Interest>,
+    mut clients: Query<(&PlayerEndpoint, &mut ClientSnapshots), With<ConnectedPlayer>>,
+    units: Query<
+        (
+            &NetEntId,
+            &Transform,
+            Option<&LinearVelocity>,
+            Option<&Rotation>,
+            Has<Man>,
+            Has<PlayerCamera>,
+            Has<ItemDrop>,
+        ),
+        With<SendNetworkTranformUpdates>,
+    >,
+) {
+    let mut entities = HashMap::new();
+    let mut type_weights = HashMap::new();
+    for (net_ent_id, transform, velocity, rotation, is_man, is_camera, is_item) in &units {
+        entities.insert(
+            *net_ent_id,
+            EntityState {
+                transform: *transform,
+                velocity: velocity.copied(),
+                rotation: rotation.copied(),
+            },
+        );
+        let weight = if is_man {
+            MAN_PRIORITY
+        } else if is_camera {
+            CAMERA_PRIORITY
+        } else if is_item {
+            ITEM_DROP_PRIORITY
+        } else {
+            1.0
+        };
+        type_weights.insert(*net_ent_id, weight);
+    }
+
+    for (endpoint, mut snapshots) in &mut clients {
+        let Some(client) = interest.clients.get(&endpoint.0) else {
+            continue;
+        };
+        let snapshots = &mut *snapshots;
+
+        // Units leaving interest show up as removed, and are sent whole when they come back
+        let mut world_snapshot = WorldSnapshot {
+            tick: tick.0,
+            entities: entities
+                .iter()
+                .filter(|(net_ent_id, _)| client.known.contains(net_ent_id))
+                .map(|(net_ent_id, state)| (*net_ent_id, *state))
+                .collect(),
+        };
+
+        let baseline = snapshots.history.acked_baseline();
+        let all_changes = world_snapshot.encode_delta(baseline);
+
+        let candidates = all_changes
+            .changed
+            .iter()
+            .map(|update| {
+                let position = entities[&update.net_ent_id].transform.translation;
+                let distance = client
+                    .centres
+                    .iter()
+                    .map(|centre| centre.distance(position))
+                    .min_by(f32::total_cmp)
+                    .unwrap_or_default();
+                let weight =
+                    type_weights[&update.net_ent_id] / (1.0 + distance / PRIORITY_FALLOFF_DISTANCE);
+                let cost = postcard::experimental::serialized_size(update).unwrap_or_default();
+                (update.net_ent_id, weight, cost)
+            })
+            .collect::<Vec<_>>();
+        let picked = snapshots
+            .priorities
+            .select(&candidates, sr.remaining_budget(endpoint.0));
+
+        // What we didn't pick keeps its baseline state in the snapshot we remember sending, so it
+        // is still a change next tick
+        for update in &all_changes.changed {
+            if picked.contains(&update.net_ent_id) {
+                continue;
+            }
+            match baseline.and_then(|b| b.entities.get(&update.net_ent_id)) {
+                Some(state) => world_snapshot.entities.insert(update.net_ent_id, *state),
+                None => world_snapshot.entities.remove(&update.net_ent_id),
+            };
+        }
+        sr.networking_stats.updates_deferred_this_second.fetch_add(
+            all_changes.changed.len() - picked.len(),
+            std::sync::atomic::Ordering::Relaxed,
+        );
+
+        let snapshot = world_snapshot.encode_delta(baseline);
+        snapshots.history.push(world_snapshot);
+
+        trace!(
+            changed = snapshot.changed.len(),
+            deferred = all_changes.changed.len() - picked.len(),
+            baseline = ?snapshot.baseline,
+            "Sending snapshot"
+        );
+        sr.send_event(endpoint.0, &EventToClient::Snapshot(snapshot));
+    }
+}
+
+fn on_snapshot_ack(
+    mut acks: UDPacketEvent<SnapshotAck>,
+    mut clients: Query<(&PlayerEndpoint, &mut ClientSnapshots)>,
+) {
+    for ack in acks.read() {
+        for (endpoint, mut snapshots) in &mut clients {
+            if endpoint.0 == ack.endpoint {
+                snapshots.history.ack(ack.event.tick);
+                break;
+            }
+        }
+    }
+}
@@ -0,0 +1,130 @@
+//! Answering the [`Rpc`]s clients call, see [`shared::rpc`].
+//!
+//! Handlers are systems registered with [`AddRpcHandler::add_rpc_handler`]. They take the decoded
+//! request along with the caller's `PlayerId`, and return the response or why they refused.
+use std::collections::HashMap;
+
+use bevy::{ecs::message::MessageCursor, prelude::*};
+use shared::{
+    CurrentTick,
+    event::{EventFromEndpoint, PlayerId, client::RpcResponse, server::RpcRequest},
+    netlib::{EventToClient, ServerNetworkingResources, rate_limit::RateLimiter},
+    rpc::{self, Rpc, RpcError},
+};
+
+use crate::{EndpointToPlayerId, ServerState};
+
+pub struct RpcPlugin;
+
+impl Plugin for RpcPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<RpcHandlers>().add_systems(
+            Update,
+            answer_rpc_requests
+                .after(crate::capture::drain_incoming_events)
+                .run_if(in_state(ServerState::Running)),
+        );
+    }
+}
+
+/// What a handler is run with
+#[derive(Debug)]
+pub struct RpcCall<R> {
+    pub caller: PlayerId,
+    pub request: R,
+}
+
+type Handler = Box<dyn Fn(&mut World, PlayerId, &[u8]) -> Result<Vec<u8>, RpcError> + Send + Sync>;
+
+/// Handlers by method name
+#[derive(Resource, Default)]
+pub struct RpcHandlers {
+    handlers: HashMap<&'static str, Handler>,
+}
+
+pub trait AddRpcHandler {
+    /// Answer calls to `R` with `handler`. There can only be one handler per method.
+    fn add_rpc_handler<R: Rpc, M>(
+        &mut self,
+        handler: impl IntoSystem<In<RpcCall<R>>, Result<R::Response, RpcError>, M> + 'static,
+    ) -> &mut Self;
+}
+
+impl AddRpcHandler for App {
+    fn add_rpc_handler<R: Rpc, M>(
+        &mut self,
+        handler: impl IntoSystem<In<RpcCall<R>>, Result<R::Response, RpcError>, M> + 'static,
+    ) -> &mut Self {
+        let world = self.world_mut();
+        let system = world.register_system(handler);
+        let handler: Handler = Box::new(move |world, caller, payload| {
+            let request = rpc::decode::<R>(payload)?;
+            match world.run_system_with(system, RpcCall { caller, request }) {
+                Ok(response) => rpc::encode(&response?),
+                Err(e) => {
+                    error!(method = R::METHOD, ?e, "Couldn't run RPC handler");
+                    Err(RpcError::Rejected("Handler failed".to_string()))
+                }
+            }
+        });
+
+        let previous = world
+            .get_resource_or_init::<RpcHandlers>()
+            .handlers
+            .insert(R::METHOD, handler);
+        assert!(previous.is_none(), "Two handlers for {}", R::METHOD);
+        self
+    }
+}
+
+fn answer_rpc_requests(
+    world: &mut World,
+    mut cursor: Local<MessageCursor<EventFromEndpoint<RpcRequest>>>,
+) {
+    let requests: Vec<_> = cursor
+        .read(world.resource::<Messages<EventFromEndpoint<RpcRequest>>>())
+        .cloned()
+        .collect();
+    if requests.is_empty() {
+        return;
+    }
+
+    let now = world.resource::<CurrentTick>().0;
+    let sr = world.resource::<ServerNetworkingResources>().clone();
+    world.resource_scope(|world, handlers: Mut<RpcHandlers>| {
+        for EventFromEndpoint { event, endpoint } in requests {
+            let caller = world
+                .resource::<EndpointToPlayerId>()
+                .map
+                .get(&endpoint)
+                .map(|p| *p);
+
+            let result = match (
+                caller,
+                handlers.handlers.get_key_value(event.method.as_str()),
+            ) {
+                (None, _) => Err(RpcError::NotJoined),
+                (Some(_), None) => Err(RpcError::UnknownMethod),
+                (Some(caller), Some((method, handler))) => {
+                    let allowed = world
+                        .get_resource_mut::<RateLimiter>()
+                        .is_none_or(|mut limiter| limiter.allow(endpoint, method, now));
+                    if allowed {
+                        handler(world, caller, &event.payload)
+                    } else {
+                        Err(RpcError::RateLimited)
+                    }
+                }
+            };
+            if let Err(e) = &result {
+                debug!(?endpoint, method = %event.method, %e, "Refused RPC");
+            }
+
+            let response = EventToClient::RpcResponse(RpcResponse {
+                id: event.id,
+                result,
+            });
+            sr.send_event(endpoint, &response);
+        }
+    });
+}
@@ -1,10 +1,11 @@
 use avian3d::prelude::RigidBody;
 use bevy::prelude::*;
+use rand::RngExt;
 use shared::{
     CurrentTick,
     character_controller::{CharacterController, NPCController},
     event::{
-        NetEntId, PlayerId, UDPacketEvent,
+        NetEntId, UDPacketEvent,
         client::{BeginThirdpersonControllingUnit, SpawnUnit2},
         server::{SpawnCircle, SpawnMan},
     },
@@ -13,9 +14,15 @@ use shared::{
         ours::{ControlledBy, Dead, DespawnOnPlayerDisconnect, HasInventory},
     },
     netlib::{EventToClient, ServerNetworkingResources},
+    rng::GameRng,
 };
 
-use crate::{ConnectedPlayer, EndpointToPlayerId, PlayerEndpoint, ServerState, make_ball};
+use crate::{
+    EndpointToPlayerId, ServerState,
+    interest::Interest,
+    make_ball,
+    movement::{MovementStats, UnitInventory},
+};
 
 pub struct SpawnPlugin;
 impl Plugin for SpawnPlugin {
@@ -23,6 +30,8 @@ impl Plugin for SpawnPlugin {
         app.add_systems(
             Update,
             (on_circle_spawn, on_man_spawn, on_unit_die)
+                .chain()
+                .after(crate::animations::on_unit_finish_cast)
                 //.run_if(on_timer(Duration::from_millis(10)))
                 .run_if(in_state(ServerState::Running)),
         );
@@ -37,19 +46,13 @@ impl Plugin for SpawnPlugin {
     }
 }
 
-#[derive(Default)]
-struct CircleSpawnCooldown {
-    player_to_last_spawn: std::collections::HashMap<PlayerId, f64>,
-}
-
 fn on_circle_spawn(
     mut spawns: UDPacketEvent<SpawnCircle>,
     mut commands: Commands,
     endpoint_to_player_id: Res<EndpointToPlayerId>,
     sr: Res<ServerNetworkingResources>,
-    clients: Query<&PlayerEndpoint, With<ConnectedPlayer>>,
-    time: Res<Time>,
-    mut circle_spawn_cooldown: Local<CircleSpawnCooldown>,
+    interest: Res<Interest>,
+    mut rng: ResMut<GameRng>,
 ) {
     for spawn_ev in spawns.read() {
         info!(?spawn_ev.event, "Spawning circle from event");
@@ -63,33 +66,15 @@ fn on_circle_spawn(
             continue;
         };
 
-        let now = time.elapsed_secs_f64();
-        if let Some(last_spawn_time) = circle_spawn_cooldown
-            .player_to_last_spawn
-            .get(&player_id_of_spawner)
-        {
-            let time_since_last_spawn = now - *last_spawn_time;
-            if time_since_last_spawn < 1.0 {
-                info!(
-                    "Player {:?} tried to spawn circle too quickly ({}s since last spawn)",
-                    player_id_of_spawner, time_since_last_spawn
-                );
-                continue;
-            }
-        }
-        circle_spawn_cooldown
-            .player_to_last_spawn
-            .insert(*player_id_of_spawner, now);
-
         debug!("Spawning circle at position: {:?}", spawn.position);
         let transform = Transform::from_translation(spawn.position);
 
         let mut unit;
 
-        if rand::random_bool(0.5) {
+        if rng.random_bool(0.5) {
             info!("Spawning a surprise goblin instead of a ball!");
-            unit = make_small_loot(transform);
-            let inventory = shared::items::goblin_drops();
+            unit = make_small_loot(&mut rng, transform);
+            let inventory = shared::items::goblin_drops(&mut rng);
             unit.components.push(
                 HasInventory {
                     inventory_id: inventory.id,
@@ -97,12 +82,13 @@ fn on_circle_spawn(
                 .to_net_component(),
             );
 
-            sr.send_outgoing_event_next_tick(
+            sr.send_event(
                 spawn_ev.endpoint,
                 &EventToClient::NewInventory(shared::event::client::NewInventory { inventory }),
             );
         } else {
             unit = make_ball(
+                &mut rng,
                 transform,
                 spawn.color,
                 ControlledBy::single(*player_id_of_spawner),
@@ -113,16 +99,13 @@ fn on_circle_spawn(
             player_id: *player_id_of_spawner,
         });
 
-        // Notify all clients about the new unit
-        let event = EventToClient::SpawnUnit2(unit);
-        info!("Notifying clients of new unit: {:?}", event);
-        for endpoint in &clients {
-            info!("Sending spawn event to endpoint: {:?}", endpoint.0);
-            sr.send_outgoing_event_next_tick(endpoint.0, &event);
-        }
+        // Notify the clients that can see it about the new unit
+        info!("Notifying clients of new unit: {:?}", unit);
+        interest.send_spawn(&sr, &unit);
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 fn on_man_spawn(
     mut spawns: UDPacketEvent<SpawnMan>,
     mut commands: Commands,
@@ -133,7 +116,8 @@ fn on_man_spawn(
     >,
     mut unit_kill: MessageWriter<UnitDie>,
     sr: Res<ServerNetworkingResources>,
-    clients: Query<&PlayerEndpoint, With<ConnectedPlayer>>,
+    interest: Res<Interest>,
+    mut rng: ResMut<GameRng>,
 ) {
     for spawn_ev in spawns.read() {
         info!(?spawn_ev.event, "Spawning man from event");
@@ -151,10 +135,12 @@ fn on_man_spawn(
         let transform = Transform::from_translation(spawn.position);
 
         // for now
-        let inventory = shared::items::goblin_drops();
-        //TODO add to server inventory
+        let inventory = shared::items::goblin_drops(&mut rng);
+        let stats = inventory.get_player_stats();
+        let movement_stats = MovementStats::from(&stats);
 
         let mut unit = make_man(
+            &mut rng,
             transform,
             ControlledBy::single(*player_id_of_spawner),
             &spawn.controller_type,
@@ -167,19 +153,21 @@ fn on_man_spawn(
         );
 
         let unit_ent = unit.clone().spawn_entity(&mut commands);
-        commands.entity(unit_ent).insert(DespawnOnPlayerDisconnect {
-            player_id: *player_id_of_spawner,
-        });
+        commands.entity(unit_ent).insert((
+            DespawnOnPlayerDisconnect {
+                player_id: *player_id_of_spawner,
+            },
+            UnitInventory(inventory.clone()),
+            stats,
+            movement_stats,
+        ));
 
-        let event = EventToClient::SpawnUnit2(unit.clone());
-        info!("Notifying clients of new unit: {:?}", event);
-        for endpoint in &clients {
-            info!("Sending spawn event to endpoint: {:?}", endpoint.0);
-            sr.send_outgoing_event_next_tick(endpoint.0, &event);
-        }
+        // The spawner owns it, so they always get it before the control event below
+        info!("Notifying clients of new unit: {:?}", unit);
+        interest.send_spawn(&sr, &unit);
 
         // Now, we send the user control event to this client
-        sr.send_outgoing_event_next_tick(
+        sr.send_event(
             spawn_ev.endpoint,
             &EventToClient::BeginThirdpersonControllingUnit(BeginThirdpersonControllingUnit {
                 player_id: *player_id_of_spawner,
@@ -187,7 +175,7 @@ fn on_man_spawn(
             }),
         );
 
-        sr.send_outgoing_event_next_tick(
+        sr.send_event(
             spawn_ev.endpoint,
             &EventToClient::NewInventory(shared::event::client::NewInventory { inventory }),
         );
@@ -210,13 +198,14 @@ pub struct UnitDie {
     pub unit_id: NetEntId,
 }
 
-fn on_unit_die(
+pub(crate) fn on_unit_die(
     mut unit_deaths: MessageReader<UnitDie>,
     mut commands: Commands,
     units: Query<(&NetEntId, Option<&HasInventory>, &Transform, Entity), Without<Dead>>,
     sr: Res<ServerNetworkingResources>,
     tick: Res<CurrentTick>,
-    clients: Query<&PlayerEndpoint, With<ConnectedPlayer>>,
+    interest: Res<Interest>,
+    mut rng: ResMut<GameRng>,
 ) {
     for death in unit_deaths.read() {
         info!("Unit died: {:?}", death.unit_id);
@@ -229,19 +218,16 @@ fn on_unit_die(
             if *net_id == death.unit_id {
                 //TODO dedup this with client
 
-                let mut angular_velocity = avian3d::prelude::AngularVelocity::default();
-                angular_velocity.0 = Vec3::new(
-                    rand::random_range(-5.0..5.0),
-                    rand::random_range(-5.0..5.0),
-                    rand::random_range(-5.0..5.0),
-                );
-
-                let mut linear_velocity = avian3d::prelude::LinearVelocity::default();
-                linear_velocity.0 = Vec3::new(
-                    rand::random_range(-2.0..2.0),
-                    rand::random_range(2.0..5.0),
-                    rand::random_range(-2.0..2.0),
-                );
+                let angular_velocity = avian3d::prelude::AngularVelocity(Vec3::new(
+                    rng.random_range(-5.0..5.0),
+                    rng.random_range(-5.0..5.0),
+                    rng.random_range(-5.0..5.0),
+                ));
+                let linear_velocity = avian3d::prelude::LinearVelocity(Vec3::new(
+                    rng.random_range(-2.0..2.0),
+                    rng.random_range(2.0..5.0),
+                    rng.random_range(-2.0..2.0),
+                ));
 
                 commands
                     .entity(ent)
@@ -252,7 +238,7 @@ fn on_unit_die(
                     .remove::<NPCController>()
                     .remove::<CharacterController>();
 
-                // Notify all clients about the unit death
+                // Notify the clients that can see the unit about its death
                 let event = EventToClient::UpdateUnit2(shared::event::client::UpdateUnit2 {
                     net_ent_id: death.unit_id,
                     changed_components: vec![
@@ -270,14 +256,12 @@ fn on_unit_die(
                     ],
                 });
 
-                for endpoint in &clients {
-                    sr.send_outgoing_event_next_tick(endpoint.0, &event);
-                }
+                interest.send_to_known(&sr, &death.unit_id, &event, None);
 
                 if let Some(inv) = has_inv {
                     let position = loc.translation;
                     let loot = SpawnUnit2 {
-                        net_ent_id: NetEntId::random(),
+                        net_ent_id: NetEntId::random(&mut rng),
                         components: vec![
                             shared::net_components::ents::ItemDrop { source: None }
                                 .to_net_component(),
@@ -288,10 +272,7 @@ fn on_unit_die(
                             .to_net_component(),
                         ],
                     };
-                    let event = EventToClient::SpawnUnit2(loot);
-                    for endpoint in &clients {
-                        sr.send_outgoing_event_next_tick(endpoint.0, &event);
-                    }
+                    interest.send_spawn(&sr, &loot);
                 }
             }
         }
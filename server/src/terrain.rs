@@ -1,19 +1,33 @@
 use avian3d::prelude::*;
 use bevy::prelude::*;
-use shared::physics::{
-    terrain::{Terrain, TerrainParams, generate_terrain_trimesh, spawn_boundary_walls},
-    water::spawn_water_shared,
+use rand::RngExt;
+use shared::{
+    physics::{
+        terrain::{Terrain, TerrainParams, generate_terrain_trimesh, spawn_boundary_walls},
+        water::spawn_water_shared,
+    },
+    rng::GameRng,
 };
 
 pub struct TerrainPlugin;
 
 impl Plugin for TerrainPlugin {
     fn build(&self, app: &mut App) {
-        app.insert_resource(TerrainParams::default())
-            .add_systems(Startup, setup_terrain_server);
+        app.add_systems(
+            Startup,
+            (insert_terrain_params, setup_terrain_server).chain(),
+        );
     }
 }
 
+/// Seeded from [`GameRng`], so a replayed capture gets the same terrain
+fn insert_terrain_params(mut commands: Commands, mut rng: ResMut<GameRng>) {
+    commands.insert_resource(TerrainParams {
+        seed: rng.random(),
+        ..default()
+    });
+}
+
 /// Setup terrain mesh with physics collider
 pub fn setup_terrain_server(mut commands: Commands, terrain_params: Res<TerrainParams>) {
     // Calculate water level: 30% between min and max terrain height
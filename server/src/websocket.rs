@@ -1,15 +1,36 @@
 use bevy::prelude::*;
-use std::{collections::HashMap, net::SocketAddr};
-use tokio_tungstenite::tungstenite::Bytes;
-//use dashmap::DashMap;
+use dashmap::DashMap;
 use futures_channel::mpsc::{UnboundedSender, unbounded};
 use shared::{
-    netlib::{EventGroupingRef, ServerNetworkingResources, WebSocketEndpoint, on_data_incoming},
+    Config, WebsocketTls,
+    netlib::{
+        EndpointGeneral, ServerNetworkingResources, WebSocketEndpoint, on_data_incoming,
+        transport::{MemoryNetwork, Transport, TransportKind},
+    },
     tokio_udp::TokioRuntimeResource,
 };
 use std::sync::Arc;
-use tokio::net::{TcpListener, TcpStream};
-use tokio_tungstenite::tungstenite::protocol::Message;
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, TcpStream},
+    time::timeout,
+};
+use tokio_rustls::{
+    TlsAcceptor,
+    rustls::{
+        ServerConfig,
+        crypto::ring,
+        pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject},
+    },
+};
+use tokio_tungstenite::tungstenite::Bytes;
+use tokio_tungstenite::tungstenite::protocol::{Message, WebSocketConfig};
 
 use futures_util::{StreamExt, TryStreamExt};
 
@@ -19,11 +40,10 @@ pub struct WebsocketPlugin;
 
 impl Plugin for WebsocketPlugin {
     fn build(&self, app: &mut App) {
-        app.add_systems(OnEnter(ServerState::Running), setup_shared_websocket_server);
-
+        // Singleplayer talks to its client in memory, and shouldn't open any ports
         app.add_systems(
-            FixedPostUpdate,
-            flush_outgoing_events_websocket.run_if(in_state(ServerState::Running)),
+            OnEnter(ServerState::Running),
+            setup_shared_websocket_server.run_if(not(resource_exists::<MemoryNetwork>)),
         );
 
         app.insert_resource(WebsocketResource::default());
@@ -36,63 +56,194 @@ pub struct SingleConnectionPeer {
 
 use std::sync::RwLock;
 
+pub const DEFAULT_MAX_CONNECTIONS_PER_IP: usize = 8;
+pub const DEFAULT_MAX_FRAME_BYTES: usize = 64 * 1024;
+/// How long the TLS and websocket handshakes each get before we give up on a connection, so a
+/// client that never finishes them can't hold on to one of its IP's slots
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long to wait after failing to accept a connection, like when we are out of file
+/// descriptors, before trying again
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_millis(100);
+
 #[derive(Resource, Clone, Default)]
-struct WebsocketResource {
+pub struct WebsocketResource {
     // must use hashmap because SocketAddr does not implement Hash + Eq
     //socket_addr_to_tx_queue: Arc<DashMap<SocketAddr, Arc<SingleConnectionPeer>>>,
     socket_addr_to_tx_queue: Arc<RwLock<HashMap<SocketAddr, Arc<SingleConnectionPeer>>>>,
+    /// Open connections, including ones still in their handshake
+    connections_per_ip: Arc<DashMap<IpAddr, usize>>,
+    handshake_failures: Arc<AtomicU64>,
+    rejected_connections: Arc<AtomicU64>,
 }
 
-fn flush_outgoing_events_websocket(
-    resources: Res<ServerNetworkingResources>,
-    ws_resource: Res<WebsocketResource>,
-) {
-    resources
-        .event_list_outgoing_websocket
-        .retain(|key, value| {
-            let maybe_tx_queue = ws_resource
-                .socket_addr_to_tx_queue
-                .read()
-                .unwrap()
-                .get(&key.socket_addr)
-                .map(|spc| spc.tx.clone());
-
-            let Some(mut peer_queue) = maybe_tx_queue else {
-                // this player has dropped the websocket connection but we still have them as a
-                // "Connected Player". They can re-establish a new websocket connection.
-                debug!(
-                    "No websocket tx queue found for socket addr: {}",
-                    key.socket_addr
-                );
-                return false;
-            };
+impl WebsocketResource {
+    /// Connections that failed or timed out in their TLS or websocket handshake
+    pub fn handshake_failures(&self) -> u64 {
+        self.handshake_failures.load(Ordering::Relaxed)
+    }
 
-            let new_msg = EventGroupingRef::Batch(value);
-            let bytes = match postcard::to_stdvec(&new_msg) {
-                Ok(b) => b,
-                Err(e) => {
-                    warn!(?e, "Failed to serialize outgoing websocket message");
-                    return false;
-                }
-            };
-            let send_result = peer_queue.start_send(Message::Binary(Bytes::from(bytes)));
-            if let Err(e) = send_result {
-                warn!(?e, "Failed to send outgoing websocket message");
-            }
-            false
+    /// Connections refused because their IP had too many open already
+    pub fn rejected_connections(&self) -> u64 {
+        self.rejected_connections.load(Ordering::Relaxed)
+    }
+}
+
+/// One of an IP's connections, given back when dropped
+struct ConnectionSlot {
+    connections_per_ip: Arc<DashMap<IpAddr, usize>>,
+    ip: IpAddr,
+}
+
+impl ConnectionSlot {
+    /// `None` if `ip` already has `limit` connections open
+    fn acquire(
+        connections_per_ip: &Arc<DashMap<IpAddr, usize>>,
+        ip: IpAddr,
+        limit: usize,
+    ) -> Option<Self> {
+        let mut count = connections_per_ip.entry(ip).or_insert(0);
+        if *count >= limit {
+            return None;
+        }
+        *count += 1;
+
+        Some(Self {
+            connections_per_ip: connections_per_ip.clone(),
+            ip,
         })
+    }
 }
 
-async fn handle_websocket_connection(
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.connections_per_ip.remove_if_mut(&self.ip, |_, count| {
+            *count -= 1;
+            *count == 0
+        });
+    }
+}
+
+/// What every connection needs from the config, copied into the listener task
+#[derive(Clone)]
+struct ListenerSettings {
+    tls: Option<TlsAcceptor>,
+    max_connections_per_ip: usize,
+    websocket_config: WebSocketConfig,
+}
+
+fn load_tls(tls: &WebsocketTls) -> Result<TlsAcceptor, Box<dyn std::error::Error>> {
+    let certs = CertificateDer::pem_file_iter(&tls.cert_path)?.collect::<Result<Vec<_>, _>>()?;
+    let key = PrivateKeyDer::from_pem_file(&tls.key_path)?;
+    let config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+impl Transport for WebsocketResource {
+    fn send(&self, endpoint: EndpointGeneral, data: &[u8]) -> bool {
+        let EndpointGeneral::WebSocket(endpoint) = endpoint else {
+            return false;
+        };
+        let maybe_tx_queue = self
+            .socket_addr_to_tx_queue
+            .read()
+            .unwrap()
+            .get(&endpoint.socket_addr)
+            .map(|spc| spc.tx.clone());
+
+        let Some(mut peer_queue) = maybe_tx_queue else {
+            debug!(
+                "No websocket tx queue found for socket addr: {}",
+                endpoint.socket_addr
+            );
+            return false;
+        };
+
+        let send_result = peer_queue.start_send(Message::Binary(Bytes::copy_from_slice(data)));
+        if let Err(e) = send_result {
+            warn!(?e, "Failed to send outgoing websocket message");
+            return false;
+        }
+        true
+    }
+}
+
+/// Finish the TLS and websocket handshakes on a new connection, then serve it until it closes
+async fn accept_connection(
     net_res: ServerNetworkingResources,
     ws_resource: WebsocketResource,
+    settings: ListenerSettings,
     raw_stream: TcpStream,
     addr: SocketAddr,
+) {
+    let Some(_slot) = ConnectionSlot::acquire(
+        &ws_resource.connections_per_ip,
+        addr.ip(),
+        settings.max_connections_per_ip,
+    ) else {
+        let rejected = ws_resource
+            .rejected_connections
+            .fetch_add(1, Ordering::Relaxed)
+            + 1;
+        warn!(
+            ?addr,
+            rejected, "Too many websocket connections from one IP, rejecting"
+        );
+        return;
+    };
+
+    match settings.tls {
+        Some(acceptor) => match timeout(HANDSHAKE_TIMEOUT, acceptor.accept(raw_stream)).await {
+            Ok(Ok(stream)) => {
+                handle_websocket_connection(
+                    net_res,
+                    ws_resource,
+                    settings.websocket_config,
+                    stream,
+                    addr,
+                )
+                .await
+            }
+            Ok(Err(e)) => handshake_failed(&ws_resource, addr, &e),
+            Err(e) => handshake_failed(&ws_resource, addr, &e),
+        },
+        None => {
+            handle_websocket_connection(
+                net_res,
+                ws_resource,
+                settings.websocket_config,
+                raw_stream,
+                addr,
+            )
+            .await
+        }
+    }
+}
+
+fn handshake_failed(ws_resource: &WebsocketResource, addr: SocketAddr, e: &dyn std::fmt::Debug) {
+    let failures = ws_resource
+        .handshake_failures
+        .fetch_add(1, Ordering::Relaxed)
+        + 1;
+    warn!(?addr, ?e, failures, "Websocket handshake failed");
+}
+
+async fn handle_websocket_connection<S: AsyncRead + AsyncWrite + Unpin>(
+    net_res: ServerNetworkingResources,
+    ws_resource: WebsocketResource,
+    websocket_config: WebSocketConfig,
+    raw_stream: S,
+    addr: SocketAddr,
 ) {
     info!("New WebSocket connection: {}", addr);
-    let ws_stream = tokio_tungstenite::accept_async(raw_stream)
-        .await
-        .expect("Error during the websocket handshake occurred");
+    let handshake = tokio_tungstenite::accept_async_with_config(raw_stream, Some(websocket_config));
+    let ws_stream = match timeout(HANDSHAKE_TIMEOUT, handshake).await {
+        Ok(Ok(ws_stream)) => ws_stream,
+        Ok(Err(e)) => return handshake_failed(&ws_resource, addr, &e),
+        Err(e) => return handshake_failed(&ws_resource, addr, &e),
+    };
 
     let (tx, rx) = unbounded();
 
@@ -118,14 +269,13 @@ async fn handle_websocket_connection(
             }
         };
 
-        let data_buffer = net_res.event_list_incoming_websocket.clone();
-        on_data_incoming(&net_res, endpoint, data_buffer, &msg);
+        on_data_incoming(&net_res, EndpointGeneral::WebSocket(endpoint), &msg);
 
         futures_util::future::ok(())
     });
 
     // Putting events into the WebsocketResource's units tx queue will send them out on the network
-    // instantly- see `flush_outgoing_events_websocket` for events being places in this queue
+    // instantly- see `Transport for WebsocketResource` for events being places in this queue
     let receive_from_others = rx.map(Ok).forward(outgoing);
 
     futures_util::pin_mut!(broadcast_incoming, receive_from_others);
@@ -136,33 +286,100 @@ async fn handle_websocket_connection(
         let mut map = ws_resource.socket_addr_to_tx_queue.write().unwrap();
         map.remove(&addr);
     }
+    net_res.forget_endpoint(EndpointGeneral::WebSocket(endpoint));
 }
 
 fn setup_shared_websocket_server(
     res: Res<ServerNetworkingResources>,
     tokio_runtime: Res<TokioRuntimeResource>,
     ws_resource: Res<WebsocketResource>,
+    config: Res<Config>,
 ) {
     let (ip, port) = res.con_str.as_ref().clone();
-    info!("Starting shared websocket server on {}:{}", ip, port);
+
+    let tls = match &config.websocket_tls {
+        Some(tls) => match load_tls(tls) {
+            Ok(acceptor) => Some(acceptor),
+            Err(e) => {
+                // Better to have no websockets than to quietly serve them unencrypted
+                error!(
+                    ?e,
+                    ?tls,
+                    "Failed to load websocket TLS certificate, not serving websockets"
+                );
+                return;
+            }
+        },
+        None => None,
+    };
+    let max_frame_bytes = config
+        .max_websocket_frame_bytes
+        .unwrap_or(DEFAULT_MAX_FRAME_BYTES);
+    let settings = ListenerSettings {
+        tls,
+        max_connections_per_ip: config
+            .max_websocket_connections_per_ip
+            .unwrap_or(DEFAULT_MAX_CONNECTIONS_PER_IP),
+        websocket_config: WebSocketConfig::default()
+            .max_frame_size(Some(max_frame_bytes))
+            .max_message_size(Some(max_frame_bytes)),
+    };
+    let scheme = if settings.tls.is_some() { "wss" } else { "ws" };
+    info!(
+        "Starting shared websocket server on {scheme}://{}:{}",
+        ip, port
+    );
 
     let ws_resource = (*ws_resource).clone();
     let net_res = res.clone();
+    net_res
+        .transports
+        .insert(TransportKind::WebSocket, Arc::new(ws_resource.clone()));
 
     tokio_runtime.spawn(async move {
         let try_socket = TcpListener::bind((ip, port)).await;
         let listener = try_socket.expect("Failed to bind");
 
-        while let Ok((stream, addr)) = listener.accept().await {
+        loop {
+            let (stream, addr) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    // Only this connection is lost, the listener itself is fine
+                    warn!(?e, "Failed to accept websocket connection");
+                    tokio::time::sleep(ACCEPT_ERROR_BACKOFF).await;
+                    continue;
+                }
+            };
             let ws_resource = ws_resource.clone();
-            tokio::spawn(handle_websocket_connection(
+            tokio::spawn(accept_connection(
                 net_res.clone(),
                 ws_resource,
+                settings.clone(),
                 stream,
                 addr,
             ));
         }
-
-        error!("WebSocket server has stopped unexpectedly");
     });
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_connection_slots_per_ip() {
+        let connections_per_ip = Arc::new(DashMap::new());
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let other: IpAddr = "10.0.0.2".parse().unwrap();
+
+        let first = ConnectionSlot::acquire(&connections_per_ip, ip, 2).unwrap();
+        let second = ConnectionSlot::acquire(&connections_per_ip, ip, 2).unwrap();
+        assert!(ConnectionSlot::acquire(&connections_per_ip, ip, 2).is_none());
+        assert!(ConnectionSlot::acquire(&connections_per_ip, other, 2).is_some());
+
+        drop(first);
+        let third = ConnectionSlot::acquire(&connections_per_ip, ip, 2).unwrap();
+        drop((second, third));
+        assert!(connections_per_ip.is_empty());
+    }
+}
@@ -1,6 +1,73 @@
 use quote::{format_ident, quote};
 use regex::Regex;
-use std::{env, fs, path::Path};
+use std::{
+    collections::{BTreeSet, HashMap},
+    env, fs,
+    path::Path,
+};
+
+const EVENT_IDS: &str = "src/event/ids.txt";
+
+/// Reads the stable event ids, see `src/event/ids.txt`
+fn read_event_ids() -> HashMap<String, u32> {
+    let contents = std::fs::read_to_string(EVENT_IDS).unwrap();
+    let mut ids = HashMap::new();
+    for line in contents.lines() {
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let (id, name) = line
+            .split_once(' ')
+            .unwrap_or_else(|| panic!("Expected `<id> <EventName>` in {EVENT_IDS}, got {line:?}"));
+        let id: u32 = id
+            .parse()
+            .unwrap_or_else(|_| panic!("Bad id {id:?} in {EVENT_IDS}"));
+        if let Some((other, _)) = ids.iter().find(|(_, other_id)| **other_id == id) {
+            panic!("{name} and {other} both have id {id} in {EVENT_IDS}");
+        }
+        if ids.insert(name.trim().to_string(), id).is_some() {
+            panic!("{name} is in {EVENT_IDS} twice");
+        }
+    }
+    ids
+}
+
+fn event_id(event_ids: &HashMap<String, u32>, name: &str) -> u32 {
+    match event_ids.get(name) {
+        Some(id) => *id,
+        None => {
+            let next = event_ids.values().max().map_or(1, |id| id + 1);
+            panic!("{name} has no stable id, add `{next} {name}` to the bottom of {EVENT_IDS}");
+        }
+    }
+}
+
+/// Reads the `// delivery: <class>` marker from the comments and attributes directly above a
+/// struct definition. Events without a marker are reliable but unordered.
+fn delivery_class_for(contents: &str, struct_start: usize, name: &str) -> &'static str {
+    // skip the `pub ` on the line of the struct itself
+    let above = &contents[..contents[..struct_start].rfind('\n').unwrap_or(0)];
+
+    for line in above.lines().rev() {
+        let line = line.trim();
+        if !line.starts_with("//") && !line.starts_with("#[") {
+            break;
+        }
+
+        if let Some(class) = line.strip_prefix("// delivery:") {
+            return match class.trim() {
+                "unreliable" => "Unreliable",
+                "reliable_unordered" => "ReliableUnordered",
+                "reliable_ordered" => "ReliableOrdered",
+                other => panic!("Unknown delivery class {other:?} on {name}"),
+            };
+        }
+    }
+
+    "ReliableUnordered"
+}
 
 //
 fn generate_code_for_event_queue(req: &GenerateRequest) -> String {
@@ -13,19 +80,89 @@ fn generate_code_for_event_queue(req: &GenerateRequest) -> String {
         .map(|x| format_ident!("{}", &x[1]))
         .collect();
 
+    let delivery_classes: Vec<_> = req
+        .struct_search_regex
+        .captures_iter(&contents)
+        .map(|x| {
+            let class = delivery_class_for(&contents, x.get(0).unwrap().start(), &x[1]);
+            format_ident!("{}", class)
+        })
+        .collect();
+
     //let all_types_lowercase: Vec<_> = all_types
     //.iter()
     //.map(|x| format_ident!("writer_{}", x.to_string().to_lowercase()))
     //.collect();
 
+    let type_names: Vec<_> = all_types.iter().map(|x| x.to_string()).collect();
+
+    let ids: Vec<_> = type_names
+        .iter()
+        .map(|name| event_id(req.event_ids, name))
+        .collect();
+
     let incoming_typename = format_ident!("EventTo{}", req.incoming_type_name);
     let outgoing_typename = format_ident!("EventTo{}", req.outgoing_type_name);
 
     let code = quote!(
-        #[derive(Debug, Clone, Serialize, Deserialize)]
+        #[derive(Debug, Clone)]
         #[non_exhaustive]
         pub enum #incoming_typename {
-            #( #all_types ( #all_types ) ),*
+            #( #all_types ( #all_types ), )*
+            /// Sent by a build with events we don't have
+            Unknown(crate::netlib::envelope::UnknownEvent),
+        }
+
+        impl Serialize for #incoming_typename {
+            fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                let envelope = match self {
+                    #(
+                        #incoming_typename :: #all_types (data) => crate::netlib::envelope::Envelope::encode::<_, S::Error>(#ids, data)?,
+                    )*
+                    #incoming_typename :: Unknown(unknown) => unknown.0.clone(),
+                };
+                envelope.serialize(serializer)
+            }
+        }
+
+        impl<'de> Deserialize<'de> for #incoming_typename {
+            fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                let envelope = crate::netlib::envelope::Envelope::deserialize(deserializer)?;
+                match envelope.id {
+                    #(
+                        #ids => Ok(match envelope.decode::<_, D::Error>() {
+                            Ok(data) => #incoming_typename :: #all_types (data),
+                            // Not worth losing the rest of the grouping over, skip it like an
+                            // event we don't know
+                            Err(e) => {
+                                debug!(id = #ids, ?e, "Couldn't decode event");
+                                #incoming_typename :: Unknown(crate::netlib::envelope::UnknownEvent(envelope))
+                            }
+                        }),
+                    )*
+                    _ => Ok(#incoming_typename :: Unknown(crate::netlib::envelope::UnknownEvent(envelope))),
+                }
+            }
+        }
+
+        impl crate::netlib::NetworkingEvent for #incoming_typename {
+            fn delivery_class(&self) -> crate::netlib::DeliveryClass {
+                match self {
+                    #(
+                        #incoming_typename :: #all_types (_) => crate::netlib::DeliveryClass:: #delivery_classes,
+                    )*
+                    #incoming_typename :: Unknown(_) => crate::netlib::DeliveryClass::ReliableUnordered,
+                }
+            }
+
+            fn name(&self) -> &'static str {
+                match self {
+                    #(
+                        #incoming_typename :: #all_types (_) => #type_names,
+                    )*
+                    #incoming_typename :: Unknown(_) => "Unknown",
+                }
+            }
         }
 
         pub fn drain_incoming_events (
@@ -33,31 +170,33 @@ fn generate_code_for_event_queue(req: &GenerateRequest) -> String {
         ) {
             let sr = world.resource::<NetworkingResources<#incoming_typename, crate::netlib:: #outgoing_typename>>().clone();
 
-            let mut new_events = sr.event_list_incoming_udp.write().unwrap();
+            let mut new_events = sr.event_list_incoming.write().unwrap();
             let new_events = std::mem::replace(new_events.as_mut(), vec![]);
 
-            for (endpoint, event) in new_events {
-                trace!(?event, "Received event from endpoint {:?}", endpoint);
-                match event {
-                    #(
-                        #incoming_typename :: #all_types (data) => {
-                            world.write_message(EventFromEndpoint::new_udp(endpoint, data));
-                        }
-                    ),*
-                }
-            }
-
-            let mut new_events2 = sr.event_list_incoming_websocket.write().unwrap();
-            let new_events2 = std::mem::replace(new_events2.as_mut(), vec![]);
+            dispatch_incoming_events(world, new_events);
+        }
 
-            for (ws_endpoint, event) in new_events2 {
-                trace!(?event, "Received event from endpoint {:?}", ws_endpoint);
+        /// Write each event as the message its systems read, as if it had just arrived. Events
+        /// the sender is over its [`crate::netlib::rate_limit`] for are dropped first.
+        pub fn dispatch_incoming_events (
+            world: &mut World,
+            events: Vec<(crate::netlib::EndpointGeneral, #incoming_typename)>,
+        ) {
+            if let Some(sr) = world.get_resource::<NetworkingResources<#incoming_typename, crate::netlib:: #outgoing_typename>>() {
+                sr.networking_stats.record_received(&events);
+            }
+            let events = crate::netlib::rate_limit::apply_rate_limits(world, events);
+            for (endpoint, event) in events {
+                trace!(?event, "Received event from endpoint {:?}", endpoint);
                 match event {
                     #(
                         #incoming_typename :: #all_types (data) => {
-                            world.write_message(EventFromEndpoint::new_ws(ws_endpoint, data));
+                            world.write_message(EventFromEndpoint::new(endpoint, data));
                         }
-                    ),*
+                    )*
+                    #incoming_typename :: Unknown(unknown) => {
+                        debug!(?endpoint, id = unknown.0.id, len = unknown.0.payload.len(), "Skipping event we don't know");
+                    }
                 }
             }
         }
@@ -84,7 +223,157 @@ fn generate_systems_for_event_queue(req: GenerateRequest) {
 
     let out_dir = env::var_os("OUT_DIR").unwrap();
     let dest_path = Path::new(&out_dir).join(req.output_filename);
-    fs::write(dest_path, code_str).unwrap();
+    fs::write(dest_path, &code_str).unwrap();
+}
+
+/// Every `struct`/`enum` definition in `source` by name, with comments and whitespace stripped so
+/// reformatting or documenting a type doesn't change its schema but touching a field or variant
+/// does.
+fn schema_items(source: &str, struct_search_regex: &Regex) -> Vec<(String, String)> {
+    let contents = without_comments(source);
+
+    let mut items = vec![];
+    for captures in struct_search_regex.captures_iter(&contents) {
+        let m = captures.get(0).unwrap();
+        let mut depth = 0;
+        for (i, c) in contents[m.end() - 1..].char_indices() {
+            match c {
+                '{' => depth += 1,
+                '}' => depth -= 1,
+                _ => {}
+            }
+            if depth == 0 {
+                let item = &contents[m.start()..m.end() + i];
+                let item = item.split_whitespace().flat_map(|w| [w, " "]).collect();
+                items.push((captures[1].to_string(), item));
+                break;
+            }
+        }
+    }
+    items
+}
+
+fn without_comments(source: &str) -> String {
+    std::fs::read_to_string(source)
+        .unwrap()
+        .lines()
+        .map(|line| line.split("//").next().unwrap())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Every `struct`/`enum` under `dir` by name, stripped like in `schema_items`. Unlike
+/// `schema_items` this includes tuple and unit structs. Types with the same name in different
+/// modules are kept together, so changing either changes the events that use the name.
+fn type_definitions(dir: &Path, definitions: &mut HashMap<String, String>) {
+    let r = Regex::new(r"\b(?:struct|enum) (\w+)").unwrap();
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            type_definitions(&path, definitions);
+            continue;
+        }
+        if path.extension().is_none_or(|e| e != "rs") {
+            continue;
+        }
+
+        let contents = without_comments(path.to_str().unwrap());
+        for captures in r.captures_iter(&contents) {
+            let start = captures.get(0).unwrap().start();
+            let mut depth = 0;
+            let end = contents[start..].char_indices().find_map(|(i, c)| {
+                match c {
+                    '{' | '(' => depth += 1,
+                    '}' | ')' => depth -= 1,
+                    ';' => {}
+                    _ => return None,
+                }
+                (depth == 0).then_some(start + i + 1)
+            });
+            let Some(end) = end else {
+                continue;
+            };
+            let item: String = contents[start..end]
+                .split_whitespace()
+                .flat_map(|w| [w, " "])
+                .collect();
+            definitions
+                .entry(captures[1].to_string())
+                .or_default()
+                .push_str(&item);
+        }
+    }
+}
+
+/// An event's schema, along with every type of ours it is made of, however deeply
+fn event_schema(item: &str, definitions: &HashMap<String, String>) -> String {
+    let r = Regex::new(r"\b[A-Z]\w*").unwrap();
+    let mut used = BTreeSet::new();
+    let mut todo = vec![item];
+    while let Some(text) = todo.pop() {
+        for name in r.find_iter(text) {
+            if let Some(definition) = definitions.get(name.as_str())
+                && used.insert(name.as_str())
+            {
+                todo.push(definition);
+            }
+        }
+    }
+
+    let mut schema = item.to_string();
+    for name in used {
+        schema.push_str(&definitions[name]);
+    }
+    schema
+}
+
+/// FNV-1a, which unlike `DefaultHasher` is the same on every toolchain
+fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |hash, b| {
+        (hash ^ *b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Events are left out of `PROTOCOL_VERSION`, they are compared one by one through
+/// `EVENT_SCHEMAS` so that adding one doesn't lock out every older client
+fn generate_protocol_version(
+    schema_sources: &[&str],
+    event_sources: &[&str],
+    event_ids: &HashMap<String, u32>,
+    r: &Regex,
+) {
+    let mut schema = String::new();
+    for source in schema_sources {
+        for (_, item) in schema_items(source, r) {
+            schema.push_str(&item);
+        }
+    }
+    let version = fnv1a(schema.as_bytes());
+
+    let mut definitions = HashMap::new();
+    type_definitions(Path::new("src"), &mut definitions);
+    let mut event_schemas = String::new();
+    for source in event_sources {
+        for (name, item) in schema_items(source, r) {
+            let id = event_id(event_ids, &name);
+            let hash = fnv1a(event_schema(&item, &definitions).as_bytes());
+            event_schemas.push_str(&format!(
+                "    (\"{name}\", crate::netlib::envelope::EventSchema {{ id: {id}, hash: {hash:#018x} }}),\n"
+            ));
+        }
+    }
+
+    let code = format!(
+        "/// Hash of the networked component types and RPCs this build was compiled with.\n\
+         /// Client and server can only talk to each other if theirs match.\n\
+         pub const PROTOCOL_VERSION: u64 = {version:#018x};\n\
+         \n\
+         /// Every event by name, see [`crate::netlib::envelope::conflicting_events`]\n\
+         pub const EVENT_SCHEMAS: &[(&str, crate::netlib::envelope::EventSchema)] = &[\n\
+         {event_schemas}];\n"
+    );
+    let out_dir = env::var_os("OUT_DIR").unwrap();
+    fs::write(Path::new(&out_dir).join("protocol_version.rs"), code).unwrap();
 }
 struct GenerateRequest<'a> {
     output_filename: &'a str,
@@ -92,16 +381,19 @@ struct GenerateRequest<'a> {
     outgoing_type_name: &'a str,
     source: &'a str,
     struct_search_regex: &'a Regex,
+    event_ids: &'a HashMap<String, u32>,
 }
 
 fn main() {
     let r = Regex::new(r#"(?:struct|enum) (\w+?) \{"#).unwrap();
+    let event_ids = read_event_ids();
     generate_systems_for_event_queue(GenerateRequest {
         source: "src/event/client.rs",
         output_filename: "./client_event.rs",
         incoming_type_name: "Client",
         outgoing_type_name: "Server",
         struct_search_regex: &r,
+        event_ids: &event_ids,
     });
 
     generate_systems_for_event_queue(GenerateRequest {
@@ -110,8 +402,21 @@ fn main() {
         incoming_type_name: "Server",
         outgoing_type_name: "Client",
         struct_search_regex: &r,
+        event_ids: &event_ids,
     });
 
+    let schema_sources = [
+        "src/netlib/envelope.rs",
+        "src/net_components.rs",
+        "src/net_components/ents.rs",
+        "src/net_components/foreign.rs",
+        "src/net_components/ours.rs",
+        "src/rpc.rs",
+        "src/rpc/methods.rs",
+    ];
+    let event_sources = ["src/event/client.rs", "src/event/server.rs"];
+    generate_protocol_version(&schema_sources, &event_sources, &event_ids, &r);
+
     //generate_systems_for_shared_components(GenerateRequest {
     //source: "src/event/shared_components.rs",
     //output_filename: "./shared_components.rs",
@@ -120,6 +425,6 @@ fn main() {
     //});
 
     println!("cargo:rerun-if-changed=build.rs");
-    println!("cargo:rerun-if-changed=src/event/server.rs");
-    println!("cargo:rerun-if-changed=src/event/client.rs");
+    // Events can be made of types from anywhere in the crate, see `event_schema`
+    println!("cargo:rerun-if-changed=src");
 }
@@ -12,8 +12,10 @@ impl Plugin for CharacterControllerPlugin {
     fn build(&self, app: &mut App) {
         app.add_message::<SpawnDebugBall>()
             .add_message::<UnitChangedMovement>()
+            // One step per tick, so the server and a predicting client simulate the same inputs the
+            // same way
             .add_systems(
-                Update,
+                FixedUpdate,
                 (
                     apply_gravity,
                     (unit_change_movement, ai_thunk),
@@ -22,7 +24,8 @@ impl Plugin for CharacterControllerPlugin {
                     movement,
                     apply_movement_damping,
                 )
-                    .chain(),
+                    .chain()
+                    .in_set(CharacterControllerSystems),
             )
             // TODO make this a cli argument
             .add_systems(
@@ -36,6 +39,10 @@ impl Plugin for CharacterControllerPlugin {
     }
 }
 
+/// Everything that steps character controllers, in `FixedUpdate`
+#[derive(SystemSet, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CharacterControllerSystems;
+
 /// A [`Message`] written for a movement input action.
 #[derive(Component, Clone, Debug, Serialize, Deserialize)]
 pub struct MovementAction {
@@ -339,7 +346,7 @@ fn movement(
     let current_time = time.elapsed_secs_f64();
 
     for (
-        movement_acceleration,
+        MovementAcceleration(acceleration),
         action,
         jump_impulse,
         mut linear_velocity,
@@ -348,38 +355,13 @@ fn movement(
         mut jump_buffer,
     ) in &mut controllers
     {
-        let input_dir = action.move_input_dir;
-        let camera_yaw = action.camera_yaw;
-        let speed_modifier = action.move_speed_modifier;
-
-        // Convert input direction to 3D movement direction
-        let input_dir_3d = Vector3::new(-input_dir.x, 0.0, input_dir.y);
-
-        // Rotate input direction based on camera yaw
-        let rotation = Quat::from_rotation_y(camera_yaw as Scalar);
-        let movement_dir = rotation * input_dir_3d;
-
-        // When grounded, project the movement direction onto the slope surface
-        // so that movement follows the slope naturally.
-        let final_movement_dir = if *is_grounded {
-            // Project the horizontal movement direction onto the slope plane.
-            // This allows smooth movement up and down slopes.
-            movement_dir
-                .reject_from_normalized(*ground_normal)
-                .normalize_or_zero()
-        } else {
-            movement_dir.normalize_or_zero()
-        };
-
-        // Apply acceleration to linear velocity
-        let acceleration =
-            final_movement_dir * movement_acceleration.0 * speed_modifier * delta_time;
-
-        if *is_grounded {
-            linear_velocity.0 += acceleration;
-        } else {
-            linear_velocity.0 += acceleration * 0.25;
-        }
+        linear_velocity.0 += movement_acceleration(
+            action,
+            *acceleration,
+            *is_grounded,
+            *ground_normal,
+            delta_time,
+        );
 
         // Check if we can execute a buffered jump
         let time_since_jump_attempt = current_time - jump_buffer.last_jump_attempt_time;
@@ -425,12 +407,53 @@ fn apply_movement_damping(
     let delta_time = time.delta_secs_f64().adjust_precision();
 
     for (damping_factor, mut linear_velocity) in &mut query {
-        // We could use `LinearDamping`, but we don't want to dampen movement along the Y axis
-        linear_velocity.x *= 1.0 / (1.0 + damping_factor.0 * delta_time);
-        linear_velocity.z *= 1.0 / (1.0 + damping_factor.0 * delta_time);
+        damp_horizontal(&mut linear_velocity.0, damping_factor.0, delta_time);
     }
 }
 
+/// The velocity `movement` adds for one step of `action`
+pub fn movement_acceleration(
+    action: &MovementAction,
+    acceleration: Scalar,
+    is_grounded: bool,
+    ground_normal: Vector,
+    delta_time: Scalar,
+) -> Vector {
+    // Convert input direction to 3D movement direction
+    let input_dir = action.move_input_dir;
+    let input_dir_3d = Vector3::new(-input_dir.x, 0.0, input_dir.y);
+
+    // Rotate input direction based on camera yaw
+    let rotation = Quat::from_rotation_y(action.camera_yaw as Scalar);
+    let movement_dir = rotation * input_dir_3d;
+
+    // When grounded, project the movement direction onto the slope surface
+    // so that movement follows the slope naturally.
+    let final_movement_dir = if is_grounded {
+        // Project the horizontal movement direction onto the slope plane.
+        // This allows smooth movement up and down slopes.
+        movement_dir
+            .reject_from_normalized(ground_normal)
+            .normalize_or_zero()
+    } else {
+        movement_dir.normalize_or_zero()
+    };
+
+    let acceleration = final_movement_dir * acceleration * action.move_speed_modifier * delta_time;
+    if is_grounded {
+        acceleration
+    } else {
+        acceleration * 0.25
+    }
+}
+
+/// Slows down movement in the X and Z directions.
+pub fn damp_horizontal(velocity: &mut Vector, damping: Scalar, delta_time: Scalar) {
+    // We could use `LinearDamping`, but we don't want to dampen movement along the Y axis
+    velocity.x *= 1.0 / (1.0 + damping * delta_time);
+    velocity.z *= 1.0 / (1.0 + damping * delta_time);
+}
+
 #[derive(Message)]
 pub struct SpawnDebugBall {
     pub position: Vector3,
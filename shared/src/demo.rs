@@ -0,0 +1,286 @@
+//! Recorded matches, as the stream of events one client received.
+//!
+//! A demo is a file of [`crate::framed`] [`DemoRecord`]s. Each event is tagged with the newest
+//! server tick from `TickHappened` when it arrived. Every [`KEYFRAME_INTERVAL`] ticks the recorder
+//! also writes the whole world as a [`WorldData2`], so playback can seek without starting over.
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    BASE_TICKS_PER_SECOND,
+    event::{
+        NetEntId,
+        client::{SpawnUnit2, UpdateUnit2, WorldData2},
+    },
+    net_components::NetComponent,
+    netlib::{EventToClient, Tick},
+};
+
+pub const KEYFRAME_INTERVAL: u64 = BASE_TICKS_PER_SECOND as u64 * 10;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum DemoRecord {
+    Event { tick: Tick, event: EventToClient },
+    Keyframe { tick: Tick, world: WorldData2 },
+}
+
+impl DemoRecord {
+    pub fn tick(&self) -> Tick {
+        match self {
+            DemoRecord::Event { tick, .. } | DemoRecord::Keyframe { tick, .. } => *tick,
+        }
+    }
+}
+
+/// The world as the recorder saw it, kept up to date from its events so keyframes can be written
+/// at any time. Snapshots must be full ones, without a baseline.
+#[derive(Debug, Clone, Default)]
+pub struct DemoWorld {
+    /// Everything but the units, from the last `WorldData2`
+    world_data: Option<WorldData2>,
+    units: HashMap<NetEntId, SpawnUnit2>,
+}
+
+impl DemoWorld {
+    pub fn from_keyframe(world: &WorldData2) -> Self {
+        let mut demo_world = Self::default();
+        demo_world.apply(&EventToClient::WorldData2(world.clone()));
+        demo_world
+    }
+
+    pub fn apply(&mut self, event: &EventToClient) {
+        match event {
+            EventToClient::WorldData2(world) => {
+                self.units = world
+                    .units
+                    .iter()
+                    .map(|u| (u.net_ent_id, u.clone()))
+                    .collect();
+                self.world_data = Some(WorldData2 {
+                    units: vec![],
+                    ..world.clone()
+                });
+            }
+            EventToClient::SpawnUnit2(spawn) => {
+                self.units.insert(spawn.net_ent_id, spawn.clone());
+            }
+            EventToClient::DespawnUnit2(despawn) => {
+                self.units.remove(&despawn.net_ent_id);
+            }
+            EventToClient::UpdateUnit2(update) => self.update_unit(update),
+            EventToClient::Snapshot(snapshot) => {
+                for update in &snapshot.changed {
+                    self.update_unit(update);
+                }
+                for net_ent_id in &snapshot.removed {
+                    self.units.remove(net_ent_id);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn update_unit(&mut self, update: &UpdateUnit2) {
+        let Some(unit) = self.units.get_mut(&update.net_ent_id) else {
+            return;
+        };
+
+        for component in update
+            .changed_components
+            .iter()
+            .chain(&update.new_component)
+        {
+            match unit.components.iter_mut().find(|c| same_kind(c, component)) {
+                Some(existing) => *existing = component.clone(),
+                None => unit.components.push(component.clone()),
+            }
+        }
+        // Same names `handle_update_unit` understands
+        for removed in &update.removed_components {
+            unit.components.retain(|c| {
+                !matches!(
+                    (removed.as_str(), c),
+                    ("NPCController", NetComponent::NPCControllerBundle(_))
+                        | (
+                            "CharacterController",
+                            NetComponent::CharacterControllerBundle(_)
+                        )
+                )
+            });
+        }
+    }
+
+    /// The whole world as one `WorldData2`, or `None` before the first one arrived
+    pub fn keyframe(&self) -> Option<WorldData2> {
+        let mut units: Vec<_> = self.units.values().cloned().collect();
+        units.sort_by_key(|u| u.net_ent_id.0);
+        Some(WorldData2 {
+            units,
+            ..self.world_data.clone()?
+        })
+    }
+}
+
+fn same_kind(a: &NetComponent, b: &NetComponent) -> bool {
+    use std::mem::discriminant;
+    match (a, b) {
+        (NetComponent::Foreign(a), NetComponent::Foreign(b)) => discriminant(a) == discriminant(b),
+        (NetComponent::Ours(a), NetComponent::Ours(b)) => discriminant(a) == discriminant(b),
+        (NetComponent::Ents(a), NetComponent::Ents(b)) => discriminant(a) == discriminant(b),
+        _ => discriminant(a) == discriminant(b),
+    }
+}
+
+/// A whole demo, loaded for playback
+#[derive(Debug, Clone, Default)]
+pub struct Demo {
+    pub records: Vec<DemoRecord>,
+}
+
+impl Demo {
+    pub fn start(&self) -> Tick {
+        self.records
+            .first()
+            .map(DemoRecord::tick)
+            .unwrap_or_default()
+    }
+
+    pub fn end(&self) -> Tick {
+        self.records
+            .last()
+            .map(DemoRecord::tick)
+            .unwrap_or_default()
+    }
+
+    /// The world after everything up to and including `tick`, and the index of the first record
+    /// after it. `None` if there is no keyframe that early.
+    pub fn world_at(&self, tick: Tick) -> Option<(WorldData2, usize)> {
+        let keyframe = self.records.iter().rposition(
+            |record| matches!(record, DemoRecord::Keyframe { tick: t, .. } if *t <= tick),
+        )?;
+        let DemoRecord::Keyframe { world, .. } = &self.records[keyframe] else {
+            unreachable!();
+        };
+
+        let mut demo_world = DemoWorld::from_keyframe(world);
+        let mut next = keyframe + 1;
+        while let Some(record) = self.records.get(next)
+            && record.tick() <= tick
+        {
+            if let DemoRecord::Event { event, .. } = record {
+                demo_world.apply(event);
+            }
+            next += 1;
+        }
+
+        Some((demo_world.keyframe()?, next))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        event::{
+            PlayerId, ResumeToken,
+            client::{DespawnUnit2, Snapshot},
+        },
+        net_components::{ToNetComponent, foreign::NetComponentForeign},
+        netlib::compression::Compression,
+        physics::terrain::TerrainParams,
+    };
+    use bevy_internal::prelude::Transform;
+
+    fn unit(id: u64, x: f32) -> SpawnUnit2 {
+        SpawnUnit2 {
+            net_ent_id: NetEntId(id),
+            components: vec![Transform::from_xyz(x, 0.0, 0.0).to_net_component()],
+        }
+    }
+
+    fn x_of(world: &WorldData2, id: u64) -> Option<f32> {
+        let unit = world.units.iter().find(|u| u.net_ent_id == NetEntId(id))?;
+        unit.components.iter().find_map(|c| match c {
+            NetComponent::Foreign(NetComponentForeign::Transform(t)) => Some(t.translation.x),
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn test_seek_from_keyframes() {
+        let world = WorldData2 {
+            your_player_id: PlayerId(1),
+            your_camera_unit_id: NetEntId(10),
+            terrain_params: TerrainParams::default(),
+            units: vec![unit(10, 0.0), unit(11, 0.0)],
+            compression: Compression::None,
+            resume_token: ResumeToken(0),
+        };
+        let moved = |tick, x| DemoRecord::Event {
+            tick: Tick(tick),
+            event: EventToClient::Snapshot(Snapshot {
+                tick: Tick(tick),
+                baseline: None,
+                changed: vec![UpdateUnit2 {
+                    net_ent_id: NetEntId(11),
+                    changed_components: vec![Transform::from_xyz(x, 0.0, 0.0).to_net_component()],
+                    ..Default::default()
+                }],
+                removed: vec![],
+            }),
+        };
+
+        let spawned = DemoRecord::Event {
+            tick: Tick(120),
+            event: EventToClient::SpawnUnit2(unit(12, 5.0)),
+        };
+        let despawned = DemoRecord::Event {
+            tick: Tick(150),
+            event: EventToClient::DespawnUnit2(DespawnUnit2 {
+                net_ent_id: NetEntId(12),
+            }),
+        };
+
+        // What the recorder would have seen by the second keyframe
+        let mut recorded = DemoWorld::from_keyframe(&world);
+        for record in [moved(110, 1.0), spawned.clone()] {
+            if let DemoRecord::Event { event, .. } = record {
+                recorded.apply(&event);
+            }
+        }
+
+        let demo = Demo {
+            records: vec![
+                DemoRecord::Keyframe {
+                    tick: Tick(100),
+                    world: world.clone(),
+                },
+                moved(110, 1.0),
+                spawned,
+                DemoRecord::Keyframe {
+                    tick: Tick(130),
+                    world: recorded.keyframe().unwrap(),
+                },
+                moved(140, 2.0),
+                despawned,
+            ],
+        };
+
+        assert!(demo.world_at(Tick(99)).is_none());
+
+        let (at_115, next) = demo.world_at(Tick(115)).unwrap();
+        assert_eq!(x_of(&at_115, 11), Some(1.0));
+        assert_eq!(x_of(&at_115, 12), None);
+        assert_eq!(next, 2);
+
+        let (at_145, next) = demo.world_at(Tick(145)).unwrap();
+        assert_eq!(x_of(&at_145, 11), Some(2.0));
+        assert_eq!(x_of(&at_145, 12), Some(5.0));
+        assert_eq!(next, 5);
+
+        let (at_end, _) = demo.world_at(demo.end()).unwrap();
+        assert_eq!(at_end.units.len(), 2);
+        assert_eq!(at_end.your_player_id, PlayerId(1));
+    }
+}
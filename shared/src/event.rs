@@ -4,11 +4,14 @@ use crate::{
     message_io::network::Endpoint,
     netlib::{EndpointGeneral, WebSocketEndpoint},
 };
+use rand::{Rng, RngExt};
 use serde::{Deserialize, Serialize};
 
 pub mod client;
 pub mod server;
 
+include!(concat!(env!("OUT_DIR"), "/protocol_version.rs"));
+
 /// Every spawned entity gets a unique NetEntId.
 #[derive(Debug, Clone, Copy, Component, Serialize, Deserialize, PartialEq, Eq, Hash)]
 pub struct NetEntId(pub u64);
@@ -22,10 +25,15 @@ pub struct PlayerId(pub u64);
 #[derive(Debug, Clone, Copy, Component, Serialize, Deserialize, PartialEq, Eq, Hash)]
 pub struct MyNetEntParentId(pub u64);
 
+/// Lets a player who lost their connection take their `PlayerId` and units back. Handed out in
+/// `WorldData2`, and sent back in the next `ConnectRequest`.
+#[derive(Debug, Clone, Copy, Component, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub struct ResumeToken(pub u64);
+
 impl NetEntId {
-    pub fn random() -> Self {
+    pub fn random(rng: &mut impl Rng) -> Self {
         // ID < 10 are reserved for special purposes.
-        Self(rand::random_range(10..=u64::MAX))
+        Self(rng.random_range(10..=u64::MAX))
     }
 
     // Very rarely used: only for special meta entities.
@@ -39,8 +47,16 @@ impl NetEntId {
 }
 
 impl PlayerId {
+    pub fn random(rng: &mut impl Rng) -> Self {
+        Self(rng.random())
+    }
+}
+
+impl ResumeToken {
+    /// From the OS, not [`crate::rng`]: that seed is logged and saved in captures, so its tokens
+    /// could be guessed. Never 0, which demos use.
     pub fn random() -> Self {
-        Self(rand::random())
+        Self(rand::random_range(1..=u64::MAX))
     }
 }
 
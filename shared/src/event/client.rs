@@ -1,31 +1,40 @@
 //!This is for events that are sent FROM the server TO the client.
-use std::collections::HashMap;
+//!
+//! Each one needs a stable id in `ids.txt`.
 
-use crate::event::PlayerId;
+use crate::ServerTPS;
+use crate::event::{PlayerId, ResumeToken};
 use crate::items::{Inventory, Item, ItemId, ItemInInventory, ItemPlacement, SkillFromSkillSource};
 use crate::net_components::PlayerConnectionInfo;
-use crate::netlib::{NetworkingResources, Tick};
+use crate::netlib::{NetworkingResources, Tick, compression::Compression};
 use crate::physics::terrain::TerrainParams;
+use crate::prediction::ControllerState;
 use crate::projectile::{ProjectileAI, ProjectileSource};
-use crate::{PlayerPing, PlayerPingInteger, ServerTPS};
+use crate::rpc::{RpcError, RpcId};
 use crate::{event::EventFromEndpoint, net_components::NetComponent};
 use bevy_internal::prelude::*;
 use serde::{Deserialize, Serialize};
 
 use super::NetEntId;
 
+// delivery: reliable_ordered
 #[derive(Debug, Clone, Serialize, Deserialize, Message)]
 pub struct SpawnUnit2 {
     pub net_ent_id: NetEntId,
     pub components: Vec<NetComponent>,
 }
 
+// delivery: reliable_ordered
 #[derive(Debug, Clone, Serialize, Deserialize, Message)]
 pub struct WorldData2 {
     pub your_player_id: PlayerId,
     pub your_camera_unit_id: NetEntId,
     pub terrain_params: TerrainParams,
     pub units: Vec<SpawnUnit2>,
+    /// What the server agreed to, from our `ConnectRequest` and its own config
+    pub compression: Compression,
+    /// Send this in our next `ConnectRequest` to get our player back if we lose connection
+    pub resume_token: ResumeToken,
 }
 
 // TODO add codegen logic systems for updating each component
@@ -48,34 +57,47 @@ impl Default for UpdateUnit2 {
     }
 }
 
+// delivery: reliable_ordered
 #[derive(Debug, Clone, Serialize, Deserialize, Message)]
 pub struct DespawnUnit2 {
     pub net_ent_id: NetEntId,
 }
 
+// delivery: reliable_ordered
 #[derive(Debug, Clone, Serialize, Deserialize, Message)]
 pub struct PlayerDisconnected {
     pub id: PlayerId,
     pub reason: String,
 }
 
+/// The server won't let us in, e.g. because it was built from a different version
+// delivery: reliable_ordered
+#[derive(Debug, Clone, Serialize, Deserialize, Message)]
+pub struct ConnectionRejected {
+    pub reason: String,
+}
+
+// delivery: reliable_ordered
 #[derive(Debug, Clone, Serialize, Deserialize, Message)]
 pub struct Chat {
     pub source: Option<NetEntId>,
     pub text: String,
 }
 
+// delivery: reliable_ordered
 #[derive(Debug, Clone, Serialize, Deserialize, Message)]
 pub struct BeginThirdpersonControllingUnit {
     pub player_id: PlayerId,
     pub unit: Option<NetEntId>,
 }
 
+// delivery: reliable_ordered
 #[derive(Debug, Clone, Serialize, Deserialize, Message)]
 pub struct NewInventory {
     pub inventory: Inventory<Item>,
 }
 
+// delivery: reliable_ordered
 #[derive(Debug, Clone, Serialize, Deserialize, Message)]
 pub struct UpdateInventory {
     pub inventory: Inventory<ItemId>,
@@ -84,6 +106,7 @@ pub struct UpdateInventory {
     pub moved_items: Vec<(ItemId, ItemPlacement, ItemPlacement)>,
 }
 
+// delivery: reliable_ordered
 #[derive(Debug, Clone, Serialize, Deserialize, Message)]
 pub struct UpdateItems {
     pub items: Vec<Item>,
@@ -97,6 +120,7 @@ pub struct ServerSoreboardInfo {
     pub players_connection_info: Vec<(PlayerId, PlayerConnectionInfo)>,
 }
 
+// delivery: unreliable
 #[derive(Debug, Clone, Serialize, Deserialize, Message)]
 pub struct HeartbeatResponse {
     pub client_started_time: f64,
@@ -104,18 +128,16 @@ pub struct HeartbeatResponse {
     pub server_tick: Tick,
 }
 
+/// The server measuring our ping. Not an [`crate::rpc`] call, since those only go from a client
+/// to the server, and the client answers right away with a `HeartbeatChallengeResponse` that
+/// carries `server_time` back. A lost challenge is simply replaced by the next one.
+// delivery: unreliable
 #[derive(Debug, Clone, Serialize, Deserialize, Message)]
 pub struct HeartbeatChallenge {
     pub server_time: f64,
     //pub server_challenge: u64,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, Message)]
-pub struct RequestScoreboardResponse {
-    pub player_names: HashMap<PlayerId, String>,
-    pub player_pings: HashMap<PlayerId, PlayerPing<PlayerPingInteger>>,
-}
-
 // TODO impl
 #[derive(Debug, Clone, Serialize, Deserialize, Message)]
 pub struct SpawnProjectile {
@@ -125,6 +147,7 @@ pub struct SpawnProjectile {
     pub projectile_type: ProjectileAI,
 }
 
+// delivery: reliable_ordered
 #[derive(Debug, Clone, Serialize, Deserialize, Message)]
 pub struct CastSkillUpdateToClient {
     pub net_ent_id: NetEntId,
@@ -138,4 +161,42 @@ pub struct TickHappened {
     pub tick: Tick,
 }
 
+/// Movement state of replicated units for one tick, see [`crate::snapshot`]
+// delivery: unreliable
+#[derive(Debug, Clone, Serialize, Deserialize, Message)]
+pub struct Snapshot {
+    pub tick: Tick,
+    /// The acked snapshot this is a delta against, or `None` if it contains everything
+    pub baseline: Option<Tick>,
+    pub changed: Vec<UpdateUnit2>,
+    /// Units in the baseline that are no longer replicated
+    pub removed: Vec<NetEntId>,
+}
+
+/// Where the server's simulation of our controlled unit's inputs left it, see
+/// [`crate::prediction`]
+// delivery: unreliable
+#[derive(Debug, Clone, Serialize, Deserialize, Message)]
+pub struct MovementAck {
+    pub net_ent_id: NetEntId,
+    /// The last input it simulated
+    pub sequence: u64,
+    pub state: ControllerState,
+}
+
+/// The server refused where we moved our camera, put it back here
+// delivery: reliable_ordered
+#[derive(Debug, Clone, Serialize, Deserialize, Message)]
+pub struct MovementCorrection {
+    pub net_ent_id: NetEntId,
+    pub transform: Transform,
+}
+
+/// The reply to the `RpcRequest` with the same id
+#[derive(Debug, Clone, Serialize, Deserialize, Message)]
+pub struct RpcResponse {
+    pub id: RpcId,
+    pub result: Result<Vec<u8>, RpcError>,
+}
+
 include!(concat!(env!("OUT_DIR"), "/client_event.rs"));
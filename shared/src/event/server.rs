@@ -1,24 +1,39 @@
 //!This is for events that are sent FROM the client TO the server.
-use crate::event::{EventFromEndpoint, NetEntId};
+//!
+//! Each one needs a stable id in `ids.txt`.
+use crate::event::{EventFromEndpoint, NetEntId, ResumeToken};
 use crate::items::SkillFromSkillSource;
 //use crate::net_components::NetComponent;
-use crate::netlib::NetworkingResources;
-use avian3d::prelude::{LinearVelocity, Rotation};
+use crate::netlib::{NetworkingResources, Tick, compression::Compression, envelope::EventSchema};
+use crate::prediction::SequencedInput;
+use crate::rpc::RpcId;
 use bevy_internal::prelude::*;
 use serde::{Deserialize, Serialize};
 
+// delivery: unreliable
 #[derive(Debug, Clone, Serialize, Deserialize, Message)]
 pub struct ConnectRequest {
+    /// These two must stay the first fields, so a server can still read them from a client built
+    /// from a different commit. See [`crate::event::PROTOCOL_VERSION`].
+    pub protocol_version: u64,
+    /// See [`crate::netlib::envelope::conflicting_events`]
+    pub event_schemas: Vec<EventSchema>,
     pub name: Option<String>,
     pub my_location: Transform,
     pub color_hue: f32,
+    /// Codec we can read, the server replies with what it will send in `WorldData2`
+    pub compression: Compression,
+    /// From the `WorldData2` of a connection we lost, to take that player back
+    pub resume: Option<ResumeToken>,
 }
 
+// delivery: reliable_ordered
 #[derive(Debug, Clone, Serialize, Deserialize, Message)]
 pub struct SendChat {
     pub text: String,
 }
 
+// delivery: unreliable
 #[derive(Debug, Clone, Serialize, Deserialize, Message)]
 pub struct Heartbeat {
     pub client_started_time: f64,
@@ -41,17 +56,23 @@ pub struct SpawnMan {
 //pub components: Vec<NetComponent>,
 //}
 
+// delivery: unreliable
 #[derive(Debug, Clone, Serialize, Deserialize, Message)]
 pub struct ChangeMovement {
     pub net_ent_id: NetEntId,
     pub transform: Transform,
-    pub velocity: Option<LinearVelocity>,
-    pub rotation: Option<Rotation>,
 }
 
+/// Our controlled unit's inputs, see [`crate::prediction`]
+// delivery: unreliable
 #[derive(Debug, Clone, Serialize, Deserialize, Message)]
-pub struct RequestScoreboard {}
+pub struct MovementInput {
+    pub net_ent_id: NetEntId,
+    /// The newest few, oldest first
+    pub inputs: Vec<SequencedInput>,
+}
 
+// delivery: unreliable
 #[derive(Debug, Clone, Serialize, Deserialize, Message)]
 pub struct HeartbeatChallengeResponse {
     pub server_time: f64,
@@ -61,15 +82,34 @@ pub struct HeartbeatChallengeResponse {
     //pub server_challenge: u64,
 }
 
+// delivery: unreliable
 #[derive(Debug, Clone, Serialize, Deserialize, Message)]
 pub struct IWantToDisconnect {}
 
+// delivery: reliable_ordered
 #[derive(Debug, Clone, Serialize, Deserialize, Message)]
 pub struct CastSkillUpdate {
     pub net_ent_id: NetEntId,
     /// True if starting to cast, false if stopping
     pub begin_casting: bool,
     pub skill: SkillFromSkillSource,
+    /// The server tick we were drawing other units at, so the server can rewind them for hits
+    pub seen_tick: Option<Tick>,
+}
+
+/// We received and decoded the snapshot for this tick, so it can be used as a baseline
+// delivery: unreliable
+#[derive(Debug, Clone, Serialize, Deserialize, Message)]
+pub struct SnapshotAck {
+    pub tick: Tick,
+}
+
+/// A call to the [`crate::rpc::Rpc`] named `method`, answered with an `RpcResponse`
+#[derive(Debug, Clone, Serialize, Deserialize, Message)]
+pub struct RpcRequest {
+    pub id: RpcId,
+    pub method: String,
+    pub payload: Vec<u8>,
 }
 
 include!(concat!(env!("OUT_DIR"), "/server_event.rs"));
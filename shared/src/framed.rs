@@ -0,0 +1,46 @@
+//! Length-prefixed postcard records, for files we append to as things happen and read back
+//! later, like server captures and demos.
+use std::io::{Read, Write};
+
+use bevy_internal::prelude::*;
+use serde::{Serialize, de::DeserializeOwned};
+
+/// Longer records are refused, so a corrupt length doesn't make us allocate gigabytes
+pub const MAX_RECORD_LEN: usize = 16 * 1024 * 1024;
+
+pub fn write_record<T: Serialize>(writer: &mut impl Write, record: &T) -> std::io::Result<()> {
+    let data = postcard::to_stdvec(record).map_err(std::io::Error::other)?;
+    if data.len() > MAX_RECORD_LEN {
+        return Err(std::io::Error::other(format!(
+            "record of {} bytes is over the limit",
+            data.len()
+        )));
+    }
+    writer.write_all(&(data.len() as u32).to_le_bytes())?;
+    writer.write_all(&data)
+}
+
+/// The next record, or `None` at the end. A record cut short by a crash counts as the end.
+pub fn read_record<T: DeserializeOwned>(reader: &mut impl Read) -> Option<T> {
+    let mut len = [0; 4];
+    reader.read_exact(&mut len).ok()?;
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_RECORD_LEN {
+        warn!(len, "Record is too long to be real, stopping there");
+        return None;
+    }
+    let mut data = vec![0; len];
+    reader.read_exact(&mut data).ok()?;
+    match postcard::from_bytes(&data) {
+        Ok(record) => Some(record),
+        Err(e) => {
+            warn!(?e, "Unreadable record, stopping there");
+            None
+        }
+    }
+}
+
+/// Every record in `reader`
+pub fn read_all<T: DeserializeOwned>(mut reader: impl Read) -> Vec<T> {
+    std::iter::from_fn(|| read_record(&mut reader)).collect()
+}
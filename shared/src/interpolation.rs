@@ -0,0 +1,267 @@
+//! Drawing remote units a little in the past, between states the server sent us.
+//!
+//! Snapshots arrive with uneven gaps, so applying each one as it comes makes remote units jitter.
+//! Instead we keep every state with the server tick it is from, and draw units at a render tick
+//! that trails our estimate of the server's current tick. The delay grows with how late packets
+//! have been arriving. When the buffer runs dry we carry on along the last velocity for a moment
+//! before holding still.
+use std::collections::VecDeque;
+
+use avian3d::prelude::{LinearVelocity, Rotation};
+
+use crate::{BASE_TICKS_PER_SECOND, netlib::Tick, snapshot::EntityState};
+
+/// The delay on a perfectly steady connection, in ticks
+pub const MIN_DELAY_TICKS: f64 = 2.0;
+/// About a quarter of a second, past this units are too far behind to be worth waiting for
+pub const MAX_DELAY_TICKS: f64 = 15.0;
+/// How many times the typical lateness we wait for
+const JITTER_MARGIN: f64 = 2.5;
+/// How quickly the typical lateness follows new packets
+const JITTER_SMOOTHING: f64 = 0.1;
+/// How quickly our estimate of the server tick falls back when packets start arriving later
+const DRIFT_SMOOTHING: f64 = 0.01;
+/// How fast the delay may change, as a fraction of the time passing. Small enough that units
+/// slow down or speed up a little rather than jump.
+const DELAY_CHANGE_RATE: f64 = 0.1;
+/// How far past the newest state we extrapolate, about a tenth of a second
+pub const MAX_EXTRAPOLATION_TICKS: f64 = 6.0;
+/// States we keep per unit, older ones are dropped even if we haven't drawn past them
+const MAX_BUFFERED_STATES: usize = 64;
+
+/// Estimates which server tick it is, and how far behind it to draw
+#[derive(Debug, Clone, Default)]
+pub struct InterpolationClock {
+    /// Server tick minus our time in ticks, from the earliest arriving packets
+    offset: Option<f64>,
+    /// How late packets typically arrive compared to the earliest ones, in ticks
+    jitter: f64,
+    delay: f64,
+    last_render: Option<f64>,
+}
+
+impl InterpolationClock {
+    /// The server was at `tick` when something it sent arrived at `now`, in seconds
+    pub fn observe(&mut self, tick: Tick, now: f64) {
+        let offset = tick.0 as f64 - now * BASE_TICKS_PER_SECOND as f64;
+        let Some(current) = self.offset else {
+            self.offset = Some(offset);
+            self.delay = MIN_DELAY_TICKS;
+            return;
+        };
+
+        let lateness = (current - offset).max(0.0);
+        self.jitter += (lateness - self.jitter) * JITTER_SMOOTHING;
+
+        // Early packets move the estimate forward straight away, late ones only slowly, in case
+        // the route really did get longer
+        self.offset = Some(if offset > current {
+            offset
+        } else {
+            current + (offset - current) * DRIFT_SMOOTHING
+        });
+    }
+
+    /// Our best guess of the server's tick at `now`
+    pub fn estimated_server_tick(&self, now: f64) -> Option<f64> {
+        self.offset
+            .map(|offset| now * BASE_TICKS_PER_SECOND as f64 + offset)
+    }
+
+    /// The delay we are easing towards
+    pub fn target_delay(&self) -> f64 {
+        (MIN_DELAY_TICKS + self.jitter * JITTER_MARGIN).clamp(MIN_DELAY_TICKS, MAX_DELAY_TICKS)
+    }
+
+    pub fn delay(&self) -> f64 {
+        self.delay
+    }
+
+    pub fn jitter(&self) -> f64 {
+        self.jitter
+    }
+
+    /// The tick to draw remote units at, `delta` seconds after the last call
+    pub fn render_tick(&mut self, now: f64, delta: f64) -> Option<f64> {
+        let server_tick = self.estimated_server_tick(now)?;
+
+        let max_change = delta * BASE_TICKS_PER_SECOND as f64 * DELAY_CHANGE_RATE;
+        let change = (self.target_delay() - self.delay).clamp(-max_change, max_change);
+        self.delay += change;
+
+        // A packet arriving early moves the estimate forward, but we never draw backwards
+        let render = server_tick - self.delay;
+        let render = self.last_render.map_or(render, |last| render.max(last));
+        self.last_render = Some(render);
+        Some(render)
+    }
+}
+
+/// Where a unit should be drawn, and whether we had to guess past the newest state
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sampled {
+    pub state: EntityState,
+    /// How far past the newest state we are, in ticks. Zero while we are between two states.
+    pub late_by: f64,
+}
+
+/// The states we received for one remote unit, oldest first
+#[derive(Debug, Clone, Default)]
+pub struct InterpolationBuffer {
+    states: VecDeque<(Tick, EntityState)>,
+}
+
+impl InterpolationBuffer {
+    /// States older than the newest one are ignored
+    pub fn push(&mut self, tick: Tick, state: EntityState) {
+        if self
+            .states
+            .back()
+            .is_some_and(|(newest, _)| tick <= *newest)
+        {
+            return;
+        }
+        self.states.push_back((tick, state));
+        while self.states.len() > MAX_BUFFERED_STATES {
+            self.states.pop_front();
+        }
+    }
+
+    pub fn newest_tick(&self) -> Option<Tick> {
+        self.states.back().map(|(tick, _)| *tick)
+    }
+
+    /// How many ticks of states we have past `render_tick`
+    pub fn buffered_ahead(&self, render_tick: f64) -> f64 {
+        self.newest_tick()
+            .map_or(0.0, |newest| (newest.0 as f64 - render_tick).max(0.0))
+    }
+
+    /// The state at `render_tick`. States we have drawn past are dropped, except the one before
+    /// it, which we still interpolate from.
+    pub fn sample(&mut self, render_tick: f64) -> Option<Sampled> {
+        while self.states.len() > 1 && (self.states[1].0.0 as f64) <= render_tick {
+            self.states.pop_front();
+        }
+
+        let (from_tick, from) = *self.states.front()?;
+        let from_tick = from_tick.0 as f64;
+
+        let Some(&(to_tick, to)) = self.states.get(1) else {
+            let late_by = (render_tick - from_tick).max(0.0);
+            return Some(Sampled {
+                state: extrapolate(&from, late_by.min(MAX_EXTRAPOLATION_TICKS)),
+                late_by,
+            });
+        };
+
+        // Not there yet, this is the first state we have
+        if render_tick <= from_tick {
+            return Some(Sampled {
+                state: from,
+                late_by: 0.0,
+            });
+        }
+
+        let t = (render_tick - from_tick) / (to_tick.0 as f64 - from_tick);
+        Some(Sampled {
+            state: lerp(&from, &to, t as f32),
+            late_by: 0.0,
+        })
+    }
+}
+
+fn lerp(from: &EntityState, to: &EntityState, t: f32) -> EntityState {
+    let mut transform = to.transform;
+    transform.translation = from.transform.translation.lerp(to.transform.translation, t);
+    transform.rotation = from.transform.rotation.slerp(to.transform.rotation, t);
+    transform.scale = from.transform.scale.lerp(to.transform.scale, t);
+
+    let velocity = match (from.velocity, to.velocity) {
+        (Some(a), Some(b)) => Some(LinearVelocity(a.0.lerp(b.0, t))),
+        (_, b) => b,
+    };
+    let rotation = match (from.rotation, to.rotation) {
+        (Some(a), Some(b)) => Some(Rotation(a.0.slerp(b.0, t))),
+        (_, b) => b,
+    };
+
+    EntityState {
+        transform,
+        velocity,
+        rotation,
+    }
+}
+
+fn extrapolate(state: &EntityState, ticks: f64) -> EntityState {
+    let mut state = *state;
+    if let Some(velocity) = state.velocity {
+        let seconds = (ticks / BASE_TICKS_PER_SECOND as f64) as f32;
+        state.transform.translation += velocity.0 * seconds;
+    }
+    state
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bevy_internal::prelude::*;
+
+    fn state(x: f32) -> EntityState {
+        EntityState {
+            transform: Transform::from_xyz(x, 0.0, 0.0),
+            velocity: Some(LinearVelocity(Vec3::X * BASE_TICKS_PER_SECOND as f32)),
+            rotation: None,
+        }
+    }
+
+    #[test]
+    fn test_interpolates_and_extrapolates() {
+        let mut buffer = InterpolationBuffer::default();
+        buffer.push(Tick(10), state(10.0));
+        buffer.push(Tick(12), state(12.0));
+        // Late and out of order, we already have something newer
+        buffer.push(Tick(11), state(100.0));
+
+        let between = buffer.sample(11.5).unwrap();
+        assert_eq!(between.late_by, 0.0);
+        assert!((between.state.transform.translation.x - 11.5).abs() < 1e-4);
+        assert_eq!(buffer.buffered_ahead(11.5), 0.5);
+
+        // One tick per tick along the last velocity, up to the limit
+        let late = buffer.sample(14.0).unwrap();
+        assert_eq!(late.late_by, 2.0);
+        assert!((late.state.transform.translation.x - 14.0).abs() < 1e-4);
+
+        let starved = buffer.sample(100.0).unwrap();
+        let limit = 12.0 + MAX_EXTRAPOLATION_TICKS as f32;
+        assert!((starved.state.transform.translation.x - limit).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_delay_grows_with_jitter() {
+        let tick_secs = 1.0 / BASE_TICKS_PER_SECOND as f64;
+
+        let mut steady = InterpolationClock::default();
+        let mut jittery = InterpolationClock::default();
+        for tick in 0..300 {
+            let now = tick as f64 * tick_secs;
+            steady.observe(Tick(tick), now);
+            // Every other packet is four ticks late
+            let late = if tick % 2 == 0 { 4.0 * tick_secs } else { 0.0 };
+            jittery.observe(Tick(tick), now + late);
+        }
+        assert!(steady.target_delay() < MIN_DELAY_TICKS + 0.01);
+        assert!(jittery.target_delay() > MIN_DELAY_TICKS + 2.0);
+
+        // The estimate follows the packets that arrived on time
+        let now = 300.0 * tick_secs;
+        assert!((jittery.estimated_server_tick(now).unwrap() - 300.0).abs() < 0.5);
+
+        // The delay eases in rather than jumping, and we never draw backwards
+        let first = jittery.render_tick(now, tick_secs).unwrap();
+        assert!(jittery.delay() < MIN_DELAY_TICKS + 1.0);
+        let second = jittery.render_tick(now + tick_secs, tick_secs).unwrap();
+        assert!(second >= first);
+    }
+}
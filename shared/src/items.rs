@@ -1,6 +1,7 @@
 use std::sync::{Arc, RwLock};
 
 use bevy_ecs::resource::Resource;
+use rand::{Rng, RngExt};
 use serde::{Deserialize, Serialize};
 
 use crate::{
@@ -327,32 +328,19 @@ impl<'de> Deserialize<'de> for ItemPlacement {
 #[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq, Eq, Hash)]
 pub struct InventoryId(pub u128);
 
-impl Default for InventoryId {
-    fn default() -> Self {
-        let current_unix_time = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_millis() as u64;
-
-        let get_server_id = || -> u16 {
-            // TODO get actual server ID
-            0u16
-        };
-
-        let rng = rand::random_range(0u64..(u64::MAX << 16));
-        let inventory_id =
-            ((current_unix_time as u128) << 64) | ((get_server_id() as u128) << 48) | (rng as u128);
-
-        InventoryId(inventory_id)
+impl InventoryId {
+    /// 64 random bits are plenty, and keep the id short on the wire
+    pub fn random(rng: &mut impl Rng) -> Self {
+        InventoryId(rng.random::<u64>().into())
     }
 }
 
 #[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq, Eq, Hash)]
 pub struct ItemId(pub u128);
 
-impl Default for ItemId {
-    fn default() -> Self {
-        Self(InventoryId::default().0)
+impl ItemId {
+    pub fn random(rng: &mut impl Rng) -> Self {
+        Self(rng.random::<u64>().into())
     }
 }
 
@@ -370,6 +358,12 @@ pub struct Item {
     pub data: ItemData,
 }
 
+impl AsRef<Item> for Item {
+    fn as_ref(&self) -> &Item {
+        self
+    }
+}
+
 impl HasMods for Item {
     fn get_mods(&self) -> Vec<Mod> {
         self.data.get_mods()
@@ -458,9 +452,9 @@ pub enum DamageLevels {
     Broken,
 }
 
-pub fn goblin_drops() -> Inventory<Item> {
+pub fn goblin_drops(rng: &mut impl Rng) -> Inventory<Item> {
     let gold = Item {
-        item_id: ItemId::default(),
+        item_id: ItemId::random(rng),
         data: ItemData {
             item_base: BaseItem::CurrencyPiece,
             mods: vec![],
@@ -469,7 +463,7 @@ pub fn goblin_drops() -> Inventory<Item> {
     };
 
     let goblin_diary_page = Item {
-        item_id: ItemId::default(),
+        item_id: ItemId::random(rng),
         data: ItemData {
             item_base: BaseItem::EnemyDiaryPage(diary::EnemyDiaryPage::Goblin),
             mods: vec![],
@@ -478,7 +472,7 @@ pub fn goblin_drops() -> Inventory<Item> {
     };
 
     let boots = Item {
-        item_id: ItemId::default(),
+        item_id: ItemId::random(rng),
         data: ItemData {
             item_base: BaseItem::Footwear(footwear::Footwear::Sandals),
             mods: vec![],
@@ -490,7 +484,7 @@ pub fn goblin_drops() -> Inventory<Item> {
     };
 
     let ranger_page = Item {
-        item_id: ItemId::default(),
+        item_id: ItemId::random(rng),
         data: ItemData {
             item_base: BaseItem::DiaryPage(diary::DiaryPage::Omniscience),
             mods: vec![],
@@ -499,7 +493,7 @@ pub fn goblin_drops() -> Inventory<Item> {
     };
 
     Inventory {
-        id: InventoryId::default(),
+        id: InventoryId::random(rng),
         items: vec![
             ItemInInventory {
                 item: gold,
@@ -550,10 +544,11 @@ pub fn goblin_drops() -> Inventory<Item> {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::rng::GameRng;
 
     #[test]
     fn test_item_drop_size() {
-        let goblin_drops = goblin_drops();
+        let goblin_drops = goblin_drops(&mut GameRng::new(0));
 
         let as_payload = postcard::to_stdvec(&goblin_drops).unwrap();
         println!("{}", as_payload.len());
@@ -562,7 +557,7 @@ mod test {
 
     #[test]
     fn test_get_equipped_skills() {
-        let goblin_drops = goblin_drops();
+        let goblin_drops = goblin_drops(&mut GameRng::new(0));
         let skills = goblin_drops.get_equipped_skills();
         assert!(skills.len() > 1);
     }
@@ -9,18 +9,27 @@ use bevy_internal::prelude::*;
 use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
 
-use crate::netlib::Tick;
+use crate::netlib::{
+    Tick, compression::Compression, conditioner::NetworkConditions, rate_limit::RateLimits,
+};
 
 pub mod character_controller;
 pub mod decimal;
+pub mod demo;
 pub mod event;
+pub mod framed;
+pub mod interpolation;
 pub mod items;
 pub mod net_components;
 pub mod netlib;
 pub mod physics;
 pub mod player_input;
+pub mod prediction;
 pub mod projectile;
+pub mod rng;
+pub mod rpc;
 pub mod skills;
+pub mod snapshot;
 pub mod stats;
 
 #[cfg(not(feature = "udp"))]
@@ -85,6 +94,17 @@ pub enum GameAction {
     Skills,
 
     Chat,
+
+    /// F5, while watching a demo
+    DemoPause,
+    /// F6
+    DemoSlower,
+    /// F7
+    DemoFaster,
+    /// F8
+    DemoSeekBack,
+    /// F9
+    DemoSeekForward,
 }
 
 static DEFAULT_BINDS: Lazy<Keybinds> = Lazy::new(|| {
@@ -125,6 +145,11 @@ static DEFAULT_BINDS: Lazy<Keybinds> = Lazy::new(|| {
         ),
         (GameAction::Scoreboard, vec![kk(KeyCode::KeyP)]),
         (GameAction::Skills, vec![kk(KeyCode::KeyK)]),
+        (GameAction::DemoPause, vec![kk(KeyCode::F5)]),
+        (GameAction::DemoSlower, vec![kk(KeyCode::F6)]),
+        (GameAction::DemoFaster, vec![kk(KeyCode::F7)]),
+        (GameAction::DemoSeekBack, vec![kk(KeyCode::F8)]),
+        (GameAction::DemoSeekForward, vec![kk(KeyCode::F9)]),
     ])
 });
 
@@ -155,10 +180,37 @@ pub struct Config {
     pub qe_sens: f32,
     /// Should sound play on hits?
     pub sound: Option<bool>,
+    /// Server only: how far from a player's camera and units entities get replicated to them
+    pub interest_radius: Option<f32>,
+    /// Server only: send budget per client, in bytes per second
+    pub bandwidth_bytes_per_second: Option<usize>,
+    /// Compress large packets when the other side supports it too. On unless set to false.
+    pub compression: Option<bool>,
+    /// Simulated loss, duplication, reordering and bandwidth caps on UDP. The client's command
+    /// line flags override these.
+    pub network_conditions: Option<NetworkConditions>,
+    /// Server only: serve websockets as wss:// with this certificate instead of plain ws://
+    pub websocket_tls: Option<WebsocketTls>,
+    /// Server only: open websockets allowed from one IP at a time
+    pub max_websocket_connections_per_ip: Option<usize>,
+    /// Server only: largest websocket frame or message a client may send us, in bytes
+    pub max_websocket_frame_bytes: Option<usize>,
+    /// Server only: how long a disconnected player's units wait for them to reconnect, in seconds
+    pub reconnect_grace_seconds: Option<f32>,
+    /// Server only: how many events of each type a client may send us
+    pub rate_limits: Option<RateLimits>,
 
     pub keybindings: Keybinds, // TODO rust_phf
 }
 
+/// PEM files for the websocket server's TLS
+#[derive(Reflect, Clone, Deserialize, Serialize, Debug)]
+pub struct WebsocketTls {
+    /// The certificate chain, leaf first
+    pub cert_path: String,
+    pub key_path: String,
+}
+
 #[derive(Reflect, Clone, Hash, Eq, PartialEq, Deserialize, Serialize, Debug)]
 pub enum KeyCodeOrMouseButton {
     KeyCode(KeyCode),
@@ -260,6 +312,15 @@ impl Default for Config {
             qe_sens: 3.0,
             name: None,
             sound: Some(false),
+            interest_radius: None,
+            bandwidth_bytes_per_second: None,
+            compression: None,
+            network_conditions: None,
+            websocket_tls: None,
+            max_websocket_connections_per_ip: None,
+            max_websocket_frame_bytes: None,
+            reconnect_grace_seconds: None,
+            rate_limits: None,
             keybindings: DEFAULT_BINDS.clone(),
         }
     }
@@ -269,6 +330,14 @@ impl Config {
     pub fn sound(&self) -> bool {
         self.sound.unwrap_or(false)
     }
+
+    /// The codec we offer in the connect handshake
+    pub fn compression(&self) -> Compression {
+        match self.compression {
+            Some(false) => Compression::None,
+            _ => Compression::Lz4,
+        }
+    }
 }
 
 pub struct ConfigPlugin;
@@ -367,6 +436,26 @@ pub struct ServerTPS {
     latest_tick_times: VecDeque<f64>,
 }
 
+impl ServerTPS {
+    /// Times between the recent ticks at each quantile, like `0.99`, in seconds. `None` before
+    /// the second tick.
+    pub fn tick_time_quantiles(&self, quantiles: &[f64]) -> Option<Vec<f64>> {
+        if self.latest_tick_times.is_empty() {
+            return None;
+        }
+        let mut ticks_in_order = self.latest_tick_times.iter().cloned().collect::<Vec<f64>>();
+        ticks_in_order.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+        let last = ticks_in_order.len() - 1;
+        Some(
+            quantiles
+                .iter()
+                .map(|q| ticks_in_order[((last as f64 * q).round() as usize).min(last)])
+                .collect(),
+        )
+    }
+}
+
 pub fn increment_ticks(
     time: Res<Time<Fixed>>,
     mut current_tick: ResMut<CurrentTick>,
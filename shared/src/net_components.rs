@@ -9,6 +9,7 @@ pub mod foreign;
 pub mod ours;
 
 use bevy_internal::prelude::*;
+use rand::Rng;
 use serde::{Deserialize, Serialize};
 
 use crate::{
@@ -160,16 +161,19 @@ impl SpawnUnit2 {
         ent_commands.id()
     }
 
-    pub fn new_with_vec(components: Vec<NetComponent>) -> Self {
+    pub fn new_with_vec(rng: &mut impl Rng, components: Vec<NetComponent>) -> Self {
         Self {
-            net_ent_id: NetEntId::random(),
+            net_ent_id: NetEntId::random(rng),
             components,
         }
     }
 
-    pub fn new_with(components: impl IntoIterator<Item = NetComponent>) -> Self {
+    pub fn new_with(
+        rng: &mut impl Rng,
+        components: impl IntoIterator<Item = NetComponent>,
+    ) -> Self {
         Self {
-            net_ent_id: NetEntId::random(),
+            net_ent_id: NetEntId::random(rng),
             components: components.into_iter().collect(),
         }
     }
@@ -179,30 +183,46 @@ pub trait ToNetComponent {
     fn to_net_component(self) -> NetComponent;
 }
 
-pub fn make_ball(transform: Transform, color: Color, owner: ControlledBy) -> SpawnUnit2 {
+pub fn make_ball(
+    rng: &mut impl Rng,
+    transform: Transform,
+    color: Color,
+    owner: ControlledBy,
+) -> SpawnUnit2 {
     let sphere_size = 0.5;
-    SpawnUnit2::new_with_vec(vec![
-        owner.to_net_component(),
-        ents::Ball(sphere_size).to_net_component(),
-        ents::SendNetworkTranformUpdates.to_net_component(),
-        avian3d::prelude::TransformInterpolation.to_net_component(),
-        transform.to_net_component(),
-        color.to_net_component(),
-        avian3d::prelude::RigidBody::Dynamic.to_net_component(),
-        avian3d::prelude::Collider::sphere(sphere_size).to_net_component(),
-        avian3d::prelude::Mass(0.3).to_net_component(), // Lighter balls that will float (density ~0.57 of water)
-                                                        // Add other ball components here as needed
-    ])
+    SpawnUnit2::new_with_vec(
+        rng,
+        vec![
+            owner.to_net_component(),
+            ents::Ball(sphere_size).to_net_component(),
+            ents::SendNetworkTranformUpdates.to_net_component(),
+            avian3d::prelude::TransformInterpolation.to_net_component(),
+            transform.to_net_component(),
+            color.to_net_component(),
+            avian3d::prelude::RigidBody::Dynamic.to_net_component(),
+            avian3d::prelude::Collider::sphere(sphere_size).to_net_component(),
+            avian3d::prelude::Mass(0.3).to_net_component(), // Lighter balls that will float (density ~0.57 of water)
+                                                            // Add other ball components here as needed
+        ],
+    )
 }
 
-pub fn make_small_loot(transform: Transform) -> SpawnUnit2 {
-    SpawnUnit2::new_with_vec(vec![
-        ents::ItemDrop { source: None }.to_net_component(),
-        transform.to_net_component(),
-    ])
+pub fn make_small_loot(rng: &mut impl Rng, transform: Transform) -> SpawnUnit2 {
+    SpawnUnit2::new_with_vec(
+        rng,
+        vec![
+            ents::ItemDrop { source: None }.to_net_component(),
+            transform.to_net_component(),
+        ],
+    )
 }
 
-pub fn make_man(transform: Transform, owner: ControlledBy, controller: &str) -> SpawnUnit2 {
+pub fn make_man(
+    rng: &mut impl Rng,
+    transform: Transform,
+    owner: ControlledBy,
+    controller: &str,
+) -> SpawnUnit2 {
     use crate::character_controller::CharacterControllerBundle;
     use avian3d::prelude::Collider;
 
@@ -236,24 +256,27 @@ pub fn make_man(transform: Transform, owner: ControlledBy, controller: &str) ->
         _ => {}
     }
 
-    SpawnUnit2::new_with_vec(comps)
+    SpawnUnit2::new_with_vec(rng, comps)
 }
 
-pub fn make_npc(transform: Transform) -> SpawnUnit2 {
+pub fn make_npc(rng: &mut impl Rng, transform: Transform) -> SpawnUnit2 {
     use crate::character_controller::NPCControllerBundle;
     use avian3d::prelude::Collider;
-    SpawnUnit2::new_with_vec(vec![
-        ents::NPC.to_net_component(),
-        ents::SendNetworkTranformUpdates.to_net_component(),
-        avian3d::prelude::TransformInterpolation.to_net_component(),
-        transform.to_net_component(),
-        NPCControllerBundle::new(Collider::capsule(1.0, 2.0), Vec3::NEG_Y * 9.81)
-            .with_movement(45.0, 0.9, 4.0, std::f32::consts::PI * 0.20)
-            .to_net_component(),
-        //avian3d::prelude::RigidBody::Dynamic.to_net_component(),
-        //avian3d::prelude::Collider::sphere(3.0).to_net_component(),
-        //avian3d::prelude::Mass(70.0).to_net_component(),
-    ])
+    SpawnUnit2::new_with_vec(
+        rng,
+        vec![
+            ents::NPC.to_net_component(),
+            ents::SendNetworkTranformUpdates.to_net_component(),
+            avian3d::prelude::TransformInterpolation.to_net_component(),
+            transform.to_net_component(),
+            NPCControllerBundle::new(Collider::capsule(1.0, 2.0), Vec3::NEG_Y * 9.81)
+                .with_movement(45.0, 0.9, 4.0, std::f32::consts::PI * 0.20)
+                .to_net_component(),
+            //avian3d::prelude::RigidBody::Dynamic.to_net_component(),
+            //avian3d::prelude::Collider::sphere(3.0).to_net_component(),
+            //avian3d::prelude::Mass(70.0).to_net_component(),
+        ],
+    )
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
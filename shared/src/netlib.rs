@@ -1,14 +1,36 @@
-use bevy_internal::prelude::*;
+use bevy_internal::{platform::time::Instant, prelude::*};
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashMap,
-    collections::VecDeque,
+    borrow::Cow,
+    collections::{HashMap, HashSet, VecDeque},
     sync::{Arc, RwLock, atomic::AtomicUsize},
 };
 
+pub mod bandwidth;
+pub mod compression;
+pub mod conditioner;
+pub mod connection_stats;
+pub mod envelope;
+pub mod fragment;
+pub mod rate_limit;
+pub mod reliable;
+pub mod session;
+pub mod transport;
+
+use bandwidth::BandwidthBudget;
+use compression::{Compression, CompressionHeader};
+use conditioner::{ConditionedTransport, LinkConditioners, NetworkConditions};
+use connection_stats::{ConnectionStats, EndpointCounters};
+use fragment::{FragmentHeader, FragmentReassembly};
+use reliable::{
+    AckHeader, OrderedReceiveBuffer, ReliableConnection, ReliableError, ReliableHeader,
+};
+use session::{ClientHandshake, HelloCookies, Session, SessionPacket, SessionState};
+use transport::{MemoryEndpoint, MemoryNetwork, Transport, TransportKind, UdpTransport};
+
 use crate::message_io::{
-    network::{Endpoint, NetEvent, Transport},
-    node::{NodeEvent, NodeHandler},
+    network::{Endpoint, NetEvent, Transport as SocketTransport},
+    node::NodeEvent,
 };
 
 pub struct NetworkingStats {
@@ -23,6 +45,36 @@ pub struct NetworkingStats {
     pub packets_received_this_second: AtomicUsize,
     pub recent_packets_sent: RwLock<VecDeque<usize>>,
     pub recent_packets_received: RwLock<VecDeque<usize>>,
+
+    pub packets_resent_this_second: AtomicUsize,
+    pub recent_packets_resent: RwLock<VecDeque<usize>>,
+
+    /// Send budget of each connection, in bytes per second
+    pub budget_bytes_per_second: AtomicUsize,
+    pub bytes_sent_per_endpoint_this_second: DashMap<EndpointGeneral, usize>,
+    /// How many bytes each endpoint was sent in the last second
+    pub recent_bytes_sent_per_endpoint: RwLock<HashMap<EndpointGeneral, usize>>,
+    /// Entity updates pushed back to a later tick because they didn't fit the budget
+    pub updates_deferred_this_second: AtomicUsize,
+    pub recent_updates_deferred: RwLock<VecDeque<usize>>,
+
+    /// Size of the groupings we compressed, before and after compressing them
+    pub bytes_before_compression_this_second: AtomicUsize,
+    pub bytes_after_compression_this_second: AtomicUsize,
+    pub recent_bytes_before_compression: RwLock<VecDeque<usize>>,
+    pub recent_bytes_after_compression: RwLock<VecDeque<usize>>,
+
+    /// Totals since we started, added to every second along with the recent queues
+    pub bytes_sent_since_start: AtomicUsize,
+    pub bytes_received_since_start: AtomicUsize,
+    pub packets_sent_since_start: AtomicUsize,
+    pub packets_received_since_start: AtomicUsize,
+    pub packets_resent_since_start: AtomicUsize,
+    /// Events of each type we handed to our systems since we started, before rate limiting
+    pub events_received_since_start: DashMap<&'static str, usize>,
+
+    /// Traffic of each endpoint we are talking to, see [`Self::connection_stats`]
+    pub per_endpoint: DashMap<EndpointGeneral, EndpointCounters>,
 }
 
 impl Default for NetworkingStats {
@@ -38,6 +90,24 @@ impl Default for NetworkingStats {
             packets_received_this_second: AtomicUsize::new(0),
             recent_packets_sent: RwLock::new(VecDeque::new()),
             recent_packets_received: RwLock::new(VecDeque::new()),
+            packets_resent_this_second: AtomicUsize::new(0),
+            recent_packets_resent: RwLock::new(VecDeque::new()),
+            budget_bytes_per_second: AtomicUsize::new(0),
+            bytes_sent_per_endpoint_this_second: DashMap::new(),
+            recent_bytes_sent_per_endpoint: RwLock::new(HashMap::new()),
+            updates_deferred_this_second: AtomicUsize::new(0),
+            recent_updates_deferred: RwLock::new(VecDeque::new()),
+            bytes_before_compression_this_second: AtomicUsize::new(0),
+            bytes_after_compression_this_second: AtomicUsize::new(0),
+            recent_bytes_before_compression: RwLock::new(VecDeque::new()),
+            recent_bytes_after_compression: RwLock::new(VecDeque::new()),
+            bytes_sent_since_start: AtomicUsize::new(0),
+            bytes_received_since_start: AtomicUsize::new(0),
+            packets_sent_since_start: AtomicUsize::new(0),
+            packets_received_since_start: AtomicUsize::new(0),
+            packets_resent_since_start: AtomicUsize::new(0),
+            events_received_since_start: DashMap::new(),
+            per_endpoint: DashMap::new(),
         }
     }
 }
@@ -85,9 +155,124 @@ impl NetworkingStats {
             .unwrap()
             .push_back(packets_received_this_second);
 
+        let packets_resent_this_second = self
+            .packets_resent_this_second
+            .swap(0, std::sync::atomic::Ordering::Relaxed);
+        self.recent_packets_resent
+            .write()
+            .unwrap()
+            .push_back(packets_resent_this_second);
+
+        for (since_start, this_second) in [
+            (&self.bytes_sent_since_start, total_bytes_sent_this_second),
+            (
+                &self.bytes_received_since_start,
+                total_bytes_received_this_second,
+            ),
+            (&self.packets_sent_since_start, packets_sent_this_second),
+            (
+                &self.packets_received_since_start,
+                packets_received_this_second,
+            ),
+            (&self.packets_resent_since_start, packets_resent_this_second),
+        ] {
+            since_start.fetch_add(this_second, std::sync::atomic::Ordering::Relaxed);
+        }
+
+        let mut bytes_sent_per_endpoint = HashMap::new();
+        self.bytes_sent_per_endpoint_this_second
+            .retain(|endpoint, bytes| {
+                bytes_sent_per_endpoint.insert(*endpoint, *bytes);
+                false
+            });
+        *self.recent_bytes_sent_per_endpoint.write().unwrap() = bytes_sent_per_endpoint;
+
+        for mut counters in self.per_endpoint.iter_mut() {
+            counters.flush();
+        }
+
+        let updates_deferred_this_second = self
+            .updates_deferred_this_second
+            .swap(0, std::sync::atomic::Ordering::Relaxed);
+        self.recent_updates_deferred
+            .write()
+            .unwrap()
+            .push_back(updates_deferred_this_second);
+
+        let bytes_before_compression_this_second = self
+            .bytes_before_compression_this_second
+            .swap(0, std::sync::atomic::Ordering::Relaxed);
+        let bytes_after_compression_this_second = self
+            .bytes_after_compression_this_second
+            .swap(0, std::sync::atomic::Ordering::Relaxed);
+        self.recent_bytes_before_compression
+            .write()
+            .unwrap()
+            .push_back(bytes_before_compression_this_second);
+        self.recent_bytes_after_compression
+            .write()
+            .unwrap()
+            .push_back(bytes_after_compression_this_second);
+
         self.cap_queues(BASE_TICKS_PER_SECOND as usize * 60);
     }
 
+    /// Count events as they are handed to our systems
+    pub fn record_received<E: NetworkingEvent>(&self, events: &[(EndpointGeneral, E)]) {
+        for (_, event) in events {
+            *self
+                .events_received_since_start
+                .entry(event.name())
+                .or_default() += 1;
+        }
+    }
+
+    /// Count a datagram or stream message we sent
+    pub fn count_sent(&self, endpoint: EndpointGeneral, bytes: usize) {
+        self.total_bytes_sent_this_second
+            .fetch_add(bytes, std::sync::atomic::Ordering::Relaxed);
+        self.packets_sent_this_second
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        if let Some(mut counters) = self.per_endpoint.get_mut(&endpoint) {
+            counters.sent(bytes);
+        }
+    }
+
+    /// Count a datagram or stream message we received. Only endpoints that got something through
+    /// to us get counters, so ones we forgot or that never answer don't pile up.
+    pub fn count_received(&self, endpoint: EndpointGeneral, bytes: usize) {
+        self.total_bytes_received_this_second
+            .fetch_add(bytes, std::sync::atomic::Ordering::Relaxed);
+        self.packets_received_this_second
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        self.per_endpoint
+            .entry(endpoint)
+            .or_default()
+            .received(bytes, Instant::now());
+    }
+
+    /// Count reliable datagrams we are about to send again
+    pub fn count_resent(&self, endpoint: EndpointGeneral, packets: usize) {
+        self.packets_resent_this_second
+            .fetch_add(packets, std::sync::atomic::Ordering::Relaxed);
+        if let Some(mut counters) = self.per_endpoint.get_mut(&endpoint) {
+            counters.resent(packets);
+        }
+    }
+
+    /// The round trip time of a heartbeat with this endpoint
+    pub fn record_rtt(&self, endpoint: EndpointGeneral, rtt: std::time::Duration) {
+        if let Some(mut counters) = self.per_endpoint.get_mut(&endpoint) {
+            counters.set_rtt(rtt);
+        }
+    }
+
+    pub fn connection_stats(&self, endpoint: EndpointGeneral) -> Option<ConnectionStats> {
+        self.per_endpoint
+            .get(&endpoint)
+            .map(|counters| counters.snapshot(Instant::now()))
+    }
+
     fn cap_queues(&self, max_len: usize) {
         let mut recent_bytes_sent = self.recent_bytes_sent.write().unwrap();
         while recent_bytes_sent.len() > max_len {
@@ -118,21 +303,75 @@ impl NetworkingStats {
             recent_packets_received.pop_front();
         }
         drop(recent_packets_received);
+
+        let mut recent_packets_resent = self.recent_packets_resent.write().unwrap();
+        while recent_packets_resent.len() > max_len {
+            recent_packets_resent.pop_front();
+        }
+        drop(recent_packets_resent);
+
+        let mut recent_updates_deferred = self.recent_updates_deferred.write().unwrap();
+        while recent_updates_deferred.len() > max_len {
+            recent_updates_deferred.pop_front();
+        }
+        drop(recent_updates_deferred);
+
+        let mut recent_bytes_before_compression =
+            self.recent_bytes_before_compression.write().unwrap();
+        while recent_bytes_before_compression.len() > max_len {
+            recent_bytes_before_compression.pop_front();
+        }
+        drop(recent_bytes_before_compression);
+
+        let mut recent_bytes_after_compression =
+            self.recent_bytes_after_compression.write().unwrap();
+        while recent_bytes_after_compression.len() > max_len {
+            recent_bytes_after_compression.pop_front();
+        }
+        drop(recent_bytes_after_compression);
     }
 }
 
-use dashmap::DashMap;
+use dashmap::{DashMap, DashSet};
 
 #[derive(Resource, Clone)]
 pub struct NetworkingResources<TI, TO> {
-    pub event_list_incoming_udp: Arc<RwLock<Vec<(Endpoint, TI)>>>,
-    pub event_list_incoming_websocket: Arc<RwLock<Vec<(WebSocketEndpoint, TI)>>>,
-    // TODO make this hashmap instead
+    pub event_list_incoming: Arc<RwLock<Vec<(EndpointGeneral, TI)>>>,
     pub event_list_outgoing_udp: Arc<DashMap<Endpoint, Vec<TO>>>,
-    pub event_list_outgoing_websocket: Arc<DashMap<WebSocketEndpoint, Vec<TO>>>,
-    pub reliable_packet_ids_seen: Arc<RwLock<HashMap<PacketIdentifier, Tick>>>,
+    /// Events for websocket and in-memory endpoints, which deliver in order by themselves
+    pub event_list_outgoing_stream: Arc<DashMap<EndpointGeneral, Vec<TO>>>,
+    /// How to reach each kind of endpoint. The websocket transport is added by whoever owns the
+    /// sockets.
+    pub transports: Arc<DashMap<TransportKind, Arc<dyn Transport>>>,
+    /// Sequence, ack and resend state for reliable packets, per connection
+    pub reliable_connections: Arc<DashMap<EndpointGeneral, ReliableConnection>>,
+    /// Connections that stopped getting our reliable datagrams through, see
+    /// [`NetworkingResources::take_lost_endpoints`]
+    pub lost_endpoints: Arc<DashSet<EndpointGeneral>>,
+    /// Forgotten once what we queued for them has been sent, see
+    /// [`NetworkingResources::forget_endpoint_after_flush`]
+    pub forget_after_flush: Arc<DashSet<EndpointGeneral>>,
+    /// Reliable-ordered events waiting for earlier ones to arrive
+    pub ordered_incoming: Arc<DashMap<EndpointGeneral, OrderedReceiveBuffer<TI>>>,
+    /// Fragments of oversized datagrams waiting for the rest of their group
+    pub fragments_incoming: Arc<DashMap<EndpointGeneral, FragmentReassembly>>,
+    /// Delay applied to events we send ourselves, only set on the client
+    pub fake_ping: Option<FakePingSettings>,
+    /// Loss, duplication and reordering of UDP datagrams we receive, see [`NetworkConditions`]
+    pub incoming_conditioners: Option<Arc<LinkConditioners>>,
+    /// What each UDP connection may still send this tick
+    pub bandwidth: Arc<DashMap<EndpointGeneral, BandwidthBudget>>,
+    /// Budget given to new connections
+    pub bytes_per_second: usize,
+    /// Encryption keys and handshake progress, per UDP connection
+    pub sessions: Arc<DashMap<Endpoint, SessionState>>,
+    /// Answers to hellos, so the server keeps no state for spoofed ones
+    pub hello_cookies: Arc<HelloCookies>,
+    /// True on the server, which answers handshakes instead of starting them
+    pub is_listener: bool,
+    /// Codec each endpoint told us it can read, agreed in `ConnectRequest` and `WorldData2`
+    pub compression: Arc<DashMap<EndpointGeneral, Compression>>,
     pub networking_stats: Arc<NetworkingStats>,
-    pub handler: Option<NodeHandler<()>>,
     pub con_str: Arc<(String, u16)>,
 }
 
@@ -149,7 +388,7 @@ impl MainServerEndpoint {
     pub fn as_websocket(&self) -> Option<WebSocketEndpoint> {
         match &self.0 {
             EndpointGeneral::WebSocket(ws_endpoint) => Some(*ws_endpoint),
-            EndpointGeneral::UDP(_) => None,
+            EndpointGeneral::UDP(_) | EndpointGeneral::Memory(_) => None,
         }
     }
 }
@@ -161,7 +400,9 @@ pub struct NetworkConnectionTarget {
     pub port: u16,
 }
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[derive(
+    Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash, PartialOrd, Ord, Default,
+)]
 pub struct Tick(pub u64);
 
 impl Tick {
@@ -190,31 +431,200 @@ pub use crate::event::client::EventToClient;
 pub use crate::event::server::EventToServer;
 use crate::{BASE_TICKS_PER_SECOND, CurrentTick};
 
+/// How an event gets to the other side. Declared per event struct with a `// delivery: <class>`
+/// comment above it, see `build.rs`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeliveryClass {
+    /// Sent immediately, may be lost. For things that are resent constantly anyway.
+    Unreliable,
+    /// Sent on the next tick and resent until acked, may arrive in any order. The default.
+    ReliableUnordered,
+    /// Like `ReliableUnordered`, but delivered in the order it was sent.
+    ReliableOrdered,
+}
+
+/// Implemented in the generated code for `EventToServer` and `EventToClient`
 pub trait NetworkingEvent:
     Clone + Serialize + for<'de> Deserialize<'de> + Send + Sync + 'static + core::fmt::Debug
 {
+    fn delivery_class(&self) -> DeliveryClass;
+    /// The event's type name, like `SpawnMan`
+    fn name(&self) -> &'static str;
 }
-impl NetworkingEvent for EventToServer {}
-impl NetworkingEvent for EventToClient {}
-
-type PacketIdentifier = u32;
-type DuplicationIdentifier = u8;
 
 #[derive(Deserialize, Serialize)]
 pub enum EventGroupingOwned<T> {
     Single(T),
     Batch(Vec<T>),
-    Reliable(PacketIdentifier, DuplicationIdentifier, Tick, Vec<T>),
+    Reliable(ReliableHeader, Vec<T>),
+    /// Sent when we owe acks but have no reliable data to piggyback them on
+    Ack(AckHeader),
+    /// One piece of a serialized grouping that was too large for a single datagram
+    Fragment(FragmentHeader, Vec<u8>),
+    /// Another serialized grouping, compressed
+    Compressed(CompressionHeader, Vec<u8>),
 }
 
 #[derive(Serialize)]
 pub enum EventGroupingRef<'a, T> {
     Single(&'a T),
     Batch(&'a [T]),
-    Reliable(PacketIdentifier, DuplicationIdentifier, Tick, &'a [T]),
+    Reliable(ReliableHeader, &'a [T]),
+    Ack(AckHeader),
+    Fragment(FragmentHeader, &'a [u8]),
+    Compressed(CompressionHeader, &'a [u8]),
+}
+
+impl<TI, TO> NetworkingResources<TI, TO> {
+    /// Drop all reliability state for this endpoint, e.g. after the player disconnected.
+    pub fn forget_endpoint(&self, endpoint: EndpointGeneral) {
+        self.reliable_connections.remove(&endpoint);
+        self.ordered_incoming.remove(&endpoint);
+        self.fragments_incoming.remove(&endpoint);
+        self.bandwidth.remove(&endpoint);
+        self.compression.remove(&endpoint);
+        self.networking_stats.per_endpoint.remove(&endpoint);
+        self.forget_after_flush.remove(&endpoint);
+        if let Some(transport) = self.transports.get(&endpoint.kind()) {
+            transport.forget(endpoint);
+        }
+        if let Some(conditioners) = &self.incoming_conditioners {
+            conditioners.forget(endpoint);
+        }
+        if let EndpointGeneral::UDP(endpoint) = endpoint {
+            self.sessions.remove(&endpoint);
+        }
+    }
+
+    /// Like [`Self::forget_endpoint`], but only once the events already queued for them, like a
+    /// goodbye, have been sent.
+    pub fn forget_endpoint_after_flush(&self, endpoint: EndpointGeneral) {
+        self.forget_after_flush.insert(endpoint);
+    }
+
+    /// Forget the endpoints waiting for a flush that `flushed` says are done
+    fn forget_flushed_endpoints(&self, flushed: impl Fn(&EndpointGeneral) -> bool) {
+        let done: Vec<_> = self
+            .forget_after_flush
+            .iter()
+            .map(|e| *e)
+            .filter(|e| flushed(e))
+            .collect();
+        for endpoint in done {
+            self.forget_endpoint(endpoint);
+        }
+    }
+
+    /// Connections whose reliable datagrams stopped getting through since we last asked. The
+    /// game should disconnect them, nothing more we send reliably will arrive.
+    pub fn take_lost_endpoints(&self) -> Vec<EndpointGeneral> {
+        let lost: Vec<_> = self.lost_endpoints.iter().map(|e| *e).collect();
+        for endpoint in &lost {
+            self.lost_endpoints.remove(endpoint);
+        }
+        lost
+    }
+
+    fn lose_endpoint(&self, endpoint: EndpointGeneral, error: ReliableError) {
+        error!(
+            ?endpoint,
+            ?error,
+            "Reliable delivery failed, dropping the connection"
+        );
+        self.lost_endpoints.insert(endpoint);
+    }
+
+    /// Hand serialized data to the transport that reaches this endpoint
+    pub fn transport_send(&self, endpoint: EndpointGeneral, data: &[u8]) -> bool {
+        match self.transports.get(&endpoint.kind()) {
+            Some(transport) => transport.send(endpoint, data),
+            None => {
+                debug!(?endpoint, "No transport for endpoint, dropping data");
+                false
+            }
+        }
+    }
+
+    /// Bytes we can still send to this endpoint this tick. Only UDP is budgeted.
+    pub fn remaining_budget(&self, endpoint: EndpointGeneral) -> usize {
+        match endpoint {
+            EndpointGeneral::WebSocket(_) | EndpointGeneral::Memory(_) => usize::MAX,
+            EndpointGeneral::UDP(_) => self
+                .bandwidth
+                .get(&endpoint)
+                .map(|budget| budget.remaining())
+                .unwrap_or_else(|| BandwidthBudget::new(self.bytes_per_second).remaining()),
+        }
+    }
+
+    fn spend_budget(&self, endpoint: EndpointGeneral, bytes: usize) {
+        self.bandwidth
+            .entry(endpoint)
+            .or_insert_with(|| BandwidthBudget::new(self.bytes_per_second))
+            .spend(bytes);
+        *self
+            .networking_stats
+            .bytes_sent_per_endpoint_this_second
+            .entry(endpoint)
+            .or_default() += bytes;
+    }
+}
+
+impl<TI, TO: NetworkingEvent> NetworkingResources<TI, TO> {
+    /// Wrap a serialized grouping in [`EventGroupingRef::Compressed`] if this endpoint can read
+    /// that and it is worth it
+    pub fn compress_grouping<'a>(
+        &self,
+        endpoint: EndpointGeneral,
+        data: &'a [u8],
+    ) -> Cow<'a, [u8]> {
+        let Some(codec) = self.compression.get(&endpoint).map(|c| *c) else {
+            return Cow::Borrowed(data);
+        };
+        let Some((header, compressed)) = compression::compress(codec, data) else {
+            return Cow::Borrowed(data);
+        };
+
+        let wrapped =
+            postcard::to_stdvec(&EventGroupingRef::<TO>::Compressed(header, &compressed)).unwrap();
+        self.networking_stats
+            .bytes_before_compression_this_second
+            .fetch_add(data.len(), std::sync::atomic::Ordering::Relaxed);
+        self.networking_stats
+            .bytes_after_compression_this_second
+            .fetch_add(wrapped.len(), std::sync::atomic::Ordering::Relaxed);
+        Cow::Owned(wrapped)
+    }
 }
 
 impl<TI: NetworkingEvent, TO: NetworkingEvent> NetworkingResources<TI, TO> {
+    /// Send an event over the channel its type declares
+    pub fn send_event(&self, endpoint: EndpointGeneral, event: &TO) {
+        match event.delivery_class() {
+            DeliveryClass::Unreliable => {
+                self.send_outgoing_event_now(endpoint, event, self.fake_ping)
+            }
+            DeliveryClass::ReliableUnordered | DeliveryClass::ReliableOrdered => {
+                self.send_outgoing_event_next_tick(endpoint, event)
+            }
+        }
+    }
+
+    /// Send events over the channels their types declare
+    pub fn send_event_batch(&self, endpoint: EndpointGeneral, events: &[TO]) {
+        let (unreliable, reliable): (Vec<TO>, Vec<TO>) = events
+            .iter()
+            .cloned()
+            .partition(|e| e.delivery_class() == DeliveryClass::Unreliable);
+
+        if !unreliable.is_empty() {
+            self.send_outgoing_event_now_batch(endpoint, &unreliable, self.fake_ping);
+        }
+        if !reliable.is_empty() {
+            self.send_outgoing_event_next_tick_batch(endpoint, &reliable);
+        }
+    }
+
     pub fn send_outgoing_event_now(
         &self,
         endpoint: EndpointGeneral,
@@ -222,10 +632,10 @@ impl<TI: NetworkingEvent, TO: NetworkingEvent> NetworkingResources<TI, TO> {
         delay: Option<FakePingSettings>,
     ) {
         match endpoint {
-            EndpointGeneral::WebSocket(ws_endpoint) => {
+            EndpointGeneral::WebSocket(_) | EndpointGeneral::Memory(_) => {
                 // TODO make this faster- but queue for now
-                self.event_list_outgoing_websocket
-                    .entry(ws_endpoint)
+                self.event_list_outgoing_stream
+                    .entry(endpoint)
                     .or_default()
                     .push(event.clone());
             }
@@ -252,10 +662,10 @@ impl<TI: NetworkingEvent, TO: NetworkingEvent> NetworkingResources<TI, TO> {
         delay: Option<FakePingSettings>,
     ) {
         match endpoint {
-            EndpointGeneral::WebSocket(ws_endpoint) => {
+            EndpointGeneral::WebSocket(_) | EndpointGeneral::Memory(_) => {
                 // TODO make this faster- but queue for now
-                self.event_list_outgoing_websocket
-                    .entry(ws_endpoint)
+                self.event_list_outgoing_stream
+                    .entry(endpoint)
                     .or_default()
                     .extend_from_slice(events);
             }
@@ -277,9 +687,9 @@ impl<TI: NetworkingEvent, TO: NetworkingEvent> NetworkingResources<TI, TO> {
 
     pub fn send_outgoing_event_next_tick(&self, endpoint: EndpointGeneral, event: &TO) {
         match endpoint {
-            EndpointGeneral::WebSocket(ws_endpoint) => {
-                self.event_list_outgoing_websocket
-                    .entry(ws_endpoint)
+            EndpointGeneral::WebSocket(_) | EndpointGeneral::Memory(_) => {
+                self.event_list_outgoing_stream
+                    .entry(endpoint)
                     .or_default()
                     .push(event.clone());
             }
@@ -290,9 +700,9 @@ impl<TI: NetworkingEvent, TO: NetworkingEvent> NetworkingResources<TI, TO> {
     }
     pub fn send_outgoing_event_next_tick_batch(&self, endpoint: EndpointGeneral, events: &[TO]) {
         match endpoint {
-            EndpointGeneral::WebSocket(ws_endpoint) => {
-                self.event_list_outgoing_websocket
-                    .entry(ws_endpoint)
+            EndpointGeneral::WebSocket(_) | EndpointGeneral::Memory(_) => {
+                self.event_list_outgoing_stream
+                    .entry(endpoint)
                     .or_default()
                     .extend_from_slice(events);
             }
@@ -312,6 +722,87 @@ pub struct WebSocketEndpoint {
 pub enum EndpointGeneral {
     WebSocket(WebSocketEndpoint),
     UDP(Endpoint),
+    Memory(MemoryEndpoint),
+}
+
+impl EndpointGeneral {
+    pub fn kind(&self) -> TransportKind {
+        match self {
+            EndpointGeneral::WebSocket(_) => TransportKind::WebSocket,
+            EndpointGeneral::UDP(_) => TransportKind::Udp,
+            EndpointGeneral::Memory(_) => TransportKind::Memory,
+        }
+    }
+}
+
+/// Largest datagram we send without fragmenting it, before encryption adds its overhead
+pub const TARGET_DATAGRAM_SIZE: usize = 1450 - session::SESSION_OVERHEAD;
+
+/// Encrypt and send one datagram. On the client, datagrams sent before the handshake finished
+/// are held until it does.
+fn send_sealed_udp<TI, TO>(
+    resources: &NetworkingResources<TI, TO>,
+    endpoint: Endpoint,
+    data: &[u8],
+) {
+    let Some(mut state) = resources.sessions.get_mut(&endpoint) else {
+        debug!(?endpoint, "No session with endpoint, dropping datagram");
+        return;
+    };
+    let session = match &mut *state {
+        SessionState::Handshaking(handshake) => {
+            if handshake.queued.len() < session::MAX_QUEUED_DATAGRAMS {
+                handshake.queued.push(data.to_vec());
+            } else {
+                warn!(?endpoint, "Handshake is taking too long, dropping datagram");
+            }
+            return;
+        }
+        SessionState::Established { session, .. } => session,
+    };
+
+    // Sent while still holding the session so counters go out in order
+    let sealed = session.seal(data);
+    resources.transport_send(EndpointGeneral::UDP(endpoint), &sealed);
+    drop(state);
+
+    resources.spend_budget(EndpointGeneral::UDP(endpoint), sealed.len());
+    resources
+        .networking_stats
+        .count_sent(EndpointGeneral::UDP(endpoint), sealed.len());
+}
+
+/// Send one serialized grouping, split into fragments if it doesn't fit in a single datagram
+fn send_datagram_udp<TI, TO: NetworkingEvent>(
+    resources: &NetworkingResources<TI, TO>,
+    endpoint: Endpoint,
+    data: &[u8],
+) {
+    let grouping = resources.compress_grouping(EndpointGeneral::UDP(endpoint), data);
+    let data = grouping.as_ref();
+    if data.len() <= TARGET_DATAGRAM_SIZE {
+        send_sealed_udp(resources, endpoint, data);
+        return;
+    }
+
+    let fragments = match fragment::split(rand::random(), data) {
+        Ok(f) => f,
+        Err(e) => {
+            error!(?endpoint, ?e, "Datagram is too large to send, dropping it");
+            return;
+        }
+    };
+
+    trace!(
+        data_len = data.len(),
+        count = fragments.len(),
+        "Fragmenting datagram"
+    );
+    for (header, payload) in fragments {
+        let fragment =
+            postcard::to_stdvec(&EventGroupingRef::<TO>::Fragment(header, payload)).unwrap();
+        send_sealed_udp(resources, endpoint, &fragment);
+    }
 }
 
 fn send_outgoing_event_now_udp<TI, TO: NetworkingEvent>(
@@ -321,21 +812,7 @@ fn send_outgoing_event_now_udp<TI, TO: NetworkingEvent>(
 ) {
     trace!(?event, "Sending event");
     let event = postcard::to_stdvec(&EventGroupingRef::Single(event)).unwrap();
-    resources
-        .handler
-        .as_ref()
-        .expect("must have udp handler")
-        .network()
-        .send(endpoint, &event);
-
-    resources
-        .networking_stats
-        .total_bytes_sent_this_second
-        .fetch_add(event.len(), std::sync::atomic::Ordering::Relaxed);
-    resources
-        .networking_stats
-        .packets_sent_this_second
-        .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    send_datagram_udp(resources, endpoint, &event);
 }
 
 fn send_outgoing_event_now_batch_udp<TI, TO: NetworkingEvent>(
@@ -346,77 +823,47 @@ fn send_outgoing_event_now_batch_udp<TI, TO: NetworkingEvent>(
     trace!(?event, "Sending batch event");
     let data = postcard::to_stdvec(&EventGroupingRef::Batch(event)).unwrap();
     if data.len() > 6000 {
+        // Unreliable, so losing any one fragment loses the whole batch
         warn!(data_len = data.len(), "Sending large batch event");
     }
-    resources
-        .handler
-        .as_ref()
-        .expect("must have udp handler")
-        .network()
-        .send(endpoint, &data);
-
-    resources
-        .networking_stats
-        .total_bytes_sent_this_second
-        .fetch_add(data.len(), std::sync::atomic::Ordering::Relaxed);
-
-    resources
-        .networking_stats
-        .packets_sent_this_second
-        .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    send_datagram_udp(resources, endpoint, &data);
 }
 
-fn send_outgoing_event_reliable_internal_chunk_udp<TI, TO: NetworkingEvent>(
+fn build_reliable_chunk_udp<TI, TO: NetworkingEvent>(
     resources: &NetworkingResources<TI, TO>,
     endpoint: Endpoint,
     event: &[TO],
     tick: &Tick,
-) {
-    trace!(?event, "Sending doubled batch event");
-    let handler = resources.handler.as_ref().expect("must have udp handler");
-
-    let packet_id: PacketIdentifier = rand::random();
-    let dedup_id: DuplicationIdentifier = rand::random();
-    let data = postcard::to_stdvec(&EventGroupingRef::Reliable(
-        packet_id, dedup_id, *tick, event,
-    ))
-    .unwrap();
-    let data_len = data.len() * 2;
-    handler.network().send(endpoint, &data);
-
-    let dedup_id: DuplicationIdentifier = rand::random();
-    let data = postcard::to_stdvec(&EventGroupingRef::Reliable(
-        packet_id, dedup_id, *tick, event,
-    ))
-    .unwrap();
-    handler.network().send(endpoint, &data);
-
-    resources
-        .networking_stats
-        .total_bytes_sent_this_second
-        .fetch_add(data_len, std::sync::atomic::Ordering::Relaxed);
-
-    resources
-        .networking_stats
-        .packets_sent_this_second
-        .fetch_add(2, std::sync::atomic::Ordering::Relaxed);
-
-    if data_len > 3000 {
-        warn!(data_len = data_len, "Sending large reliable batch event");
+    ordered: bool,
+) -> Vec<u8> {
+    trace!(?event, ordered, "Building reliable batch event");
+
+    let mut connection = resources
+        .reliable_connections
+        .entry(EndpointGeneral::UDP(endpoint))
+        .or_default();
+    let header = connection.next_header(*tick, ordered);
+    let sequence = header.sequence;
+    let data = postcard::to_stdvec(&EventGroupingRef::Reliable(header, event)).unwrap();
+    if let Err(e) = connection.track_sent(sequence, data.clone(), Instant::now()) {
+        resources.lose_endpoint(EndpointGeneral::UDP(endpoint), e);
     }
+
+    data
 }
 
-fn send_outgoing_event_reliable_internal_udp<TI, TO: NetworkingEvent>(
+/// Split events into reliable datagrams, registering each with the endpoint's resend queue
+fn build_reliable_datagrams_udp<TI, TO: NetworkingEvent>(
     resources: &NetworkingResources<TI, TO>,
     endpoint: Endpoint,
     mut event: &[TO],
     tick: &Tick,
-) {
-    const TARGET_PACKET_SIZE: usize = 1450;
+    ordered: bool,
+) -> Vec<Vec<u8>> {
+    let size_probe = ReliableHeader::size_probe(*tick);
+    let mut datagrams = vec![];
     while !event.is_empty() {
         let mut chunk_size = 1;
-        let packet_id: PacketIdentifier = rand::random();
-        let dedup_id: DuplicationIdentifier = rand::random();
         // We construct chunks until we reach the larest that fits in one chunk
         // TODO improve this a lot
         'send: loop {
@@ -426,30 +873,92 @@ fn send_outgoing_event_reliable_internal_udp<TI, TO: NetworkingEvent>(
 
             chunk_size += 1;
             let data = postcard::to_stdvec(&EventGroupingRef::Reliable(
-                packet_id,
-                dedup_id,
-                *tick,
+                size_probe.clone(),
                 &event[..chunk_size],
             ))
             .unwrap();
             let data_len = data.len();
 
-            if data_len > TARGET_PACKET_SIZE {
+            if data_len > TARGET_DATAGRAM_SIZE {
                 chunk_size -= 1;
                 break 'send;
             }
         }
-        send_outgoing_event_reliable_internal_chunk_udp(
+        datagrams.push(build_reliable_chunk_udp(
             resources,
             endpoint,
             &event[..chunk_size],
             tick,
-        );
+            ordered,
+        ));
         if chunk_size >= event.len() {
             break;
         }
         event = &event[chunk_size..];
     }
+    datagrams
+}
+
+/// Send already serialized datagrams, delayed by fake ping if we have it
+fn send_datagrams_udp<TI: NetworkingEvent, TO: NetworkingEvent>(
+    resources: &NetworkingResources<TI, TO>,
+    endpoint: Endpoint,
+    datagrams: Vec<Vec<u8>>,
+    fake_ping: Option<FakePingSettings>,
+) {
+    if datagrams.is_empty() {
+        return;
+    }
+
+    if let Some(fake_ping) = fake_ping
+        && fake_ping.to_server_ms > 0
+    {
+        let resources = resources.clone();
+        std::thread::spawn(move || {
+            let delay = fake_ping.get_delay_to_server();
+            std::thread::sleep(std::time::Duration::from_millis(delay));
+            send_datagrams_udp(&resources, endpoint, datagrams, None);
+        });
+        return;
+    }
+
+    for data in &datagrams {
+        send_datagram_udp(resources, endpoint, data);
+    }
+}
+
+/// Resend whatever timed out without an ack, and send bare acks to endpoints that we are not
+/// sending any reliable data to this tick.
+fn flush_reliable_connections_udp<TI: NetworkingEvent, TO: NetworkingEvent>(
+    resources: &NetworkingResources<TI, TO>,
+    endpoints_with_data: &HashSet<Endpoint>,
+    fake_ping: Option<FakePingSettings>,
+) {
+    let now = Instant::now();
+    for mut connection in resources.reliable_connections.iter_mut() {
+        let EndpointGeneral::UDP(endpoint) = *connection.key() else {
+            continue;
+        };
+
+        let mut datagrams = match connection.collect_resends(now) {
+            Ok(d) => d,
+            Err(e) => {
+                resources.lose_endpoint(*connection.key(), e);
+                vec![]
+            }
+        };
+        resources
+            .networking_stats
+            .count_resent(*connection.key(), datagrams.len());
+
+        if !endpoints_with_data.contains(&endpoint)
+            && let Some(ack) = connection.take_pending_ack()
+        {
+            datagrams.push(postcard::to_stdvec(&EventGroupingRef::<TO>::Ack(ack)).unwrap());
+        }
+
+        send_datagrams_udp(resources, endpoint, datagrams, fake_ping);
+    }
 }
 
 fn send_outgoing_event_next_tick_udp<TI, TO: NetworkingEvent>(
@@ -483,62 +992,190 @@ pub struct FakePingSettings {
     pub jitter_ms: u64,
 }
 
+/// How many events from the front of `events` fit in `budget`, at least one unless it is zero
+fn events_within_budget<TO: NetworkingEvent>(events: &[TO], budget: usize) -> usize {
+    let mut used = 0;
+    for (i, event) in events.iter().enumerate() {
+        used += postcard::experimental::serialized_size(event).unwrap_or_default();
+        if used > budget {
+            return if i == 0 && budget > 0 { 1 } else { i };
+        }
+    }
+    events.len()
+}
+
 pub fn flush_outgoing_events_udp<TI: NetworkingEvent, TO: NetworkingEvent>(
     tick: Res<CurrentTick>,
     resources: Res<NetworkingResources<TI, TO>>,
     fake_ping: Option<Res<FakePingSettings>>,
 ) {
+    let fake_ping = fake_ping.as_deref().cloned();
+    let mut endpoints_with_data = HashSet::new();
+
+    for mut budget in resources.bandwidth.iter_mut() {
+        budget.refill();
+    }
+
     resources.event_list_outgoing_udp.retain(|&key, value| {
-        let value = std::mem::take(&mut *value);
-        let resources = resources.clone();
-        let tick = tick.0;
-        let fake_ping = fake_ping.as_deref().cloned();
-        std::thread::spawn(move || {
-            // simulate fake ping
-            // TODO refactor fake ping below too
-            if let Some(fake_ping) = fake_ping {
-                if fake_ping.to_server_ms > 0 {
-                    let delay = fake_ping.get_delay_to_server();
+        // Forgotten while events were still queued for them. Sending would bring back the
+        // reliability state we just dropped, and without a session it can't go anywhere anyway.
+        if !resources.sessions.contains_key(&key) {
+            debug!(endpoint = ?key, "No session with endpoint, dropping its events");
+            return false;
+        }
+        // Whatever doesn't fit in the budget waits for the next tick, in order
+        let sendable =
+            events_within_budget(value, resources.remaining_budget(EndpointGeneral::UDP(key)));
+        if sendable == 0 {
+            return !value.is_empty();
+        }
+        endpoints_with_data.insert(key);
+        let deferred = value.split_off(sendable);
+        let sending = std::mem::replace(&mut *value, deferred);
+        // Sequences are taken here, in tick order, and only the (fake ping delayed) send happens
+        // on another thread. Runs of the same delivery class go out in the order they were queued
+        // so nothing arrives before the spawn of the unit it updates.
+        let mut datagrams = vec![];
+        for run in sending.chunk_by(|a, b| a.delivery_class() == b.delivery_class()) {
+            let ordered = run[0].delivery_class() == DeliveryClass::ReliableOrdered;
+            datagrams.extend(build_reliable_datagrams_udp(
+                &resources, key, run, &tick.0, ordered,
+            ));
+        }
+        send_datagrams_udp(&resources, key, datagrams, fake_ping);
+
+        !value.is_empty()
+    });
+
+    flush_reliable_connections_udp(&resources, &endpoints_with_data, fake_ping);
+    resend_client_hellos_udp(&resources);
+    expire_stale_sessions_udp(&resources);
+    // Waiting on whatever didn't fit in their budget this tick
+    resources.forget_flushed_endpoints(|endpoint| match endpoint {
+        EndpointGeneral::UDP(endpoint) => !resources.event_list_outgoing_udp.contains_key(endpoint),
+        _ => false,
+    });
+}
 
-                    std::thread::sleep(std::time::Duration::from_millis(delay));
+/// Forget clients that said hello and then never sent anything we could authenticate
+fn expire_stale_sessions_udp<TI, TO>(resources: &NetworkingResources<TI, TO>) {
+    if !resources.is_listener {
+        return;
+    }
+    let now = Instant::now();
+    let stale: Vec<_> = resources
+        .sessions
+        .iter()
+        .filter(|state| state.established().is_some_and(|s| s.is_stale(now)))
+        .map(|state| *state.key())
+        .collect();
+    for endpoint in stale {
+        debug!(?endpoint, "Dropping session that never authenticated");
+        resources.forget_endpoint(EndpointGeneral::UDP(endpoint));
+    }
+}
+
+/// Repeat our hello until the server answers it, in case it was lost
+fn resend_client_hellos_udp<TI, TO>(resources: &NetworkingResources<TI, TO>) {
+    for mut state in resources.sessions.iter_mut() {
+        let endpoint = *state.key();
+        if let SessionState::Handshaking(handshake) = &mut *state
+            && handshake.last_sent.elapsed() >= session::HANDSHAKE_RESEND
+        {
+            trace!(?endpoint, "Resending client hello");
+            resources.transport_send(EndpointGeneral::UDP(endpoint), &handshake.hello());
+            handshake.last_sent = Instant::now();
+        }
+    }
+}
+
+/// Send everything queued for websocket and in-memory endpoints, one batch per endpoint
+pub fn flush_outgoing_events_stream<TI: NetworkingEvent, TO: NetworkingEvent>(
+    resources: Res<NetworkingResources<TI, TO>>,
+) {
+    resources
+        .event_list_outgoing_stream
+        .retain(|&endpoint, events| {
+            let data = match postcard::to_stdvec(&EventGroupingRef::Batch(events)) {
+                Ok(d) => d,
+                Err(e) => {
+                    warn!(?endpoint, ?e, "Failed to serialize outgoing events");
+                    return false;
                 }
+            };
+            let data = resources.compress_grouping(endpoint, &data);
+            if !resources.transport_send(endpoint, &data) {
+                // They dropped the connection but may still be a "Connected Player", and can come
+                // back on a new one
+                debug!(?endpoint, "Endpoint is gone, dropping its events");
+                return false;
             }
 
-            // send actual event
-            send_outgoing_event_reliable_internal_udp(&resources, key, &value, &tick);
+            resources.networking_stats.count_sent(endpoint, data.len());
+            false
         });
-
-        false
-    })
+    resources.forget_flushed_endpoints(|endpoint| !matches!(endpoint, EndpointGeneral::UDP(_)));
 }
 
 pub fn setup_incoming_server<TI: NetworkingEvent, TO: NetworkingEvent>(
     commands: Commands,
     config: Res<NetworkConnectionTarget>,
+    game_config: Res<crate::Config>,
+    memory: Option<Res<MemoryNetwork>>,
+    conditions: Option<Res<NetworkConditions>>,
 ) {
+    let bytes_per_second = game_config
+        .bandwidth_bytes_per_second
+        .unwrap_or(bandwidth::DEFAULT_BYTES_PER_SECOND);
     // client does not need fake ping
-    setup_incoming_shared::<TI, TO>(commands, &config.ip, config.port, true, None);
+    setup_incoming_shared::<TI, TO>(
+        commands,
+        &config.ip,
+        config.port,
+        true,
+        None,
+        bytes_per_second,
+        memory.as_deref().cloned(),
+        conditions
+            .as_deref()
+            .copied()
+            .or(game_config.network_conditions),
+    );
 }
 
 pub fn setup_incoming_client<TI: NetworkingEvent, TO: NetworkingEvent>(
     commands: Commands,
     config: Res<NetworkConnectionTarget>,
     fake_ping: Option<Res<FakePingSettings>>,
+    memory: Option<Res<MemoryNetwork>>,
+    conditions: Option<Res<NetworkConditions>>,
 ) {
-    setup_incoming_shared::<TI, TO>(commands, &config.ip, config.port, false, fake_ping);
+    setup_incoming_shared::<TI, TO>(
+        commands,
+        &config.ip,
+        config.port,
+        false,
+        fake_ping,
+        bandwidth::DEFAULT_BYTES_PER_SECOND,
+        memory.as_deref().cloned(),
+        conditions.as_deref().copied(),
+    );
 }
 
+/// Connects over `memory` instead of UDP if we have one. `conditions` only apply to UDP.
+#[allow(clippy::too_many_arguments)]
 fn setup_incoming_shared<TI: NetworkingEvent, TO: NetworkingEvent>(
     mut commands: Commands,
     mut ip: &str,
     port: u16,
     is_listener: bool,
     fake_ping: Option<Res<FakePingSettings>>,
+    bytes_per_second: usize,
+    memory: Option<MemoryNetwork>,
+    conditions: Option<NetworkConditions>,
 ) {
     info!(is_listener, "Seting up networking!");
 
-    let (handler, listener) = crate::message_io::node::split::<()>();
-
     info!(
         "Setup networking resources for {}",
         std::any::type_name::<NetworkingResources::<TI, TO>>()
@@ -548,33 +1185,97 @@ fn setup_incoming_shared<TI: NetworkingEvent, TO: NetworkingEvent>(
         ip = "[::]";
     }
     let con_str = (ip.to_string(), port);
-    if is_listener {
-        let (_, udp_addr) = handler
-            .network()
-            .listen(Transport::Udp, con_str.clone())
-            .unwrap();
+    let sessions: Arc<DashMap<Endpoint, SessionState>> = Default::default();
+    let transports: Arc<DashMap<TransportKind, Arc<dyn Transport>>> = Default::default();
+    let mut memory_inbox = None;
+    let mut udp_listener = None;
+    let mut incoming_conditioners = None;
+
+    if let Some(network) = memory {
+        let local = if is_listener {
+            MemoryEndpoint::SERVER
+        } else {
+            commands.insert_resource(MainServerEndpoint(EndpointGeneral::Memory(
+                MemoryEndpoint::SERVER,
+            )));
+            MemoryEndpoint::random_client()
+        };
+        memory_inbox = Some(network.bind(local));
+        transports.insert(TransportKind::Memory, Arc::new(network.transport(local)));
 
-        info!(?udp_addr, "Listening")
+        info!(?local, "Using in-memory transport");
     } else {
-        let (_endpoint, addr) = handler
-            .network()
-            .connect(Transport::Udp, con_str.clone())
-            .unwrap();
+        let (handler, listener) = crate::message_io::node::split::<()>();
+
+        if is_listener {
+            let (_, udp_addr) = handler
+                .network()
+                .listen(SocketTransport::Udp, con_str.clone())
+                .unwrap();
+
+            info!(?udp_addr, "Listening")
+        } else {
+            let (endpoint, addr) = handler
+                .network()
+                .connect(SocketTransport::Udp, con_str.clone())
+                .unwrap();
+
+            // #[cfg(not(feature = "web"))]
+            commands.insert_resource(MainServerEndpoint(EndpointGeneral::UDP(endpoint)));
 
-        // #[cfg(not(feature = "web"))]
-        commands.insert_resource(MainServerEndpoint(EndpointGeneral::UDP(_endpoint)));
+            // Anything we send before the server answers waits in the handshake
+            let handshake = ClientHandshake::new(Instant::now());
+            handler.network().send(endpoint, &handshake.hello());
+            sessions.insert(endpoint, SessionState::Handshaking(handshake));
 
-        info!(?addr, "Connected");
+            info!(?addr, "Connected");
+        }
+
+        let udp: Arc<dyn Transport> = match conditions {
+            Some(conditions) if !conditions.outgoing.is_perfect() => {
+                info!(?conditions.outgoing, "Simulating outgoing network conditions");
+                Arc::new(ConditionedTransport {
+                    inner: UdpTransport(handler),
+                    conditioners: LinkConditioners::new(conditions.outgoing, conditions.seed),
+                })
+            }
+            _ => Arc::new(UdpTransport(handler)),
+        };
+        transports.insert(TransportKind::Udp, udp);
+        if let Some(conditions) = conditions
+            && !conditions.incoming.is_perfect()
+        {
+            info!(?conditions.incoming, "Simulating incoming network conditions");
+            // Different rolls than the outgoing direction
+            let seed = conditions.seed.wrapping_add(1);
+            incoming_conditioners =
+                Some(Arc::new(LinkConditioners::new(conditions.incoming, seed)));
+        }
+        udp_listener = Some(listener);
     }
 
     let res = NetworkingResources::<TI, TO> {
-        handler: Some(handler.clone()),
-        event_list_incoming_udp: Default::default(),
+        event_list_incoming: Default::default(),
         event_list_outgoing_udp: Default::default(),
-        reliable_packet_ids_seen: Default::default(),
-        networking_stats: Arc::new(NetworkingStats::default()),
-        event_list_incoming_websocket: Default::default(),
-        event_list_outgoing_websocket: Default::default(),
+        event_list_outgoing_stream: Default::default(),
+        transports,
+        reliable_connections: Default::default(),
+        lost_endpoints: Default::default(),
+        forget_after_flush: Default::default(),
+        ordered_incoming: Default::default(),
+        fragments_incoming: Default::default(),
+        fake_ping: fake_ping.as_deref().cloned(),
+        incoming_conditioners,
+        bandwidth: Default::default(),
+        bytes_per_second,
+        sessions,
+        hello_cookies: Arc::new(HelloCookies::new(Instant::now())),
+        is_listener,
+        compression: Default::default(),
+        networking_stats: Arc::new(NetworkingStats {
+            budget_bytes_per_second: AtomicUsize::new(bytes_per_second),
+            ..Default::default()
+        }),
         con_str: Arc::new(con_str),
     };
 
@@ -582,15 +1283,26 @@ fn setup_incoming_shared<TI: NetworkingEvent, TO: NetworkingEvent>(
     commands.insert_resource(res.clone());
     commands.remove_resource::<NetworkConnectionTarget>();
 
+    if let Some(inbox) = memory_inbox {
+        let res2 = res.clone();
+        std::thread::spawn(move || {
+            inbox.for_each(|from, data| {
+                on_data_incoming(&res2, EndpointGeneral::Memory(from), &data)
+            });
+        });
+    }
+
     // web doesn't support threads
     // server must support both ws and udp listeners
     #[cfg(feature = "udp")]
     {
-        let res2 = res.clone();
-        let fake_ping = fake_ping.as_deref().cloned();
-        std::thread::spawn(move || {
-            listener.for_each(|event| on_node_event_incoming(&res2, event, fake_ping.as_ref()));
-        });
+        if let Some(listener) = udp_listener {
+            let res2 = res.clone();
+            let fake_ping = fake_ping.as_deref().cloned();
+            std::thread::spawn(move || {
+                listener.for_each(|event| on_node_event_incoming(&res2, event, fake_ping.as_ref()));
+            });
+        }
 
         let res2 = res.clone();
         std::thread::spawn(move || {
@@ -622,52 +1334,236 @@ pub fn on_node_event_incoming<TI: NetworkingEvent, TO: NetworkingEvent>(
             info!(?endpoint, ?listener, "Connection Accepted")
         }
         NetEvent::Message(endpoint, data) => {
-            // TODO: Refactor fake ping here and above
-            if let Some(fake_ping) = fake_ping {
-                if fake_ping.from_server_ms > 0 {
+            let datagrams = match &res.incoming_conditioners {
+                Some(conditioners) => {
+                    conditioners.apply(EndpointGeneral::UDP(endpoint), data.to_vec())
+                }
+                None => vec![data.to_vec()],
+            };
+            for datagram in datagrams {
+                let Some(data) = open_datagram_udp(res, endpoint, &datagram) else {
+                    continue;
+                };
+
+                // TODO: Refactor fake ping here and above
+                if let Some(fake_ping) = fake_ping
+                    && fake_ping.from_server_ms > 0
+                {
                     let delay = fake_ping.get_delay_from_server();
                     std::thread::spawn({
                         let res = res.clone();
-                        let data = data.to_vec();
                         move || {
                             std::thread::sleep(std::time::Duration::from_millis(delay));
-                            on_data_incoming(
-                                &res,
-                                endpoint,
-                                res.event_list_incoming_udp.clone(),
-                                &data,
-                            );
+                            on_data_incoming(&res, EndpointGeneral::UDP(endpoint), &data);
                         }
                     });
+                } else {
+                    on_data_incoming(res, EndpointGeneral::UDP(endpoint), &data);
                 }
-            } else {
-                on_data_incoming(res, endpoint, res.event_list_incoming_udp.clone(), data);
             }
         }
         NetEvent::Disconnected(endpoint) => warn!(?endpoint, "Client disconnected"),
     }
 }
 
-pub trait EndpointTrait: std::fmt::Debug + Copy + Clone + Send + Sync + 'static {
-    fn as_socket_addr(&self) -> std::net::SocketAddr;
+/// Handle the session layer of an incoming datagram. Returns the plaintext of data packets that
+/// authenticate, handshakes and anything forged or replayed are consumed here.
+fn open_datagram_udp<TI, TO>(
+    res: &NetworkingResources<TI, TO>,
+    endpoint: Endpoint,
+    data: &[u8],
+) -> Option<Vec<u8>> {
+    let ignore = || {
+        res.networking_stats
+            .total_bytes_received_ignored_this_second
+            .fetch_add(data.len(), std::sync::atomic::Ordering::Relaxed);
+    };
+
+    let packet: SessionPacket = match postcard::from_bytes(data) {
+        Ok(p) => p,
+        Err(e) => {
+            trace!(?endpoint, ?e, "Got datagram that isn't a session packet");
+            ignore();
+            return None;
+        }
+    };
+
+    match packet {
+        SessionPacket::ClientHello { public_key, cookie } if res.is_listener => {
+            accept_client_hello(res, endpoint, public_key, cookie);
+            None
+        }
+        SessionPacket::Cookie { cookie } if !res.is_listener => {
+            resend_hello_with_cookie(res, endpoint, cookie);
+            None
+        }
+        SessionPacket::ServerHello { public_key } if !res.is_listener => {
+            finish_client_handshake(res, endpoint, public_key);
+            None
+        }
+        SessionPacket::Data {
+            counter,
+            ciphertext,
+        } => {
+            let opened = match res.sessions.get_mut(&endpoint).as_deref_mut() {
+                Some(SessionState::Established { session, .. }) => {
+                    session.open(counter, ciphertext, Instant::now())
+                }
+                _ => {
+                    trace!(?endpoint, "Got data before a session was established");
+                    ignore();
+                    return None;
+                }
+            };
+            match opened {
+                Ok(plaintext) => Some(plaintext),
+                Err(e) => {
+                    debug!(
+                        ?endpoint,
+                        ?e,
+                        "Dropping datagram that failed authentication"
+                    );
+                    ignore();
+                    None
+                }
+            }
+        }
+        _ => {
+            ignore();
+            None
+        }
+    }
 }
 
-impl EndpointTrait for WebSocketEndpoint {
-    fn as_socket_addr(&self) -> std::net::SocketAddr {
-        self.socket_addr
+/// Server side of the handshake
+fn accept_client_hello<TI, TO>(
+    res: &NetworkingResources<TI, TO>,
+    endpoint: Endpoint,
+    client_public: [u8; 32],
+    cookie: Option<[u8; 32]>,
+) {
+    // Nothing past this point until the client shows it can receive at its address
+    let now = Instant::now();
+    let addr = endpoint.addr();
+    if !cookie.is_some_and(|c| res.hello_cookies.check(addr, &client_public, &c, now)) {
+        trace!(?endpoint, "Answering hello with a cookie");
+        let cookie = res.hello_cookies.cookie(addr, &client_public, now);
+        let packet = postcard::to_stdvec(&SessionPacket::Cookie { cookie }).unwrap();
+        res.transport_send(EndpointGeneral::UDP(endpoint), &packet);
+        return;
+    }
+
+    if let Some(state) = res.sessions.get(&endpoint)
+        && let SessionState::Established {
+            session,
+            server_hello,
+        } = &*state
+    {
+        // Our answer was lost, send the same one again
+        if session.peer_public() == &client_public {
+            if let Some(server_hello) = server_hello {
+                res.transport_send(EndpointGeneral::UDP(endpoint), server_hello);
+            }
+            return;
+        }
+
+        // Otherwise anyone spoofing this address could tear down a live session
+        if session
+            .last_authenticated
+            .is_some_and(|t| t.elapsed() < session::SESSION_REPLACE_AFTER)
+        {
+            warn!(?endpoint, "Ignoring new handshake for an active session");
+            return;
+        }
+    }
+
+    // Every hello costs us a key exchange, and spoofed ones never finish
+    let unauthenticated = res
+        .sessions
+        .iter()
+        .filter(|state| state.established().is_some_and(Session::is_unauthenticated))
+        .count();
+    if unauthenticated >= session::MAX_UNAUTHENTICATED_SESSIONS {
+        debug!(?endpoint, "Too many pending handshakes, ignoring hello");
+        return;
     }
+
+    let (session, server_hello) = match Session::accept(client_public) {
+        Ok(s) => s,
+        Err(e) => {
+            warn!(?endpoint, ?e, "Rejecting handshake");
+            return;
+        }
+    };
+
+    // The client restarted, none of the old connection's state applies any more
+    res.forget_endpoint(EndpointGeneral::UDP(endpoint));
+
+    info!(?endpoint, "Session established");
+    res.transport_send(EndpointGeneral::UDP(endpoint), &server_hello);
+    res.sessions.insert(
+        endpoint,
+        SessionState::Established {
+            session,
+            server_hello: Some(server_hello),
+        },
+    );
 }
 
-impl EndpointTrait for Endpoint {
-    fn as_socket_addr(&self) -> std::net::SocketAddr {
-        self.addr()
+/// Client side of the handshake, the server wants to see its cookie before it answers
+fn resend_hello_with_cookie<TI, TO>(
+    res: &NetworkingResources<TI, TO>,
+    endpoint: Endpoint,
+    cookie: [u8; 32],
+) {
+    let Some(mut state) = res.sessions.get_mut(&endpoint) else {
+        return;
+    };
+    let SessionState::Handshaking(handshake) = &mut *state else {
+        return;
+    };
+    handshake.cookie = Some(cookie);
+    handshake.last_sent = Instant::now();
+    res.transport_send(EndpointGeneral::UDP(endpoint), &handshake.hello());
+}
+
+/// Client side of the handshake, then send everything that was waiting for it
+fn finish_client_handshake<TI, TO>(
+    res: &NetworkingResources<TI, TO>,
+    endpoint: Endpoint,
+    server_public: [u8; 32],
+) {
+    let Some(mut state) = res.sessions.get_mut(&endpoint) else {
+        return;
+    };
+    // Answers to our resent hellos
+    let SessionState::Handshaking(handshake) = &mut *state else {
+        return;
+    };
+
+    let session = match handshake.finish(server_public) {
+        Ok(s) => s,
+        Err(e) => {
+            warn!(?endpoint, ?e, "Server sent a bad handshake");
+            return;
+        }
+    };
+    let queued = std::mem::take(&mut handshake.queued);
+    *state = SessionState::Established {
+        session,
+        server_hello: None,
+    };
+    drop(state);
+
+    info!(?endpoint, queued = queued.len(), "Session established");
+    for data in queued {
+        send_sealed_udp(res, endpoint, &data);
     }
 }
 
-pub fn on_data_incoming<TI: NetworkingEvent, TO, K: EndpointTrait>(
+pub fn on_data_incoming<TI: NetworkingEvent, TO>(
     resources: &NetworkingResources<TI, TO>,
-    endpoint: K,
-    data_buffer: Arc<RwLock<Vec<(K, TI)>>>,
+    endpoint: EndpointGeneral,
     data: &[u8],
 ) {
     let event: EventGroupingOwned<TI> = match postcard::from_bytes(data) {
@@ -680,33 +1576,98 @@ pub fn on_data_incoming<TI: NetworkingEvent, TO, K: EndpointTrait>(
     let data_len = data.len();
     resources
         .networking_stats
-        .total_bytes_received_this_second
-        .fetch_add(data_len, std::sync::atomic::Ordering::Relaxed);
-    resources
-        .networking_stats
-        .packets_received_this_second
-        .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        .count_received(endpoint, data_len);
+
+    on_grouping_incoming(resources, endpoint, event, data_len);
+}
 
-    let mut list = data_buffer.write().unwrap();
+fn on_grouping_incoming<TI: NetworkingEvent, TO>(
+    resources: &NetworkingResources<TI, TO>,
+    endpoint: EndpointGeneral,
+    event: EventGroupingOwned<TI>,
+    data_len: usize,
+) {
+    let data_buffer = &resources.event_list_incoming;
     match event {
         EventGroupingOwned::Single(x) => {
             let pair = (endpoint, x);
-            list.push(pair);
+            data_buffer.write().unwrap().push(pair);
         }
         EventGroupingOwned::Batch(events) => {
+            let mut list = data_buffer.write().unwrap();
             list.extend(events.into_iter().map(|x| (endpoint, x)));
         }
-        EventGroupingOwned::Reliable(packet_id, _dedup_id, tick, events) => {
-            let mut seen_map = resources.reliable_packet_ids_seen.write().unwrap();
+        EventGroupingOwned::Reliable(header, events) => {
+            let mut connection = resources.reliable_connections.entry(endpoint).or_default();
+            connection.process_ack(&header.ack, Instant::now());
+            let is_new = connection.receive(header.sequence);
+            drop(connection);
 
-            if seen_map.get(&packet_id).is_some() {
+            if !is_new {
                 resources
                     .networking_stats
                     .total_bytes_received_ignored_this_second
                     .fetch_add(data_len, std::sync::atomic::Ordering::Relaxed);
-            } else {
-                seen_map.insert(packet_id, tick); // TODO store tick properly
-                list.extend(events.into_iter().map(|x| (endpoint, x)));
+                return;
+            }
+
+            let events = match header.ordered {
+                Some(ordered_sequence) => resources
+                    .ordered_incoming
+                    .entry(endpoint)
+                    .or_default()
+                    .push(ordered_sequence, events),
+                None => events,
+            };
+            let mut list = data_buffer.write().unwrap();
+            list.extend(events.into_iter().map(|x| (endpoint, x)));
+        }
+        EventGroupingOwned::Ack(ack) => {
+            if let Some(mut connection) = resources.reliable_connections.get_mut(&endpoint) {
+                connection.process_ack(&ack, Instant::now());
+            }
+        }
+        EventGroupingOwned::Fragment(header, payload) => {
+            let complete = resources
+                .fragments_incoming
+                .entry(endpoint)
+                .or_default()
+                .insert(header, payload, Instant::now());
+
+            match complete {
+                Ok(Some(full)) => match postcard::from_bytes(&full) {
+                    Ok(event) => on_grouping_incoming(resources, endpoint, event, data_len),
+                    Err(p) => warn!(?endpoint, ?p, "Got invalid reassembled data from endpoint"),
+                },
+                Ok(None) => {}
+                Err(e) => {
+                    warn!(?endpoint, ?e, "Dropping invalid fragment");
+                    resources
+                        .networking_stats
+                        .total_bytes_received_ignored_this_second
+                        .fetch_add(data_len, std::sync::atomic::Ordering::Relaxed);
+                }
+            }
+        }
+        EventGroupingOwned::Compressed(header, payload) => {
+            let raw = match compression::decompress(header, &payload) {
+                Ok(raw) => raw,
+                Err(e) => {
+                    warn!(?endpoint, ?e, "Dropping invalid compressed data");
+                    resources
+                        .networking_stats
+                        .total_bytes_received_ignored_this_second
+                        .fetch_add(data_len, std::sync::atomic::Ordering::Relaxed);
+                    return;
+                }
+            };
+            match postcard::from_bytes(&raw) {
+                // Nothing compresses twice, don't let anyone make us decompress in a loop
+                Ok(EventGroupingOwned::Compressed(..)) => {
+                    warn!(?endpoint, "Dropping doubly compressed data")
+                }
+                Ok(event) => on_grouping_incoming(resources, endpoint, event, data_len),
+                Err(p) => warn!(?endpoint, ?p, "Got invalid decompressed data from endpoint"),
             }
         }
     }
@@ -0,0 +1,151 @@
+//! Per-connection send budget, and the priority accumulator that decides what fits in it.
+//!
+//! Every UDP datagram we send to an endpoint is paid for out of its budget, which is refilled
+//! once per tick. Reliable events that don't fit wait in the outgoing queue, entity updates that
+//! don't fit gain priority until they do.
+use std::{
+    collections::{HashMap, HashSet},
+    hash::Hash,
+};
+
+use crate::BASE_TICKS_PER_SECOND;
+
+/// Used when `bandwidth_bytes_per_second` is not set in the config
+pub const DEFAULT_BYTES_PER_SECOND: usize = 64 * 1024;
+
+#[derive(Debug, Clone)]
+pub struct BandwidthBudget {
+    bytes_per_tick: usize,
+    /// Negative when a send was larger than what was left, paid off by the next refills
+    available: isize,
+}
+
+impl BandwidthBudget {
+    pub fn new(bytes_per_second: usize) -> Self {
+        let bytes_per_tick = (bytes_per_second / BASE_TICKS_PER_SECOND as usize).max(1);
+        Self {
+            bytes_per_tick,
+            available: bytes_per_tick as isize,
+        }
+    }
+
+    /// Called once per tick. Unused budget doesn't carry over, so we never burst above the rate.
+    pub fn refill(&mut self) {
+        self.available =
+            (self.available + self.bytes_per_tick as isize).min(self.bytes_per_tick as isize);
+    }
+
+    pub fn spend(&mut self, bytes: usize) {
+        self.available -= bytes as isize;
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.available.max(0) as usize
+    }
+
+    pub fn bytes_per_second(&self) -> usize {
+        self.bytes_per_tick * BASE_TICKS_PER_SECOND as usize
+    }
+}
+
+/// Priorities of things waiting to be sent to one endpoint
+#[derive(Debug)]
+pub struct PriorityAccumulator<K> {
+    priorities: HashMap<K, f32>,
+}
+
+impl<K> Default for PriorityAccumulator<K> {
+    fn default() -> Self {
+        Self {
+            priorities: HashMap::new(),
+        }
+    }
+}
+
+impl<K: Hash + Eq + Copy> PriorityAccumulator<K> {
+    /// Add each candidate's `(key, weight, cost)` weight to its priority, then pick the highest
+    /// priority candidates whose cost fits in `budget`. Picked keys start over from zero, and keys
+    /// that are no longer candidates are forgotten.
+    ///
+    /// The top candidate is always picked while there is any budget left, so something larger
+    /// than a whole tick's budget still goes out eventually.
+    pub fn select(&mut self, candidates: &[(K, f32, usize)], budget: usize) -> HashSet<K> {
+        let candidate_keys = candidates.iter().map(|(k, ..)| *k).collect::<HashSet<_>>();
+        self.priorities.retain(|k, _| candidate_keys.contains(k));
+
+        let mut by_priority = candidates
+            .iter()
+            .map(|(key, weight, cost)| {
+                let priority = self.priorities.entry(*key).or_default();
+                *priority += weight;
+                (*key, *priority, *cost)
+            })
+            .collect::<Vec<_>>();
+        by_priority.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+        let mut remaining = budget;
+        let mut picked = HashSet::new();
+        for (key, _, cost) in by_priority {
+            if remaining == 0 {
+                break;
+            }
+            if cost > remaining && !picked.is_empty() {
+                continue;
+            }
+            remaining = remaining.saturating_sub(cost);
+            picked.insert(key);
+            self.priorities.remove(&key);
+        }
+
+        picked
+    }
+
+    pub fn priority(&self, key: &K) -> f32 {
+        self.priorities.get(key).copied().unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_budget_debt_and_refill() {
+        let per_second = 100 * BASE_TICKS_PER_SECOND as usize;
+        let mut budget = BandwidthBudget::new(per_second);
+        assert_eq!(budget.remaining(), 100);
+        assert_eq!(budget.bytes_per_second(), per_second);
+
+        budget.spend(250);
+        assert_eq!(budget.remaining(), 0);
+        budget.refill();
+        assert_eq!(budget.remaining(), 0);
+        budget.refill();
+        assert_eq!(budget.remaining(), 50);
+
+        // Idle ticks don't save up
+        budget.refill();
+        budget.refill();
+        assert_eq!(budget.remaining(), 100);
+    }
+
+    #[test]
+    fn test_starved_keys_catch_up() {
+        let mut acc = PriorityAccumulator::default();
+        let candidates = [(1, 4.0, 10), (2, 1.0, 10)];
+
+        // Only room for one, the heavier key wins first
+        assert_eq!(acc.select(&candidates, 10), HashSet::from([1]));
+        assert_eq!(acc.priority(&2), 1.0);
+
+        // ..but the lighter one keeps accumulating until it overtakes
+        for _ in 0..3 {
+            acc.select(&candidates, 10);
+        }
+        assert_eq!(acc.select(&candidates, 10), HashSet::from([2]));
+
+        // Too big to ever fit still goes out on its own
+        assert_eq!(acc.select(&[(3, 1.0, 500)], 10), HashSet::from([3]));
+        assert!(acc.select(&candidates, 0).is_empty());
+    }
+}
@@ -0,0 +1,140 @@
+//! Optional compression of serialized groupings.
+//!
+//! Each side says in the connect handshake which codec it can read, and only groupings of at least
+//! [`COMPRESSION_THRESHOLD`] bytes that actually get smaller are sent compressed. Compressed
+//! groupings are wrapped in a header naming the codec, so the receiver never has to guess.
+use serde::{Deserialize, Serialize};
+
+use super::fragment::MAX_REASSEMBLED_SIZE;
+
+/// Smaller groupings are sent as they are, they rarely shrink enough to be worth it
+pub const COMPRESSION_THRESHOLD: usize = 256;
+/// Anything that claims to decompress to more than this is dropped without allocating for it
+pub const MAX_DECOMPRESSED_SIZE: usize = MAX_REASSEMBLED_SIZE;
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub enum Compression {
+    #[default]
+    None,
+    Lz4,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct CompressionHeader {
+    pub codec: Compression,
+    pub raw_len: u32,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum CompressionError {
+    TooLarge {
+        raw_len: u32,
+    },
+    /// `Compression::None` never goes on the wire
+    NoCodec,
+    Corrupt,
+}
+
+/// Compress `data` with `codec`, or `None` if it should be sent as it is
+pub fn compress(codec: Compression, data: &[u8]) -> Option<(CompressionHeader, Vec<u8>)> {
+    if data.len() < COMPRESSION_THRESHOLD || data.len() > MAX_DECOMPRESSED_SIZE {
+        return None;
+    }
+
+    let compressed = match codec {
+        Compression::None => return None,
+        Compression::Lz4 => lz4_flex::block::compress(data),
+    };
+    if compressed.len() >= data.len() {
+        return None;
+    }
+
+    let header = CompressionHeader {
+        codec,
+        raw_len: data.len() as u32,
+    };
+    Some((header, compressed))
+}
+
+pub fn decompress(header: CompressionHeader, payload: &[u8]) -> Result<Vec<u8>, CompressionError> {
+    let raw_len = header.raw_len as usize;
+    if raw_len > MAX_DECOMPRESSED_SIZE {
+        return Err(CompressionError::TooLarge {
+            raw_len: header.raw_len,
+        });
+    }
+
+    match header.codec {
+        Compression::None => Err(CompressionError::NoCodec),
+        Compression::Lz4 => {
+            let mut out = vec![0; raw_len];
+            match lz4_flex::block::decompress_into(payload, &mut out) {
+                Ok(len) if len == raw_len => Ok(out),
+                _ => Err(CompressionError::Corrupt),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip_and_threshold() {
+        let data = b"SpawnUnit2 Transform PlayerName ".repeat(40);
+        let (header, compressed) = compress(Compression::Lz4, &data).unwrap();
+        assert!(compressed.len() < data.len());
+        assert_eq!(decompress(header, &compressed).unwrap(), data);
+
+        assert!(compress(Compression::Lz4, &data[..COMPRESSION_THRESHOLD - 1]).is_none());
+        assert!(compress(Compression::None, &data).is_none());
+
+        // Noise doesn't get smaller, so it goes out as is
+        let mut state = 0x2545f4914f6cdd1du64;
+        let noise = (0..1024)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect::<Vec<_>>();
+        assert!(compress(Compression::Lz4, &noise).is_none());
+    }
+
+    #[test]
+    fn test_rejects_bad_headers() {
+        let data = vec![7; 1000];
+        let (header, compressed) = compress(Compression::Lz4, &data).unwrap();
+
+        let lying = CompressionHeader {
+            raw_len: header.raw_len - 1,
+            ..header
+        };
+        assert_eq!(
+            decompress(lying, &compressed),
+            Err(CompressionError::Corrupt)
+        );
+
+        let bomb = CompressionHeader {
+            raw_len: u32::MAX,
+            ..header
+        };
+        assert_eq!(
+            decompress(bomb, &compressed),
+            Err(CompressionError::TooLarge { raw_len: u32::MAX })
+        );
+
+        assert_eq!(
+            decompress(
+                CompressionHeader {
+                    codec: Compression::None,
+                    ..header
+                },
+                &compressed
+            ),
+            Err(CompressionError::NoCodec)
+        );
+    }
+}
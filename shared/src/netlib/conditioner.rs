@@ -0,0 +1,307 @@
+//! Simulated bad networks, for seeing how reliability and interpolation hold up.
+//!
+//! Each direction of our UDP link gets its own [`Conditioner`], which decides for every datagram
+//! whether it is lost, duplicated or held back behind the next one. Everything is decided by a
+//! seeded RNG, so the same seed and the same traffic give the same losses.
+use std::hash::{BuildHasher, BuildHasherDefault, DefaultHasher};
+
+use bevy_internal::{platform::time::Instant, prelude::*};
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+
+use super::{EndpointGeneral, transport::Transport};
+
+/// What one direction of the link does to datagrams. All chances are percentages.
+#[derive(Reflect, Clone, Copy, Deserialize, Serialize, Debug, Default, PartialEq)]
+pub struct LinkConditions {
+    pub loss_percent: f32,
+    /// Chance that a datagram starts a burst of `burst_length` lost datagrams
+    pub burst_loss_percent: f32,
+    pub burst_length: u32,
+    pub duplicate_percent: f32,
+    /// Chance that a datagram is held back and sent after the next one
+    pub reorder_percent: f32,
+    /// Datagrams that don't fit are dropped, like a full router queue
+    pub bytes_per_second: Option<usize>,
+}
+
+impl LinkConditions {
+    pub fn is_perfect(&self) -> bool {
+        self.loss_percent <= 0.0
+            && self.burst_loss_percent <= 0.0
+            && self.duplicate_percent <= 0.0
+            && self.reorder_percent <= 0.0
+            && self.bytes_per_second.is_none()
+    }
+}
+
+#[derive(Reflect, Resource, Clone, Copy, Deserialize, Serialize, Debug, Default, PartialEq)]
+pub struct NetworkConditions {
+    pub outgoing: LinkConditions,
+    pub incoming: LinkConditions,
+    pub seed: u64,
+}
+
+/// The `--net-*` command line flags, laid over `network_conditions` from the config. They apply
+/// to both directions.
+#[derive(Resource, Clone, Copy, Debug, Default)]
+pub struct NetworkConditionOverrides {
+    pub loss_percent: Option<f32>,
+    pub burst_loss_percent: Option<f32>,
+    pub burst_length: u32,
+    pub duplicate_percent: Option<f32>,
+    pub reorder_percent: Option<f32>,
+    pub bytes_per_second: Option<usize>,
+    pub seed: Option<u64>,
+}
+
+impl NetworkConditionOverrides {
+    pub fn apply(&self, mut conditions: NetworkConditions) -> NetworkConditions {
+        for link in [&mut conditions.outgoing, &mut conditions.incoming] {
+            if let Some(loss) = self.loss_percent {
+                link.loss_percent = loss;
+            }
+            if let Some(burst_loss) = self.burst_loss_percent {
+                link.burst_loss_percent = burst_loss;
+                link.burst_length = self.burst_length;
+            }
+            if let Some(duplicate) = self.duplicate_percent {
+                link.duplicate_percent = duplicate;
+            }
+            if let Some(reorder) = self.reorder_percent {
+                link.reorder_percent = reorder;
+            }
+            if let Some(bandwidth) = self.bytes_per_second {
+                link.bytes_per_second = Some(bandwidth);
+            }
+        }
+        if let Some(seed) = self.seed {
+            conditions.seed = seed;
+        }
+        conditions
+    }
+}
+
+pub struct Conditioner {
+    conditions: LinkConditions,
+    rng: fastrand::Rng,
+    burst_remaining: u32,
+    /// Waiting to be sent after the next datagram
+    held: Option<Vec<u8>>,
+    /// Bytes we may still send, refilled at `bytes_per_second` up to one second's worth
+    allowance: f64,
+    last_refill: Option<Instant>,
+}
+
+impl Conditioner {
+    pub fn new(conditions: LinkConditions, seed: u64) -> Self {
+        Self {
+            conditions,
+            rng: fastrand::Rng::with_seed(seed),
+            burst_remaining: 0,
+            held: None,
+            allowance: conditions.bytes_per_second.unwrap_or_default() as f64,
+            last_refill: None,
+        }
+    }
+
+    fn chance(&mut self, percent: f32) -> bool {
+        percent > 0.0 && self.rng.f32() * 100.0 < percent
+    }
+
+    fn fits_bandwidth(&mut self, len: usize, now: Instant) -> bool {
+        let Some(bytes_per_second) = self.conditions.bytes_per_second else {
+            return true;
+        };
+        if let Some(last) = self.last_refill {
+            let refill =
+                now.saturating_duration_since(last).as_secs_f64() * bytes_per_second as f64;
+            self.allowance = (self.allowance + refill).min(bytes_per_second as f64);
+        }
+        self.last_refill = Some(now);
+
+        if self.allowance < len as f64 {
+            return false;
+        }
+        self.allowance -= len as f64;
+        true
+    }
+
+    /// The datagrams that actually make it through, in the order they arrive
+    pub fn apply(&mut self, data: Vec<u8>, now: Instant) -> Vec<Vec<u8>> {
+        if !self.fits_bandwidth(data.len(), now) {
+            return vec![];
+        }
+
+        if self.burst_remaining > 0 {
+            self.burst_remaining -= 1;
+            return vec![];
+        }
+        if self.chance(self.conditions.burst_loss_percent) {
+            self.burst_remaining = self.conditions.burst_length.saturating_sub(1);
+            return vec![];
+        }
+        if self.chance(self.conditions.loss_percent) {
+            return vec![];
+        }
+
+        let mut out = vec![];
+        if self.chance(self.conditions.duplicate_percent) {
+            out.push(data.clone());
+        }
+        out.push(data);
+
+        // Only one is held at a time, it goes out behind whatever is sent next
+        if let Some(held) = self.held.take() {
+            out.push(held);
+        } else if self.chance(self.conditions.reorder_percent) {
+            self.held = out.pop();
+        }
+        out
+    }
+}
+
+/// One [`Conditioner`] per connection, so a held back datagram goes to the right place and each
+/// connection gets its own bandwidth
+pub struct LinkConditioners {
+    conditions: LinkConditions,
+    seed: u64,
+    links: DashMap<EndpointGeneral, Conditioner>,
+}
+
+impl LinkConditioners {
+    pub fn new(conditions: LinkConditions, seed: u64) -> Self {
+        Self {
+            conditions,
+            seed,
+            links: Default::default(),
+        }
+    }
+
+    /// Every connection rolls its own losses, still decided by `seed`
+    fn seed_for(&self, endpoint: EndpointGeneral) -> u64 {
+        BuildHasherDefault::<DefaultHasher>::default().hash_one((self.seed, endpoint))
+    }
+
+    pub fn apply(&self, endpoint: EndpointGeneral, data: Vec<u8>) -> Vec<Vec<u8>> {
+        self.links
+            .entry(endpoint)
+            .or_insert_with(|| Conditioner::new(self.conditions, self.seed_for(endpoint)))
+            .apply(data, Instant::now())
+    }
+
+    pub fn forget(&self, endpoint: EndpointGeneral) {
+        self.links.remove(&endpoint);
+    }
+}
+
+/// Runs everything we send through [`LinkConditioners`] before handing it to `inner`
+pub struct ConditionedTransport<T> {
+    pub inner: T,
+    pub conditioners: LinkConditioners,
+}
+
+impl<T: Transport> Transport for ConditionedTransport<T> {
+    fn send(&self, endpoint: EndpointGeneral, data: &[u8]) -> bool {
+        for datagram in self.conditioners.apply(endpoint, data.to_vec()) {
+            if !self.inner.send(endpoint, &datagram) {
+                return false;
+            }
+        }
+        // Losing it on purpose isn't the endpoint going away
+        true
+    }
+
+    fn forget(&self, endpoint: EndpointGeneral) {
+        self.conditioners.forget(endpoint);
+        self.inner.forget(endpoint);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn run(conditions: LinkConditions, seed: u64, count: u8) -> Vec<Vec<u8>> {
+        let mut conditioner = Conditioner::new(conditions, seed);
+        let now = Instant::now();
+        (0..count)
+            .flat_map(|i| conditioner.apply(vec![i], now))
+            .collect()
+    }
+
+    #[test]
+    fn test_same_seed_same_network() {
+        let conditions = LinkConditions {
+            loss_percent: 20.0,
+            duplicate_percent: 10.0,
+            reorder_percent: 10.0,
+            burst_loss_percent: 5.0,
+            burst_length: 3,
+            ..Default::default()
+        };
+        let a = run(conditions, 7, 200);
+        assert_eq!(a, run(conditions, 7, 200));
+        assert_ne!(a, run(conditions, 8, 200));
+
+        let delivered = a.iter().collect::<std::collections::HashSet<_>>().len();
+        assert!(delivered < 190 && delivered > 100, "{delivered}");
+
+        let perfect = run(LinkConditions::default(), 7, 200);
+        assert_eq!(perfect, (0..200).map(|i| vec![i]).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_connections_roll_their_own_losses() {
+        use crate::netlib::transport::MemoryEndpoint;
+
+        let conditioners = LinkConditioners::new(
+            LinkConditions {
+                loss_percent: 50.0,
+                ..Default::default()
+            },
+            7,
+        );
+        let run = |endpoint| -> Vec<_> {
+            (0..100)
+                .flat_map(|i| conditioners.apply(endpoint, vec![i]))
+                .collect()
+        };
+        let a = EndpointGeneral::Memory(MemoryEndpoint(1));
+        let b = EndpointGeneral::Memory(MemoryEndpoint(2));
+        let first = run(a);
+        assert_ne!(first, run(b));
+
+        // Forgotten connections start over
+        conditioners.forget(a);
+        assert_eq!(conditioners.links.len(), 1);
+        assert_eq!(first, run(a));
+    }
+
+    #[test]
+    fn test_reorder_and_bandwidth() {
+        let reordered = run(
+            LinkConditions {
+                reorder_percent: 100.0,
+                ..Default::default()
+            },
+            1,
+            4,
+        );
+        // Every other one is held, and sent behind the next
+        assert_eq!(reordered, vec![vec![1], vec![0], vec![3], vec![2]]);
+
+        let mut conditioner = Conditioner::new(
+            LinkConditions {
+                bytes_per_second: Some(1000),
+                ..Default::default()
+            },
+            1,
+        );
+        let now = Instant::now();
+        assert_eq!(conditioner.apply(vec![0; 600], now).len(), 1);
+        assert!(conditioner.apply(vec![0; 600], now).is_empty());
+        let later = now + std::time::Duration::from_millis(500);
+        assert_eq!(conditioner.apply(vec![0; 600], later).len(), 1);
+    }
+}
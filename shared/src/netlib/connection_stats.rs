@@ -0,0 +1,140 @@
+//! Traffic counters for each endpoint, so one bad connection can be told apart from a struggling
+//! server.
+use std::time::Duration;
+
+use bevy_internal::platform::time::Instant;
+use serde::{Deserialize, Serialize};
+
+/// How much of the new loss estimate is mixed in each second
+const LOSS_SMOOTHING: f32 = 0.3;
+
+/// Counted as data goes in and out, see [`super::NetworkingStats::per_endpoint`]
+#[derive(Debug, Default)]
+pub struct EndpointCounters {
+    bytes_sent: usize,
+    bytes_received: usize,
+    packets_sent: usize,
+    packets_received: usize,
+    packets_resent: usize,
+    last_received: Option<Instant>,
+    rtt: Option<Duration>,
+
+    /// Totals as of the last flush, to work out what happened since
+    flushed: FlushedTotals,
+    bytes_sent_last_second: usize,
+    bytes_received_last_second: usize,
+    loss: f32,
+}
+
+#[derive(Debug, Default)]
+struct FlushedTotals {
+    bytes_sent: usize,
+    bytes_received: usize,
+    packets_sent: usize,
+    packets_resent: usize,
+}
+
+impl EndpointCounters {
+    pub fn sent(&mut self, bytes: usize) {
+        self.bytes_sent += bytes;
+        self.packets_sent += 1;
+    }
+
+    pub fn received(&mut self, bytes: usize, now: Instant) {
+        self.bytes_received += bytes;
+        self.packets_received += 1;
+        self.last_received = Some(now);
+    }
+
+    pub fn resent(&mut self, packets: usize) {
+        self.packets_resent += packets;
+    }
+
+    pub fn set_rtt(&mut self, rtt: Duration) {
+        self.rtt = Some(rtt);
+    }
+
+    /// Called once a second to update the rates and the loss estimate
+    pub fn flush(&mut self) {
+        self.bytes_sent_last_second = self.bytes_sent - self.flushed.bytes_sent;
+        self.bytes_received_last_second = self.bytes_received - self.flushed.bytes_received;
+
+        // Every resend is a datagram we think was lost, or at least its ack was
+        let sent = self.packets_sent - self.flushed.packets_sent;
+        let resent = self.packets_resent - self.flushed.packets_resent;
+        if sent > 0 {
+            let loss = (resent as f32 / sent as f32).min(1.0);
+            self.loss += (loss - self.loss) * LOSS_SMOOTHING;
+        }
+
+        self.flushed = FlushedTotals {
+            bytes_sent: self.bytes_sent,
+            bytes_received: self.bytes_received,
+            packets_sent: self.packets_sent,
+            packets_resent: self.packets_resent,
+        };
+    }
+
+    pub fn snapshot(&self, now: Instant) -> ConnectionStats {
+        ConnectionStats {
+            bytes_sent: self.bytes_sent,
+            bytes_received: self.bytes_received,
+            packets_sent: self.packets_sent,
+            packets_received: self.packets_received,
+            packets_resent: self.packets_resent,
+            bytes_sent_last_second: self.bytes_sent_last_second,
+            bytes_received_last_second: self.bytes_received_last_second,
+            estimated_loss: self.loss,
+            rtt_ms: self.rtt.map(|rtt| rtt.as_secs_f32() * 1000.0),
+            last_seen_secs_ago: self
+                .last_received
+                .map(|last| now.saturating_duration_since(last).as_secs_f32()),
+        }
+    }
+}
+
+/// The counters of one endpoint at some point in time
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ConnectionStats {
+    pub bytes_sent: usize,
+    pub bytes_received: usize,
+    pub packets_sent: usize,
+    pub packets_received: usize,
+    /// Reliable datagrams we had to send again
+    pub packets_resent: usize,
+    pub bytes_sent_last_second: usize,
+    pub bytes_received_last_second: usize,
+    /// Fraction of datagrams lost, estimated from resends
+    pub estimated_loss: f32,
+    /// Round trip of the last heartbeat
+    pub rtt_ms: Option<f32>,
+    pub last_seen_secs_ago: Option<f32>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_loss_is_estimated_from_resends() {
+        let mut counters = EndpointCounters::default();
+        for _ in 0..10 {
+            counters.sent(100);
+        }
+        counters.resent(5);
+        counters.flush();
+
+        let now = Instant::now();
+        counters.received(40, now);
+        let stats = counters.snapshot(now);
+        assert_eq!(stats.bytes_sent_last_second, 1000);
+        assert_eq!(stats.packets_received, 1);
+        assert_eq!(stats.last_seen_secs_ago, Some(0.0));
+        assert!(stats.estimated_loss > 0.0 && stats.estimated_loss < 0.5);
+
+        // Quiet seconds don't change the estimate
+        counters.flush();
+        assert_eq!(counters.snapshot(now).estimated_loss, stats.estimated_loss);
+        assert_eq!(counters.snapshot(now).bytes_sent_last_second, 0);
+    }
+}
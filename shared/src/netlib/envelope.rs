@@ -0,0 +1,140 @@
+//! How each event is written inside a grouping.
+//!
+//! Every event goes out as an [`Envelope`]: its stable id from `src/event/ids.txt` and its
+//! serialized fields behind a length prefix. A receiver that doesn't know the id can step over the
+//! payload and keeps the event as an [`UnknownEvent`], which is logged and dropped instead of
+//! breaking the rest of the grouping. So is an event we know but can't decode. The `EventTo*` enums are (de)serialized through this by
+//! their generated `Serialize`/`Deserialize` impls.
+//!
+//! Client and server compare [`EventSchema`]s when connecting, so an event both of them know but
+//! disagree on the fields of still gets the client rejected.
+use serde::{Deserialize, Serialize, de, ser};
+
+use crate::event::EVENT_SCHEMAS;
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Envelope {
+    pub id: u32,
+    pub payload: Vec<u8>,
+}
+
+impl Envelope {
+    pub fn encode<T: Serialize, E: ser::Error>(id: u32, event: &T) -> Result<Self, E> {
+        let payload = postcard::to_stdvec(event).map_err(E::custom)?;
+        Ok(Self { id, payload })
+    }
+
+    pub fn decode<T: de::DeserializeOwned, E: de::Error>(&self) -> Result<T, E> {
+        postcard::from_bytes(&self.payload).map_err(E::custom)
+    }
+}
+
+/// An event with an id this build doesn't have. Sending it again writes the same envelope.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownEvent(pub Envelope);
+
+/// The stable id of an event and a hash of its fields
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct EventSchema {
+    pub id: u32,
+    pub hash: u64,
+}
+
+/// Every event we have in this build, to send in the `ConnectRequest`
+pub fn our_event_schemas() -> Vec<EventSchema> {
+    EVENT_SCHEMAS.iter().map(|(_, schema)| *schema).collect()
+}
+
+/// Names of the events both sides have but with different fields. Events only one side has are
+/// fine, the other one skips them.
+///
+/// An event's hash covers the types of this crate it is made of, however deeply, but not types
+/// from other crates. An event with a `Transform` still matches a build with a different
+/// version of bevy, and fails to decode if the fields of `Transform` changed.
+pub fn conflicting_events(theirs: &[EventSchema]) -> Vec<&'static str> {
+    EVENT_SCHEMAS
+        .iter()
+        .filter(|(_, ours)| {
+            theirs
+                .iter()
+                .any(|their| their.id == ours.id && their.hash != ours.hash)
+        })
+        .map(|(name, _)| *name)
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        event::client::{Chat, TickHappened},
+        netlib::{EventGroupingOwned, EventGroupingRef, EventToClient, NetworkingEvent, Tick},
+    };
+
+    #[test]
+    fn test_skips_unknown_events() {
+        let known = [
+            EventToClient::TickHappened(TickHappened { tick: Tick(3) }),
+            EventToClient::Unknown(UnknownEvent(Envelope {
+                id: u32::MAX,
+                payload: vec![1, 2, 3],
+            })),
+            EventToClient::Chat(Chat {
+                source: None,
+                text: "hi".to_string(),
+            }),
+        ];
+        let data = postcard::to_stdvec(&EventGroupingRef::Batch(&known)).unwrap();
+
+        let Ok(EventGroupingOwned::Batch(events)) = postcard::from_bytes(&data) else {
+            panic!("Couldn't read the batch back");
+        };
+        let names: Vec<_> = events.iter().map(EventToClient::name).collect();
+        assert_eq!(names, ["TickHappened", "Unknown", "Chat"]);
+        assert!(matches!(
+            &events[2],
+            EventToClient::Chat(Chat { text, .. }) if text == "hi"
+        ));
+    }
+
+    #[test]
+    fn test_skips_events_that_dont_decode() {
+        let chat = EVENT_SCHEMAS
+            .iter()
+            .find(|(name, _)| *name == "Chat")
+            .unwrap()
+            .1;
+        let sent = [
+            // Our Chat, but not one we can read
+            EventToClient::Unknown(UnknownEvent(Envelope {
+                id: chat.id,
+                payload: vec![0xff, 0xff],
+            })),
+            EventToClient::TickHappened(TickHappened { tick: Tick(3) }),
+        ];
+        let data = postcard::to_stdvec(&EventGroupingRef::Batch(&sent)).unwrap();
+
+        let Ok(EventGroupingOwned::Batch(events)) = postcard::from_bytes(&data) else {
+            panic!("Couldn't read the batch back");
+        };
+        let names: Vec<_> = events.iter().map(EventToClient::name).collect();
+        assert_eq!(names, ["Unknown", "TickHappened"]);
+    }
+
+    #[test]
+    fn test_only_shared_events_conflict() {
+        let mut theirs = our_event_schemas();
+        assert!(conflicting_events(&theirs).is_empty());
+
+        // New to them, or gone for them
+        theirs.push(EventSchema {
+            id: u32::MAX,
+            hash: 0,
+        });
+        theirs.remove(0);
+        assert!(conflicting_events(&theirs).is_empty());
+
+        theirs[0].hash ^= 1;
+        assert_eq!(conflicting_events(&theirs), [EVENT_SCHEMAS[1].0]);
+    }
+}
@@ -0,0 +1,213 @@
+//! Splitting datagrams that don't fit in one UDP packet, and putting them back together.
+//!
+//! Fragmentation happens below the reliable layer: a reliable datagram is acked and resent as a
+//! whole, and every (re)send is fragmented again under a fresh group id. Incomplete groups are
+//! dropped after [`FRAGMENT_TIMEOUT`].
+use std::{collections::HashMap, time::Duration};
+
+use bevy_internal::platform::time::Instant;
+use serde::{Deserialize, Serialize};
+
+/// Payload bytes per fragment, leaves room for the header under a typical MTU
+pub const MAX_FRAGMENT_PAYLOAD: usize = 1400;
+/// Fragments per group we are willing to send or reassemble
+pub const MAX_FRAGMENT_COUNT: u16 = 256;
+/// Largest datagram we are willing to reassemble
+pub const MAX_REASSEMBLED_SIZE: usize = MAX_FRAGMENT_PAYLOAD * MAX_FRAGMENT_COUNT as usize;
+/// Incomplete groups held per endpoint, the oldest is dropped beyond this
+pub const MAX_PENDING_GROUPS: usize = 16;
+pub const FRAGMENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub type FragmentGroup = u32;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct FragmentHeader {
+    pub group: FragmentGroup,
+    pub index: u16,
+    pub count: u16,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum FragmentError {
+    TooLarge { len: usize },
+    InvalidHeader(FragmentHeader),
+    CountMismatch { expected: u16, got: u16 },
+    PayloadTooLarge { len: usize },
+}
+
+/// Split `data` into fragments of at most [`MAX_FRAGMENT_PAYLOAD`] bytes.
+pub fn split(
+    group: FragmentGroup,
+    data: &[u8],
+) -> Result<Vec<(FragmentHeader, &[u8])>, FragmentError> {
+    let count = data.len().div_ceil(MAX_FRAGMENT_PAYLOAD);
+    if count > MAX_FRAGMENT_COUNT as usize {
+        return Err(FragmentError::TooLarge { len: data.len() });
+    }
+
+    Ok(data
+        .chunks(MAX_FRAGMENT_PAYLOAD)
+        .enumerate()
+        .map(|(index, chunk)| {
+            let header = FragmentHeader {
+                group,
+                index: index as u16,
+                count: count as u16,
+            };
+            (header, chunk)
+        })
+        .collect())
+}
+
+struct PartialGroup {
+    started: Instant,
+    fragments: Vec<Option<Vec<u8>>>,
+    received: u16,
+}
+
+/// Per-endpoint buffers of fragments that are still waiting for the rest of their group.
+#[derive(Default)]
+pub struct FragmentReassembly {
+    groups: HashMap<FragmentGroup, PartialGroup>,
+}
+
+impl FragmentReassembly {
+    /// Add a fragment. Returns the full datagram once every fragment of its group has arrived.
+    pub fn insert(
+        &mut self,
+        header: FragmentHeader,
+        payload: Vec<u8>,
+        now: Instant,
+    ) -> Result<Option<Vec<u8>>, FragmentError> {
+        self.groups
+            .retain(|_, group| now.saturating_duration_since(group.started) < FRAGMENT_TIMEOUT);
+
+        if header.count == 0 || header.count > MAX_FRAGMENT_COUNT || header.index >= header.count {
+            return Err(FragmentError::InvalidHeader(header));
+        }
+        if payload.len() > MAX_FRAGMENT_PAYLOAD {
+            return Err(FragmentError::PayloadTooLarge { len: payload.len() });
+        }
+
+        if !self.groups.contains_key(&header.group) && self.groups.len() >= MAX_PENDING_GROUPS {
+            let oldest = self
+                .groups
+                .iter()
+                .min_by_key(|(_, group)| group.started)
+                .map(|(&id, _)| id);
+            if let Some(oldest) = oldest {
+                self.groups.remove(&oldest);
+            }
+        }
+
+        let group = self
+            .groups
+            .entry(header.group)
+            .or_insert_with(|| PartialGroup {
+                started: now,
+                fragments: vec![None; header.count as usize],
+                received: 0,
+            });
+
+        if group.fragments.len() != header.count as usize {
+            let expected = group.fragments.len() as u16;
+            self.groups.remove(&header.group);
+            return Err(FragmentError::CountMismatch {
+                expected,
+                got: header.count,
+            });
+        }
+
+        let slot = &mut group.fragments[header.index as usize];
+        if slot.is_none() {
+            *slot = Some(payload);
+            group.received += 1;
+        }
+
+        if group.received < header.count {
+            return Ok(None);
+        }
+
+        let group = self.groups.remove(&header.group).unwrap();
+        Ok(Some(
+            group.fragments.into_iter().flatten().flatten().collect(),
+        ))
+    }
+
+    pub fn pending_groups(&self) -> usize {
+        self.groups.len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_split_and_reassemble_out_of_order() {
+        let data: Vec<u8> = (0..(MAX_FRAGMENT_PAYLOAD * 3 + 10))
+            .map(|i| i as u8)
+            .collect();
+        let fragments = split(7, &data).unwrap();
+        assert_eq!(fragments.len(), 4);
+
+        let now = Instant::now();
+        let mut reassembly = FragmentReassembly::default();
+        for (header, payload) in fragments.iter().rev().skip(1) {
+            let out = reassembly.insert(*header, payload.to_vec(), now).unwrap();
+            assert_eq!(out, None);
+        }
+        // duplicates don't complete a group
+        let (header, payload) = fragments[1];
+        assert_eq!(reassembly.insert(header, payload.to_vec(), now), Ok(None));
+
+        let (header, payload) = fragments[3];
+        let out = reassembly.insert(header, payload.to_vec(), now).unwrap();
+        assert_eq!(out, Some(data));
+        assert_eq!(reassembly.pending_groups(), 0);
+    }
+
+    #[test]
+    fn test_rejects_bad_fragments() {
+        let now = Instant::now();
+        let mut reassembly = FragmentReassembly::default();
+        let header = FragmentHeader {
+            group: 1,
+            index: 2,
+            count: 2,
+        };
+        assert!(reassembly.insert(header, vec![0], now).is_err());
+
+        let header = FragmentHeader {
+            group: 1,
+            index: 0,
+            count: MAX_FRAGMENT_COUNT + 1,
+        };
+        assert!(reassembly.insert(header, vec![0], now).is_err());
+
+        assert!(split(1, &vec![0; MAX_REASSEMBLED_SIZE + 1]).is_err());
+    }
+
+    #[test]
+    fn test_incomplete_groups_time_out() {
+        let now = Instant::now();
+        let mut reassembly = FragmentReassembly::default();
+        let header = FragmentHeader {
+            group: 1,
+            index: 0,
+            count: 2,
+        };
+        reassembly.insert(header, vec![0], now).unwrap();
+        assert_eq!(reassembly.pending_groups(), 1);
+
+        let header = FragmentHeader {
+            group: 2,
+            index: 0,
+            count: 2,
+        };
+        reassembly
+            .insert(header, vec![0], now + FRAGMENT_TIMEOUT)
+            .unwrap();
+        assert_eq!(reassembly.pending_groups(), 1);
+    }
+}
@@ -0,0 +1,261 @@
+//! Token buckets limiting how many events of each type an endpoint may send us.
+//!
+//! Every endpoint gets a bucket per event type. Each event takes a token and buckets refill every
+//! tick up to their burst size. Events that find their bucket empty are dropped before any system
+//! sees them. Drops also fill an abuse meter that slowly drains, and an endpoint that keeps it
+//! full is reported so the server can kick it.
+use std::collections::{BTreeMap, HashMap};
+
+use bevy_internal::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    BASE_TICKS_PER_SECOND, CurrentTick,
+    netlib::{EndpointGeneral, NetworkingEvent, Tick},
+};
+
+#[derive(Reflect, Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct RateLimit {
+    /// Tokens regained every second
+    pub per_second: f32,
+    /// Most tokens a bucket holds, so how many events may arrive at once
+    pub burst: f32,
+}
+
+impl RateLimit {
+    const fn new(per_second: f32, burst: f32) -> Self {
+        Self { per_second, burst }
+    }
+}
+
+#[derive(Reflect, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RateLimits {
+    /// For event types without their own entry
+    pub default: RateLimit,
+    /// By event type name, like `SpawnMan`, or RPC method
+    pub events: HashMap<String, RateLimit>,
+    /// Dropped events forgiven every second
+    pub forgiven_drops_per_second: f32,
+    /// Unforgiven drops before we report the endpoint for abuse
+    pub abuse_threshold: f32,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        let events = [
+            ("SpawnMan", RateLimit::new(1.0, 3.0)),
+            ("SpawnCircle", RateLimit::new(1.0, 1.0)),
+            ("CastSkillUpdate", RateLimit::new(10.0, 20.0)),
+            // An RPC, the scoreboard calls it ten times a second while it is open
+            ("RequestScoreboard", RateLimit::new(20.0, 20.0)),
+            ("SendChat", RateLimit::new(2.0, 5.0)),
+        ];
+
+        Self {
+            // Movement is sent every tick, this leaves plenty of room above that
+            default: RateLimit::new(240.0, 480.0),
+            events: events
+                .into_iter()
+                .map(|(name, limit)| (name.to_string(), limit))
+                .collect(),
+            forgiven_drops_per_second: 10.0,
+            abuse_threshold: 300.0,
+        }
+    }
+}
+
+/// A level that rises by one per event and drains at a fixed rate per tick
+#[derive(Debug, Clone, Copy)]
+struct Meter {
+    level: f32,
+    updated: Tick,
+}
+
+impl Meter {
+    fn new(level: f32, now: Tick) -> Self {
+        Self {
+            level,
+            updated: now,
+        }
+    }
+
+    /// Move the level `per_second` towards `towards` for every tick since the last update
+    fn update(&mut self, per_second: f32, towards: f32, now: Tick) {
+        let ticks = now.0.saturating_sub(self.updated.0) as f32;
+        let change = ticks * per_second / BASE_TICKS_PER_SECOND as f32;
+        self.level = if self.level < towards {
+            (self.level + change).min(towards)
+        } else {
+            (self.level - change).max(towards)
+        };
+        self.updated = self.updated.max(now);
+    }
+}
+
+#[derive(Debug, Default)]
+struct EndpointLimits {
+    buckets: HashMap<&'static str, Meter>,
+    abuse: Option<Meter>,
+    reported: bool,
+}
+
+#[derive(Resource, Debug)]
+pub struct RateLimiter {
+    limits: RateLimits,
+    endpoints: HashMap<EndpointGeneral, EndpointLimits>,
+    abusive: Vec<EndpointGeneral>,
+    /// Since we started, by type, so forgetting endpoints doesn't lose any
+    dropped: BTreeMap<&'static str, u64>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits) -> Self {
+        Self {
+            limits,
+            endpoints: HashMap::new(),
+            abusive: vec![],
+            dropped: BTreeMap::new(),
+        }
+    }
+
+    fn limit_for(&self, name: &str) -> RateLimit {
+        self.limits
+            .events
+            .get(name)
+            .copied()
+            .unwrap_or(self.limits.default)
+    }
+
+    /// Whether `endpoint` may send us an event of type `name` at `now`. Refused events count
+    /// towards kicking it.
+    pub fn allow(&mut self, endpoint: EndpointGeneral, name: &'static str, now: Tick) -> bool {
+        let limit = self.limit_for(name);
+        let endpoint_limits = self.endpoints.entry(endpoint).or_default();
+
+        let bucket = endpoint_limits
+            .buckets
+            .entry(name)
+            .or_insert_with(|| Meter::new(limit.burst, now));
+        bucket.update(limit.per_second, limit.burst, now);
+        if bucket.level >= 1.0 {
+            bucket.level -= 1.0;
+            return true;
+        }
+
+        *self.dropped.entry(name).or_default() += 1;
+        let abuse = endpoint_limits
+            .abuse
+            .get_or_insert_with(|| Meter::new(0.0, now));
+        abuse.update(self.limits.forgiven_drops_per_second, 0.0, now);
+        abuse.level += 1.0;
+        debug!(
+            ?endpoint,
+            name,
+            abuse = abuse.level,
+            "Dropped rate limited event"
+        );
+
+        if abuse.level > self.limits.abuse_threshold && !endpoint_limits.reported {
+            warn!(?endpoint, "Endpoint kept going over its rate limits");
+            endpoint_limits.reported = true;
+            self.abusive.push(endpoint);
+        }
+        false
+    }
+
+    /// Events dropped since we started, by type
+    pub fn dropped(&self) -> impl Iterator<Item = (&'static str, u64)> {
+        self.dropped.iter().map(|(name, count)| (*name, *count))
+    }
+
+    /// Endpoints that kept going over their limits since the last call
+    pub fn take_abusive(&mut self) -> Vec<EndpointGeneral> {
+        std::mem::take(&mut self.abusive)
+    }
+
+    pub fn forget(&mut self, endpoint: EndpointGeneral) {
+        self.endpoints.remove(&endpoint);
+    }
+}
+
+/// Drop the events their sender is over its limits for, if there is a [`RateLimiter`]
+pub fn apply_rate_limits<E: NetworkingEvent>(
+    world: &mut World,
+    events: Vec<(EndpointGeneral, E)>,
+) -> Vec<(EndpointGeneral, E)> {
+    let Some(now) = world.get_resource::<CurrentTick>().map(|t| t.0) else {
+        return events;
+    };
+    let Some(mut limiter) = world.get_resource_mut::<RateLimiter>() else {
+        return events;
+    };
+
+    events
+        .into_iter()
+        .filter(|(endpoint, event)| limiter.allow(*endpoint, event.name(), now))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::netlib::transport::MemoryEndpoint;
+
+    fn limiter() -> RateLimiter {
+        RateLimiter::new(RateLimits {
+            default: RateLimit::new(BASE_TICKS_PER_SECOND as f32, 2.0),
+            events: HashMap::new(),
+            forgiven_drops_per_second: BASE_TICKS_PER_SECOND as f32,
+            abuse_threshold: 10.0,
+        })
+    }
+
+    #[test]
+    fn test_buckets_refill_per_tick() {
+        let mut limiter = limiter();
+        let a = EndpointGeneral::Memory(MemoryEndpoint(1));
+        let b = EndpointGeneral::Memory(MemoryEndpoint(2));
+
+        // The burst, then nothing until the next tick refills one token
+        assert!(limiter.allow(a, "SpawnMan", Tick(1)));
+        assert!(limiter.allow(a, "SpawnMan", Tick(1)));
+        assert!(!limiter.allow(a, "SpawnMan", Tick(1)));
+        assert!(limiter.allow(a, "SpawnMan", Tick(2)));
+        assert!(!limiter.allow(a, "SpawnMan", Tick(2)));
+
+        // Other types and endpoints have their own buckets
+        assert!(limiter.allow(a, "SendChat", Tick(2)));
+        assert!(limiter.allow(b, "SpawnMan", Tick(2)));
+
+        assert!(limiter.take_abusive().is_empty());
+
+        // Still counted once the endpoint is forgotten
+        limiter.forget(a);
+        assert_eq!(limiter.dropped().collect::<Vec<_>>(), vec![("SpawnMan", 2)]);
+    }
+
+    #[test]
+    fn test_reports_sustained_abuse_once() {
+        let mut limiter = limiter();
+        let a = EndpointGeneral::Memory(MemoryEndpoint(1));
+
+        // One drop a tick is forgiven as fast as it happens
+        for tick in 1..100 {
+            for _ in 0..2 {
+                limiter.allow(a, "SpawnMan", Tick(tick));
+            }
+        }
+        assert!(limiter.take_abusive().is_empty());
+
+        // Ten a tick is not
+        for tick in 100..110 {
+            for _ in 0..10 {
+                limiter.allow(a, "SpawnMan", Tick(tick));
+            }
+        }
+        assert_eq!(limiter.take_abusive(), vec![a]);
+        limiter.allow(a, "SpawnMan", Tick(110));
+        assert!(limiter.take_abusive().is_empty());
+    }
+}
@@ -0,0 +1,569 @@
+//! Ack-based reliable delivery for UDP endpoints.
+//!
+//! Every reliable datagram carries a [`Sequence`] number and a piggybacked [`AckHeader`] telling
+//! the other side which of its datagrams we have received. Datagrams that have not been acked are
+//! kept in a bounded resend queue and sent again once they are older than the RTT-derived resend
+//! timeout of the connection.
+//!
+//! Reliable-ordered datagrams additionally carry an ordering sequence, and are held back in an
+//! [`OrderedReceiveBuffer`] until everything before them has been delivered.
+use std::{
+    collections::{HashMap, VecDeque},
+    time::Duration,
+};
+
+use bevy_internal::platform::time::Instant;
+use serde::{Deserialize, Serialize};
+
+use super::Tick;
+
+pub type Sequence = u16;
+
+/// How far behind the latest sequence we can still tell a resend from a duplicate. Half of the
+/// sequence space, anything further looks newer than the latest.
+pub const RECEIVE_WINDOW_SIZE: usize = 1 << 15;
+/// Number of sequences before [`AckHeader::ack`] that are described by [`AckHeader::ack_bits`]
+const ACK_BITS: u16 = 64;
+/// Maximum number of unacked datagrams we hold per connection before giving up on it
+pub const MAX_RESEND_QUEUE: usize = 512;
+/// Maximum number of late acks sent in a single header
+pub const MAX_LATE_ACKS: usize = 8;
+/// After this many resends we assume the other side is gone and stop trying
+pub const MAX_RESEND_ATTEMPTS: u32 = 30;
+
+const MIN_RESEND_TIMEOUT: Duration = Duration::from_millis(50);
+const MAX_RESEND_TIMEOUT: Duration = Duration::from_millis(1000);
+const INITIAL_RTT_SECS: f64 = 0.1;
+
+/// Returns true if `a` is more recent than `b`, accounting for wrap-around
+pub fn sequence_greater_than(a: Sequence, b: Sequence) -> bool {
+    (a > b && a - b <= 32768) || (a < b && b - a > 32768)
+}
+
+/// Why a connection can't deliver reliable datagrams any more. Nothing unacked will get through,
+/// so whoever owns the connection should drop it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReliableError {
+    /// More than [`MAX_RESEND_QUEUE`] datagrams are waiting for an ack
+    ResendQueueFull,
+    /// Resent [`MAX_RESEND_ATTEMPTS`] times without an ack
+    TooManyResends { sequence: Sequence },
+    /// Still unacked [`RECEIVE_WINDOW_SIZE`] sequences later, where the other side can't tell a
+    /// resend of it from a duplicate
+    TooFarBehind { sequence: Sequence },
+}
+
+/// Tells the other side which of its reliable datagrams we have received.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct AckHeader {
+    /// The most recent sequence we have received, if any
+    pub ack: Option<Sequence>,
+    /// Bit `n` set means `ack - 1 - n` was also received
+    pub ack_bits: u64,
+    /// Resent datagrams that arrived again after they fell out of `ack_bits`
+    pub late_acks: Vec<Sequence>,
+}
+
+impl AckHeader {
+    pub fn acks(&self, sequence: Sequence) -> bool {
+        if self.late_acks.contains(&sequence) {
+            return true;
+        }
+
+        let Some(ack) = self.ack else {
+            return false;
+        };
+
+        if ack == sequence {
+            return true;
+        }
+
+        let age = ack.wrapping_sub(sequence);
+        (1..=ACK_BITS).contains(&age) && self.ack_bits & (1 << (age - 1)) != 0
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ReliableHeader {
+    pub sequence: Sequence,
+    /// Set for reliable-ordered datagrams, counts separately from `sequence`
+    pub ordered: Option<Sequence>,
+    pub tick: Tick,
+    pub ack: AckHeader,
+}
+
+impl ReliableHeader {
+    /// A header at least as large as any real header, used to size chunks before the real
+    /// sequence number is assigned.
+    pub fn size_probe(tick: Tick) -> Self {
+        Self {
+            sequence: Sequence::MAX,
+            ordered: Some(Sequence::MAX),
+            tick,
+            ack: AckHeader {
+                ack: Some(Sequence::MAX),
+                ack_bits: u64::MAX,
+                late_acks: vec![Sequence::MAX; MAX_LATE_ACKS],
+            },
+        }
+    }
+}
+
+/// Remembers which sequences we have received from the other side of a connection.
+pub struct ReceiveWindow {
+    latest: Option<Sequence>,
+    /// One bit for every sequence number. Bits are cleared as `latest` moves past them, so only
+    /// the last [`RECEIVE_WINDOW_SIZE`] sequences can be set.
+    received: Vec<u64>,
+    late_acks: Vec<Sequence>,
+}
+
+impl Default for ReceiveWindow {
+    fn default() -> Self {
+        Self {
+            latest: None,
+            received: vec![0; (Sequence::MAX as usize + 1) / 64],
+            late_acks: Vec::new(),
+        }
+    }
+}
+
+impl ReceiveWindow {
+    pub fn contains(&self, sequence: Sequence) -> bool {
+        self.received[sequence as usize / 64] & (1 << (sequence % 64)) != 0
+    }
+
+    fn set(&mut self, sequence: Sequence, received: bool) {
+        let word = &mut self.received[sequence as usize / 64];
+        if received {
+            *word |= 1 << (sequence % 64);
+        } else {
+            *word &= !(1 << (sequence % 64));
+        }
+    }
+
+    /// Record `sequence` as received. Returns false if we have already seen it.
+    pub fn insert(&mut self, sequence: Sequence) -> bool {
+        let Some(latest) = self.latest else {
+            self.set(sequence, true);
+            self.latest = Some(sequence);
+            return true;
+        };
+
+        if sequence_greater_than(sequence, latest) {
+            // Clear the bits we skipped, they were set a whole lap of the sequence space ago
+            let mut skipped = latest.wrapping_add(1);
+            while skipped != sequence {
+                self.set(skipped, false);
+                skipped = skipped.wrapping_add(1);
+            }
+
+            self.set(sequence, true);
+            self.latest = Some(sequence);
+            return true;
+        }
+
+        let age = latest.wrapping_sub(sequence);
+        if age as usize >= RECEIVE_WINDOW_SIZE {
+            // We can't tell if we've had this one. Acking it could throw away a packet we never
+            // delivered, so leave it unacked and let the sender give up on the connection.
+            return false;
+        }
+
+        // The bitfield can't describe this sequence anymore, so ack it explicitly
+        if age > ACK_BITS {
+            self.push_late_ack(sequence);
+        }
+
+        if self.contains(sequence) {
+            return false;
+        }
+
+        self.set(sequence, true);
+        true
+    }
+
+    fn push_late_ack(&mut self, sequence: Sequence) {
+        if !self.late_acks.contains(&sequence) {
+            self.late_acks.push(sequence);
+        }
+    }
+
+    pub fn ack_header(&mut self) -> AckHeader {
+        let Some(latest) = self.latest else {
+            return AckHeader::default();
+        };
+
+        let mut ack_bits = 0;
+        for i in 0..ACK_BITS {
+            if self.contains(latest.wrapping_sub(i + 1)) {
+                ack_bits |= 1 << i;
+            }
+        }
+
+        let late_ack_count = self.late_acks.len().min(MAX_LATE_ACKS);
+        AckHeader {
+            ack: Some(latest),
+            ack_bits,
+            late_acks: self.late_acks.drain(..late_ack_count).collect(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct SentPacket {
+    pub sequence: Sequence,
+    pub data: Vec<u8>,
+    first_sent: Instant,
+    last_sent: Instant,
+    resend_count: u32,
+}
+
+/// Reliability state for one endpoint, for both directions.
+pub struct ReliableConnection {
+    next_sequence: Sequence,
+    next_ordered_sequence: Sequence,
+    received: ReceiveWindow,
+    unacked: VecDeque<SentPacket>,
+    smoothed_rtt_secs: f64,
+    rtt_variance_secs: f64,
+    /// Set when we received reliable data that we have not acked yet
+    ack_pending: bool,
+}
+
+impl Default for ReliableConnection {
+    fn default() -> Self {
+        Self {
+            next_sequence: 0,
+            next_ordered_sequence: 0,
+            received: ReceiveWindow::default(),
+            unacked: VecDeque::new(),
+            smoothed_rtt_secs: INITIAL_RTT_SECS,
+            rtt_variance_secs: INITIAL_RTT_SECS / 2.0,
+            ack_pending: false,
+        }
+    }
+}
+
+impl ReliableConnection {
+    /// Assign the next sequence number and attach our current acks.
+    pub fn next_header(&mut self, tick: Tick, ordered: bool) -> ReliableHeader {
+        let sequence = self.next_sequence;
+        self.next_sequence = self.next_sequence.wrapping_add(1);
+        self.ack_pending = false;
+
+        let ordered = ordered.then(|| {
+            let ordered = self.next_ordered_sequence;
+            self.next_ordered_sequence = self.next_ordered_sequence.wrapping_add(1);
+            ordered
+        });
+
+        ReliableHeader {
+            sequence,
+            ordered,
+            tick,
+            ack: self.received.ack_header(),
+        }
+    }
+
+    /// Keep a sent datagram around until it is acked. On error everything unacked is dropped,
+    /// the connection is done for.
+    pub fn track_sent(
+        &mut self,
+        sequence: Sequence,
+        data: Vec<u8>,
+        now: Instant,
+    ) -> Result<(), ReliableError> {
+        self.unacked.push_back(SentPacket {
+            sequence,
+            data,
+            first_sent: now,
+            last_sent: now,
+            resend_count: 0,
+        });
+
+        let oldest = self.unacked[0].sequence;
+        let error = if self.unacked.len() > MAX_RESEND_QUEUE {
+            ReliableError::ResendQueueFull
+        } else if sequence.wrapping_sub(oldest) as usize >= RECEIVE_WINDOW_SIZE {
+            ReliableError::TooFarBehind { sequence: oldest }
+        } else {
+            return Ok(());
+        };
+        self.unacked.clear();
+        Err(error)
+    }
+
+    /// Record a reliable datagram from the other side. Returns false if it is a duplicate.
+    pub fn receive(&mut self, sequence: Sequence) -> bool {
+        self.ack_pending = true;
+        self.received.insert(sequence)
+    }
+
+    /// If we owe the other side an ack and haven't piggybacked it yet, build one now.
+    pub fn take_pending_ack(&mut self) -> Option<AckHeader> {
+        if !self.ack_pending {
+            return None;
+        }
+        self.ack_pending = false;
+        Some(self.received.ack_header())
+    }
+
+    /// Remove everything the other side acked. Returns how many datagrams were newly acked.
+    pub fn process_ack(&mut self, ack: &AckHeader, now: Instant) -> usize {
+        let mut rtt_samples = vec![];
+        let before = self.unacked.len();
+        self.unacked.retain(|packet| {
+            if !ack.acks(packet.sequence) {
+                return true;
+            }
+            // Karn's algorithm: resent packets give ambiguous samples
+            if packet.resend_count == 0 {
+                rtt_samples.push(
+                    now.saturating_duration_since(packet.first_sent)
+                        .as_secs_f64(),
+                );
+            }
+            false
+        });
+
+        for sample in rtt_samples {
+            self.add_rtt_sample(sample);
+        }
+
+        before - self.unacked.len()
+    }
+
+    fn add_rtt_sample(&mut self, sample_secs: f64) {
+        self.rtt_variance_secs =
+            0.75 * self.rtt_variance_secs + 0.25 * (self.smoothed_rtt_secs - sample_secs).abs();
+        self.smoothed_rtt_secs = 0.875 * self.smoothed_rtt_secs + 0.125 * sample_secs;
+    }
+
+    pub fn rtt(&self) -> Duration {
+        Duration::from_secs_f64(self.smoothed_rtt_secs)
+    }
+
+    pub fn resend_timeout(&self) -> Duration {
+        Duration::from_secs_f64(self.smoothed_rtt_secs + 4.0 * self.rtt_variance_secs)
+            .clamp(MIN_RESEND_TIMEOUT, MAX_RESEND_TIMEOUT)
+    }
+
+    /// Datagrams that have waited longer than the resend timeout. They are marked as sent again.
+    /// On error everything unacked is dropped, like in [`Self::track_sent`].
+    pub fn collect_resends(&mut self, now: Instant) -> Result<Vec<Vec<u8>>, ReliableError> {
+        if let Some(packet) = self
+            .unacked
+            .iter()
+            .find(|packet| packet.resend_count >= MAX_RESEND_ATTEMPTS)
+        {
+            let sequence = packet.sequence;
+            self.unacked.clear();
+            return Err(ReliableError::TooManyResends { sequence });
+        }
+
+        let timeout = self.resend_timeout();
+        let mut resends = vec![];
+        for packet in self.unacked.iter_mut() {
+            if now.saturating_duration_since(packet.last_sent) >= timeout {
+                packet.last_sent = now;
+                packet.resend_count += 1;
+                resends.push(packet.data.clone());
+            }
+        }
+        Ok(resends)
+    }
+
+    pub fn unacked_len(&self) -> usize {
+        self.unacked.len()
+    }
+}
+
+/// Holds back reliable-ordered events until all earlier ones have arrived.
+pub struct OrderedReceiveBuffer<T> {
+    next_expected: Sequence,
+    pending: HashMap<Sequence, Vec<T>>,
+}
+
+impl<T> Default for OrderedReceiveBuffer<T> {
+    fn default() -> Self {
+        Self {
+            next_expected: 0,
+            pending: HashMap::new(),
+        }
+    }
+}
+
+impl<T> OrderedReceiveBuffer<T> {
+    /// Add the events of one ordered datagram, returning everything that can be delivered now.
+    pub fn push(&mut self, sequence: Sequence, events: Vec<T>) -> Vec<T> {
+        if sequence_greater_than(self.next_expected, sequence) {
+            // Already delivered, or skipped over below
+            return vec![];
+        }
+        self.pending.insert(sequence, events);
+
+        // The sender drops the connection before it lets a gap get this big, but don't let a
+        // broken one make us hold on to everything after it.
+        if self.pending.len() > MAX_RESEND_QUEUE
+            && let Some(&oldest) = self
+                .pending
+                .keys()
+                .min_by_key(|s| s.wrapping_sub(self.next_expected))
+        {
+            self.next_expected = oldest;
+        }
+
+        let mut ready = vec![];
+        while let Some(events) = self.pending.remove(&self.next_expected) {
+            ready.extend(events);
+            self.next_expected = self.next_expected.wrapping_add(1);
+        }
+        ready
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_sequence_wraps() {
+        assert!(sequence_greater_than(1, 0));
+        assert!(!sequence_greater_than(0, 1));
+        assert!(sequence_greater_than(0, Sequence::MAX));
+        assert!(sequence_greater_than(5, Sequence::MAX - 5));
+        assert!(!sequence_greater_than(Sequence::MAX, 0));
+    }
+
+    #[test]
+    fn test_receive_window_dedup() {
+        let mut window = ReceiveWindow::default();
+        assert!(window.insert(0));
+        assert!(window.insert(2));
+        assert!(!window.insert(2));
+        assert!(window.insert(1));
+        assert!(!window.insert(0));
+
+        // Wrap around the sequence space without false duplicates
+        let mut window = ReceiveWindow::default();
+        for i in 0..(Sequence::MAX as u32 + 10) {
+            assert!(window.insert(i as Sequence));
+        }
+        assert!(!window.insert(5));
+    }
+
+    #[test]
+    fn test_ack_removes_received_packets() {
+        let now = Instant::now();
+        let mut sender = ReliableConnection::default();
+        let mut receiver = ReliableConnection::default();
+
+        for _ in 0..3 {
+            let header = sender.next_header(Tick(1), false);
+            sender
+                .track_sent(header.sequence, vec![header.sequence as u8], now)
+                .unwrap();
+        }
+
+        // Sequence 1 was lost
+        assert!(receiver.receive(0));
+        assert!(receiver.receive(2));
+        let ack = receiver.take_pending_ack().unwrap();
+        assert!(receiver.take_pending_ack().is_none());
+
+        assert_eq!(sender.process_ack(&ack, now), 2);
+        assert_eq!(sender.unacked_len(), 1);
+
+        assert!(sender.collect_resends(now).unwrap().is_empty());
+        let resends = sender.collect_resends(now + MAX_RESEND_TIMEOUT).unwrap();
+        assert_eq!(resends, vec![vec![1]]);
+    }
+
+    #[test]
+    fn test_gives_up_loudly() {
+        let mut now = Instant::now();
+        let mut sender = ReliableConnection::default();
+        sender.track_sent(0, vec![0], now).unwrap();
+        for _ in 0..MAX_RESEND_ATTEMPTS {
+            now += MAX_RESEND_TIMEOUT;
+            assert_eq!(sender.collect_resends(now).unwrap().len(), 1);
+        }
+        assert_eq!(
+            sender.collect_resends(now + MAX_RESEND_TIMEOUT),
+            Err(ReliableError::TooManyResends { sequence: 0 })
+        );
+        assert_eq!(sender.unacked_len(), 0);
+
+        // The oldest is never acked while everything after it is
+        let mut sender = ReliableConnection::default();
+        let oldest = sender.next_header(Tick(1), false).sequence;
+        sender.track_sent(oldest, vec![], now).unwrap();
+        let error = (1..=RECEIVE_WINDOW_SIZE).find_map(|_| {
+            let sequence = sender.next_header(Tick(1), false).sequence;
+            let tracked = sender.track_sent(sequence, vec![], now);
+            sender.process_ack(
+                &AckHeader {
+                    ack: Some(sequence),
+                    ..Default::default()
+                },
+                now,
+            );
+            tracked.err()
+        });
+        assert_eq!(
+            error,
+            Some(ReliableError::TooFarBehind { sequence: oldest })
+        );
+
+        let mut sender = ReliableConnection::default();
+        let error = (0..=MAX_RESEND_QUEUE as Sequence)
+            .find_map(|sequence| sender.track_sent(sequence, vec![], now).err());
+        assert_eq!(error, Some(ReliableError::ResendQueueFull));
+    }
+
+    #[test]
+    fn test_late_acks_for_old_resends() {
+        let mut receiver = ReliableConnection::default();
+        assert!(receiver.receive(0));
+        for sequence in 2..200 {
+            assert!(receiver.receive(sequence));
+        }
+        // Sequence 1 is resent long after the bitfield moved past it
+        assert!(receiver.receive(1));
+        let ack = receiver.take_pending_ack().unwrap();
+        assert!(ack.acks(1));
+        assert!(ack.acks(199));
+        assert!(!ack.acks(200));
+    }
+
+    #[test]
+    fn test_resend_after_many_newer_packets() {
+        let mut receiver = ReliableConnection::default();
+        assert!(receiver.receive(0));
+        for sequence in 2..3000 {
+            assert!(receiver.receive(sequence));
+        }
+        // Sequence 1 kept getting lost while thousands of others made it
+        assert!(receiver.receive(1));
+        assert!(!receiver.receive(1));
+        assert!(!receiver.receive(2));
+        assert!(receiver.take_pending_ack().unwrap().acks(1));
+
+        // Half the sequence space behind, we can't tell, so it isn't acked
+        let mut window = ReceiveWindow::default();
+        assert!(window.insert(RECEIVE_WINDOW_SIZE as Sequence));
+        assert!(!window.insert(0));
+        assert!(!window.ack_header().acks(0));
+    }
+
+    #[test]
+    fn test_ordered_buffer_holds_back_gaps() {
+        let mut buffer = OrderedReceiveBuffer::default();
+        assert_eq!(buffer.push(1, vec!["b"]), Vec::<&str>::new());
+        assert_eq!(buffer.push(2, vec!["c"]), Vec::<&str>::new());
+        assert_eq!(buffer.push(0, vec!["a"]), vec!["a", "b", "c"]);
+        assert_eq!(buffer.push(1, vec!["b"]), Vec::<&str>::new());
+        assert_eq!(buffer.push(3, vec!["d"]), vec!["d"]);
+    }
+}
@@ -0,0 +1,479 @@
+//! Encrypted and authenticated UDP sessions.
+//!
+//! The client opens a session with a [`SessionPacket::ClientHello`] carrying an ephemeral X25519
+//! public key. The server first answers with a [`SessionPacket::Cookie`] the client has to echo
+//! in its next hello, which proves it can receive at the address it sends from, and only then
+//! with its own key. Both sides derive one ChaCha20-Poly1305 key
+//! per direction from the shared secret, and from then on every datagram is sent as a
+//! [`SessionPacket::Data`]. Anything that doesn't decrypt under the session key, or replays a
+//! counter we have already seen, is dropped before it is deserialized.
+//!
+//! The server keeps nothing for a hello until its cookie checks out, see [`HelloCookies`].
+//!
+//! The server has no long-term identity, so this stops spoofed and replayed packets from other
+//! hosts but not an attacker that sits on the path during the handshake.
+use std::{net::SocketAddr, time::Duration};
+
+use bevy_internal::platform::time::Instant;
+use chacha20poly1305::{
+    ChaCha20Poly1305, Key, KeyInit, Nonce,
+    aead::{Aead, Payload},
+};
+use serde::{Deserialize, Serialize};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+const CLIENT_TO_SERVER_CONTEXT: &str = "bevy2025 2025-01 udp session client to server";
+const SERVER_TO_CLIENT_CONTEXT: &str = "bevy2025 2025-01 udp session server to client";
+
+/// Counters older than this many behind the newest one are rejected as replays
+const REPLAY_WINDOW: u64 = 64;
+/// How often the client repeats its hello until the server answers
+pub const HANDSHAKE_RESEND: Duration = Duration::from_millis(250);
+/// Datagrams the client holds while the handshake is in flight
+pub const MAX_QUEUED_DATAGRAMS: usize = 256;
+/// A session that authenticated a packet this recently can't be replaced by a new hello
+pub const SESSION_REPLACE_AFTER: Duration = Duration::from_secs(5);
+/// A session the client hasn't sent anything through this long after the handshake is dropped
+pub const UNAUTHENTICATED_SESSION_TIMEOUT: Duration = Duration::from_secs(10);
+/// The server answers no new hellos while this many sessions are waiting for their first packet.
+/// Only hosts that can receive at their address get this far, see [`HelloCookies`].
+pub const MAX_UNAUTHENTICATED_SESSIONS: usize = 1024;
+/// A cookie is accepted in the period it was made in and the one after
+pub const COOKIE_PERIOD: Duration = Duration::from_secs(10);
+/// ChaCha20-Poly1305 tag, plus the enum tag and counter of [`SessionPacket::Data`]
+pub const SESSION_OVERHEAD: usize = 16 + 1 + 10 + 2;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum SessionPacket<'a> {
+    ClientHello {
+        public_key: [u8; 32],
+        cookie: Option<[u8; 32]>,
+    },
+    /// The server's answer to a hello without a valid cookie
+    Cookie {
+        cookie: [u8; 32],
+    },
+    ServerHello {
+        public_key: [u8; 32],
+    },
+    Data {
+        counter: u64,
+        ciphertext: &'a [u8],
+    },
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum SessionError {
+    /// The peer sent a key that gives an all-zero shared secret
+    WeakKey,
+    Replayed {
+        counter: u64,
+    },
+    Unauthenticated,
+}
+
+fn nonce(counter: u64) -> Nonce {
+    let mut nonce = [0; 12];
+    nonce[4..].copy_from_slice(&counter.to_le_bytes());
+    nonce.into()
+}
+
+/// Sliding window over received counters
+#[derive(Debug, Default)]
+struct ReplayWindow {
+    newest: Option<u64>,
+    /// Bit `n` set means `newest - 1 - n` was received
+    seen: u64,
+}
+
+impl ReplayWindow {
+    fn is_fresh(&self, counter: u64) -> bool {
+        let Some(newest) = self.newest else {
+            return true;
+        };
+        if counter > newest {
+            return true;
+        }
+        let age = newest - counter;
+        (1..=REPLAY_WINDOW).contains(&age) && self.seen & (1 << (age - 1)) == 0
+    }
+
+    fn mark(&mut self, counter: u64) {
+        match self.newest {
+            Some(newest) if counter <= newest => {
+                self.seen |= 1 << (newest - counter - 1);
+            }
+            Some(newest) => {
+                let shift = counter - newest;
+                self.seen = if shift > REPLAY_WINDOW {
+                    0
+                } else {
+                    // the old newest becomes bit `shift - 1`
+                    (self.seen << 1 | 1)
+                        .checked_shl(shift as u32 - 1)
+                        .unwrap_or(0)
+                };
+                self.newest = Some(counter);
+            }
+            None => self.newest = Some(counter),
+        }
+    }
+}
+
+pub struct Session {
+    send: ChaCha20Poly1305,
+    receive: ChaCha20Poly1305,
+    send_counter: u64,
+    replay: ReplayWindow,
+    peer_public: [u8; 32],
+    established: Instant,
+    /// Last time a packet from the peer passed authentication
+    pub last_authenticated: Option<Instant>,
+}
+
+impl Session {
+    fn derive(
+        secret: &StaticSecret,
+        peer_public: [u8; 32],
+        client_public: &[u8; 32],
+        server_public: &[u8; 32],
+        is_client: bool,
+    ) -> Result<Self, SessionError> {
+        let shared = secret.diffie_hellman(&PublicKey::from(peer_public));
+        if !shared.was_contributory() {
+            return Err(SessionError::WeakKey);
+        }
+
+        let mut material = Vec::with_capacity(96);
+        material.extend_from_slice(shared.as_bytes());
+        material.extend_from_slice(client_public);
+        material.extend_from_slice(server_public);
+        let client_to_server = blake3::derive_key(CLIENT_TO_SERVER_CONTEXT, &material);
+        let server_to_client = blake3::derive_key(SERVER_TO_CLIENT_CONTEXT, &material);
+
+        let (send, receive) = if is_client {
+            (client_to_server, server_to_client)
+        } else {
+            (server_to_client, client_to_server)
+        };
+
+        Ok(Self {
+            send: ChaCha20Poly1305::new(Key::from_slice(&send)),
+            receive: ChaCha20Poly1305::new(Key::from_slice(&receive)),
+            send_counter: 0,
+            replay: ReplayWindow::default(),
+            peer_public,
+            established: Instant::now(),
+            last_authenticated: None,
+        })
+    }
+
+    /// Server side: answer a client's hello. Returns the session and our hello to send back.
+    pub fn accept(client_public: [u8; 32]) -> Result<(Self, Vec<u8>), SessionError> {
+        let secret = StaticSecret::from(rand::random::<[u8; 32]>());
+        let server_public = PublicKey::from(&secret).to_bytes();
+        let session = Self::derive(
+            &secret,
+            client_public,
+            &client_public,
+            &server_public,
+            false,
+        )?;
+        let hello = postcard::to_stdvec(&SessionPacket::ServerHello {
+            public_key: server_public,
+        })
+        .unwrap();
+        Ok((session, hello))
+    }
+
+    pub fn peer_public(&self) -> &[u8; 32] {
+        &self.peer_public
+    }
+
+    /// Nothing from the peer has got through yet
+    pub fn is_unauthenticated(&self) -> bool {
+        self.last_authenticated.is_none()
+    }
+
+    /// See [`UNAUTHENTICATED_SESSION_TIMEOUT`]
+    pub fn is_stale(&self, now: Instant) -> bool {
+        self.is_unauthenticated()
+            && now.saturating_duration_since(self.established) >= UNAUTHENTICATED_SESSION_TIMEOUT
+    }
+
+    /// Encrypt a datagram into a [`SessionPacket::Data`]
+    pub fn seal(&mut self, plaintext: &[u8]) -> Vec<u8> {
+        let counter = self.send_counter;
+        self.send_counter += 1;
+        let ciphertext = self
+            .send
+            .encrypt(&nonce(counter), plaintext)
+            .expect("chacha20poly1305 only fails on absurdly large inputs");
+        postcard::to_stdvec(&SessionPacket::Data {
+            counter,
+            ciphertext: &ciphertext,
+        })
+        .unwrap()
+    }
+
+    /// Decrypt the payload of a [`SessionPacket::Data`], rejecting forgeries and replays
+    pub fn open(
+        &mut self,
+        counter: u64,
+        ciphertext: &[u8],
+        now: Instant,
+    ) -> Result<Vec<u8>, SessionError> {
+        if !self.replay.is_fresh(counter) {
+            return Err(SessionError::Replayed { counter });
+        }
+        let plaintext = self
+            .receive
+            .decrypt(
+                &nonce(counter),
+                Payload {
+                    msg: ciphertext,
+                    aad: &[],
+                },
+            )
+            .map_err(|_| SessionError::Unauthenticated)?;
+
+        self.replay.mark(counter);
+        self.last_authenticated = Some(now);
+        Ok(plaintext)
+    }
+}
+
+/// Lets the server check that a hello comes from a host that can receive at its address without
+/// keeping anything per hello. The cookie is a keyed hash of the address, the client's key and
+/// the current [`COOKIE_PERIOD`], so a spoofed hello never gets the answer it needs to go on.
+pub struct HelloCookies {
+    key: [u8; 32],
+    started: Instant,
+}
+
+impl HelloCookies {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            key: rand::random(),
+            started: now,
+        }
+    }
+
+    fn period(&self, now: Instant) -> u64 {
+        now.saturating_duration_since(self.started).as_secs() / COOKIE_PERIOD.as_secs()
+    }
+
+    fn hash(&self, addr: SocketAddr, client_public: &[u8; 32], period: u64) -> blake3::Hash {
+        let mut hasher = blake3::Hasher::new_keyed(&self.key);
+        hasher.update(addr.to_string().as_bytes());
+        hasher.update(client_public);
+        hasher.update(&period.to_le_bytes());
+        hasher.finalize()
+    }
+
+    pub fn cookie(&self, addr: SocketAddr, client_public: &[u8; 32], now: Instant) -> [u8; 32] {
+        *self.hash(addr, client_public, self.period(now)).as_bytes()
+    }
+
+    pub fn check(
+        &self,
+        addr: SocketAddr,
+        client_public: &[u8; 32],
+        cookie: &[u8; 32],
+        now: Instant,
+    ) -> bool {
+        let period = self.period(now);
+        let cookie = blake3::Hash::from_bytes(*cookie);
+        // Hash compares in constant time
+        [Some(period), period.checked_sub(1)]
+            .into_iter()
+            .flatten()
+            .any(|period| self.hash(addr, client_public, period) == cookie)
+    }
+}
+
+/// Client side of a handshake that the server hasn't answered yet
+pub struct ClientHandshake {
+    secret: StaticSecret,
+    public: [u8; 32],
+    /// Echoed in our hellos once the server sent one
+    pub cookie: Option<[u8; 32]>,
+    pub last_sent: Instant,
+    /// Datagrams sent before the session was up, sent once it is
+    pub queued: Vec<Vec<u8>>,
+}
+
+impl ClientHandshake {
+    pub fn new(now: Instant) -> Self {
+        let secret = StaticSecret::from(rand::random::<[u8; 32]>());
+        let public = PublicKey::from(&secret).to_bytes();
+        Self {
+            secret,
+            public,
+            cookie: None,
+            last_sent: now,
+            queued: vec![],
+        }
+    }
+
+    pub fn hello(&self) -> Vec<u8> {
+        postcard::to_stdvec(&SessionPacket::ClientHello {
+            public_key: self.public,
+            cookie: self.cookie,
+        })
+        .unwrap()
+    }
+
+    pub fn finish(&self, server_public: [u8; 32]) -> Result<Session, SessionError> {
+        Session::derive(
+            &self.secret,
+            server_public,
+            &self.public,
+            &server_public,
+            true,
+        )
+    }
+}
+
+pub enum SessionState {
+    Handshaking(ClientHandshake),
+    Established {
+        session: Session,
+        /// Kept by the server to answer repeated hellos from the same client
+        server_hello: Option<Vec<u8>>,
+    },
+}
+
+impl SessionState {
+    pub fn established(&self) -> Option<&Session> {
+        match self {
+            SessionState::Handshaking(_) => None,
+            SessionState::Established { session, .. } => Some(session),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn handshake() -> (Session, Session) {
+        let now = Instant::now();
+        let client = ClientHandshake::new(now);
+        let SessionPacket::ClientHello { public_key, .. } =
+            postcard::from_bytes(&client.hello()).unwrap()
+        else {
+            panic!("expected client hello");
+        };
+
+        let (server, server_hello) = Session::accept(public_key).unwrap();
+        let SessionPacket::ServerHello { public_key } =
+            postcard::from_bytes(&server_hello).unwrap()
+        else {
+            panic!("expected server hello");
+        };
+        (client.finish(public_key).unwrap(), server)
+    }
+
+    fn open(session: &mut Session, packet: &[u8]) -> Result<Vec<u8>, SessionError> {
+        let SessionPacket::Data {
+            counter,
+            ciphertext,
+        } = postcard::from_bytes(packet).unwrap()
+        else {
+            panic!("expected data");
+        };
+        session.open(counter, ciphertext, Instant::now())
+    }
+
+    #[test]
+    fn test_roundtrip_both_directions() {
+        let (mut client, mut server) = handshake();
+
+        let packet = client.seal(b"movement");
+        assert_eq!(open(&mut server, &packet).unwrap(), b"movement");
+
+        let packet = server.seal(b"snapshot");
+        assert_eq!(open(&mut client, &packet).unwrap(), b"snapshot");
+        assert!(server.last_authenticated.is_some());
+        assert!(!server.is_stale(Instant::now() + UNAUTHENTICATED_SESSION_TIMEOUT));
+    }
+
+    #[test]
+    fn test_sessions_nothing_came_through_go_stale() {
+        let (_, server) = handshake();
+        assert!(server.is_unauthenticated());
+        assert!(!server.is_stale(Instant::now()));
+        assert!(server.is_stale(Instant::now() + UNAUTHENTICATED_SESSION_TIMEOUT));
+    }
+
+    #[test]
+    fn test_rejects_tampering_and_replays() {
+        let (mut client, mut server) = handshake();
+
+        let mut packet = client.seal(b"cast skill");
+        let last = packet.len() - 1;
+        packet[last] ^= 1;
+        assert_eq!(
+            open(&mut server, &packet),
+            Err(SessionError::Unauthenticated)
+        );
+
+        let packet = client.seal(b"cast skill");
+        assert!(open(&mut server, &packet).is_ok());
+        assert_eq!(
+            open(&mut server, &packet),
+            Err(SessionError::Replayed { counter: 1 })
+        );
+
+        // Our own packets don't decrypt under the receive key
+        let packet = server.seal(b"echo");
+        assert_eq!(
+            open(&mut server, &packet),
+            Err(SessionError::Unauthenticated)
+        );
+
+        // A session from another handshake can't forge packets for this one
+        let (mut other_client, _) = handshake();
+        let packet = other_client.seal(b"spoofed");
+        assert_eq!(
+            open(&mut server, &packet),
+            Err(SessionError::Unauthenticated)
+        );
+    }
+
+    #[test]
+    fn test_cookies_only_check_out_for_their_address() {
+        let now = Instant::now();
+        let cookies = HelloCookies::new(now);
+        let addr: SocketAddr = "192.0.2.1:4000".parse().unwrap();
+        let client_public = [7; 32];
+
+        let cookie = cookies.cookie(addr, &client_public, now);
+        assert!(cookies.check(addr, &client_public, &cookie, now));
+        assert!(cookies.check(addr, &client_public, &cookie, now + COOKIE_PERIOD));
+        assert!(!cookies.check(addr, &client_public, &cookie, now + COOKIE_PERIOD * 2));
+
+        let other: SocketAddr = "192.0.2.1:4001".parse().unwrap();
+        assert!(!cookies.check(other, &client_public, &cookie, now));
+        assert!(!cookies.check(addr, &[8; 32], &cookie, now));
+        assert!(!HelloCookies::new(now).check(addr, &client_public, &cookie, now));
+    }
+
+    #[test]
+    fn test_replay_window_out_of_order() {
+        let mut window = ReplayWindow::default();
+        window.mark(5);
+        window.mark(3);
+        window.mark(10);
+        assert!(!window.is_fresh(5));
+        assert!(!window.is_fresh(3));
+        assert!(!window.is_fresh(10));
+        assert!(window.is_fresh(4));
+        assert!(window.is_fresh(11));
+
+        window.mark(10 + REPLAY_WINDOW + 1);
+        // Too old to tell apart from a replay
+        assert!(!window.is_fresh(9));
+    }
+}
@@ -0,0 +1,174 @@
+//! The ways serialized groupings get to the other side.
+//!
+//! UDP goes through the session, reliability and fragmentation layers in `netlib` before it gets
+//! here. Websockets and the in-memory transport already deliver everything in order, so events
+//! for them are queued and sent as one batch per tick, see `flush_outgoing_events_stream`.
+use std::sync::{
+    Arc,
+    atomic::{AtomicUsize, Ordering},
+    mpsc,
+};
+
+use bevy_internal::prelude::*;
+use dashmap::DashMap;
+
+use super::EndpointGeneral;
+use crate::message_io::node::NodeHandler;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TransportKind {
+    Udp,
+    WebSocket,
+    Memory,
+}
+
+pub trait Transport: Send + Sync + 'static {
+    /// Send one serialized grouping. Returns false if the endpoint is gone.
+    fn send(&self, endpoint: EndpointGeneral, data: &[u8]) -> bool;
+
+    /// Drop whatever is kept for `endpoint`, we won't be sending to it again
+    fn forget(&self, _endpoint: EndpointGeneral) {}
+}
+
+pub struct UdpTransport(pub NodeHandler<()>);
+
+impl Transport for UdpTransport {
+    fn send(&self, endpoint: EndpointGeneral, data: &[u8]) -> bool {
+        let EndpointGeneral::UDP(endpoint) = endpoint else {
+            return false;
+        };
+        // UDP can't tell us if anyone is listening
+        self.0.network().send(endpoint, data);
+        true
+    }
+}
+
+/// Address of one App on a [`MemoryNetwork`]
+#[derive(Clone, Debug, Copy, PartialEq, Eq, Hash)]
+pub struct MemoryEndpoint(pub u64);
+
+impl MemoryEndpoint {
+    pub const SERVER: Self = Self(0);
+
+    pub fn random_client() -> Self {
+        Self(rand::random_range(1..=u64::MAX))
+    }
+}
+
+type Inbox = mpsc::Sender<(MemoryEndpoint, Vec<u8>)>;
+
+/// Connects a server and its clients running in the same process, without any sockets. Clones
+/// are handles to the same network, so insert one into every App that should talk to the others.
+#[derive(Resource, Clone, Default)]
+pub struct MemoryNetwork {
+    inboxes: Arc<DashMap<MemoryEndpoint, Inbox>>,
+    /// Sent but not yet handled by the receiver
+    in_flight: Arc<AtomicUsize>,
+}
+
+impl MemoryNetwork {
+    /// Claim `endpoint`, returning everything sent to it and who sent it
+    pub fn bind(&self, endpoint: MemoryEndpoint) -> MemoryInbox {
+        let (tx, rx) = mpsc::channel();
+        self.inboxes.insert(endpoint, tx);
+        MemoryInbox {
+            receiver: rx,
+            in_flight: self.in_flight.clone(),
+        }
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.in_flight.load(Ordering::Acquire) == 0
+    }
+
+    /// Block until everything sent so far has been handled, so tests can step Apps in lockstep
+    pub fn wait_idle(&self) {
+        while !self.is_idle() {
+            std::thread::yield_now();
+        }
+    }
+
+    pub fn transport(&self, local: MemoryEndpoint) -> MemoryTransport {
+        MemoryTransport {
+            network: self.clone(),
+            local,
+        }
+    }
+}
+
+pub struct MemoryInbox {
+    receiver: mpsc::Receiver<(MemoryEndpoint, Vec<u8>)>,
+    in_flight: Arc<AtomicUsize>,
+}
+
+impl MemoryInbox {
+    /// Handle everything sent to us, for as long as the network exists
+    pub fn for_each(self, mut f: impl FnMut(MemoryEndpoint, Vec<u8>)) {
+        for (from, data) in self.receiver.iter() {
+            f(from, data);
+            self.in_flight.fetch_sub(1, Ordering::AcqRel);
+        }
+    }
+
+    pub fn try_recv(&self) -> Option<(MemoryEndpoint, Vec<u8>)> {
+        let received = self.receiver.try_recv().ok()?;
+        self.in_flight.fetch_sub(1, Ordering::AcqRel);
+        Some(received)
+    }
+}
+
+pub struct MemoryTransport {
+    network: MemoryNetwork,
+    local: MemoryEndpoint,
+}
+
+impl Transport for MemoryTransport {
+    fn send(&self, endpoint: EndpointGeneral, data: &[u8]) -> bool {
+        let EndpointGeneral::Memory(to) = endpoint else {
+            return false;
+        };
+        let Some(inbox) = self.network.inboxes.get(&to) else {
+            return false;
+        };
+        self.network.in_flight.fetch_add(1, Ordering::AcqRel);
+        if inbox.send((self.local, data.to_vec())).is_err() {
+            self.network.in_flight.fetch_sub(1, Ordering::AcqRel);
+            return false;
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_memory_transport_delivers_in_order() {
+        let network = MemoryNetwork::default();
+        let server = network.bind(MemoryEndpoint::SERVER);
+        let client = MemoryEndpoint(7);
+        let client_inbox = network.bind(client);
+
+        let to_server = network.transport(client);
+        assert!(to_server.send(EndpointGeneral::Memory(MemoryEndpoint::SERVER), b"hello"));
+        assert!(to_server.send(EndpointGeneral::Memory(MemoryEndpoint::SERVER), b"again"));
+        assert!(!network.is_idle());
+        assert_eq!(server.try_recv().unwrap(), (client, b"hello".to_vec()));
+        assert_eq!(server.try_recv().unwrap(), (client, b"again".to_vec()));
+        assert!(network.is_idle());
+
+        let to_client = network.transport(MemoryEndpoint::SERVER);
+        assert!(to_client.send(EndpointGeneral::Memory(client), b"world"));
+        assert_eq!(
+            client_inbox.try_recv().unwrap(),
+            (MemoryEndpoint::SERVER, b"world".to_vec())
+        );
+
+        // Nobody bound this one, or they went away
+        assert!(!to_client.send(EndpointGeneral::Memory(MemoryEndpoint(8)), b"lost"));
+        drop(client_inbox);
+        assert!(!to_client.send(EndpointGeneral::Memory(client), b"lost"));
+        assert!(network.is_idle());
+    }
+}
@@ -0,0 +1,288 @@
+//! Input-based movement for the units players control.
+//!
+//! Every tick the client applies its [`MovementAction`] to its own unit straight away and sends it
+//! to the server with a sequence number. The server queues them, simulates one per tick, and acks
+//! the last one it simulated with where that left the unit. The client compares that with what it
+//! predicted for the same input. If they disagree it takes the server's state and replays the
+//! inputs the server hasn't simulated yet on top of it.
+use std::collections::VecDeque;
+
+use avian3d::math::{Scalar, Vector};
+use serde::{Deserialize, Serialize};
+
+use crate::character_controller::{MovementAction, damp_horizontal, movement_acceleration};
+
+/// The server drops the oldest queued inputs beyond this, so a burst can't delay everything after
+pub const MAX_QUEUED_INPUTS: usize = 8;
+/// How many of its newest inputs the client sends every tick, so one lost packet loses nothing
+pub const INPUT_REDUNDANCY: usize = 4;
+/// Inputs the client remembers while it waits for acks, about two seconds of ticks
+const MAX_PENDING_INPUTS: usize = 128;
+/// How far the server may be from our prediction before we take its state
+const POSITION_TOLERANCE: Scalar = 0.05;
+const VELOCITY_TOLERANCE: Scalar = 0.5;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SequencedInput {
+    pub sequence: u64,
+    pub action: MovementAction,
+}
+
+/// Where a controller is after simulating an input
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub struct ControllerState {
+    pub position: Vector,
+    pub velocity: Vector,
+}
+
+impl ControllerState {
+    fn close_to(&self, other: &Self) -> bool {
+        self.position.distance(other.position) <= POSITION_TOLERANCE
+            && self.velocity.distance(other.velocity) <= VELOCITY_TOLERANCE
+    }
+}
+
+/// What stepping a controller depends on besides its state and input. Replays assume these stay
+/// the same for every input they replay.
+#[derive(Debug, Clone, Copy)]
+pub struct ControllerParams {
+    pub acceleration: Scalar,
+    pub damping: Scalar,
+    pub jump_impulse: Scalar,
+    pub gravity: Vector,
+    pub is_grounded: bool,
+    pub ground_normal: Vector,
+}
+
+impl ControllerParams {
+    /// One tick of the character controller systems and integration, for replaying inputs.
+    /// Collisions are only approximated by not moving into the ground we stand on.
+    pub fn step(
+        &self,
+        state: ControllerState,
+        action: &MovementAction,
+        delta_time: Scalar,
+    ) -> ControllerState {
+        let mut velocity = state.velocity + self.gravity * delta_time;
+        velocity += movement_acceleration(
+            action,
+            self.acceleration,
+            self.is_grounded,
+            self.ground_normal,
+            delta_time,
+        );
+        if action.is_jumping && self.is_grounded {
+            velocity.y = self.jump_impulse;
+        }
+        damp_horizontal(&mut velocity, self.damping, delta_time);
+
+        if self.is_grounded && velocity.dot(self.ground_normal) < 0.0 {
+            velocity = velocity.reject_from_normalized(self.ground_normal);
+        }
+
+        ControllerState {
+            position: state.position + velocity * delta_time,
+            velocity,
+        }
+    }
+}
+
+/// The server's queue of inputs for one unit
+#[derive(Debug, Clone, Default)]
+pub struct InputQueue {
+    queued: VecDeque<SequencedInput>,
+    /// The newest sequence we have queued, older resends are ignored
+    newest: Option<u64>,
+}
+
+impl InputQueue {
+    pub fn push(&mut self, inputs: impl IntoIterator<Item = SequencedInput>) {
+        for input in inputs {
+            if self.newest.is_some_and(|newest| input.sequence <= newest) {
+                continue;
+            }
+            self.newest = Some(input.sequence);
+            self.queued.push_back(input);
+        }
+
+        while self.queued.len() > MAX_QUEUED_INPUTS {
+            self.queued.pop_front();
+        }
+    }
+
+    /// The input to simulate this tick
+    pub fn pop(&mut self) -> Option<SequencedInput> {
+        self.queued.pop_front()
+    }
+}
+
+#[derive(Debug, Clone)]
+struct PredictedInput {
+    input: SequencedInput,
+    /// Where it left us, once its tick has been simulated
+    state: Option<ControllerState>,
+}
+
+/// The client's inputs the server hasn't acked yet, oldest first
+#[derive(Debug, Clone, Default)]
+pub struct PredictionHistory {
+    next_sequence: u64,
+    pending: VecDeque<PredictedInput>,
+}
+
+impl PredictionHistory {
+    /// Start predicting `action` for this tick
+    pub fn push(&mut self, action: MovementAction) -> SequencedInput {
+        let input = SequencedInput {
+            sequence: self.next_sequence,
+            action,
+        };
+        self.next_sequence += 1;
+
+        self.pending.push_back(PredictedInput {
+            input: input.clone(),
+            state: None,
+        });
+        if self.pending.len() > MAX_PENDING_INPUTS {
+            self.pending.pop_front();
+        }
+        input
+    }
+
+    /// Where the newest input left us, once its tick has been simulated
+    pub fn record(&mut self, state: ControllerState) {
+        if let Some(newest) = self.pending.back_mut() {
+            newest.state = Some(state);
+        }
+    }
+
+    /// The newest few inputs, to send to the server
+    pub fn to_send(&self) -> Vec<SequencedInput> {
+        let skip = self.pending.len().saturating_sub(INPUT_REDUNDANCY);
+        self.pending
+            .iter()
+            .skip(skip)
+            .map(|p| p.input.clone())
+            .collect()
+    }
+
+    /// The server simulated up to `sequence` and ended up at `server`. Returns where we should be
+    /// now if that isn't what we predicted.
+    pub fn acknowledge(
+        &mut self,
+        sequence: u64,
+        server: ControllerState,
+        params: &ControllerParams,
+        delta_time: Scalar,
+    ) -> Option<ControllerState> {
+        while self
+            .pending
+            .front()
+            .is_some_and(|p| p.input.sequence < sequence)
+        {
+            self.pending.pop_front();
+        }
+
+        let acked = self.pending.front()?;
+        if acked.input.sequence != sequence {
+            return None;
+        }
+        let predicted = acked.state?;
+        self.pending.pop_front();
+
+        if predicted.close_to(&server) {
+            return None;
+        }
+
+        let mut state = server;
+        for pending in &mut self.pending {
+            state = params.step(state, &pending.input.action, delta_time);
+            pending.state = Some(state);
+        }
+        Some(state)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use avian3d::math::Vector2;
+
+    const DELTA: Scalar = 1.0 / 60.0;
+
+    fn params() -> ControllerParams {
+        ControllerParams {
+            acceleration: 30.0,
+            damping: 0.9,
+            jump_impulse: 7.0,
+            gravity: Vector::NEG_Y * 9.81,
+            is_grounded: true,
+            ground_normal: Vector::Y,
+        }
+    }
+
+    fn forward() -> MovementAction {
+        MovementAction {
+            move_input_dir: Vector2::Y,
+            ..Default::default()
+        }
+    }
+
+    fn input(sequence: u64) -> SequencedInput {
+        SequencedInput {
+            sequence,
+            action: forward(),
+        }
+    }
+
+    #[test]
+    fn test_input_queue_ignores_resends() {
+        let mut queue = InputQueue::default();
+        queue.push([input(0), input(1)]);
+        queue.push([input(0), input(1), input(2)]);
+
+        let popped: Vec<_> = std::iter::from_fn(|| queue.pop())
+            .map(|i| i.sequence)
+            .collect();
+        assert_eq!(popped, vec![0, 1, 2]);
+
+        queue.push((0..20).map(input));
+        assert_eq!(queue.pop().unwrap().sequence, 20 - MAX_QUEUED_INPUTS as u64);
+    }
+
+    #[test]
+    fn test_replays_unacked_inputs_on_correction() {
+        let params = params();
+        let mut history = PredictionHistory::default();
+        let mut predicted = ControllerState::default();
+        for _ in 0..5 {
+            let input = history.push(forward());
+            predicted = params.step(predicted, &input.action, DELTA);
+            history.record(predicted);
+        }
+        assert_eq!(
+            history.to_send().first().unwrap().sequence,
+            5 - INPUT_REDUNDANCY as u64
+        );
+
+        // The server agrees with where the first input left us
+        let first = params.step(ControllerState::default(), &forward(), DELTA);
+        assert_eq!(history.acknowledge(0, first, &params, DELTA), None);
+
+        // It was pushed a metre sideways by the second one
+        let mut server = params.step(first, &forward(), DELTA);
+        server.position.x += 1.0;
+        let corrected = history.acknowledge(1, server, &params, DELTA).unwrap();
+
+        let mut expected = server;
+        for _ in 2..5 {
+            expected = params.step(expected, &forward(), DELTA);
+        }
+        assert_eq!(corrected, expected);
+        assert!((corrected.position.x - predicted.position.x - 1.0).abs() < 1e-4);
+
+        // Later acks are compared against the replayed states
+        let third = params.step(server, &forward(), DELTA);
+        assert_eq!(history.acknowledge(2, third, &params, DELTA), None);
+    }
+}
@@ -5,8 +5,10 @@ use crate::{
     BASE_TICKS_PER_SECOND, CurrentTick,
     event::client::SpawnProjectile,
     physics::terrain::TerrainParams,
+    rng::GameRng,
     skills::{Skill, SkillSource},
 };
+use rand::RngExt;
 use serde::{Deserialize, Serialize};
 
 use crate::{event::NetEntId, netlib::Tick};
@@ -107,10 +109,12 @@ impl ProjectileAI {
 
 impl Plugin for ProjectilePlugin {
     fn build(&self, app: &mut App) {
-        app.add_message::<SpawnProjectile>().add_systems(
-            Update,
-            (update_projectiles, despawn_projectile_after_duration),
-        );
+        app.add_message::<SpawnProjectile>()
+            .init_resource::<GameRng>()
+            .add_systems(
+                Update,
+                (update_projectiles, despawn_projectile_after_duration),
+            );
     }
 }
 
@@ -151,7 +155,8 @@ fn despawn_projectile_after_duration(
     }
 }
 
-fn update_projectiles(
+#[allow(clippy::too_many_arguments)]
+pub fn update_projectiles(
     mut query: Query<(
         Entity,
         &mut Transform,
@@ -166,6 +171,7 @@ fn update_projectiles(
     mut commands: Commands,
     terrain_info: Res<TerrainParams>,
     mut projectile_spawner: MessageWriter<SpawnProjectile>,
+    mut rng: ResMut<GameRng>,
 ) {
     let noise = terrain_info.perlin();
     for (ent, mut transform, origin, real_spawn_time, projectile_ai, projectile_source) in
@@ -297,10 +303,10 @@ fn update_projectiles(
 
                     for x in 0..5 {
                         for y in 0..5 {
-                            let rand_offset_x =
-                                (rand::random_range(-2.0..2.0)) + (x as f32 * 1.0) - 2.0;
-                            let rand_offset_z =
-                                (rand::random_range(-2.0..2.0)) + (y as f32 * 1.0) - 2.0;
+                            let rand_x = rng.random_range(-2.0..2.0);
+                            let rand_z = rng.random_range(-2.0..2.0);
+                            let rand_offset_x = rand_x + (x as f32 * 1.0) - 2.0;
+                            let rand_offset_z = rand_z + (y as f32 * 1.0) - 2.0;
                             let target_xz = Vec2::new(
                                 ground_target.x + rand_offset_x,
                                 ground_target.z + rand_offset_z,
@@ -0,0 +1,54 @@
+//! Randomness that a server capture can replay.
+//!
+//! Entity ids and gameplay rolls come from the [`GameRng`] resource instead of `rand::random`, so
+//! a server seeded with the seed recorded in a capture gives the same ids and rolls again. Each
+//! `App` has its own, and the server systems drawing from it are ordered one after the other,
+//! since systems running in parallel would take turns in whatever order they happen to finish.
+use bevy_internal::prelude::*;
+use rand::{SeedableRng, rngs::StdRng};
+
+#[derive(Resource)]
+pub struct GameRng {
+    seed: u64,
+    rng: StdRng,
+}
+
+impl GameRng {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+
+    /// What we were seeded with
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// A generator of its own for a system that can't take this mutably, e.g. because it reads
+    /// the whole `World`. Each `stream` gives different values, the same ones for the same seed.
+    pub fn fork(&self, stream: u64) -> StdRng {
+        StdRng::seed_from_u64(self.seed ^ stream.wrapping_mul(0x9e37_79b9_7f4a_7c15))
+    }
+}
+
+impl Default for GameRng {
+    fn default() -> Self {
+        Self::new(rand::random())
+    }
+}
+
+impl std::ops::Deref for GameRng {
+    type Target = StdRng;
+
+    fn deref(&self) -> &StdRng {
+        &self.rng
+    }
+}
+
+impl std::ops::DerefMut for GameRng {
+    fn deref_mut(&mut self) -> &mut StdRng {
+        &mut self.rng
+    }
+}
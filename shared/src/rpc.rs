@@ -0,0 +1,300 @@
+//! Typed calls from a client to the server, each answered by exactly one reply.
+//!
+//! A request type implements [`Rpc`], which names the method and the response it gets. The client
+//! calls it through [`RpcClient`] and gets an [`RpcHandle`] back, which a system can poll or a
+//! task can await. The request travels as an `RpcRequest` event, and the server's `RpcResponse`
+//! carries the same [`RpcId`] back so replies find their call in any order. Calls whose reply
+//! doesn't arrive in time fail with [`RpcError::Timeout`].
+use std::{
+    collections::HashMap,
+    future::Future,
+    marker::PhantomData,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+
+use bevy_internal::prelude::*;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+
+use crate::event::{client::RpcResponse, server::RpcRequest};
+
+pub mod methods;
+
+/// How long a call waits for its reply, unless it asks for something else
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A request the server answers with a [`Rpc::Response`]
+pub trait Rpc: Serialize + DeserializeOwned + Send + Sync + 'static {
+    /// Unique among all RPCs, the server finds the handler by it. Also the name of the method's
+    /// own [`crate::netlib::rate_limit`].
+    const METHOD: &'static str;
+    type Response: Serialize + DeserializeOwned + Send + Sync + 'static;
+}
+
+/// Picked by the caller, unique among its calls
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub struct RpcId(pub u64);
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RpcError {
+    /// The reply didn't arrive in time
+    Timeout,
+    /// We lost the connection before the reply arrived
+    Disconnected,
+    /// The caller hasn't joined as a player
+    NotJoined,
+    /// The server has no handler for this method
+    UnknownMethod,
+    /// The request or its reply couldn't be read
+    Malformed,
+    /// The caller is making too many calls to this method
+    RateLimited,
+    /// The handler refused the request
+    Rejected(String),
+}
+
+impl std::fmt::Display for RpcError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RpcError::Timeout => write!(f, "no reply in time"),
+            RpcError::Disconnected => write!(f, "disconnected before the reply"),
+            RpcError::NotJoined => write!(f, "not joined"),
+            RpcError::UnknownMethod => write!(f, "unknown method"),
+            RpcError::Malformed => write!(f, "malformed request or reply"),
+            RpcError::RateLimited => write!(f, "rate limited"),
+            RpcError::Rejected(reason) => write!(f, "rejected: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for RpcError {}
+
+pub fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, RpcError> {
+    postcard::to_stdvec(value).map_err(|_| RpcError::Malformed)
+}
+
+pub fn decode<T: DeserializeOwned>(data: &[u8]) -> Result<T, RpcError> {
+    postcard::from_bytes(data).map_err(|_| RpcError::Malformed)
+}
+
+/// What a handle and the [`RpcClient`] share about one call
+#[derive(Default)]
+struct CallState {
+    result: Option<Result<Vec<u8>, RpcError>>,
+    waker: Option<Waker>,
+}
+
+type SharedCall = Arc<Mutex<CallState>>;
+
+fn complete(call: &SharedCall, result: Result<Vec<u8>, RpcError>) {
+    let mut call = call.lock().unwrap();
+    call.result = Some(result);
+    if let Some(waker) = call.waker.take() {
+        waker.wake();
+    }
+}
+
+/// The reply to one call, once it arrives. Dropping it doesn't cancel the call.
+pub struct RpcHandle<R: Rpc> {
+    id: RpcId,
+    call: SharedCall,
+    _rpc: PhantomData<fn() -> R>,
+}
+
+impl<R: Rpc> RpcHandle<R> {
+    pub fn id(&self) -> RpcId {
+        self.id
+    }
+
+    /// The reply if it arrived, for polling from a system. It is only returned once.
+    pub fn try_take(&mut self) -> Option<Result<R::Response, RpcError>> {
+        let result = self.call.lock().unwrap().result.take()?;
+        Some(result.and_then(|data| decode(&data)))
+    }
+}
+
+impl<R: Rpc> Future for RpcHandle<R> {
+    type Output = Result<R::Response, RpcError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        if let Some(result) = this.try_take() {
+            return Poll::Ready(result);
+        }
+        this.call.lock().unwrap().waker = Some(cx.waker().clone());
+        // It may have arrived while we weren't holding the lock
+        match this.try_take() {
+            Some(result) => Poll::Ready(result),
+            None => Poll::Pending,
+        }
+    }
+}
+
+struct PendingCall {
+    method: &'static str,
+    timeout: Duration,
+    /// Set once the request is sent
+    deadline: Option<f64>,
+    call: SharedCall,
+}
+
+/// The client's calls, see the [module docs](self)
+#[derive(Resource, Default)]
+pub struct RpcClient {
+    next_id: u64,
+    /// Called, but not sent yet
+    outgoing: Vec<RpcRequest>,
+    pending: HashMap<RpcId, PendingCall>,
+}
+
+impl RpcClient {
+    pub fn call<R: Rpc>(&mut self, request: &R) -> RpcHandle<R> {
+        self.call_with_timeout(request, DEFAULT_TIMEOUT)
+    }
+
+    /// Call and wait at most `timeout` from when the request is sent
+    pub fn call_with_timeout<R: Rpc>(&mut self, request: &R, timeout: Duration) -> RpcHandle<R> {
+        self.next_id += 1;
+        let id = RpcId(self.next_id);
+        let call = SharedCall::default();
+
+        match encode(request) {
+            Ok(payload) => {
+                self.outgoing.push(RpcRequest {
+                    id,
+                    method: R::METHOD.to_string(),
+                    payload,
+                });
+                self.pending.insert(
+                    id,
+                    PendingCall {
+                        method: R::METHOD,
+                        timeout,
+                        deadline: None,
+                        call: call.clone(),
+                    },
+                );
+            }
+            Err(e) => complete(&call, Err(e)),
+        }
+
+        RpcHandle {
+            id,
+            call,
+            _rpc: PhantomData,
+        }
+    }
+
+    /// Requests to send, starting their timeouts at `now`
+    pub fn take_outgoing(&mut self, now: f64) -> Vec<RpcRequest> {
+        let outgoing = std::mem::take(&mut self.outgoing);
+        for request in &outgoing {
+            if let Some(pending) = self.pending.get_mut(&request.id) {
+                pending.deadline = Some(now + pending.timeout.as_secs_f64());
+            }
+        }
+        outgoing
+    }
+
+    /// Hand a reply to its call. Replies to calls that already timed out are ignored.
+    pub fn resolve(&mut self, response: RpcResponse) {
+        match self.pending.remove(&response.id) {
+            Some(pending) => complete(&pending.call, response.result),
+            None => debug!(id = ?response.id, "Reply to a call we gave up on"),
+        }
+    }
+
+    /// Fail calls that have waited too long for their reply
+    pub fn expire(&mut self, now: f64) {
+        self.pending.retain(|id, pending| {
+            if pending.deadline.is_none_or(|deadline| now < deadline) {
+                return true;
+            }
+            warn!(?id, method = pending.method, "Call timed out");
+            complete(&pending.call, Err(RpcError::Timeout));
+            false
+        });
+    }
+
+    /// Fail every call that hasn't been answered, sent or not
+    pub fn fail_all(&mut self, error: RpcError) {
+        self.outgoing.clear();
+        for (_, pending) in self.pending.drain() {
+            complete(&pending.call, Err(error.clone()));
+        }
+    }
+
+    /// Calls still waiting for their reply
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(Serialize, Deserialize)]
+    struct Double(u32);
+
+    impl Rpc for Double {
+        const METHOD: &'static str = "Double";
+        type Response = u32;
+    }
+
+    fn reply(request: &RpcRequest) -> RpcResponse {
+        let Double(n) = decode(&request.payload).unwrap();
+        RpcResponse {
+            id: request.id,
+            result: encode(&(n * 2)),
+        }
+    }
+
+    #[test]
+    fn test_replies_find_their_call() {
+        let mut rpc = RpcClient::default();
+        let mut a = rpc.call(&Double(1));
+        let mut b = rpc.call(&Double(2));
+        assert_ne!(a.id(), b.id());
+
+        let sent = rpc.take_outgoing(0.0);
+        assert_eq!(sent.len(), 2);
+        assert!(a.try_take().is_none());
+
+        // In the other order
+        rpc.resolve(reply(&sent[1]));
+        rpc.resolve(reply(&sent[0]));
+        assert_eq!(a.try_take(), Some(Ok(2)));
+        assert_eq!(b.try_take(), Some(Ok(4)));
+        assert_eq!(a.try_take(), None);
+        assert_eq!(rpc.pending(), 0);
+    }
+
+    #[test]
+    fn test_calls_time_out() {
+        let mut rpc = RpcClient::default();
+        let mut slow = rpc.call_with_timeout(&Double(1), Duration::from_secs(1));
+        let mut patient = rpc.call(&Double(2));
+
+        // The clock only starts once they are sent
+        rpc.expire(100.0);
+        let sent = rpc.take_outgoing(100.0);
+        rpc.expire(100.5);
+        assert!(slow.try_take().is_none());
+
+        rpc.expire(101.0);
+        assert_eq!(slow.try_take(), Some(Err(RpcError::Timeout)));
+        assert!(patient.try_take().is_none());
+        // Too late
+        rpc.resolve(reply(&sent[0]));
+        assert_eq!(slow.try_take(), None);
+
+        let mut unsent = rpc.call(&Double(3));
+        rpc.fail_all(RpcError::Disconnected);
+        assert_eq!(patient.try_take(), Some(Err(RpcError::Disconnected)));
+        assert_eq!(unsent.try_take(), Some(Err(RpcError::Disconnected)));
+        assert!(rpc.take_outgoing(102.0).is_empty());
+    }
+}
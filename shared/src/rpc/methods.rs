@@ -0,0 +1,21 @@
+//! Every [`Rpc`] the server answers, with the response it gets.
+use std::collections::HashMap;
+
+use bevy_internal::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{PlayerPing, PlayerPingInteger, event::PlayerId, rpc::Rpc};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RequestScoreboard {}
+
+impl Rpc for RequestScoreboard {
+    const METHOD: &'static str = "RequestScoreboard";
+    type Response = RequestScoreboardResponse;
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Message)]
+pub struct RequestScoreboardResponse {
+    pub player_names: HashMap<PlayerId, String>,
+    pub player_pings: HashMap<PlayerId, PlayerPing<PlayerPingInteger>>,
+}
@@ -0,0 +1,231 @@
+//! Per-tick snapshots of replicated movement state.
+//!
+//! The server keeps the snapshots it sent to each client and encodes every new one as a delta
+//! against the newest snapshot that client has acked. Entities and components that did not change
+//! since that baseline are left out entirely.
+use std::collections::{HashMap, VecDeque};
+
+use avian3d::prelude::{LinearVelocity, Rotation};
+use bevy_internal::prelude::*;
+
+use crate::{
+    event::{
+        NetEntId,
+        client::{Snapshot, UpdateUnit2},
+    },
+    net_components::{NetComponent, ToNetComponent, foreign::NetComponentForeign},
+    netlib::Tick,
+};
+
+/// How many snapshots we remember to decode or encode deltas against, about a second of ticks
+pub const SNAPSHOT_HISTORY: usize = 64;
+
+/// Replicated state of one entity with `SendNetworkTranformUpdates`
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct EntityState {
+    pub transform: Transform,
+    pub velocity: Option<LinearVelocity>,
+    pub rotation: Option<Rotation>,
+}
+
+impl EntityState {
+    fn changed_components(&self, baseline: Option<&EntityState>) -> Vec<NetComponent> {
+        let mut components = vec![];
+
+        if baseline.is_none_or(|b| b.transform != self.transform) {
+            components.push(self.transform.to_net_component());
+        }
+        if let Some(velocity) = self.velocity
+            && baseline.is_none_or(|b| b.velocity != self.velocity)
+        {
+            components.push(velocity.to_net_component());
+        }
+        if let Some(rotation) = self.rotation
+            && baseline.is_none_or(|b| b.rotation != self.rotation)
+        {
+            components.push(rotation.to_net_component());
+        }
+
+        components
+    }
+
+    fn apply(&mut self, components: &[NetComponent]) {
+        for component in components {
+            match component {
+                NetComponent::Foreign(NetComponentForeign::Transform(t)) => self.transform = *t,
+                NetComponent::Foreign(NetComponentForeign::LinearVelocity(v)) => {
+                    self.velocity = Some(*v)
+                }
+                NetComponent::Foreign(NetComponentForeign::Rotation(r)) => self.rotation = Some(*r),
+                _ => {}
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct WorldSnapshot {
+    pub tick: Tick,
+    pub entities: HashMap<NetEntId, EntityState>,
+}
+
+impl WorldSnapshot {
+    pub fn new(tick: Tick) -> Self {
+        Self {
+            tick,
+            entities: HashMap::new(),
+        }
+    }
+
+    /// Encode only what changed since `baseline`. Without a baseline everything is sent.
+    pub fn encode_delta(&self, baseline: Option<&WorldSnapshot>) -> Snapshot {
+        let mut changed = vec![];
+        for (net_ent_id, state) in &self.entities {
+            let base_state = baseline.and_then(|b| b.entities.get(net_ent_id));
+            let changed_components = state.changed_components(base_state);
+            if changed_components.is_empty() {
+                continue;
+            }
+
+            changed.push(UpdateUnit2 {
+                net_ent_id: *net_ent_id,
+                changed_components,
+                ..Default::default()
+            });
+        }
+
+        let removed = baseline
+            .map(|b| {
+                b.entities
+                    .keys()
+                    .filter(|id| !self.entities.contains_key(id))
+                    .copied()
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Snapshot {
+            tick: self.tick,
+            baseline: baseline.map(|b| b.tick),
+            changed,
+            removed,
+        }
+    }
+
+    /// Rebuild the full snapshot from a delta and the baseline it was encoded against.
+    pub fn decode_delta(delta: &Snapshot, baseline: Option<&WorldSnapshot>) -> WorldSnapshot {
+        let mut entities = baseline.map(|b| b.entities.clone()).unwrap_or_default();
+
+        for net_ent_id in &delta.removed {
+            entities.remove(net_ent_id);
+        }
+
+        for update in &delta.changed {
+            entities
+                .entry(update.net_ent_id)
+                .or_default()
+                .apply(&update.changed_components);
+        }
+
+        WorldSnapshot {
+            tick: delta.tick,
+            entities,
+        }
+    }
+}
+
+/// Snapshots we sent to (server) or received from (client) the other side
+#[derive(Debug, Default)]
+pub struct SnapshotHistory {
+    snapshots: VecDeque<WorldSnapshot>,
+    acked: Option<Tick>,
+}
+
+impl SnapshotHistory {
+    pub fn push(&mut self, snapshot: WorldSnapshot) {
+        self.snapshots.push_back(snapshot);
+        while self.snapshots.len() > SNAPSHOT_HISTORY {
+            self.snapshots.pop_front();
+        }
+    }
+
+    pub fn get(&self, tick: Tick) -> Option<&WorldSnapshot> {
+        self.snapshots.iter().rev().find(|s| s.tick == tick)
+    }
+
+    /// The other side has this snapshot, so it can be used as a baseline
+    pub fn ack(&mut self, tick: Tick) {
+        if self.acked.is_none_or(|acked| tick > acked) {
+            self.acked = Some(tick);
+        }
+    }
+
+    /// The newest acked snapshot we still remember
+    pub fn acked_baseline(&self) -> Option<&WorldSnapshot> {
+        self.acked.and_then(|tick| self.get(tick))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn state(x: f32) -> EntityState {
+        EntityState {
+            transform: Transform::from_xyz(x, 0.0, 0.0),
+            velocity: Some(LinearVelocity(Vec3::X)),
+            rotation: None,
+        }
+    }
+
+    #[test]
+    fn test_delta_roundtrip() {
+        let mut first = WorldSnapshot::new(Tick(1));
+        first.entities.insert(NetEntId(10), state(1.0));
+        first.entities.insert(NetEntId(11), state(2.0));
+        first.entities.insert(NetEntId(12), state(3.0));
+
+        let full = first.encode_delta(None);
+        assert_eq!(full.changed.len(), 3);
+        let decoded_first = WorldSnapshot::decode_delta(&full, None);
+        assert_eq!(decoded_first.entities, first.entities);
+
+        let mut second = first.clone();
+        second.tick = Tick(2);
+        second
+            .entities
+            .get_mut(&NetEntId(10))
+            .unwrap()
+            .transform
+            .translation
+            .x = 5.0;
+        second.entities.remove(&NetEntId(12));
+
+        let delta = second.encode_delta(Some(&first));
+        assert_eq!(delta.baseline, Some(Tick(1)));
+        // Only the moved entity is sent, and only its transform
+        assert_eq!(delta.changed.len(), 1);
+        assert_eq!(delta.changed[0].changed_components.len(), 1);
+        assert_eq!(delta.removed, vec![NetEntId(12)]);
+
+        let decoded = WorldSnapshot::decode_delta(&delta, Some(&decoded_first));
+        assert_eq!(decoded.entities, second.entities);
+    }
+
+    #[test]
+    fn test_history_acks() {
+        let mut history = SnapshotHistory::default();
+        for tick in 0..(SNAPSHOT_HISTORY as u64 + 5) {
+            history.push(WorldSnapshot::new(Tick(tick)));
+        }
+        assert!(history.acked_baseline().is_none());
+
+        history.ack(Tick(20));
+        history.ack(Tick(10));
+        assert_eq!(history.acked_baseline().unwrap().tick, Tick(20));
+
+        // Too old to still be in the history
+        history.ack(Tick(1));
+        assert_eq!(history.acked_baseline().unwrap().tick, Tick(20));
+    }
+}
@@ -1,6 +1,8 @@
 use crate::{decimal::Decimal, skills::Skill};
+use bevy_ecs::component::Component;
 use serde::{Deserialize, Serialize};
 
+#[derive(Component)]
 pub struct PlayerFinalStats {
     pub max_health: Decimal,
     pub movement_mods: Vec<MovementModifier>,
@@ -23,6 +25,21 @@ impl Default for PlayerFinalStats {
     }
 }
 
+impl PlayerFinalStats {
+    /// How much faster than base the movement speed mods make us, they are in percent
+    pub fn movement_speed_multiplier(&self) -> f32 {
+        let increase: f32 = self
+            .movement_mods
+            .iter()
+            .map(|modifier| match modifier {
+                MovementModifier::MomementSpeed(percent) => percent.to_f32() / 100.0,
+                MovementModifier::JumpHeight(_) => 0.0,
+            })
+            .sum();
+        1.0 + increase
+    }
+}
+
 pub enum MovementModifier {
     MomementSpeed(Decimal),
     JumpHeight(Decimal),